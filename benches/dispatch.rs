@@ -0,0 +1,143 @@
+//! Measures how long a request takes to be dispatched, depending on how many routes the
+//! router has, and how long finding its route takes with type IDs hashed to themselves
+//! rather than with the standard library's default hasher.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use pontifex::{
+	Request, Router, TypeId, route_type_id,
+	server::{MAX_ROUTES, TypeIdMap},
+	testing,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, hint::black_box};
+
+/// How many request types are generated, see [`Numbered`].
+const ROUTE_COUNT: usize = 256;
+
+/// Route IDs of the generated request types, from `route_00` to `route_ff`.
+static ROUTE_IDS: [[u8; 8]; ROUTE_COUNT] = route_ids();
+
+const fn route_ids() -> [[u8; 8]; ROUTE_COUNT] {
+	let mut ids = [*b"route_00"; ROUTE_COUNT];
+
+	let mut n = 0;
+	while n < ROUTE_COUNT {
+		ids[n][6] = b"0123456789abcdef"[n >> 4];
+		ids[n][7] = b"0123456789abcdef"[n & 0xf];
+		n += 1;
+	}
+
+	ids
+}
+
+/// One of [`ROUTE_COUNT`] request types, told apart by the two hexadecimal digits of their
+/// number.
+#[derive(Serialize, Deserialize)]
+struct Numbered<const HIGH: usize, const LOW: usize>;
+
+impl<const HIGH: usize, const LOW: usize> Request for Numbered<HIGH, LOW> {
+	const ROUTE_ID: &'static str = match std::str::from_utf8(&ROUTE_IDS[HIGH * 16 + LOW]) {
+		Ok(route_id) => route_id,
+		Err(_) => panic!("route IDs are ASCII"),
+	};
+	type Response = ();
+}
+
+/// Invoke `$register!` with the digits of every generated request type, in order.
+macro_rules! each_route {
+	($register:ident) => {
+		each_route!(@high $register [0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15]);
+	};
+	(@high $register:ident [$($high:literal)*]) => {
+		$(each_route!(@low $register $high [0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15]);)*
+	};
+	(@low $register:ident $high:literal [$($low:literal)*]) => {
+		$($register!($high, $low);)*
+	};
+}
+
+/// A router serving the first `count` generated request types.
+fn router(count: usize) -> Router {
+	let mut router = Router::new().with_capacity(count);
+	let mut remaining = 0..count;
+
+	macro_rules! register {
+		($high:literal, $low:literal) => {
+			if remaining.next().is_some() {
+				router = router.route::<Numbered<$high, $low>, _, _>(|(), _| async {});
+			}
+		};
+	}
+	each_route!(register);
+
+	router
+}
+
+/// Round trips of a request over a connection kept open, to routers of growing sizes.
+fn dispatch(c: &mut Criterion) {
+	let runtime = tokio::runtime::Builder::new_current_thread()
+		.enable_all()
+		.build()
+		.unwrap();
+	let mut group = c.benchmark_group("dispatch");
+
+	for count in [1, 16, ROUTE_COUNT] {
+		let client = testing::local(router(count)).unwrap();
+		let mut connection = runtime.block_on(client.connect()).unwrap();
+
+		group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+			b.iter(|| {
+				runtime
+					.block_on(connection.send(black_box(&Numbered::<0, 0>)))
+					.unwrap();
+			});
+		});
+	}
+
+	group.finish();
+}
+
+/// Lookups of every type ID of maps of growing sizes, in the router's own map or in one
+/// hashing type IDs with SipHash.
+fn lookup(c: &mut Criterion) {
+	let mut group = c.benchmark_group("lookup");
+
+	for count in [16, 256, 4096, MAX_ROUTES] {
+		let type_ids: Vec<TypeId> = (0..count)
+			.map(|n| route_type_id(&format!("route_{n:04x}")))
+			.collect();
+		let identity: TypeIdMap<_> = type_ids.iter().copied().zip(0..count).collect();
+		let siphash: HashMap<_, _> = type_ids.iter().copied().zip(0..count).collect();
+
+		group.throughput(Throughput::Elements(count as u64));
+		group.bench_with_input(
+			BenchmarkId::new("identity", count),
+			&type_ids,
+			|b, type_ids| {
+				b.iter(|| {
+					type_ids
+						.iter()
+						.map(|type_id| identity[black_box(type_id)])
+						.sum::<usize>()
+				});
+			},
+		);
+		group.bench_with_input(
+			BenchmarkId::new("siphash", count),
+			&type_ids,
+			|b, type_ids| {
+				b.iter(|| {
+					type_ids
+						.iter()
+						.map(|type_id| siphash[black_box(type_id)])
+						.sum::<usize>()
+				});
+			},
+		);
+	}
+
+	group.finish();
+}
+
+criterion_group!(benches, dispatch, lookup);
+criterion_main!(benches);
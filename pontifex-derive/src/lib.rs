@@ -0,0 +1,85 @@
+#![deny(
+	clippy::all,
+	clippy::pedantic,
+	clippy::nursery,
+	missing_docs,
+	dead_code
+)]
+//! Derive macros for [pontifex](https://docs.rs/pontifex) request types.
+//!
+//! Use them through pontifex's `derive` feature rather than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{DeriveInput, LitStr, Type, parse_macro_input};
+
+/// Implements `pontifex::Request` for a type.
+///
+/// The route ID and the response type are given in a `#[pontifex]` attribute:
+///
+/// ```rust,ignore
+/// #[derive(Serialize, Deserialize, Request)]
+/// #[pontifex(route = "echo_v1", response = EchoResponse)]
+/// struct Echo {
+///     message: String,
+/// }
+/// ```
+///
+/// An empty route ID is refused at compile time.
+#[proc_macro_derive(Request, attributes(pontifex))]
+pub fn derive_request(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+
+	expand_request(&input)
+		.unwrap_or_else(syn::Error::into_compile_error)
+		.into()
+}
+
+fn expand_request(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+	let mut route: Option<LitStr> = None;
+	let mut response: Option<Type> = None;
+
+	for attribute in input
+		.attrs
+		.iter()
+		.filter(|attr| attr.path().is_ident("pontifex"))
+	{
+		attribute.parse_nested_meta(|meta| {
+			if meta.path.is_ident("route") {
+				route = Some(meta.value()?.parse()?);
+			} else if meta.path.is_ident("response") {
+				response = Some(meta.value()?.parse()?);
+			} else {
+				return Err(meta.error("expected `route` or `response`"));
+			}
+
+			Ok(())
+		})?;
+	}
+
+	let missing = |key: &str| {
+		syn::Error::new_spanned(
+			&input.ident,
+			format!("missing `#[pontifex({key} = ...)]` attribute"),
+		)
+	};
+	let route = route.ok_or_else(|| missing("route"))?;
+	let response = response.ok_or_else(|| missing("response"))?;
+
+	if route.value().is_empty() {
+		return Err(syn::Error::new_spanned(
+			&route,
+			"the route ID can't be empty",
+		));
+	}
+
+	let name = &input.ident;
+	let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+	Ok(quote! {
+		impl #impl_generics ::pontifex::Request for #name #ty_generics #where_clause {
+			const ROUTE_ID: &'static str = #route;
+			type Response = #response;
+		}
+	})
+}
@@ -0,0 +1,235 @@
+//! vsock addresses are a context identifier (CID), naming a virtual machine, and a port.
+//!
+//! A handful of CIDs are reserved and mean something specific depending on whether they
+//! are used to bind a listener or to connect to a peer:
+//!
+//! | CID          | Name                    | Meaning                                                   |
+//! |--------------|-------------------------|-----------------------------------------------------------|
+//! | `0`          | `VMADDR_CID_HYPERVISOR` | The hypervisor. Never a valid peer or listening address.  |
+//! | `1`          | `VMADDR_CID_LOCAL`      | Loopback, only reachable from the same virtual machine.   |
+//! | `2`          | `VMADDR_CID_HOST`       | The host. A valid peer, but not a CID a guest can bind.   |
+//! | `0xFFFFFFFF` | `VMADDR_CID_ANY`        | Any local CID. Only meaningful when binding a listener.   |
+//!
+//! Every other CID identifies a specific virtual machine, such as an enclave. Nitro
+//! Enclaves reach the EC2 instance they were launched from, which runs the vsock proxy,
+//! at [`PARENT_CID`], rather than at the host CID.
+
+use std::{fmt::Display, num::ParseIntError};
+
+/// The CID of the hypervisor.
+pub const VMADDR_CID_HYPERVISOR: u32 = 0;
+/// The CID for local (loopback) communication within the same virtual machine.
+pub const VMADDR_CID_LOCAL: u32 = 1;
+/// The CID of the host.
+pub const VMADDR_CID_HOST: u32 = 2;
+/// The CID of the parent instance, as seen from within a Nitro Enclave.
+pub const PARENT_CID: u32 = 3;
+/// Wildcard CID, binding a listener to every local CID.
+pub const VMADDR_CID_ANY: u32 = 0xFFFF_FFFF;
+/// Wildcard port, letting the kernel pick a free port when binding.
+pub const VMADDR_PORT_ANY: u32 = 0xFFFF_FFFF;
+
+/// How an address is going to be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+	/// Binding a listener to a local address.
+	Bind,
+	/// Connecting to a remote peer.
+	Connect,
+}
+
+impl Display for Role {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Bind => write!(f, "binding"),
+			Self::Connect => write!(f, "connecting"),
+		}
+	}
+}
+
+/// Errors that can occur when parsing or validating a vsock address.
+#[derive(Debug, thiserror::Error)]
+pub enum AddrError {
+	/// The CID isn't a valid number.
+	#[error("invalid CID {0:?}")]
+	InvalidCid(String, #[source] ParseIntError),
+	/// The port isn't a valid number.
+	#[error("invalid port {0:?}")]
+	InvalidPort(String, #[source] ParseIntError),
+	/// The address isn't written as `cid:port`.
+	#[error("invalid address {0:?}: expected `cid:port`")]
+	MissingSeparator(String),
+	/// The CID is reserved and can't be used for this role.
+	#[error("CID {cid} can't be used when {role}: {reason}")]
+	ReservedCid {
+		/// The offending CID.
+		cid: u32,
+		/// What the CID was going to be used for.
+		role: Role,
+		/// Why the CID can't be used.
+		reason: &'static str,
+	},
+	/// The port is reserved and can't be used for this role.
+	#[error("port {port} can't be used when {role}: {reason}")]
+	ReservedPort {
+		/// The offending port.
+		port: u32,
+		/// What the port was going to be used for.
+		role: Role,
+		/// Why the port can't be used.
+		reason: &'static str,
+	},
+}
+
+/// Parse a CID, written in decimal or as `0x`-prefixed hexadecimal.
+///
+/// # Errors
+///
+/// Returns `AddrError::InvalidCid` if the input isn't a valid `u32`.
+pub fn parse_cid(input: &str) -> Result<u32, AddrError> {
+	parse_u32(input).map_err(|e| AddrError::InvalidCid(input.to_string(), e))
+}
+
+/// Parse a port, written in decimal or as `0x`-prefixed hexadecimal.
+///
+/// # Errors
+///
+/// Returns `AddrError::InvalidPort` if the input isn't a valid `u32`.
+pub fn parse_port(input: &str) -> Result<u32, AddrError> {
+	parse_u32(input).map_err(|e| AddrError::InvalidPort(input.to_string(), e))
+}
+
+fn parse_u32(input: &str) -> Result<u32, ParseIntError> {
+	let input = input.trim();
+
+	input
+		.strip_prefix("0x")
+		.or_else(|| input.strip_prefix("0X"))
+		.map_or_else(|| input.parse(), |hex| u32::from_str_radix(hex, 16))
+}
+
+/// Parse an address written as `cid:port`, each written like [`parse_cid`] and [`parse_port`]
+/// accept.
+///
+/// # Errors
+///
+/// - `AddrError::MissingSeparator`: The input has no `:` between the CID and the port
+/// - `AddrError::InvalidCid`, `AddrError::InvalidPort`: Either side isn't a valid `u32`
+pub fn parse_addr(input: &str) -> Result<(u32, u32), AddrError> {
+	let (cid, port) = input
+		.split_once(':')
+		.ok_or_else(|| AddrError::MissingSeparator(input.to_string()))?;
+
+	Ok((parse_cid(cid)?, parse_port(port)?))
+}
+
+/// Check that a CID makes sense for the given role.
+///
+/// The hypervisor CID is always refused, as is the wildcard CID when connecting. Binding
+/// to the host CID is suspicious but not necessarily wrong, so it is only logged.
+///
+/// # Errors
+///
+/// Returns `AddrError::ReservedCid` if the CID can't be used for this role.
+pub fn validate_cid(cid: u32, role: Role) -> Result<u32, AddrError> {
+	let reserved = |reason| AddrError::ReservedCid { cid, role, reason };
+
+	match (cid, role) {
+		(VMADDR_CID_HYPERVISOR, _) => Err(reserved("it is reserved for the hypervisor")),
+		(VMADDR_CID_ANY, Role::Connect) => {
+			Err(reserved("the wildcard CID only makes sense when binding"))
+		},
+		(VMADDR_CID_HOST, Role::Bind) => {
+			tracing::warn!(
+				cid,
+				"binding to the host CID, which guests usually can't use"
+			);
+			Ok(cid)
+		},
+		(VMADDR_CID_LOCAL, Role::Connect) => {
+			tracing::warn!(
+				cid,
+				"connecting to the loopback CID, which only reaches this machine"
+			);
+			Ok(cid)
+		},
+		_ => Ok(cid),
+	}
+}
+
+/// Check that a port makes sense for the given role.
+///
+/// # Errors
+///
+/// Returns `AddrError::ReservedPort` when connecting to the wildcard port.
+pub fn validate_port(port: u32, role: Role) -> Result<u32, AddrError> {
+	if port == VMADDR_PORT_ANY && role == Role::Connect {
+		return Err(AddrError::ReservedPort {
+			port,
+			role,
+			reason: "the wildcard port only makes sense when binding",
+		});
+	}
+
+	Ok(port)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(parse_cid("16").unwrap(), 16);
+		assert_eq!(parse_cid(" 0x10 ").unwrap(), 16);
+		assert_eq!(parse_cid("0xFFFFFFFF").unwrap(), VMADDR_CID_ANY);
+		assert_eq!(parse_port("1000").unwrap(), 1000);
+
+		assert!(matches!(
+			parse_cid("enclave"),
+			Err(AddrError::InvalidCid(..))
+		));
+		assert!(matches!(parse_cid("-1"), Err(AddrError::InvalidCid(..))));
+		assert!(matches!(
+			parse_port("4294967296"),
+			Err(AddrError::InvalidPort(..))
+		));
+	}
+
+	#[test]
+	fn test_parse_addr() {
+		assert_eq!(parse_addr("16:1000").unwrap(), (16, 1000));
+		assert_eq!(parse_addr("0x10: 0x3e8").unwrap(), (16, 1000));
+
+		assert!(matches!(
+			parse_addr("16"),
+			Err(AddrError::MissingSeparator(..))
+		));
+		assert!(matches!(
+			parse_addr("enclave:1000"),
+			Err(AddrError::InvalidCid(..))
+		));
+		assert!(matches!(
+			parse_addr("16:1000:1"),
+			Err(AddrError::InvalidPort(..))
+		));
+		assert!(matches!(
+			parse_addr("4294967296:1000"),
+			Err(AddrError::InvalidCid(..))
+		));
+	}
+
+	#[test]
+	fn test_reserved_cids() {
+		assert!(validate_cid(16, Role::Connect).is_ok());
+		assert!(validate_cid(VMADDR_CID_HOST, Role::Connect).is_ok());
+		assert!(validate_cid(VMADDR_CID_ANY, Role::Bind).is_ok());
+
+		assert!(validate_cid(VMADDR_CID_ANY, Role::Connect).is_err());
+		assert!(validate_cid(VMADDR_CID_HYPERVISOR, Role::Bind).is_err());
+		assert!(validate_cid(VMADDR_CID_HYPERVISOR, Role::Connect).is_err());
+
+		assert!(validate_port(VMADDR_PORT_ANY, Role::Bind).is_ok());
+		assert!(validate_port(VMADDR_PORT_ANY, Role::Connect).is_err());
+	}
+}
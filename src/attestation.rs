@@ -0,0 +1,554 @@
+//! Attestation documents are `COSE_Sign1` structures whose payload is an `AttestationDoc`.
+//!
+//! They are signed by a per-enclave certificate that the NSM embeds in the payload itself.
+//!
+//! The Nitro Secure Module always signs with ES384 (ECDSA over P-384 with SHA-384), matching
+//! the P-384 keys of the AWS Nitro PKI it chains up to. A document claiming any other
+//! algorithm wasn't produced by a genuine NSM, so [`VerifyOptions`] only accepts ES384 by
+//! default. Widening that list, even to algorithms that are stronger on paper, only gives an
+//! attacker more ways to get a forged document accepted.
+//!
+//! A valid signature only proves the document matches the certificate embedded in it, so
+//! [`verify`] also walks that certificate's chain up to one of the trusted roots given in
+//! [`VerifyOptions`], normally the AWS Nitro Enclaves root certificate, and refuses
+//! documents older than [`VerifyOptions::max_age`], or dated more than [`MAX_CLOCK_SKEW`]
+//! into the future.
+
+use aws_nitro_enclaves_cose::{
+	CoseSign1,
+	crypto::{MessageDigest, SignatureAlgorithm, SigningPublicKey},
+	error::CoseError,
+};
+use p384::ecdsa::{
+	Signature, VerifyingKey,
+	signature::{Verifier, hazmat::PrehashVerifier},
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+pub use x509_cert::Certificate;
+use x509_cert::{
+	der::{Decode, Encode, oid::AssociatedOid},
+	ext::pkix::BasicConstraints,
+	spki::ObjectIdentifier,
+};
+
+use crate::nsm::{
+	self, AttestationDoc, AttestationError, CoseAlgorithm, Sha2Hasher, decode_attestation_doc,
+};
+
+/// Errors that can occur when verifying an attestation document.
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+	/// The document couldn't be parsed.
+	#[error("VerifyError::Parse")]
+	Parse(#[source] AttestationError),
+	/// The document is signed with an algorithm missing from the allowlist, or doesn't name one.
+	#[error("VerifyError::UnexpectedAlgorithm: {0:?}")]
+	UnexpectedAlgorithm(Option<CoseAlgorithm>),
+	/// The algorithm is allowed but this verifier can't check signatures made with it.
+	#[error("VerifyError::UnsupportedAlgorithm: {0}")]
+	UnsupportedAlgorithm(CoseAlgorithm),
+	/// The certificate embedded in the document couldn't be decoded.
+	#[error("VerifyError::InvalidCertificate")]
+	InvalidCertificate(#[source] x509_cert::der::Error),
+	/// The certificate embedded in the document doesn't hold a P-384 public key.
+	#[error("VerifyError::InvalidPublicKey")]
+	InvalidPublicKey(#[source] p384::ecdsa::Error),
+	/// The COSE structure couldn't be processed.
+	#[error("VerifyError::Cose")]
+	Cose(#[source] CoseError),
+	/// The signature doesn't match the document.
+	#[error("VerifyError::InvalidSignature")]
+	InvalidSignature,
+	/// The signing certificate doesn't chain up to a trusted root.
+	#[error("VerifyError::InvalidCertChain: {0}")]
+	InvalidCertChain(&'static str),
+	/// The document, or a certificate of its chain, isn't valid at the time of verification,
+	/// either because it is too old or because it is dated in the future.
+	#[error("VerifyError::Expired")]
+	Expired,
+}
+
+impl From<VerifyError> for AttestationError {
+	fn from(error: VerifyError) -> Self {
+		match error {
+			VerifyError::Parse(error) => error,
+			VerifyError::Cose(error) => Self::Cose(error),
+			VerifyError::InvalidCertChain(reason) => Self::InvalidCertChain(reason),
+			VerifyError::InvalidCertificate(_) | VerifyError::InvalidPublicKey(_) => {
+				Self::InvalidCertChain("a certificate of the chain couldn't be decoded")
+			},
+			VerifyError::UnexpectedAlgorithm(_)
+			| VerifyError::UnsupportedAlgorithm(_)
+			| VerifyError::InvalidSignature => Self::InvalidSignature,
+			VerifyError::Expired => Self::Expired,
+		}
+	}
+}
+
+/// How old a document [`verify`] accepts by default.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_mins(5);
+
+/// How far in the future a document's timestamp may be, to allow for the enclave's clock
+/// running ahead of the verifier's.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// The signature algorithm of every certificate in the AWS Nitro PKI.
+const ECDSA_WITH_SHA_384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+
+/// Which documents [`verify`] is willing to accept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyOptions {
+	/// The signature algorithms a document may be signed with. Defaults to ES384 only.
+	pub algorithms: Vec<CoseAlgorithm>,
+	/// The certificates a document's chain must lead to. Empty by default, which refuses
+	/// every document: this should hold the AWS Nitro Enclaves root certificate.
+	pub roots: Vec<Certificate>,
+	/// How long after its creation a document is accepted. Defaults to [`DEFAULT_MAX_AGE`].
+	pub max_age: Duration,
+}
+
+impl Default for VerifyOptions {
+	fn default() -> Self {
+		Self {
+			algorithms: vec![CoseAlgorithm::Es384],
+			roots: Vec::new(),
+			max_age: DEFAULT_MAX_AGE,
+		}
+	}
+}
+
+impl VerifyOptions {
+	/// Replace the signature algorithms a document may be signed with.
+	#[must_use]
+	pub fn with_algorithms(mut self, algorithms: impl Into<Vec<CoseAlgorithm>>) -> Self {
+		self.algorithms = algorithms.into();
+		self
+	}
+
+	/// Replace the certificates a document's chain must lead to.
+	#[must_use]
+	pub fn with_roots(mut self, roots: impl Into<Vec<Certificate>>) -> Self {
+		self.roots = roots.into();
+		self
+	}
+
+	/// Accept documents up to the given age.
+	#[must_use]
+	pub const fn with_max_age(mut self, max_age: Duration) -> Self {
+		self.max_age = max_age;
+		self
+	}
+}
+
+/// Verify a raw attestation document as of now, and return its parsed contents.
+///
+/// See [`verify_at`].
+///
+/// # Errors
+///
+/// Same as [`verify_at`].
+pub fn verify(document: &[u8], options: &VerifyOptions) -> Result<AttestationDoc, VerifyError> {
+	verify_at(document, options, SystemTime::now())
+}
+
+/// Verify a raw attestation document as of the given time, and return its parsed contents.
+///
+/// The signing algorithm is checked against `options.algorithms` before anything else, so a
+/// document signed with an unexpected algorithm is refused without its signature ever being
+/// looked at. The signature is then checked against the certificate embedded in the document,
+/// which proves the document hasn't been tampered with since that certificate signed it.
+///
+/// To prove the certificate belongs to a genuine enclave, its chain is then walked through
+/// the document's CA bundle up to one of `options.roots`, checking every signature along the
+/// way and that every certificate is valid at `now`. Finally, the document must have been
+/// created no longer than `options.max_age` before `now`, and no later than
+/// [`MAX_CLOCK_SKEW`] after it.
+///
+/// # Errors
+///
+/// Returns an error if the document can't be parsed, is signed with an algorithm that isn't
+/// allowed, its signature doesn't match, its chain doesn't lead to a trusted root or it is
+/// too old or dated in the future.
+pub fn verify_at(
+	document: &[u8],
+	options: &VerifyOptions,
+	now: SystemTime,
+) -> Result<AttestationDoc, VerifyError> {
+	let headers = nsm::parse_cose_headers(document).map_err(VerifyError::Parse)?;
+
+	let algorithm = headers
+		.algorithm
+		.filter(|algorithm| options.algorithms.contains(algorithm))
+		.ok_or(VerifyError::UnexpectedAlgorithm(headers.algorithm))?;
+
+	if algorithm != CoseAlgorithm::Es384 {
+		return Err(VerifyError::UnsupportedAlgorithm(algorithm));
+	}
+
+	let cose_document = CoseSign1::from_bytes(document).map_err(VerifyError::Cose)?;
+
+	// The signing certificate lives inside the payload, so it has to be read before the
+	// signature can be checked. Nothing from it is returned unless the signature matches.
+	let payload = cose_document
+		.get_payload::<Sha2Hasher>(None)
+		.map_err(VerifyError::Cose)?;
+	let attestation_doc = decode_attestation_doc(&payload).map_err(VerifyError::Parse)?;
+
+	let leaf = Certificate::from_der(&attestation_doc.certificate)
+		.map_err(VerifyError::InvalidCertificate)?;
+	let key = Es384Key::from_certificate(&leaf)?;
+	let valid = cose_document
+		.verify_signature::<Sha2Hasher>(&key)
+		.map_err(VerifyError::Cose)?;
+
+	if !valid {
+		return Err(VerifyError::InvalidSignature);
+	}
+
+	// Times before the epoch can't be represented in certificates, let alone documents.
+	let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+	verify_chain(leaf, &attestation_doc.cabundle, &options.roots, now)?;
+
+	// A document from the future would otherwise pass for a fresh one for as long as it likes.
+	let created_at = Duration::from_millis(attestation_doc.timestamp);
+	if now.saturating_sub(created_at) > options.max_age
+		|| created_at.saturating_sub(now) > MAX_CLOCK_SKEW
+	{
+		return Err(VerifyError::Expired);
+	}
+
+	Ok(attestation_doc)
+}
+
+/// Check that `leaf` chains up to one of `roots` through the CA bundle of its document.
+///
+/// The bundle is ordered from the root down to the certificate that issued the leaf, so it
+/// is walked backwards. Every certificate of the chain must be valid at `now`, and all but
+/// the leaf must be CAs.
+fn verify_chain<B: AsRef<[u8]>>(
+	leaf: Certificate,
+	bundle: &[B],
+	roots: &[Certificate],
+	now: Duration,
+) -> Result<(), VerifyError> {
+	if roots.is_empty() {
+		return Err(VerifyError::InvalidCertChain(
+			"no trusted root certificates were provided",
+		));
+	}
+
+	check_validity(&leaf, now)?;
+
+	let mut certificate = leaf;
+	for der in bundle.iter().rev() {
+		let issuer =
+			Certificate::from_der(der.as_ref()).map_err(VerifyError::InvalidCertificate)?;
+
+		check_validity(&issuer, now)?;
+		verify_issued_by(&certificate, &issuer)?;
+
+		certificate = issuer;
+	}
+
+	// The NSM includes the root itself at the start of the bundle, in which case it gets
+	// checked against its trusted copy: that works out because roots are self-signed.
+	if !roots
+		.iter()
+		.any(|root| verify_issued_by(&certificate, root).is_ok())
+	{
+		return Err(VerifyError::InvalidCertChain(
+			"the chain doesn't lead to a trusted root",
+		));
+	}
+
+	Ok(())
+}
+
+/// Check that a certificate is valid at `now`.
+fn check_validity(certificate: &Certificate, now: Duration) -> Result<(), VerifyError> {
+	let validity = &certificate.tbs_certificate.validity;
+
+	if now < validity.not_before.to_unix_duration() || now > validity.not_after.to_unix_duration() {
+		return Err(VerifyError::Expired);
+	}
+
+	Ok(())
+}
+
+/// Check that `certificate` was signed by `issuer`, and that `issuer` is a CA.
+fn verify_issued_by(certificate: &Certificate, issuer: &Certificate) -> Result<(), VerifyError> {
+	if certificate.tbs_certificate.issuer != issuer.tbs_certificate.subject {
+		return Err(VerifyError::InvalidCertChain(
+			"a certificate's issuer doesn't match the next certificate of the chain",
+		));
+	}
+
+	if !is_ca(issuer)? {
+		return Err(VerifyError::InvalidCertChain(
+			"a certificate is issued by a certificate that isn't a CA",
+		));
+	}
+
+	if certificate.signature_algorithm.oid != ECDSA_WITH_SHA_384 {
+		return Err(VerifyError::InvalidCertChain(
+			"a certificate isn't signed with ECDSA over SHA-384",
+		));
+	}
+
+	let Es384Key(key) = Es384Key::from_certificate(issuer)?;
+	let signed = certificate
+		.tbs_certificate
+		.to_der()
+		.map_err(VerifyError::InvalidCertificate)?;
+	let signature = Signature::from_der(certificate.signature.raw_bytes())
+		.map_err(|_| VerifyError::InvalidCertChain("a certificate's signature is malformed"))?;
+
+	key.verify(&signed, &signature).map_err(|_| {
+		VerifyError::InvalidCertChain("a certificate's signature doesn't match its issuer")
+	})
+}
+
+/// Whether a certificate's basic constraints allow it to issue other certificates.
+fn is_ca(certificate: &Certificate) -> Result<bool, VerifyError> {
+	let Some(extensions) = &certificate.tbs_certificate.extensions else {
+		return Ok(false);
+	};
+
+	let Some(extension) = extensions
+		.iter()
+		.find(|extension| extension.extn_id == BasicConstraints::OID)
+	else {
+		return Ok(false);
+	};
+
+	let constraints = BasicConstraints::from_der(extension.extn_value.as_bytes())
+		.map_err(VerifyError::InvalidCertificate)?;
+
+	Ok(constraints.ca)
+}
+
+/// The P-384 public key of a signing certificate.
+struct Es384Key(VerifyingKey);
+
+impl Es384Key {
+	fn from_certificate(certificate: &Certificate) -> Result<Self, VerifyError> {
+		let public_key = certificate
+			.tbs_certificate
+			.subject_public_key_info
+			.subject_public_key
+			.raw_bytes();
+
+		VerifyingKey::from_sec1_bytes(public_key)
+			.map(Self)
+			.map_err(VerifyError::InvalidPublicKey)
+	}
+}
+
+impl SigningPublicKey for Es384Key {
+	fn get_parameters(&self) -> Result<(SignatureAlgorithm, MessageDigest), CoseError> {
+		Ok((SignatureAlgorithm::ES384, MessageDigest::Sha384))
+	}
+
+	fn verify(&self, digest: &[u8], signature: &[u8]) -> Result<bool, CoseError> {
+		// A signature that isn't even well-formed simply doesn't match.
+		let Ok(signature) = Signature::from_slice(signature) else {
+			return Ok(false);
+		};
+
+		Ok(self.0.verify_prehash(digest, &signature).is_ok())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use aws_nitro_enclaves_cose::{crypto::SigningPrivateKey, header_map::HeaderMap};
+	use p384::{
+		ecdsa::{SigningKey, signature::hazmat::PrehashSigner},
+		pkcs8::DecodePrivateKey,
+	};
+	use std::collections::BTreeMap;
+
+	/// A P-384 chain laid out like the AWS Nitro PKI: a self-signed root, an intermediate CA
+	/// and the enclave's signing certificate, all valid from 2026 to 2125.
+	const ROOT: &[u8] = include_bytes!("../tests/attestation/root.crt.der");
+	const INTERMEDIATE: &[u8] = include_bytes!("../tests/attestation/intermediate.crt.der");
+	const ENCLAVE_CERT: &[u8] = include_bytes!("../tests/attestation/enclave.crt.der");
+	const ENCLAVE_KEY: &[u8] = include_bytes!("../tests/attestation/enclave.key.der");
+	/// A root with the same name as [`ROOT`], but another key.
+	const IMPOSTOR_ROOT: &[u8] = include_bytes!("../tests/attestation/impostor-root.crt.der");
+
+	/// When the test documents are created, in 2030.
+	const CREATED_AT: Duration = Duration::from_secs(1_900_000_000);
+
+	struct TestKey(SigningKey);
+
+	impl SigningPublicKey for TestKey {
+		fn get_parameters(&self) -> Result<(SignatureAlgorithm, MessageDigest), CoseError> {
+			Ok((SignatureAlgorithm::ES384, MessageDigest::Sha384))
+		}
+
+		fn verify(&self, digest: &[u8], signature: &[u8]) -> Result<bool, CoseError> {
+			Es384Key(*self.0.verifying_key()).verify(digest, signature)
+		}
+	}
+
+	impl SigningPrivateKey for TestKey {
+		fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, CoseError> {
+			let signature: Signature = self
+				.0
+				.sign_prehash(digest)
+				.map_err(|e| CoseError::SignatureError(Box::new(e)))?;
+
+			Ok(signature.to_vec())
+		}
+	}
+
+	/// An attestation document embedding the test chain, created at `created_at` and signed
+	/// with `key`.
+	fn signed_document(key: SigningKey, created_at: Duration) -> Vec<u8> {
+		let document = AttestationDoc::new(
+			"i-test-enc0000000000000000".to_string(),
+			nsm::Digest::SHA384,
+			u64::try_from(created_at.as_millis()).unwrap(),
+			BTreeMap::from([(0, vec![0; 48])]),
+			ENCLAVE_CERT.to_vec(),
+			vec![ROOT.to_vec(), INTERMEDIATE.to_vec()],
+			None,
+			None,
+			None,
+		);
+
+		CoseSign1::new::<Sha2Hasher>(&document.to_binary(), &HeaderMap::new(), &TestKey(key))
+			.and_then(|document| document.as_bytes(true))
+			.unwrap()
+	}
+
+	fn enclave_key() -> SigningKey {
+		SigningKey::from_pkcs8_der(ENCLAVE_KEY).unwrap()
+	}
+
+	fn trusting(root: &[u8]) -> VerifyOptions {
+		VerifyOptions::default().with_roots([Certificate::from_der(root).unwrap()])
+	}
+
+	fn at(time: Duration) -> SystemTime {
+		UNIX_EPOCH + time
+	}
+
+	#[test]
+	fn test_verifies_documents_chaining_to_a_trusted_root() {
+		let document = signed_document(enclave_key(), CREATED_AT);
+		let now = at(CREATED_AT + Duration::from_secs(1));
+
+		let verified = verify_at(&document, &trusting(ROOT), now).unwrap();
+		assert_eq!(verified.module_id, "i-test-enc0000000000000000");
+		assert_eq!(verified.certificate.as_slice(), ENCLAVE_CERT);
+	}
+
+	#[test]
+	fn test_rejects_documents_outside_their_freshness_window() {
+		let document = signed_document(enclave_key(), CREATED_AT);
+		let options = trusting(ROOT);
+
+		let expired = at(CREATED_AT + DEFAULT_MAX_AGE + Duration::from_secs(1));
+		assert!(matches!(
+			verify_at(&document, &options, expired),
+			Err(VerifyError::Expired)
+		));
+
+		// A clock slightly behind the enclave's is tolerated, a document from the future isn't.
+		let behind = at(CREATED_AT - MAX_CLOCK_SKEW);
+		assert!(verify_at(&document, &options, behind).is_ok());
+		let before = at(CREATED_AT - MAX_CLOCK_SKEW - Duration::from_secs(1));
+		assert!(matches!(
+			verify_at(&document, &options, before),
+			Err(VerifyError::Expired)
+		));
+
+		// Past the validity of the chain, the document's age doesn't matter.
+		let options = options.with_max_age(Duration::MAX);
+		let after_chain = at(Duration::from_secs(5_000_000_000));
+		assert!(matches!(
+			verify_at(&document, &options, after_chain),
+			Err(VerifyError::Expired)
+		));
+	}
+
+	#[test]
+	fn test_rejects_chains_leading_to_an_untrusted_root() {
+		let document = signed_document(enclave_key(), CREATED_AT);
+
+		assert!(matches!(
+			verify_at(&document, &trusting(IMPOSTOR_ROOT), at(CREATED_AT)),
+			Err(VerifyError::InvalidCertChain(_))
+		));
+		assert!(matches!(
+			verify_at(&document, &VerifyOptions::default(), at(CREATED_AT)),
+			Err(VerifyError::InvalidCertChain(_))
+		));
+	}
+
+	/// A document signed by another key than its certificate's is refused before its chain
+	/// is even looked at.
+	#[test]
+	fn test_rejects_documents_not_signed_by_their_certificate() {
+		let forger = SigningKey::from_slice(&[0x42; 48]).unwrap();
+		let document = signed_document(forger, CREATED_AT);
+
+		assert!(matches!(
+			verify_at(&document, &trusting(ROOT), at(CREATED_AT)),
+			Err(VerifyError::InvalidSignature)
+		));
+	}
+
+	/// The mock document is signed with ES256, which must be refused unless explicitly allowed.
+	#[test]
+	fn test_rejects_unexpected_algorithm() {
+		let document = include_bytes!("../tests/mock-attestation-doc.cose");
+
+		assert!(matches!(
+			verify(document, &VerifyOptions::default()),
+			Err(VerifyError::UnexpectedAlgorithm(Some(CoseAlgorithm::Es256)))
+		));
+
+		let options = VerifyOptions::default().with_algorithms([CoseAlgorithm::Es256]);
+		assert!(matches!(
+			verify(document, &options),
+			Err(VerifyError::UnsupportedAlgorithm(CoseAlgorithm::Es256))
+		));
+	}
+
+	#[test]
+	fn test_verify_attestation_reports_attestation_errors() {
+		let document = include_bytes!("../tests/mock-attestation-doc.cose");
+
+		assert!(matches!(
+			nsm::verify_attestation(document, &[], SystemTime::now()),
+			Err(AttestationError::InvalidSignature)
+		));
+		assert!(matches!(
+			nsm::verify_attestation(b"garbage", &[], SystemTime::now()),
+			Err(AttestationError::Encoding(_))
+		));
+	}
+
+	/// The mock document binds a public key, but isn't signed by a genuine NSM.
+	#[test]
+	fn test_attested_public_key_requires_a_verified_document() {
+		let document = include_bytes!("../tests/mock-attestation-doc.cose");
+		let payload = CoseSign1::from_bytes(document)
+			.unwrap()
+			.get_payload::<Sha2Hasher>(None)
+			.unwrap();
+		let parsed = decode_attestation_doc(&payload).unwrap();
+		assert!(parsed.public_key.is_some());
+
+		let expected = nsm::PcrSet::new();
+		assert!(matches!(
+			nsm::attested_public_key(document, &[], &expected, SystemTime::now()),
+			Err(AttestationError::InvalidSignature)
+		));
+	}
+}
@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::Request;
+
+/// The prefix of route IDs reserved for the routes built into pontifex.
+///
+/// Routers refuse to build if an application route uses it, so that built-in routes never
+/// clash with application ones.
+pub const RESERVED_ROUTE_PREFIX: &str = "__pontifex_";
+
+/// Ask a server how it is doing, answered by routers set up with `Router::with_health`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let status = send(connection, &HealthCheck).await?;
+/// tracing::info!(version = status.version, uptime = ?status.uptime, "enclave is up");
+/// ```
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct HealthCheck;
+
+/// The answer to a [`HealthCheck`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthStatus {
+	/// The version of pontifex the server was built with.
+	pub version: String,
+	/// How long the server has been running.
+	pub uptime: Duration,
+	/// How many application routes the server handles, not counting built-in ones.
+	pub routes: usize,
+}
+
+impl Request for HealthCheck {
+	const ROUTE_ID: &'static str = "__pontifex_health_v1";
+	type Response = HealthStatus;
+}
+
+/// Ask a server which routes it handles, answered by routers set up with
+/// `Router::with_reflection`.
+///
+/// This lets clients check, for example at deploy time, that they agree with the server on
+/// the set of routes.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let served = send(connection, &ListRoutes).await?;
+/// assert!(served.route_ids.iter().any(|id| id == GetUser::ROUTE_ID));
+/// ```
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ListRoutes;
+
+/// The answer to a [`ListRoutes`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteList {
+	/// The route IDs of the application routes the server handles, in alphabetical order.
+	pub route_ids: Vec<String>,
+}
+
+impl Request for ListRoutes {
+	const ROUTE_ID: &'static str = "__pontifex_list_routes_v1";
+	type Response = RouteList;
+}
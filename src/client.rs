@@ -1,8 +1,42 @@
-use std::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use serde::Serialize;
+use std::{
+	fmt::Display,
+	io,
+	str::FromStr,
+	time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{Instrument, Span};
 
-pub use crate::utils::CodingKey;
-use crate::utils::Stream;
+mod connection;
+mod enclave;
+mod multiplex;
+#[cfg(feature = "tower")]
+mod service;
+
+#[cfg(feature = "tower")]
+pub use self::service::RequestService;
+pub use self::{
+	connection::Connection,
+	enclave::{EnclaveClient, EnclaveClientBuilder, MissingConnection, RetryPolicy},
+	multiplex::MultiplexedConnection,
+};
+#[cfg(feature = "nsm")]
+use crate::nsm::{AttestationError, Freshness, SecureModule};
+#[cfg(feature = "tls")]
+use crate::tls::ClientTls;
+pub use crate::utils::{CodingKey, StreamConfig};
+#[cfg(feature = "compression")]
+use crate::wire::{Compression, CompressionConfig};
+use crate::{
+	TypeId,
+	addr::{self, AddrError, Role},
+	utils::{FrameTooLarge, ReadFramed, Stream, timed},
+	wire::{
+		self, Checksum, ChecksumMismatch, CodecMismatch, ErrorFrame, Format, Metadata,
+		StructEncoding,
+	},
+};
 
 /// Details about a connection.
 #[derive(Debug, Clone, Copy)]
@@ -11,34 +45,325 @@ pub struct ConnectionDetails {
 	pub cid: u32,
 	/// The port of the connection.
 	pub port: u32,
+	/// The payload format, which must be compatible with the server's.
+	pub format: Format,
+	/// The format responses should be encoded with, if different from `format`.
+	pub response_format: Option<Format>,
+	/// The largest response payload accepted, in bytes.
+	pub max_payload_bytes: u64,
+	/// How long a whole round trip may take, if bounded.
+	pub timeout: Option<Duration>,
+	/// When request payloads get compressed, if at all.
+	#[cfg(feature = "compression")]
+	pub compression: Option<CompressionConfig>,
+	/// How request and response payloads are checked for corruption.
+	pub checksum: Checksum,
+	/// The socket options set on vsock connections.
+	pub stream: StreamConfig,
+	/// The TLS session requests are sent through, if any.
+	#[cfg(feature = "tls")]
+	pub tls: Option<&'static ClientTls>,
+	/// The Unix socket requests are sent through instead of vsock, if any.
+	#[cfg(feature = "test-transport")]
+	pub unix_socket: Option<&'static std::path::Path>,
 }
 
 impl ConnectionDetails {
 	/// Create a new `ConnectionDetails` instance.
 	#[must_use]
 	pub const fn new(cid: u32, port: u32) -> Self {
-		Self { cid, port }
+		Self {
+			cid,
+			port,
+			format: Format::new(StructEncoding::Array),
+			response_format: None,
+			max_payload_bytes: wire::DEFAULT_MAX_PAYLOAD_BYTES,
+			timeout: None,
+			#[cfg(feature = "compression")]
+			compression: None,
+			checksum: Checksum::None,
+			stream: StreamConfig { buffer_size: None },
+			#[cfg(feature = "tls")]
+			tls: None,
+			#[cfg(feature = "test-transport")]
+			unix_socket: None,
+		}
+	}
+
+	/// Connect to `port` on the parent instance, from within an enclave.
+	///
+	/// See [`addr::PARENT_CID`] for the CIDs enclaves reach their peers at.
+	#[must_use]
+	pub const fn to_host(port: u32) -> Self {
+		Self::new(addr::PARENT_CID, port)
+	}
+
+	/// Set the largest response payload accepted, in bytes.
+	///
+	/// Responses declaring a longer payload fail with `Error::PayloadTooLarge` before
+	/// anything is allocated for them. Defaults to [`wire::DEFAULT_MAX_PAYLOAD_BYTES`].
+	#[must_use]
+	pub const fn with_max_payload(mut self, bytes: u64) -> Self {
+		self.max_payload_bytes = bytes;
+		self
+	}
+
+	/// Compress request payloads according to `config`, and accept compressed responses.
+	///
+	/// Requests are only compressed above the configured threshold, so small ones are
+	/// sent as is. The server decides on its own whether to compress its responses, which
+	/// are decompressed up to [`max_payload_bytes`](Self::max_payload_bytes). Servers
+	/// built without the `compression` feature refuse compressed requests with
+	/// `UNSUPPORTED_CODEC`.
+	#[cfg(feature = "compression")]
+	#[must_use]
+	pub const fn with_compression(mut self, config: CompressionConfig) -> Self {
+		self.compression = Some(config);
+		self
+	}
+
+	/// Checksum request payloads with `checksum`, and have the server checksum its responses.
+	///
+	/// Requests or responses corrupted on the way then fail with `ChecksumMismatch` instead
+	/// of being decoded. Servers built without the `checksum` feature refuse checksummed
+	/// requests with `UNSUPPORTED_CODEC`.
+	#[must_use]
+	pub const fn with_checksum(mut self, checksum: Checksum) -> Self {
+		self.checksum = checksum;
+		self
+	}
+
+	/// Set `config`'s socket options on every vsock connection made with these details.
+	///
+	/// Servers set their own options on the connections they accept, see
+	/// `Router::with_stream_config`.
+	#[must_use]
+	pub const fn with_stream_config(mut self, config: StreamConfig) -> Self {
+		self.stream = config;
+		self
+	}
+
+	/// Send requests through a TLS session established according to `tls`.
+	///
+	/// The server must terminate TLS too, see `Router::with_tls`, or connections fail with
+	/// `Error::Tls`. The configuration is shared by every connection made with these details,
+	/// which keeps them `Copy`: set it up once, in a static or with [`Box::leak`].
+	#[cfg(feature = "tls")]
+	#[must_use]
+	pub const fn with_tls(mut self, tls: &'static ClientTls) -> Self {
+		self.tls = Some(tls);
+		self
+	}
+
+	/// Send requests through the Unix socket at `path` rather than over vsock, to a server
+	/// started with `Router::serve_unix`.
+	///
+	/// This lets clients and servers run on machines without vsock, such as in CI, with the
+	/// same framing as over vsock. The CID and port are then ignored, and TLS isn't supported:
+	/// connections fail with `Error::Tls` if it is configured. Like TLS configurations, the
+	/// path is shared by every connection made with these details, see
+	/// `ConnectionDetails::with_tls`.
+	#[cfg(feature = "test-transport")]
+	#[must_use]
+	pub const fn with_unix_socket(mut self, path: &'static std::path::Path) -> Self {
+		self.unix_socket = Some(path);
+		self
+	}
+
+	/// Give up on a request after `timeout`, failing it with `Error::Timeout`.
+	///
+	/// The timeout covers the whole round trip, from connecting to reading the last byte of
+	/// the response, so a hung enclave can't hang the caller. The connection of a request
+	/// that timed out is dropped, along with anything it had read so far. For streamed
+	/// responses, it only covers the exchange up to the start of the stream.
+	/// By default, requests can wait forever.
+	#[must_use]
+	pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// Use the given payload format instead of the default one.
+	#[must_use]
+	pub const fn with_format(mut self, format: Format) -> Self {
+		self.format = format;
+		self
+	}
+
+	/// Ask the server to encode responses with the given format, independently of the one
+	/// requests are encoded with.
+	///
+	/// Servers refuse requests whose response format they don't support with
+	/// `UnsupportedCodec`.
+	#[must_use]
+	pub const fn with_response_format(mut self, format: Format) -> Self {
+		self.response_format = Some(format);
+		self
+	}
+
+	/// The format responses are expected in.
+	const fn expected_response_format(&self) -> Format {
+		match self.response_format {
+			Some(format) => format,
+			None => self.format,
+		}
+	}
+}
+
+/// Parses the address of an enclave service, written as `cid:port`, with default settings
+/// for everything else.
+///
+/// The CID and port may each be written in decimal or as `0x`-prefixed hexadecimal, and must
+/// be valid to connect to.
+///
+/// # Example
+///
+/// ```rust
+/// use pontifex::client::ConnectionDetails;
+///
+/// let details: ConnectionDetails = "16:1000".parse()?;
+/// assert_eq!((details.cid, details.port), (16, 1000));
+/// assert_eq!(details.to_string(), "16:1000");
+///
+/// assert!("16".parse::<ConnectionDetails>().is_err());
+/// assert!("16:http".parse::<ConnectionDetails>().is_err());
+/// assert!("16:4294967296".parse::<ConnectionDetails>().is_err());
+/// # Ok::<(), pontifex::addr::AddrError>(())
+/// ```
+impl FromStr for ConnectionDetails {
+	type Err = AddrError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (cid, port) = addr::parse_addr(s)?;
+
+		Ok(Self::new(
+			addr::validate_cid(cid, Role::Connect)?,
+			addr::validate_port(port, Role::Connect)?,
+		))
+	}
+}
+
+/// Displays the address of the enclave service as `cid:port`, which parses back into the
+/// same address.
+impl Display for ConnectionDetails {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}:{}", self.cid, self.port)
 	}
 }
 
 /// Errors that can occur when sending a request.
+///
+/// Variants are added as features are, so matches on this enum need a wildcard arm.
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error {
 	/// Failed to connect to the enclave.
-	#[error("connection failed: {0}")]
-	Connection(io::Error),
+	#[error("connection failed")]
+	Connection(#[source] io::Error),
+	/// Failed to establish a TLS session with the enclave.
+	#[cfg(feature = "tls")]
+	#[error("TLS handshake failed")]
+	Tls(#[source] io::Error),
 	/// Failed to encode the request payload.
-	#[error("encoding failed: {0}")]
-	Encoding(rmp_serde::encode::Error),
-	/// Failed to decode the response payload.
-	#[error("decoding failed: {0}")]
-	Decoding(rmp_serde::decode::Error),
+	#[error("encoding failed for {route_id}")]
+	Encoding {
+		/// The route ID of the request.
+		route_id: &'static str,
+		/// Why encoding failed.
+		#[source]
+		source: wire::EncodeError,
+	},
+	/// Failed to decode the response payload, or the error frame sent instead.
+	#[error("decoding failed for {route_id} ({bytes} bytes)")]
+	Decoding {
+		/// The route ID of the request.
+		route_id: &'static str,
+		/// The length of the payload.
+		bytes: usize,
+		/// Why decoding failed.
+		#[source]
+		source: wire::DecodeError,
+	},
 	/// Failed to send the request.
-	#[error("failed to write {0}: {1}")]
-	Writing(CodingKey, io::Error),
+	#[error("failed to write {0}")]
+	Writing(CodingKey, #[source] io::Error),
 	/// Failed to receive the response.
-	#[error("failed to read {0}: {1}")]
-	Reading(CodingKey, io::Error),
+	#[error("failed to read {0}")]
+	Reading(CodingKey, #[source] io::Error),
+	/// The server speaks another version of the wire protocol.
+	#[error("unsupported protocol version {got}, expected {expected}")]
+	ProtocolVersion {
+		/// The version announced by the server.
+		got: u8,
+		/// The version this client speaks, [`wire::PROTOCOL_VERSION`].
+		expected: u8,
+	},
+	/// The server uses an incompatible payload format.
+	#[error(transparent)]
+	CodecMismatch(CodecMismatch),
+	/// The server failed to handle the request, and reported why.
+	///
+	/// `code` is one of the constants defined on [`ErrorFrame`].
+	#[error("server error {code}: {message}")]
+	Remote {
+		/// What kind of error occurred.
+		code: u16,
+		/// The server's description of the error.
+		message: String,
+	},
+	/// The server declared a response larger than the connection accepts.
+	#[error("payload too large: {declared} bytes declared, limit is {limit}")]
+	PayloadTooLarge {
+		/// The length announced by the server.
+		declared: u64,
+		/// The largest payload the connection accepts.
+		limit: u64,
+	},
+	/// The compressed response payload couldn't be decompressed.
+	#[error("decompression failed")]
+	Decompression(#[source] io::Error),
+	/// The response payload doesn't match its checksum.
+	#[error(transparent)]
+	ChecksumMismatch(ChecksumMismatch),
+	/// A previous request failed halfway through, leaving the [`Connection`] unusable.
+	#[error("connection broken by a previous request")]
+	Broken,
+	/// A request of a batch failed, failing the whole batch, see [`send_batch`].
+	#[error("request {index} of the batch failed")]
+	Batch {
+		/// The position of the request in the batch.
+		index: usize,
+		/// Why the request failed.
+		#[source]
+		source: Box<Self>,
+	},
+	/// The enclave didn't answer in time.
+	#[error("request timed out after {0:?}")]
+	Timeout(Duration),
+	/// Failed to obtain an attestation document to attach to the request.
+	#[cfg(feature = "nsm")]
+	#[error("attestation failed")]
+	Attestation(#[source] AttestationError),
+}
+
+/// A request that carries an attestation document of the enclave sending it.
+///
+/// Implement this for request types whose receiver expects the enclave to prove itself on
+/// every call, then send them with [`send_attested`] instead of [`send`].
+#[cfg(feature = "nsm")]
+pub trait AttestedRequest: crate::Request {
+	/// Data to bind into the `user_data` field of the attestation document.
+	fn user_data(&self) -> Option<Vec<u8>> {
+		None
+	}
+
+	/// Public key to bind into the `public_key` field of the attestation document.
+	fn public_key(&self) -> Option<Vec<u8>> {
+		None
+	}
+
+	/// Store the COSE-signed attestation document in the request before it is sent.
+	fn attach_attestation(&mut self, document: Vec<u8>);
 }
 
 /// Send a type-safe request to the enclave and receive its corresponding response.
@@ -49,11 +374,12 @@ pub enum Error {
 ///
 /// # How It Works
 ///
-/// 1. The function first sends a type identifier (hash of `ROUTE_ID`) to tell
-///    the server which handler to use
-/// 2. Then it sends the serialized request payload
-/// 3. The server uses the type ID to route to the correct handler
-/// 4. The response is automatically deserialized to the correct type
+/// 1. The function first announces its payload format, so that both peers can check they
+///    encode data the same way
+/// 2. It sends a type identifier (hash of `ROUTE_ID`) to tell the server which handler to use
+/// 3. Then it sends the request's metadata headers (none, for `send`) and its serialized payload
+/// 4. The server uses the type ID to route to the correct handler
+/// 5. The response is automatically deserialized to the correct type
 ///
 /// # Example
 ///
@@ -66,61 +392,756 @@ pub enum Error {
 /// # Errors
 ///
 /// - `Error::Connection`: Failed to connect to the enclave
+/// - `Error::Tls`: Failed to establish a TLS session with the enclave, if configured to
+/// - `Error::ProtocolVersion`: The server speaks another version of the wire protocol
+/// - `Error::CodecMismatch`: The server uses an incompatible payload format
 /// - `Error::Encoding`: Failed to serialize the request
 /// - `Error::Writing`: Failed to send data to the enclave  
 /// - `Error::Reading`: Failed to receive data from the enclave
 /// - `Error::Decoding`: Failed to deserialize the response
+/// - `Error::PayloadTooLarge`: The response is larger than the connection accepts
+/// - `Error::Remote`: The server failed to handle the request
+/// - `Error::ChecksumMismatch`: The response was corrupted on the way
+/// - `Error::Timeout`: The round trip took longer than the connection's timeout
 pub async fn send<R>(connection: ConnectionDetails, request: &R) -> Result<R::Response, Error>
 where
 	R: crate::Request,
 {
-	let mut stream = Stream::connect(connection.cid, connection.port)
+	send_with_metadata(connection, request, &Metadata::new()).await
+}
+
+/// Send a request like [`send`], retrying it with exponential backoff when it fails in a way
+/// that is safe to retry.
+///
+/// **The request may be sent more than once.** Only failures to connect are retried by
+/// default, which never reach a handler, so this is safe for any request: it covers the
+/// window where the enclave is still booting. With [`RetryPolicy::retry_unanswered`], a
+/// request whose connection broke before any of its response was read is retried too, but
+/// it may already have been handled, so only use that for idempotent requests.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let policy = RetryPolicy::new(5).with_jitter(Duration::from_millis(50));
+/// let status: HealthStatus = send_with_retry(connection, &HealthCheck {}, policy).await?;
+/// ```
+///
+/// # Errors
+///
+/// Same as [`send`], with the error of the last attempt.
+pub async fn send_with_retry<R>(
+	connection: ConnectionDetails,
+	request: &R,
+	policy: RetryPolicy,
+) -> Result<R::Response, Error>
+where
+	R: crate::Request,
+{
+	policy.run(|| send(connection, request)).await
+}
+
+/// Send a request along with metadata headers, like an idempotency key, and receive its response.
+///
+/// See [`send`] for how requests are exchanged.
+///
+/// # Errors
+///
+/// Same as [`send`]. Headers that are too large to be sent fail with `Error::Writing`.
+pub async fn send_with_metadata<R>(
+	connection: ConnectionDetails,
+	request: &R,
+	metadata: &Metadata,
+) -> Result<R::Response, Error>
+where
+	R: crate::Request,
+{
+	let (response, _) = exchange(connection, request, metadata).await?;
+
+	Ok(response)
+}
+
+/// Send a batch of requests over a single connection, and receive their responses in order.
+///
+/// Every request is written without waiting for the responses to the previous ones, which
+/// are read as they arrive, so a batch of small requests costs a single connection and about
+/// a single round trip. The server handles the requests one after the other, in order. See
+/// [`Connection::send_batch`] to send batches over a connection that is already open.
+///
+/// The batch fails as a whole if any of its requests does, with the error of the first
+/// request that failed, as `Error::Batch`. Requests after it were already sent by then, so
+/// the server may still have handled them: only batch requests that are safe to handle
+/// without their response being read, such as idempotent ones.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let requests: Vec<_> = ids.into_iter().map(|id| GetBalance { id }).collect();
+/// let balances = send_batch(connection, &requests).await?;
+/// ```
+///
+/// # Errors
+///
+/// - `Error::Batch`: A request of the batch failed, with any error [`send`] fails a request with
+/// - `Error::Connection`, `Error::Tls`, `Error::ProtocolVersion`, `Error::CodecMismatch`:
+///   The connection couldn't be opened, see [`Connection::open`]
+/// - `Error::Timeout`: Opening the connection, or exchanging the whole batch, took longer
+///   than the connection's timeout
+pub async fn send_batch<R>(
+	connection: ConnectionDetails,
+	requests: &[R],
+) -> Result<Vec<R::Response>, Error>
+where
+	R: crate::Request,
+{
+	if requests.is_empty() {
+		return Ok(Vec::new());
+	}
+
+	Connection::open(connection)
+		.await?
+		.send_batch(requests)
 		.await
-		.map_err(Error::Connection)?;
+}
+
+/// What a request sent with [`send_with_stats`] transferred, and how long it took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferStats {
+	/// How many bytes were written to the connection: the handshake and the request frame.
+	pub request_bytes: u64,
+	/// How many bytes were read off the connection: the handshake and the response frame.
+	pub response_bytes: u64,
+	/// How long the request took, from connecting to reading the last byte of the response.
+	pub round_trip: Duration,
+}
+
+/// Send a request like [`send`], also reporting what it transferred and how long it took.
+///
+/// Byte counts are those of everything sent over the connection, framing and metadata
+/// headers included, so that they can be attributed to the request type, and payloads are
+/// counted as sent: after compression, if any. Over TLS, they are counted before encryption.
+///
+/// # Errors
+///
+/// Same as [`send`].
+pub async fn send_with_stats<R>(
+	connection: ConnectionDetails,
+	request: &R,
+) -> Result<(R::Response, TransferStats), Error>
+where
+	R: crate::Request,
+{
+	exchange(connection, request, &Metadata::new()).await
+}
+
+/// Send a request over a new connection and read its response.
+async fn exchange<R>(
+	connection: ConnectionDetails,
+	request: &R,
+	metadata: &Metadata,
+) -> Result<(R::Response, TransferStats), Error>
+where
+	R: crate::Request,
+{
+	let started = Instant::now();
+	let (metadata, span) = request_span(R::ROUTE_ID, R::type_id(), metadata);
+	let exchange = async {
+		let mut stream =
+			open_exchange(connection, R::ROUTE_ID, R::type_id(), request, &metadata).await?;
+
+		// Step 3: Read the response, or the error the server reported instead.
+		let response = read_response::<R>(&mut stream, connection).await?;
+
+		Ok((response, stream.transferred()))
+	};
+
+	let (response, (read, written)) = within(connection.timeout, exchange)
+		.instrument(span)
+		.await?;
+
+	Ok((
+		response,
+		TransferStats {
+			request_bytes: written,
+			response_bytes: read,
+			round_trip: started.elapsed(),
+		},
+	))
+}
+
+/// Send a request answered with a stream of items, and receive the items one by one.
+///
+/// Each item is read off the connection only when the returned stream is polled, so a
+/// consumer that falls behind makes the server wait rather than buffer. The stream ends once
+/// the server has sent every item, or with the first error, after which it yields nothing.
+/// Every call opens its own connection, which is closed when the stream is dropped.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let lines = send_stream(connection, &TailLogs { since }).await?;
+/// let mut lines = std::pin::pin!(lines);
+///
+/// while let Some(line) = lines.try_next().await? {
+///     println!("{line}");
+/// }
+/// ```
+///
+/// # Errors
+///
+/// Same as [`send`], either right away if the request fails before the first item, or
+/// from the stream if it fails later on.
+pub async fn send_stream<R>(
+	connection: ConnectionDetails,
+	request: &R,
+) -> Result<impl futures_util::Stream<Item = Result<R::Item, Error>>, Error>
+where
+	R: crate::StreamingRequest,
+{
+	let (metadata, span) = request_span(R::ROUTE_ID, R::type_id(), &Metadata::new());
+	let start = async {
+		let mut stream =
+			open_exchange(connection, R::ROUTE_ID, R::type_id(), request, &metadata).await?;
+
+		// Streams are never compressed, so anything but a plain success is an error.
+		match read_status(&mut stream).await? {
+			wire::STATUS_OK => Ok(stream),
+			wire::STATUS_ERROR => {
+				let frame = read_frame(&mut stream, connection.max_payload_bytes).await?;
+				Err(remote_error(R::ROUTE_ID, &frame))
+			},
+			status => Err(invalid_status(status)),
+		}
+	};
+
+	let stream = within(connection.timeout, start).instrument(span).await?;
+
+	Ok(futures_util::stream::try_unfold(
+		stream,
+		move |mut stream| async move {
+			let item = read_frame(&mut stream, connection.max_payload_bytes).await?;
+
+			// An empty frame marks the end of the stream, as no item ever encodes to nothing.
+			if item.is_empty() {
+				return Ok(None);
+			}
+
+			let item = connection
+				.expected_response_format()
+				.decode(&item)
+				.map_err(|source| Error::Decoding {
+					route_id: R::ROUTE_ID,
+					bytes: item.len(),
+					source,
+				})?;
+
+			Ok(Some((item, stream)))
+		},
+	))
+}
+
+/// Give a request an ID unless the caller already did, and open the span it is sent in.
+///
+/// The server handles the request in a span of the same name and fields, so that the logs
+/// of both ends can be matched by request ID.
+fn request_span(route_id: &'static str, type_id: TypeId, metadata: &Metadata) -> (Metadata, Span) {
+	let mut metadata = metadata.clone();
+	let request_id = metadata
+		.request_id()
+		.map_or_else(wire::generate_request_id, ToOwned::to_owned);
+	metadata.insert(Metadata::REQUEST_ID, request_id.clone());
+
+	let span = tracing::info_span!(
+		"pontifex.request",
+		route_id,
+		type_id = format!("0x{type_id:08x}"),
+		request_id,
+		trace_id = tracing::field::Empty,
+	);
+	if let Some(trace_id) = metadata.trace_id() {
+		span.record("trace_id", tracing::field::display(trace_id));
+	}
+
+	(metadata, span)
+}
+
+/// Run `future`, failing with `Error::Timeout` if it takes longer than `timeout`.
+///
+/// Dropping the timed out future drops the connection it owns.
+async fn within<T>(
+	timeout: Option<Duration>,
+	future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+	let Some(timeout) = timeout else {
+		return future.await;
+	};
+
+	timed(Some(timeout), future)
+		.await
+		.unwrap_or(Err(Error::Timeout(timeout)))
+}
+
+/// Connect to the enclave and send a request, leaving the stream ready for the response.
+async fn open_exchange<R>(
+	connection: ConnectionDetails,
+	route_id: &'static str,
+	type_id: TypeId,
+	request: &R,
+	metadata: &Metadata,
+) -> Result<Stream, Error>
+where
+	R: Serialize + Sync,
+{
+	let mut stream = connect(connection).await?;
 
 	tracing::debug!("established connection to enclave");
 
-	// Step 1: Send the type ID so the server knows which handler to use.
-	let type_id = R::type_id();
+	// Step 1: Announce our protocol version and payload format. The server answers with its
+	// own before the response.
+	write_handshake(&mut stream, connection.format, wire::MODE_SEQUENTIAL).await?;
+
+	// Step 2: Send the request itself, without waiting for the server's answer to the handshake.
+	write_request(
+		&mut stream,
+		connection,
+		None,
+		route_id,
+		type_id,
+		request,
+		metadata,
+	)
+	.await?;
+
+	read_handshake(&mut stream, connection.format).await?;
+
+	Ok(stream)
+}
+
+/// Connect to the enclave, establishing a TLS session over the connection if configured to.
+async fn connect(connection: ConnectionDetails) -> Result<Stream, Error> {
+	#[cfg(feature = "test-transport")]
+	if let Some(path) = connection.unix_socket {
+		#[cfg(feature = "tls")]
+		if connection.tls.is_some() {
+			return Err(Error::Tls(io::Error::new(
+				io::ErrorKind::Unsupported,
+				"TLS isn't supported over Unix sockets",
+			)));
+		}
+
+		return Stream::connect_unix(path).await.map_err(Error::Connection);
+	}
+
+	#[cfg(feature = "tls")]
+	if let Some(tls) = connection.tls {
+		return Stream::connect_tls(connection.cid, connection.port, connection.stream, tls)
+			.await
+			.map_err(Error::Connection)?
+			.map_err(Error::Tls);
+	}
+
+	Stream::connect(connection.cid, connection.port, connection.stream)
+		.await
+		.map_err(Error::Connection)
+}
+
+/// Announce our protocol version, payload format and connection mode, opening the handshake.
+async fn write_handshake(stream: &mut Stream, format: Format, mode: u8) -> Result<(), Error> {
 	stream
-		.write_u32(type_id)
+		.write_all(&[wire::PROTOCOL_VERSION, format.descriptor(), mode])
 		.await
-		.map_err(|e| Error::Writing(CodingKey::Length, e))?;
+		.map_err(|e| Error::Writing(CodingKey::Handshake, e))?;
 
-	tracing::debug!(type_id = format!("0x{:08x}", type_id), "sent type ID");
+	// Nothing is buffered over plain vsock, but TLS sessions hold writes back until flushed.
+	stream
+		.flush()
+		.await
+		.map_err(|e| Error::Writing(CodingKey::Handshake, e))
+}
+
+/// Read the server's protocol version and payload format, and check both are compatible
+/// with ours.
+async fn read_handshake(stream: &mut Stream, format: Format) -> Result<(), Error> {
+	let server_version = stream
+		.read_u8()
+		.await
+		.map_err(|e| Error::Reading(CodingKey::Handshake, e))?;
+
+	if server_version != wire::PROTOCOL_VERSION {
+		return Err(Error::ProtocolVersion {
+			got: server_version,
+			expected: wire::PROTOCOL_VERSION,
+		});
+	}
+
+	let server_format = stream
+		.read_u8()
+		.await
+		.map_err(|e| Error::Reading(CodingKey::Handshake, e))?;
+
+	format
+		.negotiate(server_format)
+		.map_err(Error::CodecMismatch)
+}
 
-	// Step 2: Serialize and send the actual request data
-	let request_bytes = rmp_serde::to_vec(request).map_err(Error::Encoding)?;
+/// Write a request: its stream ID on multiplexed connections, then its type ID, payload
+/// formats, metadata headers and payload.
+///
+/// The payload is encoded before anything is written, so a request that fails to encode
+/// leaves the stream untouched.
+async fn write_request<R>(
+	stream: &mut (impl AsyncWrite + Unpin + Send),
+	connection: ConnectionDetails,
+	stream_id: Option<u32>,
+	route_id: &'static str,
+	type_id: TypeId,
+	request: &R,
+	metadata: &Metadata,
+) -> Result<(), Error>
+where
+	R: Serialize + Sync,
+{
+	let request_bytes = connection
+		.format
+		.encode(request)
+		.map_err(|source| Error::Encoding { route_id, source })?;
 
 	tracing::debug!(payload =? request_bytes, "encoded request payload");
 
+	#[cfg(feature = "compression")]
+	let (request_bytes, metadata) = &match connection.compression {
+		Some(config) => compress_request(config, request_bytes, metadata.clone()),
+		None => (request_bytes, metadata.clone()),
+	};
+
+	let mut metadata = metadata.clone();
+	if connection.checksum != Checksum::None {
+		metadata.insert(Metadata::CHECKSUM, connection.checksum.name());
+	}
+
+	if let Some(stream_id) = stream_id {
+		stream
+			.write_u32(stream_id)
+			.await
+			.map_err(|e| Error::Writing(CodingKey::StreamId, e))?;
+	}
+
+	// Send the type ID so the server knows which handler to use.
 	stream
-		.write_u64(request_bytes.len() as u64)
+		.write_all(&type_id.to_be_bytes())
 		.await
 		.map_err(|e| Error::Writing(CodingKey::Length, e))?;
 
-	tracing::debug!(length = request_bytes.len(), "sent request length");
+	tracing::debug!(type_id = format!("0x{:08x}", type_id), "sent type ID");
+
+	// Tag the payload with its format, and the format we want the response in.
+	for format in [connection.format, connection.expected_response_format()] {
+		stream
+			.write_u8(format.descriptor())
+			.await
+			.map_err(|e| Error::Writing(CodingKey::Format, e))?;
+	}
+
+	wire::write_metadata(stream, &metadata, Error::Writing).await?;
+	wire::write_frame(stream, request_bytes.as_slice(), Error::Writing).await?;
+
+	if let Some(checksum) = connection.checksum.compute(request_bytes.as_slice()) {
+		stream
+			.write_u32(checksum)
+			.await
+			.map_err(|e| Error::Writing(CodingKey::Checksum, e))?;
+	}
 
 	stream
-		.write_all(&request_bytes)
+		.flush()
 		.await
 		.map_err(|e| Error::Writing(CodingKey::Payload, e))?;
 
 	tracing::debug!(payload =? request_bytes, "sent encoded request payload");
 
-	let len = stream
-		.read_u64()
+	Ok(())
+}
+
+/// Read the response to a request, turning an error frame into `Error::Remote`.
+///
+/// The whole frame is read before it is decoded, so a response that fails to decode leaves
+/// the stream at the start of the next one.
+async fn read_response<R>(
+	stream: &mut (impl AsyncRead + Unpin + Send),
+	connection: ConnectionDetails,
+) -> Result<R::Response, Error>
+where
+	R: crate::Request,
+{
+	let status = read_status(stream).await?;
+	let response = read_frame(stream, connection.max_payload_bytes).await?;
+	read_checksum(stream, status, connection.checksum, &response).await?;
+
+	decode_response::<R>(status, response, connection)
+}
+
+/// Decode a response read in full, turning an error frame into `Error::Remote`.
+fn decode_response<R>(
+	status: u8,
+	response: Vec<u8>,
+	connection: ConnectionDetails,
+) -> Result<R::Response, Error>
+where
+	R: crate::Request,
+{
+	tracing::debug!(payload =? response, "received encoded response payload");
+
+	let response = match status & !wire::STATUS_CHECKSUM_FLAG {
+		wire::STATUS_ERROR => return Err(remote_error(R::ROUTE_ID, &response)),
+		wire::STATUS_OK_ZSTD => decompress_response(response, connection.max_payload_bytes)?,
+		_ => response,
+	};
+
+	connection
+		.expected_response_format()
+		.decode(&response)
+		.map_err(|source| Error::Decoding {
+			route_id: R::ROUTE_ID,
+			bytes: response.len(),
+			source,
+		})
+}
+
+/// Read the status byte opening a response.
+async fn read_status(stream: &mut (impl AsyncRead + Unpin + Send)) -> Result<u8, Error> {
+	let status = stream
+		.read_u8()
 		.await
-		.map_err(|e| Error::Reading(CodingKey::Length, e))?;
+		.map_err(|e| Error::Reading(CodingKey::Status, e))?;
+
+	if !matches!(
+		status & !wire::STATUS_CHECKSUM_FLAG,
+		wire::STATUS_OK | wire::STATUS_ERROR | wire::STATUS_OK_ZSTD
+	) {
+		return Err(invalid_status(status));
+	}
 
-	tracing::debug!(length = len, "received response length");
+	Ok(status)
+}
+
+/// Read the checksum following a response frame if its status announces one, and check it.
+///
+/// Servers only checksum responses to checksummed requests, so a checksum announced
+/// otherwise is a protocol error.
+async fn read_checksum(
+	stream: &mut (impl AsyncRead + Unpin + Send),
+	status: u8,
+	checksum: Checksum,
+	response: &[u8],
+) -> Result<(), Error> {
+	if status & wire::STATUS_CHECKSUM_FLAG == 0 {
+		return Ok(());
+	}
 
-	let response = stream
-		.read_exact(len)
+	let Some(actual) = checksum.compute(response) else {
+		return Err(invalid_status(status));
+	};
+
+	let expected = stream
+		.read_u32()
 		.await
-		.map_err(|e| Error::Reading(CodingKey::Payload, e))?;
+		.map_err(|e| Error::Reading(CodingKey::Checksum, e))?;
 
-	tracing::debug!(payload =? response, "received encoded response payload");
+	if expected != actual {
+		return Err(Error::ChecksumMismatch(ChecksumMismatch {
+			expected,
+			actual,
+		}));
+	}
+
+	Ok(())
+}
+
+fn invalid_status(status: u8) -> Error {
+	Error::Reading(
+		CodingKey::Status,
+		io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("unknown response status 0x{status:02x}"),
+		),
+	)
+}
+
+/// Read a length-prefixed frame, refusing to allocate more than `limit` bytes for it.
+async fn read_frame(
+	stream: &mut (impl AsyncRead + Unpin + Send),
+	limit: u64,
+) -> Result<Vec<u8>, Error> {
+	stream.read_framed(limit).await.map_err(|e| {
+		FrameTooLarge::find(&e).copied().map_or_else(
+			|| Error::Reading(CodingKey::Payload, e),
+			|frame| Error::PayloadTooLarge {
+				declared: frame.declared,
+				limit: frame.limit,
+			},
+		)
+	})
+}
+
+/// Turn an error frame into the error the server reported for a request for `route_id`.
+fn remote_error(route_id: &'static str, frame: &[u8]) -> Error {
+	match ErrorFrame::decode(frame) {
+		Ok(frame) => Error::Remote {
+			code: frame.code,
+			message: frame.message,
+		},
+		Err(source) => Error::Decoding {
+			route_id,
+			bytes: frame.len(),
+			source,
+		},
+	}
+}
 
-	rmp_serde::from_slice(&response).map_err(Error::Decoding)
+/// Compress a request payload if it's worth it, and tell the server we accept compressed
+/// responses in return.
+#[cfg(feature = "compression")]
+fn compress_request(
+	config: CompressionConfig,
+	payload: Vec<u8>,
+	metadata: Metadata,
+) -> (Vec<u8>, Metadata) {
+	let mut metadata = metadata.with(Metadata::ACCEPT_ENCODING, Compression::Zstd.name());
+	let (compression, payload) = config.compress(payload);
+
+	if compression != Compression::None {
+		metadata.insert(Metadata::CONTENT_ENCODING, compression.name());
+	}
+
+	(payload, metadata)
+}
+
+/// Decompress a response the server compressed with zstd.
+///
+/// Servers only compress responses to clients that accept it, so this fails without the
+/// `compression` feature.
+fn decompress_response(response: Vec<u8>, limit: u64) -> Result<Vec<u8>, Error> {
+	#[cfg(feature = "compression")]
+	let decompressed = wire::decompress(Compression::Zstd, response, limit);
+
+	#[cfg(not(feature = "compression"))]
+	let decompressed = {
+		let _ = (response, limit);
+		Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			"zstd compression is not enabled",
+		))
+	};
+
+	decompressed.map_err(Error::Decompression)
+}
+
+/// Attach an attestation document to the request, then send it like [`send`].
+///
+/// The document is produced by [`SecureModule::attest_cached`], binding the request's
+/// `user_data` and `public_key`. With `Freshness::MaxAge`, consecutive requests binding the
+/// same data share a document until it gets older than the allowed age, so the receiver may
+/// see the same document several times; use `Freshness::Always` if it must be unique per call.
+///
+/// Note that the NSM call is blocking and runs on the current task.
+///
+/// # Errors
+///
+/// - `Error::Attestation`: The NSM failed to produce an attestation document
+/// - Any error returned by [`send`]
+#[cfg(feature = "nsm")]
+pub async fn send_attested<R>(
+	connection: ConnectionDetails,
+	secure_module: &SecureModule,
+	mut request: R,
+	freshness: Freshness,
+) -> Result<R::Response, Error>
+where
+	R: AttestedRequest,
+{
+	let (user_data, public_key) = (request.user_data(), request.public_key());
+	let document = secure_module
+		.attest_cached(user_data.as_deref(), public_key.as_deref(), freshness)
+		.map_err(Error::Attestation)?;
+
+	tracing::debug!(length = document.len(), "attaching attestation document");
+	request.attach_attestation(document);
+
+	send(connection, &request).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::error::Error as _;
+
+	#[test]
+	fn test_connection_details_round_trip() {
+		let details: ConnectionDetails = "16:1000".parse().unwrap();
+		assert_eq!((details.cid, details.port), (16, 1000));
+		assert_eq!(details.to_string(), "16:1000");
+
+		let details: ConnectionDetails = "0x10:0x3e8".parse().unwrap();
+		assert_eq!(details.to_string(), "16:1000");
+
+		assert_eq!(ConnectionDetails::to_host(8000).to_string(), "3:8000");
+
+		let details = ConnectionDetails::new(u32::MAX - 1, u32::MAX - 1);
+		let parsed: ConnectionDetails = details.to_string().parse().unwrap();
+		assert_eq!((parsed.cid, parsed.port), (details.cid, details.port));
+	}
+
+	#[test]
+	fn test_connection_details_parse_errors() {
+		let parse = |input: &str| input.parse::<ConnectionDetails>().unwrap_err();
+
+		assert!(matches!(parse("16"), AddrError::MissingSeparator(..)));
+		assert!(matches!(parse(""), AddrError::MissingSeparator(..)));
+		assert!(matches!(parse("enclave:1000"), AddrError::InvalidCid(..)));
+		assert!(matches!(parse("16:"), AddrError::InvalidPort(..)));
+		assert!(matches!(parse("16:-1"), AddrError::InvalidPort(..)));
+		assert!(matches!(
+			parse("4294967296:1000"),
+			AddrError::InvalidCid(..)
+		));
+		assert!(matches!(parse("16:4294967296"), AddrError::InvalidPort(..)));
+		assert!(matches!(parse("0:1000"), AddrError::ReservedCid { .. }));
+		assert!(matches!(
+			parse("0xFFFFFFFF:1000"),
+			AddrError::ReservedCid { .. }
+		));
+		assert!(matches!(
+			parse("16:0xFFFFFFFF"),
+			AddrError::ReservedPort { .. }
+		));
+	}
+
+	/// Wrapped IO and serde errors are exposed as the error's source, so that error reporters can walk the chain.
+	#[test]
+	fn test_error_source_chain() {
+		let error = Error::Writing(CodingKey::Payload, io::Error::other("broken pipe"));
+		// Reporters print the source after the error, so the message leaves it out.
+		assert_eq!(error.to_string(), "failed to write payload");
+		let source = error.source().expect("writing errors have a source");
+		assert_eq!(
+			source.downcast_ref::<io::Error>().unwrap().to_string(),
+			"broken pipe"
+		);
+
+		let decoding = Format::default().decode::<String>(&[0xC1]).unwrap_err();
+		let error = Error::Decoding {
+			route_id: "echo_v1",
+			bytes: 1,
+			source: decoding,
+		};
+		assert_eq!(error.to_string(), "decoding failed for echo_v1 (1 bytes)");
+		let source = error.source().unwrap();
+		assert!(source.downcast_ref::<wire::DecodeError>().is_some());
+		assert!(
+			source
+				.source()
+				.unwrap()
+				.downcast_ref::<rmp_serde::decode::Error>()
+				.is_some()
+		);
+	}
 }
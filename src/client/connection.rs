@@ -0,0 +1,243 @@
+use super::{
+	ConnectionDetails, Error, connect, read_handshake, read_response, request_span, within,
+	write_handshake, write_request,
+};
+use tracing::Instrument;
+
+use crate::{
+	utils::Stream,
+	wire::{self, Metadata},
+};
+
+/// An open connection to an enclave, carrying any number of requests one after the other.
+///
+/// [`send`](super::send) opens a new connection for every request, which dominates the
+/// latency of small requests. A `Connection` performs the handshake once, then sends each
+/// request and reads its response over the same stream.
+///
+/// Every request and response is a length-delimited frame that is read in full before it is
+/// decoded, so a request the server fails to decode, or a response that fails to decode
+/// here, doesn't affect the next one. A failure halfway through a frame does, so after an IO
+/// error every further request fails with `Error::Broken`: open a new connection instead.
+/// The server may also close the connection after reporting an error it can't recover from,
+/// such as an oversized payload.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut connection = Connection::open(ConnectionDetails::new(cid, port)).await?;
+///
+/// for id in ids {
+///     let balance: Balance = connection.send(&GetBalance { id }).await?;
+/// }
+/// ```
+pub struct Connection {
+	details: ConnectionDetails,
+	stream: Stream,
+	broken: bool,
+}
+
+impl Connection {
+	/// Connect to the enclave and exchange payload formats with it.
+	///
+	/// # Errors
+	///
+	/// - `Error::Connection`: Failed to connect to the enclave
+	/// - `Error::Tls`: Failed to establish a TLS session with the enclave, if configured to
+	/// - `Error::Writing`, `Error::Reading`: The handshake couldn't be exchanged
+	/// - `Error::ProtocolVersion`: The server speaks another version of the wire protocol
+	/// - `Error::CodecMismatch`: The server uses an incompatible payload format
+	/// - `Error::Timeout`: The handshake took longer than the connection's timeout
+	pub async fn open(details: ConnectionDetails) -> Result<Self, Error> {
+		let connect = async { Self::handshake(connect(details).await?, details).await };
+		let stream = within(details.timeout, connect).await?;
+
+		tracing::debug!("opened connection to enclave");
+
+		Ok(Self {
+			details,
+			stream,
+			broken: false,
+		})
+	}
+
+	/// Exchange payload formats over a stream opened some other way than from `details`,
+	/// such as an in-memory pipe to a router running in the same process.
+	#[cfg(all(feature = "server", feature = "test-transport"))]
+	pub(crate) async fn over(stream: Stream, details: ConnectionDetails) -> Result<Self, Error> {
+		let stream = within(details.timeout, Self::handshake(stream, details)).await?;
+
+		Ok(Self {
+			details,
+			stream,
+			broken: false,
+		})
+	}
+
+	async fn handshake(mut stream: Stream, details: ConnectionDetails) -> Result<Stream, Error> {
+		write_handshake(&mut stream, details.format, wire::MODE_SEQUENTIAL).await?;
+		read_handshake(&mut stream, details.format).await?;
+
+		Ok(stream)
+	}
+
+	/// The enclave service this connection is open to.
+	#[must_use]
+	pub const fn details(&self) -> ConnectionDetails {
+		self.details
+	}
+
+	/// Send a request over the connection and receive its response.
+	///
+	/// # Errors
+	///
+	/// - `Error::Broken`: A previous request left the connection unusable
+	/// - Any error returned by [`send`](super::send), except for `Error::Connection` and `Error::Tls`
+	pub async fn send<R>(&mut self, request: &R) -> Result<R::Response, Error>
+	where
+		R: crate::Request,
+	{
+		self.send_with_metadata(request, &Metadata::new()).await
+	}
+
+	/// Send a request along with metadata headers over the connection, and receive its response.
+	///
+	/// # Errors
+	///
+	/// Same as [`Connection::send`].
+	pub async fn send_with_metadata<R>(
+		&mut self,
+		request: &R,
+		metadata: &Metadata,
+	) -> Result<R::Response, Error>
+	where
+		R: crate::Request,
+	{
+		if self.broken {
+			return Err(Error::Broken);
+		}
+
+		let (metadata, span) = request_span(R::ROUTE_ID, R::type_id(), metadata);
+		let result = within(self.details.timeout, self.exchange(request, &metadata))
+			.instrument(span)
+			.await;
+
+		// Only these errors happen on a frame boundary: anything else may have left part of a
+		// frame on the stream, which the next request would be misread against.
+		if let Err(error) = &result {
+			self.broken = !matches!(
+				error,
+				Error::Encoding { .. }
+					| Error::Decoding { .. }
+					| Error::Decompression(_)
+					| Error::ChecksumMismatch(_)
+					| Error::Remote { .. }
+			);
+		}
+
+		result
+	}
+
+	/// Send a batch of requests over the connection, and receive their responses in order.
+	///
+	/// See [`send_batch`](super::send_batch) for how batches are exchanged, and how they
+	/// fail. The connection's timeout covers the whole batch rather than each request. A
+	/// failed batch leaves the connection unusable, as the responses to the requests after
+	/// the one that failed may still be on their way.
+	///
+	/// # Errors
+	///
+	/// - `Error::Broken`: A previous request left the connection unusable
+	/// - `Error::Batch`: A request of the batch failed, with any error [`Connection::send`]
+	///   fails a request with
+	/// - `Error::Timeout`: The whole batch took longer than the connection's timeout
+	pub async fn send_batch<R>(&mut self, requests: &[R]) -> Result<Vec<R::Response>, Error>
+	where
+		R: crate::Request,
+	{
+		if self.broken {
+			return Err(Error::Broken);
+		}
+
+		let span = tracing::info_span!(
+			"pontifex.batch",
+			route_id = R::ROUTE_ID,
+			requests = requests.len(),
+		);
+		let result = within(self.details.timeout, self.pipeline(requests))
+			.instrument(span)
+			.await;
+
+		self.broken = result.is_err();
+
+		result
+	}
+
+	/// Write every request of a batch while reading the responses to the ones written so far,
+	/// so that neither side waits on the other to drain the connection.
+	async fn pipeline<R>(&mut self, requests: &[R]) -> Result<Vec<R::Response>, Error>
+	where
+		R: crate::Request,
+	{
+		let details = self.details;
+		let failed = |index| {
+			move |error| Error::Batch {
+				index,
+				source: Box::new(error),
+			}
+		};
+		let (mut reader, mut writer) = tokio::io::split(&mut self.stream);
+
+		let write = async {
+			for (index, request) in requests.iter().enumerate() {
+				let (metadata, span) = request_span(R::ROUTE_ID, R::type_id(), &Metadata::new());
+				write_request(
+					&mut writer,
+					details,
+					None,
+					R::ROUTE_ID,
+					R::type_id(),
+					request,
+					&metadata,
+				)
+				.instrument(span)
+				.await
+				.map_err(failed(index))?;
+			}
+
+			Ok(())
+		};
+
+		let read = async {
+			let mut responses = Vec::with_capacity(requests.len());
+			for index in 0..requests.len() {
+				let response = read_response::<R>(&mut reader, details).await;
+				responses.push(response.map_err(failed(index))?);
+			}
+
+			Ok(responses)
+		};
+
+		let ((), responses) = futures_util::future::try_join(write, read).await?;
+
+		Ok(responses)
+	}
+
+	async fn exchange<R>(&mut self, request: &R, metadata: &Metadata) -> Result<R::Response, Error>
+	where
+		R: crate::Request,
+	{
+		write_request(
+			&mut self.stream,
+			self.details,
+			None,
+			R::ROUTE_ID,
+			R::type_id(),
+			request,
+			metadata,
+		)
+		.await?;
+
+		read_response::<R>(&mut self.stream, self.details).await
+	}
+}
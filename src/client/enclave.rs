@@ -0,0 +1,363 @@
+use std::{
+	hash::{BuildHasher, RandomState},
+	sync::Arc,
+	time::Duration,
+};
+use tokio::sync::Semaphore;
+
+use super::{CodingKey, ConnectionDetails, Error, send_with_metadata, within};
+use crate::wire::Metadata;
+
+/// How failed requests are retried, by an [`EnclaveClient`] or by
+/// [`send_with_retry`](super::send_with_retry).
+///
+/// By default only failures to connect are retried: once a connection is established the
+/// request may already have reached its handler, and sending it again could run it twice.
+/// Decoding errors and errors reported by the server are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+	/// How many times a request is attempted in total, including the first attempt.
+	pub max_attempts: u32,
+	/// How long to wait before the first retry.
+	pub base_delay: Duration,
+	/// How much the delay is multiplied by after each retry.
+	pub factor: u32,
+	/// The most random extra time added to each delay, so that clients failing together
+	/// don't all retry at the same instant.
+	pub jitter: Duration,
+	/// Whether to also retry requests that failed to be written, or whose connection broke
+	/// before the first byte of the response was read.
+	pub retry_unanswered: bool,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self::new(3)
+	}
+}
+
+impl RetryPolicy {
+	/// Attempt requests up to `max_attempts` times, waiting 100ms before the first retry and
+	/// doubling the delay after each one.
+	#[must_use]
+	pub const fn new(max_attempts: u32) -> Self {
+		Self {
+			max_attempts,
+			base_delay: Duration::from_millis(100),
+			factor: 2,
+			jitter: Duration::ZERO,
+			retry_unanswered: false,
+		}
+	}
+
+	/// Never retry requests.
+	#[must_use]
+	pub const fn none() -> Self {
+		Self::new(1)
+	}
+
+	/// Wait for the given delay before the first retry.
+	#[must_use]
+	pub const fn with_base_delay(mut self, base_delay: Duration) -> Self {
+		self.base_delay = base_delay;
+		self
+	}
+
+	/// Multiply the delay by the given factor after each retry.
+	#[must_use]
+	pub const fn with_factor(mut self, factor: u32) -> Self {
+		self.factor = factor;
+		self
+	}
+
+	/// Add up to `jitter` of random time to each delay.
+	#[must_use]
+	pub const fn with_jitter(mut self, jitter: Duration) -> Self {
+		self.jitter = jitter;
+		self
+	}
+
+	/// Also retry requests that were sent but not answered, see [`RetryPolicy::retries`].
+	///
+	/// **Only enable this for idempotent requests.** A request whose connection broke before
+	/// its response arrived may still have been handled by the enclave, in which case
+	/// retrying it runs its handler a second time.
+	#[must_use]
+	pub const fn retry_unanswered(mut self) -> Self {
+		self.retry_unanswered = true;
+		self
+	}
+
+	/// Whether a request that failed with `error` is retried, attempts permitting.
+	///
+	/// Failures to connect always are. With [`RetryPolicy::retry_unanswered`], so are
+	/// failures to write the request and to read the start of the response.
+	#[must_use]
+	pub const fn retries(self, error: &Error) -> bool {
+		match error {
+			Error::Connection(_) => true,
+			Error::Writing(..) | Error::Reading(CodingKey::Handshake | CodingKey::Status, _) => {
+				self.retry_unanswered
+			},
+			_ => false,
+		}
+	}
+
+	/// How long to wait before the given retry, counting from zero, without jitter.
+	const fn delay(self, retry: u32) -> Duration {
+		let multiplier = self.factor.saturating_pow(retry);
+
+		self.base_delay.saturating_mul(multiplier)
+	}
+
+	/// A random duration of at most `jitter`.
+	fn random_jitter(self) -> Duration {
+		if self.jitter.is_zero() {
+			return Duration::ZERO;
+		}
+
+		// Jitter only has to differ between retries and clients, which the keys of a new
+		// `RandomState` already do, without pulling in a random number generator.
+		let random = RandomState::new().hash_one(());
+		let max = u64::try_from(self.jitter.as_nanos()).unwrap_or(u64::MAX);
+
+		Duration::from_nanos(random % max.saturating_add(1))
+	}
+
+	/// Run `attempt` until it succeeds, fails with an error that isn't retried, or runs out
+	/// of attempts.
+	pub(super) async fn run<T, F, Fut>(self, mut attempt: F) -> Result<T, Error>
+	where
+		F: FnMut() -> Fut,
+		Fut: Future<Output = Result<T, Error>>,
+	{
+		let mut retry = 0;
+		loop {
+			match attempt().await {
+				Err(e) if self.retries(&e) && retry + 1 < self.max_attempts => {
+					let delay = self.delay(retry).saturating_add(self.random_jitter());
+					tracing::warn!(
+						error = &e as &dyn std::error::Error,
+						?delay,
+						"request to enclave failed, retrying"
+					);
+
+					tokio::time::sleep(delay).await;
+					retry += 1;
+				},
+				result => return result,
+			}
+		}
+	}
+}
+
+/// The builder was asked to build a client without connection details.
+#[derive(Debug, thiserror::Error)]
+#[error("no connection details were provided to the client builder")]
+pub struct MissingConnection;
+
+/// A client for a single enclave service, applying a timeout, retry and concurrency policy
+/// to every request it sends.
+///
+/// This is what most host applications should use; [`send`](super::send) remains available
+/// for callers that want to handle those concerns themselves. Cloning the client is cheap,
+/// and clones share the same limit on requests in flight.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let client = EnclaveClient::builder()
+///     .connection(ConnectionDetails::new(cid, port))
+///     .timeout(Duration::from_secs(5))
+///     .retry(RetryPolicy::new(5))
+///     .max_in_flight(16)
+///     .build()?;
+///
+/// let status: HealthStatus = client.call(&HealthCheck {}).await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct EnclaveClient {
+	connection: ConnectionDetails,
+	timeout: Option<Duration>,
+	retry: RetryPolicy,
+	in_flight: Arc<Semaphore>,
+}
+
+/// Builds an [`EnclaveClient`].
+#[derive(Debug, Clone, Default)]
+pub struct EnclaveClientBuilder {
+	connection: Option<ConnectionDetails>,
+	timeout: Option<Duration>,
+	retry: Option<RetryPolicy>,
+	max_in_flight: Option<usize>,
+}
+
+impl EnclaveClientBuilder {
+	/// The enclave service to send requests to. Required.
+	#[must_use]
+	pub const fn connection(mut self, connection: ConnectionDetails) -> Self {
+		self.connection = Some(connection);
+		self
+	}
+
+	/// Give up on an attempt after this long, failing it with `Error::Timeout`.
+	///
+	/// The timeout covers a whole round trip, from connecting to reading the response, and
+	/// applies to each attempt separately. By default, requests can wait forever.
+	#[must_use]
+	pub const fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// Retry failed requests according to this policy. By default, requests are never retried.
+	#[must_use]
+	pub const fn retry(mut self, retry: RetryPolicy) -> Self {
+		self.retry = Some(retry);
+		self
+	}
+
+	/// Send at most this many requests to the enclave at once; further calls wait for one
+	/// of them to complete.
+	///
+	/// Every request still opens a connection of its own, so this bounds how many
+	/// connections are open at once, but doesn't keep idle ones around for reuse: use a
+	/// [`Connection`](super::Connection) for that. At least one request is always allowed.
+	/// By default, the number of requests in flight is unbounded.
+	#[must_use]
+	pub const fn max_in_flight(mut self, requests: usize) -> Self {
+		self.max_in_flight = Some(requests);
+		self
+	}
+
+	/// Build the client.
+	///
+	/// # Errors
+	///
+	/// Returns `MissingConnection` if no connection details were provided.
+	pub fn build(self) -> Result<EnclaveClient, MissingConnection> {
+		Ok(EnclaveClient {
+			connection: self.connection.ok_or(MissingConnection)?,
+			timeout: self.timeout,
+			retry: self.retry.unwrap_or_else(RetryPolicy::none),
+			in_flight: Arc::new(Semaphore::new(
+				self.max_in_flight.map_or(Semaphore::MAX_PERMITS, |max| {
+					max.clamp(1, Semaphore::MAX_PERMITS)
+				}),
+			)),
+		})
+	}
+}
+
+impl EnclaveClient {
+	/// Start building a new client.
+	#[must_use]
+	pub fn builder() -> EnclaveClientBuilder {
+		EnclaveClientBuilder::default()
+	}
+
+	/// The enclave service this client sends requests to.
+	#[must_use]
+	pub const fn connection(&self) -> ConnectionDetails {
+		self.connection
+	}
+
+	/// Send a request to the enclave and receive its response, applying the client's policies.
+	///
+	/// # Errors
+	///
+	/// - `Error::Timeout`: An attempt took longer than the configured timeout
+	/// - Any error returned by [`send`](super::send), once the retry policy gives up
+	pub async fn call<R>(&self, request: &R) -> Result<R::Response, Error>
+	where
+		R: crate::Request,
+	{
+		self.call_with_metadata(request, &Metadata::new()).await
+	}
+
+	/// Send a request along with metadata headers, applying the client's policies.
+	///
+	/// Retried attempts carry the same headers, so an idempotency key lets a server
+	/// recognize a request it already handled.
+	///
+	/// # Errors
+	///
+	/// Same as [`EnclaveClient::call`].
+	pub async fn call_with_metadata<R>(
+		&self,
+		request: &R,
+		metadata: &Metadata,
+	) -> Result<R::Response, Error>
+	where
+		R: crate::Request,
+	{
+		// The semaphore is never closed, so acquiring a permit can't fail.
+		let _permit = self.in_flight.acquire().await.ok();
+
+		self.retry.run(|| self.attempt(request, metadata)).await
+	}
+
+	async fn attempt<R>(&self, request: &R, metadata: &Metadata) -> Result<R::Response, Error>
+	where
+		R: crate::Request,
+	{
+		let exchange = send_with_metadata(self.connection, request, metadata);
+
+		within(self.timeout, exchange).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io;
+
+	#[test]
+	fn test_retry_delay_backs_off() {
+		let policy = RetryPolicy::new(5).with_base_delay(Duration::from_millis(10));
+
+		assert_eq!(policy.delay(0), Duration::from_millis(10));
+		assert_eq!(policy.delay(1), Duration::from_millis(20));
+		assert_eq!(policy.delay(3), Duration::from_millis(80));
+		assert_eq!(
+			policy.delay(u32::MAX),
+			Duration::from_millis(10).saturating_mul(u32::MAX)
+		);
+	}
+
+	#[test]
+	fn test_retry_policy_only_retries_safe_errors() {
+		let refused = || Error::Connection(io::ErrorKind::ConnectionRefused.into());
+		let reset = || Error::Reading(CodingKey::Status, io::ErrorKind::ConnectionReset.into());
+		let truncated = || Error::Reading(CodingKey::Payload, io::ErrorKind::UnexpectedEof.into());
+
+		let policy = RetryPolicy::new(3);
+		assert!(policy.retries(&refused()));
+		assert!(!policy.retries(&reset()));
+
+		let policy = policy.retry_unanswered();
+		assert!(policy.retries(&reset()));
+		assert!(!policy.retries(&truncated()));
+		assert!(!policy.retries(&Error::Remote {
+			code: 0,
+			message: String::new(),
+		}));
+
+		let jittered = policy.with_jitter(Duration::from_millis(5));
+		assert!(jittered.random_jitter() <= Duration::from_millis(5));
+		assert_eq!(policy.random_jitter(), Duration::ZERO);
+	}
+
+	#[test]
+	fn test_build_requires_connection() {
+		assert!(EnclaveClient::builder().build().is_err());
+
+		let client = EnclaveClient::builder()
+			.connection(ConnectionDetails::new(16, 1000))
+			.max_in_flight(4)
+			.build()
+			.unwrap();
+		assert_eq!(client.connection().cid, 16);
+		assert_eq!(client.retry, RetryPolicy::none());
+	}
+}
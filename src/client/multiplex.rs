@@ -0,0 +1,294 @@
+use std::{
+	collections::HashMap,
+	sync::{
+		Arc, Mutex, MutexGuard, PoisonError,
+		atomic::{AtomicU32, Ordering},
+	},
+};
+use tokio::{
+	io::{AsyncReadExt, ReadHalf, WriteHalf},
+	sync::{Mutex as AsyncMutex, oneshot},
+	task::JoinHandle,
+};
+use tracing::Instrument;
+
+use super::{
+	ConnectionDetails, Error, connect, decode_response, read_checksum, read_frame, read_handshake,
+	read_status, request_span, within, write_handshake, write_request,
+};
+use crate::{
+	utils::Stream,
+	wire::{self, Metadata},
+};
+
+/// A response read in full, as its status and frame, or why it couldn't be.
+type Response = Result<(u8, Vec<u8>), Error>;
+
+/// An open connection to an enclave, carrying many requests at once.
+///
+/// A [`Connection`](super::Connection) sends one request at a time, so a slow request holds
+/// up every request behind it. A `MultiplexedConnection` tags each request with a stream ID
+/// instead: the server handles them concurrently, up to its `Router::max_streams`, and
+/// answers each one as soon as it is done. Requests are sent through a shared reference, so
+/// the connection can be shared between tasks behind an `Arc`.
+///
+/// A background task reads the responses and hands each to the request waiting for it. A
+/// failure halfway through a frame leaves the connection unusable: every request still
+/// waiting, and every further one, then fails with `Error::Broken`. Streaming requests can't
+/// be sent over a multiplexed connection.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let connection = MultiplexedConnection::open(ConnectionDetails::new(cid, port)).await?;
+///
+/// let (user, balance) = tokio::try_join!(
+///     connection.send(&GetUser { id }),
+///     connection.send(&GetBalance { id }),
+/// )?;
+/// ```
+pub struct MultiplexedConnection {
+	details: ConnectionDetails,
+	writer: AsyncMutex<WriteHalf<Stream>>,
+	pending: Arc<Mutex<Pending>>,
+	next_id: AtomicU32,
+	reader: JoinHandle<()>,
+}
+
+/// The requests waiting for their response.
+#[derive(Default)]
+struct Pending {
+	/// Where to send the response of each request, by stream ID.
+	senders: HashMap<u32, oneshot::Sender<Response>>,
+	/// Whether the connection broke, so that no request can be sent over it anymore.
+	broken: bool,
+}
+
+impl MultiplexedConnection {
+	/// Connect to the enclave and exchange payload formats with it, announcing a multiplexed
+	/// connection.
+	///
+	/// # Errors
+	///
+	/// Same as [`Connection::open`](super::Connection::open).
+	pub async fn open(details: ConnectionDetails) -> Result<Self, Error> {
+		let stream = within(details.timeout, Self::handshake(details)).await?;
+		let (reader, writer) = tokio::io::split(stream);
+		let pending = Arc::new(Mutex::new(Pending::default()));
+
+		tracing::debug!("opened multiplexed connection to enclave");
+
+		Ok(Self {
+			details,
+			writer: AsyncMutex::new(writer),
+			pending: pending.clone(),
+			next_id: AtomicU32::new(0),
+			reader: tokio::spawn(read_responses(reader, details, pending)),
+		})
+	}
+
+	async fn handshake(details: ConnectionDetails) -> Result<Stream, Error> {
+		let mut stream = connect(details).await?;
+
+		write_handshake(&mut stream, details.format, wire::MODE_MULTIPLEXED).await?;
+		read_handshake(&mut stream, details.format).await?;
+
+		Ok(stream)
+	}
+
+	/// The enclave service this connection is open to.
+	#[must_use]
+	pub const fn details(&self) -> ConnectionDetails {
+		self.details
+	}
+
+	/// Send a request over the connection, and receive its response.
+	///
+	/// Other requests may be sent over the connection while this one waits for its response.
+	///
+	/// # Errors
+	///
+	/// Same as [`send`](super::send), as well as:
+	/// - `Error::Broken`: The connection broke, before or while this request was sent
+	/// - `Error::Remote`: The server can't serve the request over a multiplexed connection,
+	///   such as a streaming request, with code `UNSUPPORTED_MODE`
+	pub async fn send<R>(&self, request: &R) -> Result<R::Response, Error>
+	where
+		R: crate::Request,
+	{
+		self.send_with_metadata(request, &Metadata::new()).await
+	}
+
+	/// Send a request along with metadata headers over the connection, and receive its response.
+	///
+	/// # Errors
+	///
+	/// Same as [`MultiplexedConnection::send`].
+	pub async fn send_with_metadata<R>(
+		&self,
+		request: &R,
+		metadata: &Metadata,
+	) -> Result<R::Response, Error>
+	where
+		R: crate::Request,
+	{
+		let (metadata, span) = request_span(R::ROUTE_ID, R::type_id(), metadata);
+
+		within(self.details.timeout, self.exchange(request, &metadata))
+			.instrument(span)
+			.await
+	}
+
+	async fn exchange<R>(&self, request: &R, metadata: &Metadata) -> Result<R::Response, Error>
+	where
+		R: crate::Request,
+	{
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let mut waiting = self.wait_for(id)?;
+
+		self.write(id, request, metadata).await?;
+
+		let (status, response) = (&mut waiting.receiver).await.map_err(|_| Error::Broken)??;
+
+		decode_response::<R>(status, response, self.details)
+	}
+
+	/// Register a request as waiting for its response, before it is sent.
+	fn wait_for(&self, id: u32) -> Result<Waiting<'_>, Error> {
+		let mut pending = lock(&self.pending);
+		if pending.broken {
+			return Err(Error::Broken);
+		}
+
+		let (sender, receiver) = oneshot::channel();
+		pending.senders.insert(id, sender);
+		drop(pending);
+
+		Ok(Waiting {
+			pending: &self.pending,
+			id,
+			receiver,
+		})
+	}
+
+	/// Write a request, preceded by its stream ID.
+	///
+	/// A request given up halfway through being written, or failing to, leaves the server
+	/// unable to find the next one, so it breaks the connection.
+	async fn write<R>(&self, id: u32, request: &R, metadata: &Metadata) -> Result<(), Error>
+	where
+		R: crate::Request,
+	{
+		let mut writer = self.writer.lock().await;
+		let mut guard = WriteGuard {
+			pending: &self.pending,
+			intact: false,
+		};
+
+		let written = write_request(
+			&mut *writer,
+			self.details,
+			Some(id),
+			R::ROUTE_ID,
+			R::type_id(),
+			request,
+			metadata,
+		)
+		.await;
+
+		// Requests are encoded before anything is written.
+		guard.intact = matches!(written, Ok(()) | Err(Error::Encoding { .. }));
+		drop(writer);
+
+		written
+	}
+}
+
+impl Drop for MultiplexedConnection {
+	fn drop(&mut self) {
+		self.reader.abort();
+	}
+}
+
+/// A request waiting for its response, which stops waiting once dropped.
+struct Waiting<'a> {
+	pending: &'a Mutex<Pending>,
+	id: u32,
+	receiver: oneshot::Receiver<Response>,
+}
+
+impl Drop for Waiting<'_> {
+	fn drop(&mut self) {
+		lock(self.pending).senders.remove(&self.id);
+	}
+}
+
+/// Breaks the connection when dropped, unless the request being written was written in full.
+struct WriteGuard<'a> {
+	pending: &'a Mutex<Pending>,
+	intact: bool,
+}
+
+impl Drop for WriteGuard<'_> {
+	fn drop(&mut self) {
+		if !self.intact {
+			lock(self.pending).broken = true;
+		}
+	}
+}
+
+fn lock(pending: &Mutex<Pending>) -> MutexGuard<'_, Pending> {
+	pending.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Read responses off the connection until it closes, handing each one to the request
+/// waiting for it.
+async fn read_responses(
+	mut reader: ReadHalf<Stream>,
+	details: ConnectionDetails,
+	pending: Arc<Mutex<Pending>>,
+) {
+	loop {
+		let id = match reader.read_u32().await {
+			Ok(id) => id,
+			Err(e) => {
+				tracing::debug!(
+					error = &e as &dyn std::error::Error,
+					"multiplexed connection closed"
+				);
+				break;
+			},
+		};
+
+		let response = read_response(&mut reader, details).await;
+
+		// A corrupted response was still read in full, unlike one that failed to be read.
+		let broken =
+			matches!(&response, Err(error) if !matches!(error, Error::ChecksumMismatch(_)));
+
+		// The request may have been given up since, in which case nobody is waiting for it.
+		let sender = lock(&pending).senders.remove(&id);
+		if let Some(sender) = sender {
+			_ = sender.send(response);
+		}
+
+		if broken {
+			break;
+		}
+	}
+
+	let mut pending = lock(&pending);
+	pending.broken = true;
+
+	// Dropping the senders fails the requests still waiting with `Error::Broken`.
+	pending.senders.clear();
+}
+
+/// Read the response following a stream ID, without decoding it.
+async fn read_response(reader: &mut ReadHalf<Stream>, details: ConnectionDetails) -> Response {
+	let status = read_status(reader).await?;
+	let response = read_frame(reader, details.max_payload_bytes).await?;
+	read_checksum(reader, status, details.checksum, &response).await?;
+
+	Ok((status, response))
+}
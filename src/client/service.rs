@@ -0,0 +1,93 @@
+use std::{
+	fmt,
+	marker::PhantomData,
+	pin::Pin,
+	task::{Context, Poll},
+};
+use tower_service::Service;
+
+use super::{EnclaveClient, Error};
+use crate::Request;
+
+/// A tower `Service` sending requests of type `R` through an [`EnclaveClient`].
+///
+/// Each request type has its own response type, so a service only handles one of them: get
+/// one per request type with [`EnclaveClient::service`]. This lets pontifex requests go
+/// through standard tower middleware, such as rate limiting or load shedding, on top of the
+/// client's own timeout, retry and concurrency policies.
+///
+/// The service is always ready: a client limiting its requests in flight waits for one of
+/// them to complete once the request is sent, rather than in `poll_ready`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let service = ServiceBuilder::new()
+///     .rate_limit(100, Duration::from_secs(1))
+///     .service(client.service::<GetUser>());
+///
+/// let user = service.oneshot(GetUser { id }).await?;
+/// ```
+pub struct RequestService<R> {
+	client: EnclaveClient,
+	_request: PhantomData<fn(R) -> R>,
+}
+
+impl<R> RequestService<R> {
+	/// Create a service sending requests through `client`.
+	#[must_use]
+	pub const fn new(client: EnclaveClient) -> Self {
+		Self {
+			client,
+			_request: PhantomData,
+		}
+	}
+
+	/// The client requests are sent through.
+	#[must_use]
+	pub const fn client(&self) -> &EnclaveClient {
+		&self.client
+	}
+}
+
+// Implemented by hand, as deriving would require `R` to implement them too.
+impl<R> Clone for RequestService<R> {
+	fn clone(&self) -> Self {
+		Self::new(self.client.clone())
+	}
+}
+
+impl<R> fmt::Debug for RequestService<R> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("RequestService")
+			.field("request", &std::any::type_name::<R>())
+			.field("client", &self.client)
+			.finish()
+	}
+}
+
+impl<R: Request> Service<R> for RequestService<R> {
+	type Response = R::Response;
+	type Error = Error;
+	type Future = Pin<Box<dyn Future<Output = Result<R::Response, Error>> + Send>>;
+
+	fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, request: R) -> Self::Future {
+		let client = self.client.clone();
+
+		Box::pin(async move { client.call(&request).await })
+	}
+}
+
+impl EnclaveClient {
+	/// A tower `Service` sending requests of type `R` through this client.
+	///
+	/// See [`RequestService`].
+	#[must_use]
+	pub fn service<R: Request>(&self) -> RequestService<R> {
+		RequestService::new(self.clone())
+	}
+}
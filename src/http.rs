@@ -1,19 +1,51 @@
-use std::time::Duration;
+use std::{fmt, sync::Arc, time::Duration};
 
-use hyper::Client;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Request, Response, Uri, body::Incoming};
 use hyper_rustls::HttpsConnector;
+use hyper_util::{
+	client::legacy::Client,
+	rt::{TokioExecutor, TokioTimer},
+};
+use rustls::{
+	CertificateError, DigitallySignedStruct, OtherError, RootCertStore, SignatureScheme,
+	client::{
+		WebPkiServerVerifier,
+		danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+	},
+	crypto::ring,
+	pki_types::{CertificateDer, ServerName, UnixTime},
+};
+use sha2::{Digest, Sha256};
 use tokio_vsock::VsockAddr;
+use x509_cert::{
+	Certificate,
+	der::{Decode, Encode},
+};
 
-use crate::utils::http::{vsock_proxy, vsock_proxy_http2_only};
+use crate::{
+	transport::{PoolConfig, VsockConnector},
+	utils::http::{
+		vsock_proxy_http2_only, vsock_proxy_with_roots, vsock_proxy_with_verifier, webpki_roots,
+	},
+};
 
-// Re-export VSockClientBuilder for public use
-pub use crate::utils::http::VSockClientBuilder;
+/// The connector used by the clients of this module.
+#[deprecated(note = "use `pontifex::transport::VsockConnector` instead")]
+pub type VSockClientBuilder = VsockConnector;
 
-/// The CID of the vsock proxy.
-pub const VSOCK_PROXY_CID: u32 = 3;
+/// The CID of the vsock proxy, which runs on the parent instance.
+pub const VSOCK_PROXY_CID: u32 = crate::addr::PARENT_CID;
 
 /// A HTTP client that tunnels all requests through the host's vsock proxy.
-pub type HttpClient = Client<HttpsConnector<VSockClientBuilder>>;
+///
+/// Request bodies are sent in full, responses are streamed as [`Incoming`] bodies.
+pub type HttpClient = Client<HttpsConnector<VsockConnector>, Full<Bytes>>;
+
+/// A HTTP client that sends all requests in plaintext through the host's vsock proxy, see
+/// [`client_plaintext`].
+pub type PlaintextHttpClient = Client<VsockConnector, Full<Bytes>>;
 
 #[must_use]
 /// Creates an HTTPS client that tunnels all requests through the host's vsock proxy.
@@ -30,21 +62,22 @@ pub type HttpClient = Client<HttpsConnector<VSockClientBuilder>>;
 ///
 /// Example usage (generic HTTPS request):
 /// ```rust,ignore
-/// use hyper::{Client, Request, Body, body::to_bytes};
+/// use http_body_util::{BodyExt, Full};
+/// use hyper::Request;
 /// use pontifex::http;
 ///
 /// #[tokio::main]
 /// async fn main() -> anyhow::Result<()> {
 ///     // The port where your host's vsock proxy listens
-///     let client: Client<_> = http::client(8000);
+///     let client = http::client(8000);
 ///
 ///     let req = Request::builder()
 ///         .method("GET")
 ///         .uri("https://api.example.com/v1/ping")
-///         .body(Body::empty())?;
+///         .body(Full::default())?;
 ///
 ///     let res = client.request(req).await?;
-///     let body = to_bytes(res).await?;
+///     let body = res.into_body().collect().await?.to_bytes();
 ///     println!("{}", String::from_utf8_lossy(&body));
 ///     Ok(())
 /// }
@@ -57,16 +90,208 @@ pub type HttpClient = Client<HttpsConnector<VSockClientBuilder>>;
 /// - The connector ignores the dial target from the URI and always connects to
 ///   the fixed vsock address (CID 3 + `vsock_proxy_port`), while preserving
 ///   Host/SNI for end-to-end TLS to the upstream.
+/// - Requests to `http://` URIs fail rather than being sent in plaintext, use
+///   [`client_plaintext`] to reach plaintext services.
 pub fn client(vsock_proxy_port: u32) -> HttpClient {
-	Client::builder()
+	client_with_roots(vsock_proxy_port, webpki_roots())
+}
+
+/// Creates an HTTPS client like [`client`], that trusts the certificate authorities in
+/// `roots` instead of the webpki root store.
+///
+/// Use this to reach services behind a private certificate authority, such as internal
+/// APIs. Only the authorities in `roots` are trusted, so add the webpki roots to it as well
+/// if the client also needs to reach public services.
+#[must_use]
+pub fn client_with_roots(vsock_proxy_port: u32, roots: RootCertStore) -> HttpClient {
+	http2_client(
+		vsock_proxy_with_roots(proxy(vsock_proxy_port), roots),
+		PoolConfig::default(),
+	)
+}
+
+/// Creates an HTTPS client like [`client`], that keeps idle connections for reuse according
+/// to `pool`.
+///
+/// Requests are sent over HTTP/2, so all the requests to a host share a single connection
+/// while it is open. The pool decides how long that connection, along with its TLS session,
+/// outlives the last request, so that the next one doesn't pay for a new handshake over
+/// vsock. The other clients of this module use [`PoolConfig::default`].
+#[must_use]
+pub fn client_with_pool(vsock_proxy_port: u32, pool: PoolConfig) -> HttpClient {
+	http2_client(
+		vsock_proxy_with_roots(proxy(vsock_proxy_port), webpki_roots()),
+		pool,
+	)
+}
+
+/// Creates a HTTP client that sends all requests **in plaintext** through the host's vsock
+/// proxy.
+///
+/// # Security
+///
+/// Nothing sent or received by this client is encrypted or authenticated: the host, and
+/// anything between the proxy and the upstream service, can read and tamper with requests
+/// and responses. This defeats the isolation enclaves exist for, so only use it for services
+/// whose responses aren't trusted and to which nothing secret is sent, such as a metadata
+/// endpoint the host itself exposes in the clear. Prefer [`client`] everywhere else.
+///
+/// Requests are sent over HTTP/1.1, which plaintext services usually speak, to `http://`
+/// URIs: `https://` URIs fail, as this client doesn't speak TLS. Like the other clients of
+/// this module, the URI's host is only used for the `Host` header, connections always go to
+/// the vsock proxy.
+#[must_use]
+pub fn client_plaintext(vsock_proxy_port: u32) -> PlaintextHttpClient {
+	PoolConfig::default()
+		.apply(&mut Client::builder(TokioExecutor::new()))
+		.build(proxy(vsock_proxy_port))
+}
+
+/// Timeouts and pooling of a client created with [`client_with_config`].
+///
+/// The default bounds connecting to the vsock proxy to [`HttpConfig::DEFAULT_CONNECT_TIMEOUT`],
+/// leaves requests unbounded and closes idle connections like [`PoolConfig::default`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HttpConfig {
+	/// How long connecting to the vsock proxy may take, or `None` to wait indefinitely.
+	pub connect_timeout: Option<Duration>,
+	/// How long a request may take until its response headers are received, or `None` to
+	/// wait indefinitely. Reading the response body isn't bounded.
+	pub request_timeout: Option<Duration>,
+	/// How long a connection may stay idle before it is closed, or `None` to keep it until
+	/// the peer closes it.
+	pub pool_idle_timeout: Option<Duration>,
+}
+
+impl HttpConfig {
+	/// How long connecting to the vsock proxy may take by default.
+	pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+}
+
+impl Default for HttpConfig {
+	fn default() -> Self {
+		Self {
+			connect_timeout: Some(Self::DEFAULT_CONNECT_TIMEOUT),
+			request_timeout: None,
+			pool_idle_timeout: PoolConfig::default().idle_timeout,
+		}
+	}
+}
+
+/// Creates an HTTPS client like [`client`], bounding connections and requests according to
+/// `config`.
+///
+/// The host's vsock proxy may silently drop connections, or not be running at all, which
+/// leaves requests of a [`client`] hanging forever. A connect timeout fails them with an
+/// [`std::io::ErrorKind::TimedOut`] error instead, and a request timeout with
+/// [`RequestError::Timeout`].
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let client = http::client_with_config(8000, HttpConfig {
+///     request_timeout: Some(Duration::from_secs(30)),
+///     ..HttpConfig::default()
+/// });
+///
+/// let response = client.get("https://api.example.com/v1/ping".parse()?).await?;
+/// ```
+#[must_use]
+pub fn client_with_config(vsock_proxy_port: u32, config: HttpConfig) -> TimeoutClient {
+	let mut proxy = proxy(vsock_proxy_port);
+	if let Some(timeout) = config.connect_timeout {
+		proxy = proxy.with_connect_timeout(timeout);
+	}
+
+	let pool = PoolConfig {
+		idle_timeout: config.pool_idle_timeout,
+		..PoolConfig::default()
+	};
+
+	TimeoutClient {
+		client: http2_client(vsock_proxy_with_roots(proxy, webpki_roots()), pool),
+		timeout: config.request_timeout,
+	}
+}
+
+/// An [`HttpClient`] giving up on requests that take too long, created with
+/// [`client_with_config`].
+#[derive(Debug, Clone)]
+pub struct TimeoutClient {
+	client: HttpClient,
+	timeout: Option<Duration>,
+}
+
+impl TimeoutClient {
+	/// Send a request, waiting for the response headers at most for the request timeout.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request fails, or if it times out.
+	pub async fn request(
+		&self,
+		request: Request<Full<Bytes>>,
+	) -> Result<Response<Incoming>, RequestError> {
+		let Some(timeout) = self.timeout else {
+			return Ok(self.client.request(request).await?);
+		};
+
+		tokio::time::timeout(timeout, self.client.request(request))
+			.await
+			.map_err(|_| RequestError::Timeout(timeout))?
+			.map_err(RequestError::Http)
+	}
+
+	/// Send a `GET` request to `uri`, like [`TimeoutClient::request`].
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request fails, or if it times out.
+	pub async fn get(&self, uri: Uri) -> Result<Response<Incoming>, RequestError> {
+		let mut request = Request::new(Full::default());
+		*request.uri_mut() = uri;
+
+		self.request(request).await
+	}
+
+	/// The underlying client, which doesn't apply the request timeout.
+	#[must_use]
+	pub const fn inner(&self) -> &HttpClient {
+		&self.client
+	}
+
+	/// How long requests may take until their response headers are received, if bounded.
+	#[must_use]
+	pub const fn timeout(&self) -> Option<Duration> {
+		self.timeout
+	}
+}
+
+/// Errors that can occur when sending a request with a [`TimeoutClient`].
+#[derive(Debug, thiserror::Error)]
+pub enum RequestError {
+	/// The request failed, including when connecting timed out.
+	#[error(transparent)]
+	Http(#[from] hyper_util::client::legacy::Error),
+	/// No response was received within the request timeout.
+	#[error("request timed out after {0:?}")]
+	Timeout(Duration),
+}
+
+/// The connector to the host's vsock proxy listening on `vsock_proxy_port`.
+fn proxy(vsock_proxy_port: u32) -> VsockConnector {
+	VsockConnector::new(VsockAddr::new(VSOCK_PROXY_CID, vsock_proxy_port))
+}
+
+/// Build an HTTP/2 client with the settings shared by the clients of this module.
+fn http2_client(connector: HttpsConnector<VsockConnector>, pool: PoolConfig) -> HttpClient {
+	pool.apply(&mut Client::builder(TokioExecutor::new()))
+		.timer(TokioTimer::new())
 		.http2_only(true)
 		.http2_adaptive_window(false)  // Prevent large window updates
 		.http2_keep_alive_interval(Some(Duration::from_secs(30)))
 		.http2_keep_alive_timeout(Duration::from_secs(10))
-		.build(vsock_proxy(VsockAddr::new(
-			VSOCK_PROXY_CID,
-			vsock_proxy_port,
-		)))
+		.build(connector)
 }
 
 /// Configuration for an HTTPS client that tunnels all requests through the host's vsock proxy and only uses HTTP/2.
@@ -94,15 +319,157 @@ impl Default for Http2ClientConfig {
 /// Creates an HTTPS client that tunnels all requests through the host's vsock proxy and only uses HTTP/2.
 #[must_use]
 pub fn client_http2_only(vsock_proxy_port: u32, config: &Http2ClientConfig) -> HttpClient {
-	Client::builder()
+	PoolConfig::default()
+		.apply(&mut Client::builder(TokioExecutor::new()))
+		.timer(TokioTimer::new())
 		.http2_only(true)
 		.http2_adaptive_window(config.adaptive_window)
 		.http2_keep_alive_interval(config.keep_alive_interval)
 		.http2_keep_alive_timeout(config.keep_alive_timeout)
 		.http2_initial_stream_window_size(config.initial_stream_window_size)
 		.http2_initial_connection_window_size(config.initial_connection_window_size)
-		.build(vsock_proxy_http2_only(VsockAddr::new(
-			VSOCK_PROXY_CID,
-			vsock_proxy_port,
-		)))
+		.build(vsock_proxy_http2_only(proxy(vsock_proxy_port)))
+}
+
+/// A SHA-256 digest of the DER-encoded `SubjectPublicKeyInfo` of a trusted certificate.
+///
+/// Pinning the public key rather than the whole certificate keeps the pin valid when the
+/// certificate is renewed with the same key. The digest is the one computed by
+/// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CertificatePin([u8; 32]);
+
+impl CertificatePin {
+	/// Create a pin from the SHA-256 digest of a `SubjectPublicKeyInfo`.
+	#[must_use]
+	pub const fn new(sha256: [u8; 32]) -> Self {
+		Self(sha256)
+	}
+
+	/// Create a pin from a DER-encoded `SubjectPublicKeyInfo`.
+	#[must_use]
+	pub fn from_spki(spki_der: &[u8]) -> Self {
+		Self(Sha256::digest(spki_der).into())
+	}
+
+	/// Create a pin for the public key of a DER-encoded certificate, or `None` if it can't be parsed.
+	#[must_use]
+	pub fn from_certificate(certificate_der: &[u8]) -> Option<Self> {
+		let certificate = Certificate::from_der(certificate_der).ok()?;
+		let spki = certificate
+			.tbs_certificate
+			.subject_public_key_info
+			.to_der()
+			.ok()?;
+
+		Some(Self::from_spki(&spki))
+	}
+}
+
+impl fmt::Debug for CertificatePin {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "CertificatePin(")?;
+		for byte in self.0 {
+			write!(f, "{byte:02x}")?;
+		}
+		write!(f, ")")
+	}
+}
+
+/// None of the certificates presented by a server matched a pin.
+#[derive(Debug, thiserror::Error)]
+#[error("no certificate presented by the server matches a pinned public key")]
+pub struct PinMismatch;
+
+/// Verifies certificates against the webpki roots as usual, then requires one of the
+/// presented certificates to match a pin.
+#[derive(Debug)]
+struct PinnedVerifier {
+	inner: Arc<WebPkiServerVerifier>,
+	pins: Vec<CertificatePin>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		intermediates: &[CertificateDer<'_>],
+		server_name: &ServerName<'_>,
+		ocsp_response: &[u8],
+		now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		let verified = self.inner.verify_server_cert(
+			end_entity,
+			intermediates,
+			server_name,
+			ocsp_response,
+			now,
+		)?;
+
+		let pinned = std::iter::once(end_entity)
+			.chain(intermediates)
+			.filter_map(|certificate| CertificatePin::from_certificate(certificate))
+			.any(|pin| self.pins.contains(&pin));
+
+		if !pinned {
+			tracing::warn!("refusing server certificate chain matching no pinned key");
+			return Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+				OtherError(Arc::new(PinMismatch)),
+			)));
+		}
+
+		Ok(verified)
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		self.inner.verify_tls12_signature(message, cert, dss)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		self.inner.verify_tls13_signature(message, cert, dss)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.inner.supported_verify_schemes()
+	}
+}
+
+/// Creates an HTTPS client like [`client`], that only trusts servers presenting a pinned key.
+///
+/// Certificates are still verified against the webpki roots and the request's hostname.
+/// On top of that, the end-entity certificate or one of the intermediates must carry a
+/// public key in `pins`, otherwise the handshake fails with a [`PinMismatch`] error. This
+/// keeps a compromised certificate authority from intercepting the enclave's outbound
+/// calls. Pin more than one key, such as the next key of a planned rotation, or the
+/// intermediate CA's key, so that a renewal doesn't lock the enclave out.
+///
+/// # Panics
+///
+/// Panics if `pins` is empty, since no server could ever be trusted.
+#[must_use]
+pub fn client_pinned(vsock_proxy_port: u32, pins: Vec<CertificatePin>) -> HttpClient {
+	assert!(!pins.is_empty(), "a pinned client needs at least one pin");
+
+	let inner = WebPkiServerVerifier::builder_with_provider(
+		Arc::new(webpki_roots()),
+		Arc::new(ring::default_provider()),
+	)
+	.build()
+	.expect("the webpki roots are valid trust anchors");
+	let verifier = Arc::new(PinnedVerifier { inner, pins });
+
+	http2_client(
+		vsock_proxy_with_verifier(proxy(vsock_proxy_port), verifier),
+		PoolConfig::default(),
+	)
 }
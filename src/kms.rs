@@ -1,18 +1,53 @@
-use aws_sdk_kms::config::SharedCredentialsProvider;
-use aws_smithy_http_client::hyper_014::HyperClientBuilder;
+use std::{
+	collections::HashMap,
+	error::Error as StdError,
+	fmt,
+	future::Future,
+	io,
+	pin::Pin,
+	sync::{Arc, Mutex, PoisonError},
+	time::{Duration, SystemTime},
+};
+
+use aws_credential_types::provider::{
+	ProvideCredentials, error::CredentialsError, future::ProvideCredentials as Provided,
+};
+use aws_sdk_kms::{
+	config::SharedCredentialsProvider,
+	error::SdkError,
+	operation::decrypt::DecryptError as KmsDecryptError,
+	primitives::Blob,
+	types::{KeyEncryptionMechanism, RecipientInfo},
+};
+use aws_smithy_runtime_api::client::{
+	http::{
+		HttpClient, HttpConnector, HttpConnectorFuture, HttpConnectorSettings, SharedHttpConnector,
+	},
+	orchestrator::{HttpRequest, HttpResponse},
+	result::ConnectorError,
+	runtime_components::RuntimeComponents,
+};
+use aws_smithy_types::body::SdkBody;
 use aws_types::SdkConfig;
+use hyper_rustls::HttpsConnector;
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use rustls::RootCertStore;
 use tokio_vsock::VsockAddr;
 
-use crate::utils::http::vsock_proxy;
+use crate::{
+	transport::{PoolConfig, VsockConnector},
+	utils::http::{vsock_proxy_with_roots, webpki_roots},
+};
 
-/// The CID of the vsock proxy.
-pub const VSOCK_PROXY_CID: u32 = 3;
+/// The CID of the vsock proxy, which runs on the parent instance.
+pub const VSOCK_PROXY_CID: u32 = crate::addr::PARENT_CID;
 
 /// Credentials to use for KMS requests.
 pub struct Credentials {
 	access_key_id: String,
 	secret_access_key: String,
 	session_token: Option<String>,
+	expiry: Option<SystemTime>,
 }
 
 impl Credentials {
@@ -26,33 +61,325 @@ impl Credentials {
 			session_token,
 			access_key_id: access_key_id.into(),
 			secret_access_key: secret_access_key.into(),
+			expiry: None,
 		}
 	}
+
+	/// Sets when the credentials expire, as with temporary credentials issued by STS.
+	///
+	/// [`RefreshingCredentials`] fetches new credentials shortly before this point.
+	#[must_use]
+	pub const fn with_expiry(mut self, expiry: SystemTime) -> Self {
+		self.expiry = Some(expiry);
+		self
+	}
+}
+
+impl From<Credentials> for aws_sdk_kms::config::Credentials {
+	fn from(credentials: Credentials) -> Self {
+		Self::new(
+			credentials.access_key_id,
+			credentials.secret_access_key,
+			credentials.session_token,
+			credentials.expiry,
+			"SDK",
+		)
+	}
+}
+
+impl From<Credentials> for SharedCredentialsProvider {
+	fn from(credentials: Credentials) -> Self {
+		Self::new(aws_sdk_kms::config::Credentials::from(credentials))
+	}
+}
+
+type CredentialsFuture =
+	Pin<Box<dyn Future<Output = Result<Credentials, Box<dyn StdError + Send + Sync>>> + Send>>;
+
+/// Credentials that are fetched again whenever they are about to expire.
+///
+/// Enclaves usually run with temporary credentials, which the AWS SDK can't fetch from
+/// inside the enclave and which expire after a few hours at most. This wraps a function
+/// fetching fresh credentials, typically from a broker on the parent instance, so that a
+/// KMS client built with it keeps working past the lifetime of any single set. The SDK
+/// caches the credentials it gets, and only calls the function again once they are close to
+/// their [expiry](Credentials::with_expiry), or after a while if they have none.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let credentials = RefreshingCredentials::new(move || async move {
+///     let issued: IssuedCredentials = pontifex::send(broker, &GetCredentials).await?;
+///
+///     Ok::<_, pontifex::client::Error>(
+///         Credentials::new(issued.access_key_id, issued.secret_access_key, issued.session_token)
+///             .with_expiry(issued.expiry),
+///     )
+/// });
+///
+/// let kms = kms::client(&config, credentials, proxy_port);
+/// ```
+#[derive(Clone)]
+pub struct RefreshingCredentials {
+	provider: Arc<dyn Fn() -> CredentialsFuture + Send + Sync>,
+}
+
+impl RefreshingCredentials {
+	/// Fetch credentials by calling `provider` whenever the current ones expire.
+	pub fn new<F, Fut, E>(provider: F) -> Self
+	where
+		F: Fn() -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = Result<Credentials, E>> + Send + 'static,
+		E: Into<Box<dyn StdError + Send + Sync>>,
+	{
+		Self {
+			provider: Arc::new(move || {
+				let fetch = provider();
+				Box::pin(async move { fetch.await.map_err(Into::into) })
+			}),
+		}
+	}
+}
+
+impl fmt::Debug for RefreshingCredentials {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("RefreshingCredentials")
+			.finish_non_exhaustive()
+	}
+}
+
+impl ProvideCredentials for RefreshingCredentials {
+	fn provide_credentials<'a>(&'a self) -> Provided<'a>
+	where
+		Self: 'a,
+	{
+		Provided::new(async move {
+			let credentials = (self.provider)().await.map_err(|e| {
+				tracing::warn!(error = %e, "failed to refresh KMS credentials");
+				CredentialsError::provider_error(e)
+			})?;
+
+			Ok(credentials.into())
+		})
+	}
+}
+
+impl From<RefreshingCredentials> for SharedCredentialsProvider {
+	fn from(credentials: RefreshingCredentials) -> Self {
+		Self::new(credentials)
+	}
 }
 
 /// Creates a new KMS client.
+///
+/// `credentials` is either a static set of [`Credentials`], or [`RefreshingCredentials`]
+/// for enclaves that outlive them.
 #[must_use]
 pub fn client(
 	config: &SdkConfig,
-	credentials: Credentials,
+	credentials: impl Into<SharedCredentialsProvider>,
+	vsock_proxy_port: u32,
+) -> aws_sdk_kms::Client {
+	client_with_roots(config, credentials, vsock_proxy_port, webpki_roots())
+}
+
+/// Creates a new KMS client that trusts the certificate authorities in `roots` instead of
+/// the webpki root store, for KMS endpoints behind a private certificate authority.
+#[must_use]
+pub fn client_with_roots(
+	config: &SdkConfig,
+	credentials: impl Into<SharedCredentialsProvider>,
 	vsock_proxy_port: u32,
+	roots: RootCertStore,
 ) -> aws_sdk_kms::Client {
+	build_client(
+		config,
+		credentials,
+		vsock_proxy_port,
+		roots,
+		PoolConfig::default(),
+	)
+}
+
+/// Creates a new KMS client like [`client`], that keeps idle connections for reuse according
+/// to `pool`.
+///
+/// Every KMS call reusing an idle connection saves dialing the vsock proxy and a TLS
+/// handshake with KMS, which adds up for enclaves making many calls. The other clients of
+/// this module use [`PoolConfig::default`].
+#[must_use]
+pub fn client_with_pool(
+	config: &SdkConfig,
+	credentials: impl Into<SharedCredentialsProvider>,
+	vsock_proxy_port: u32,
+	pool: PoolConfig,
+) -> aws_sdk_kms::Client {
+	build_client(config, credentials, vsock_proxy_port, webpki_roots(), pool)
+}
+
+fn build_client(
+	config: &SdkConfig,
+	credentials: impl Into<SharedCredentialsProvider>,
+	vsock_proxy_port: u32,
+	roots: RootCertStore,
+	pool: PoolConfig,
+) -> aws_sdk_kms::Client {
+	let http_client = VsockHttpClient {
+		proxy: VsockConnector::new(VsockAddr::new(VSOCK_PROXY_CID, vsock_proxy_port)),
+		roots,
+		pool,
+		connectors: Mutex::default(),
+	};
+
 	let builder = config
 		.to_builder()
-		.credentials_provider(SharedCredentialsProvider::new(
-			aws_sdk_kms::config::Credentials::new(
-				credentials.access_key_id,
-				credentials.secret_access_key,
-				credentials.session_token,
-				None,
-				"SDK",
-			),
-		))
-		.http_client(HyperClientBuilder::new().build(vsock_proxy(VsockAddr::new(
-			VSOCK_PROXY_CID,
-			vsock_proxy_port,
-		))))
+		.credentials_provider(credentials.into())
+		.http_client(http_client)
 		.build();
 
 	aws_sdk_kms::Client::new(&builder)
 }
+
+/// Sends the SDK's requests with a hyper client, over TLS through the vsock proxy.
+///
+/// The SDK asks for a connector for each set of connect and read timeouts it is configured
+/// with, so a hyper client is kept for each of them, and with it a pool of connections.
+#[derive(Debug)]
+struct VsockHttpClient {
+	proxy: VsockConnector,
+	roots: RootCertStore,
+	pool: PoolConfig,
+	connectors: Mutex<HashMap<Timeouts, SharedHttpConnector>>,
+}
+
+/// The connect and read timeouts of a connector.
+type Timeouts = (Option<Duration>, Option<Duration>);
+
+impl HttpClient for VsockHttpClient {
+	fn http_connector(
+		&self,
+		settings: &HttpConnectorSettings,
+		_: &RuntimeComponents,
+	) -> SharedHttpConnector {
+		let timeouts = (settings.connect_timeout(), settings.read_timeout());
+		let mut connectors = self
+			.connectors
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+
+		connectors
+			.entry(timeouts)
+			.or_insert_with(|| {
+				let mut proxy = self.proxy;
+				if let Some(timeout) = settings.connect_timeout() {
+					proxy = proxy.with_connect_timeout(timeout);
+				}
+
+				let client = self
+					.pool
+					.apply(&mut Client::builder(TokioExecutor::new()))
+					.build(vsock_proxy_with_roots(proxy, self.roots.clone()));
+
+				SharedHttpConnector::new(VsockHttpConnector {
+					client,
+					read_timeout: settings.read_timeout(),
+				})
+			})
+			.clone()
+	}
+}
+
+/// Sends the SDK's requests with a hyper client, failing those that get no response within
+/// the read timeout.
+#[derive(Debug)]
+struct VsockHttpConnector {
+	client: Client<HttpsConnector<VsockConnector>, SdkBody>,
+	read_timeout: Option<Duration>,
+}
+
+impl HttpConnector for VsockHttpConnector {
+	fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+		let request = match request.try_into_http1x() {
+			Ok(request) => request,
+			Err(e) => return HttpConnectorFuture::ready(Err(ConnectorError::user(e.into()))),
+		};
+
+		let response = self.client.request(request);
+		let read_timeout = self.read_timeout;
+
+		HttpConnectorFuture::new(async move {
+			let response = match read_timeout {
+				Some(timeout) => tokio::time::timeout(timeout, response)
+					.await
+					.map_err(|e| ConnectorError::timeout(e.into()))?,
+				None => response.await,
+			}
+			.map_err(connector_error)?;
+
+			HttpResponse::try_from(response.map(SdkBody::from_body_1_x))
+				.map_err(|e| ConnectorError::other(e.into(), None))
+		})
+	}
+}
+
+/// Classify a hyper error, so that the SDK retries those caused by the connection.
+fn connector_error(error: hyper_util::client::legacy::Error) -> ConnectorError {
+	let io = std::iter::successors(Some(&error as &dyn StdError), |e| (*e).source())
+		.any(<dyn StdError>::is::<io::Error>);
+
+	if error.is_connect() || io {
+		ConnectorError::io(error.into())
+	} else {
+		ConnectorError::other(error.into(), None)
+	}
+}
+
+/// Errors that can occur when decrypting for an enclave.
+#[derive(Debug, thiserror::Error)]
+pub enum DecryptError {
+	/// KMS refused or failed the decrypt request.
+	#[error("KMS decrypt failed")]
+	Kms(#[source] SdkError<KmsDecryptError>),
+	/// KMS answered without ciphertext for the enclave, which happens when the request
+	/// wasn't recognized as coming from an enclave.
+	#[error("KMS returned no ciphertext for the recipient")]
+	MissingCiphertext,
+}
+
+/// Decrypts `ciphertext` with KMS so that only the enclave that produced `attestation_doc`
+/// can read the plaintext.
+///
+/// The attestation document is sent as the `Recipient` of the request, and must bind an
+/// RSA public key held by the enclave (see `SecureModule::attest`). Instead of the plaintext,
+/// KMS then returns it encrypted to that key with RSAES-OAEP-SHA-256, wrapped in a CMS
+/// `EnvelopedData` structure, which is what this returns. Key policies can match the
+/// measurements in the document with the `kms:RecipientAttestation` condition keys.
+///
+/// # Errors
+///
+/// - `DecryptError::Kms`: KMS failed the request, for example because the key policy
+///   doesn't allow this enclave
+/// - `DecryptError::MissingCiphertext`: KMS didn't encrypt the plaintext for the enclave
+pub async fn decrypt_for_enclave(
+	client: &aws_sdk_kms::Client,
+	ciphertext: impl Into<Vec<u8>>,
+	attestation_doc: impl Into<Vec<u8>>,
+) -> Result<Vec<u8>, DecryptError> {
+	let recipient = RecipientInfo::builder()
+		.key_encryption_algorithm(KeyEncryptionMechanism::RsaesOaepSha256)
+		.attestation_document(Blob::new(attestation_doc))
+		.build();
+
+	let output = client
+		.decrypt()
+		.ciphertext_blob(Blob::new(ciphertext))
+		.recipient(recipient)
+		.send()
+		.await
+		.map_err(DecryptError::Kms)?;
+
+	output
+		.ciphertext_for_recipient
+		.map(Blob::into_inner)
+		.ok_or(DecryptError::MissingCiphertext)
+}
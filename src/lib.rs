@@ -7,9 +7,35 @@
 )]
 #![doc = include_str!("../README.md")]
 
-use const_fnv1a_hash::fnv1a_hash_str_32;
 use serde::{Serialize, de::DeserializeOwned};
 
+/// The numeric ID requests are routed by, derived from their route ID.
+///
+/// Type IDs are 32-bit FNV-1a hashes of route IDs, which start colliding in earnest once a
+/// service has tens of thousands of routes. With the `wide-ids` feature, they are 64-bit
+/// hashes instead, at the cost of 4 more bytes per request. Peers with and without the
+/// feature announce different protocol versions, so they refuse to talk to each other
+/// rather than misread each other's requests.
+#[cfg(not(feature = "wide-ids"))]
+pub type TypeId = u32;
+/// The numeric ID requests are routed by, derived from their route ID.
+///
+/// These are 64-bit FNV-1a hashes of route IDs, as the `wide-ids` feature is enabled.
+#[cfg(feature = "wide-ids")]
+pub type TypeId = u64;
+
+/// Hash a route ID into the type ID it is routed by, as [`Request::TYPE_ID`] does.
+///
+/// This is a `const fn`, for dispatchers written by hand that route IDs known at compile
+/// time, such as routes other services define without sharing their request types.
+#[must_use]
+pub const fn route_type_id(route_id: &str) -> TypeId {
+	#[cfg(not(feature = "wide-ids"))]
+	return const_fnv1a_hash::fnv1a_hash_str_32(route_id);
+	#[cfg(feature = "wide-ids")]
+	return const_fnv1a_hash::fnv1a_hash_str_64(route_id);
+}
+
 /// Type-safe request-response pairing for client-server communication.
 ///
 /// This trait links each request type to its corresponding response type at compile time,
@@ -53,44 +79,132 @@ pub trait Request: Serialize + DeserializeOwned + Send + Sync + 'static {
 	/// This creates a compile-time guarantee that requests and responses match.
 	type Response: Serialize + DeserializeOwned + Send;
 
-	/// Computes a numeric ID from `ROUTE_ID` for efficient routing.
+	/// The numeric ID computed from `ROUTE_ID` for efficient routing.
 	///
 	/// This is used internally by the router to quickly dispatch requests.
 	/// The hash function (FNV-1a) is deterministic, so the same `ROUTE_ID`
-	/// always produces the same numeric ID.
+	/// always produces the same numeric ID. Being a constant, it can be matched on:
+	///
+	/// ```rust,ignore
+	/// match type_id {
+	///     HealthCheck::TYPE_ID => handle_health(payload),
+	///     GetUser::TYPE_ID => handle_get_user(payload),
+	///     _ => Err(Unknown(type_id)),
+	/// }
+	/// ```
+	// FNV-1a is a fast, simple hash that's deterministic across runs
+	const TYPE_ID: TypeId = route_type_id(Self::ROUTE_ID);
+
+	/// Computes a numeric ID from `ROUTE_ID` for efficient routing, see [`Request::TYPE_ID`].
 	#[must_use]
-	fn type_id() -> u32 {
-		// FNV-1a is a fast, simple hash that's deterministic across runs
-		fnv1a_hash_str_32(Self::ROUTE_ID)
+	fn type_id() -> TypeId {
+		Self::TYPE_ID
 	}
 }
 
+/// A request answered with a stream of items rather than a single response.
+///
+/// Works like [`Request`], for requests that naturally produce many results, such as tailing
+/// logs or listing keys page by page. The server sends each item as soon as its handler
+/// produces it, and the client reads them one by one, so the whole result never has to fit
+/// in a single response.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// #[derive(Serialize, Deserialize)]
+/// struct TailLogs { since: u64 }
+///
+/// impl StreamingRequest for TailLogs {
+///     const ROUTE_ID: &'static str = "tail_logs_v1";
+///     type Item = LogLine;
+/// }
+/// ```
+pub trait StreamingRequest: Serialize + DeserializeOwned + Send + Sync + 'static {
+	/// Unique string identifier for this request type.
+	///
+	/// Shares its namespace with [`Request::ROUTE_ID`], and must never change after
+	/// deployment either.
+	const ROUTE_ID: &'static str;
+
+	/// The type of the items streamed back in response.
+	type Item: Serialize + DeserializeOwned + Send;
+
+	/// The numeric ID computed from `ROUTE_ID` for efficient routing, like [`Request::TYPE_ID`].
+	const TYPE_ID: TypeId = route_type_id(Self::ROUTE_ID);
+
+	/// Computes a numeric ID from `ROUTE_ID` for efficient routing, like [`Request::type_id`].
+	#[must_use]
+	fn type_id() -> TypeId {
+		Self::TYPE_ID
+	}
+}
+
+/// Derive [`Request`] from a `#[pontifex(route = "...", response = ...)]` attribute.
+#[cfg(feature = "derive")]
+pub use pontifex_derive::Request;
+
+/// Parsing and validation of vsock addresses.
+pub mod addr;
+
+/// The requests answered by routes built into pontifex, such as health checks.
+pub mod builtin;
+
 /// Client-side functionality.
 #[cfg(feature = "client")]
 pub mod client;
 #[cfg(feature = "client")]
-pub use client::{ConnectionDetails, send};
+pub use client::{
+	Connection, ConnectionDetails, EnclaveClient, MultiplexedConnection, send, send_stream,
+};
 
 /// Server-side functionality.
 #[cfg(feature = "server")]
 pub mod server;
 #[cfg(feature = "server")]
-pub use server::Router;
+pub use server::{DispatchModel, Router};
 
 /// Enables low-level interfacing with the Nitro Secure Module (NSM).
 #[cfg(any(feature = "nsm", feature = "nsm-types"))]
 pub mod nsm;
-#[cfg(feature = "nsm")]
-pub use nsm::SecureModule;
+#[cfg(feature = "nsm-mock")]
+pub use nsm::MockSecureModule;
 #[cfg(feature = "nsm-types")]
-pub use nsm::{AttestationDoc, AttestationError};
+pub use nsm::{
+	AttestationDoc, AttestationDocExt, AttestationError, CoseAlgorithm, CoseHeaders, NsmError,
+	NsmOperation, PcrSet,
+};
+#[cfg(feature = "nsm")]
+pub use nsm::{Attester, Freshness, NsmRng, PcrDescription, SecureModule};
+
+/// Verification of attestation documents produced by the NSM.
+#[cfg(feature = "verify")]
+pub mod attestation;
+#[cfg(feature = "verify")]
+pub use attestation::{VerifyError, VerifyOptions};
 
 /// KMS functionality.
 #[cfg(feature = "kms")]
 pub mod kms;
 
+/// Wire format shared by the client and the server.
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod wire;
+
+/// TLS sessions between clients and enclaves, identified by their attestation documents.
+#[cfg(feature = "tls")]
+pub mod tls;
+
+/// Hyper connectors over vsock, shared by the HTTP and KMS clients.
+#[cfg(any(feature = "http", feature = "kms"))]
+pub mod transport;
+
 /// HTTP-through-vsock
 #[cfg(feature = "http")]
 pub mod http;
 
+/// Routers served to clients in the same process, to test them without vsock.
+#[cfg(all(feature = "client", feature = "server", feature = "test-transport"))]
+pub mod testing;
+
 mod utils;
@@ -1,48 +1,653 @@
 pub use aws_nitro_enclaves_nsm_api::api::{AttestationDoc, Digest, ErrorCode, Request, Response};
+pub use rustls_pki_types::CertificateDer;
 
-#[cfg(feature = "nsm")]
+use {
+	serde::de::DeserializeOwned,
+	serde_cbor::Value,
+	std::{collections::BTreeMap, fmt::Display},
+};
+
+#[cfg(any(feature = "nsm", feature = "verify"))]
 use {
 	aws_nitro_enclaves_cose::{
-		CoseSign1,
 		crypto::{Hash, MessageDigest},
 		error::CoseError,
 	},
 	aws_nitro_enclaves_nsm_api::api::Error,
+	sha2::{Digest as _, Sha256, Sha384, Sha512},
+};
+
+#[cfg(feature = "nsm")]
+mod rng;
+
+#[cfg(feature = "nsm")]
+pub use self::rng::NsmRng;
+
+#[cfg(feature = "nsm-mock")]
+mod mock;
+
+#[cfg(feature = "nsm-mock")]
+pub use self::mock::MockSecureModule;
+
+#[cfg(feature = "nsm")]
+use {
+	aws_nitro_enclaves_cose::CoseSign1,
 	aws_nitro_enclaves_nsm_api::driver::{nsm_exit, nsm_init, nsm_process_request},
 	serde_bytes::ByteBuf,
-	sha2::{Digest as _, Sha256, Sha384, Sha512},
-	std::{io, os::fd::RawFd},
-	tokio::sync::OnceCell,
+	std::{
+		io,
+		os::fd::RawFd,
+		sync::{Mutex, OnceLock, PoisonError, RwLock},
+		time::{Duration, Instant},
+	},
 };
 
+/// The state of a platform configuration register (PCR), as described by the NSM.
+#[cfg(feature = "nsm")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcrDescription {
+	/// Whether the PCR is locked, in which case it can no longer be extended.
+	pub locked: bool,
+	/// The current value of the PCR.
+	pub value: Vec<u8>,
+}
+
 /// A global connection to the Nitro Secure Module (NSM).
 #[cfg(feature = "nsm")]
-pub(crate) static SECURE_MODULE_GLOBAL: OnceCell<SecureModule> = OnceCell::const_new();
+pub(crate) static SECURE_MODULE_GLOBAL: OnceLock<SecureModule> = OnceLock::new();
+
+/// How many running servers hold a [`GlobalLease`].
+#[cfg(all(feature = "nsm", feature = "server"))]
+static GLOBAL_LEASES: Mutex<usize> = Mutex::new(0);
+
+/// Keeps the global NSM instance connected for as long as it is held.
+///
+/// Every running server holds one, so that the global is only closed once the last server
+/// of the process stopped, rather than by whichever stops first.
+#[cfg(all(feature = "nsm", feature = "server"))]
+pub(crate) struct GlobalLease(());
+
+#[cfg(all(feature = "nsm", feature = "server"))]
+impl GlobalLease {
+	/// Initialize the global NSM instance if needed, and keep it connected until the lease
+	/// is dropped.
+	pub(crate) async fn acquire() -> io::Result<Self> {
+		SecureModule::try_init_global().await?;
+		*GLOBAL_LEASES.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+
+		Ok(Self(()))
+	}
+}
+
+#[cfg(all(feature = "nsm", feature = "server"))]
+impl Drop for GlobalLease {
+	fn drop(&mut self) {
+		let mut leases = GLOBAL_LEASES.lock().unwrap_or_else(PoisonError::into_inner);
+		*leases -= 1;
+
+		if *leases == 0 {
+			SecureModule::shutdown_global();
+		}
+	}
+}
 
 /// A connection to the Nitro Secure Module (NSM).
 #[cfg(feature = "nsm")]
 pub struct SecureModule {
-	fd: RawFd,
+	driver: Box<dyn Driver>,
+	fd: RwLock<RawFd>,
+	reconnect_grace_period: Duration,
+	last_reconnect: Mutex<Option<Instant>>,
+	last_attestation: Mutex<Option<CachedAttestation>>,
+}
+
+/// The calls into the NSM driver, behind a trait so that tests can simulate the device.
+#[cfg(feature = "nsm")]
+trait Driver: Send + Sync {
+	fn init(&self) -> RawFd;
+	fn process_request(&self, fd: RawFd, request: Request) -> Response;
+	fn exit(&self, fd: RawFd);
+}
+
+/// The NSM driver of a Nitro enclave.
+#[cfg(feature = "nsm")]
+struct NitroDriver;
+
+#[cfg(feature = "nsm")]
+impl Driver for NitroDriver {
+	fn init(&self) -> RawFd {
+		nsm_init()
+	}
+
+	fn process_request(&self, fd: RawFd, request: Request) -> Response {
+		nsm_process_request(fd, request)
+	}
+
+	fn exit(&self, fd: RawFd) {
+		nsm_exit(fd);
+	}
+}
+
+/// How old a reused attestation document is allowed to be.
+#[cfg(feature = "nsm")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+	/// Always request a new document from the NSM.
+	Always,
+	/// Reuse a document generated for the same inputs as long as it is younger than this.
+	MaxAge(Duration),
+}
+
+/// The most recent document produced by `SecureModule::attest_cached`, with the inputs it binds.
+#[cfg(feature = "nsm")]
+struct CachedAttestation {
+	user_data: Option<Vec<u8>>,
+	public_key: Option<Vec<u8>>,
+	document: Vec<u8>,
+	created_at: Instant,
+}
+
+/// An operation of the NSM, as reported by [`NsmError::Driver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NsmOperation {
+	/// Creating an attestation document.
+	Attest,
+	/// Getting random bytes.
+	GetRandom,
+	/// Describing a PCR.
+	DescribePcr,
+	/// Extending a PCR.
+	ExtendPcr,
+}
+
+impl std::fmt::Display for NsmOperation {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::Attest => "attest",
+			Self::GetRandom => "get_random",
+			Self::DescribePcr => "describe_pcr",
+			Self::ExtendPcr => "extend_pcr",
+		})
+	}
+}
+
+/// Errors that can occur when calling the NSM, whatever the operation.
+#[derive(Debug, thiserror::Error)]
+pub enum NsmError {
+	/// The NSM driver returned an error code.
+	#[error("NsmError::Driver: {operation} failed with {code:?}")]
+	Driver {
+		/// The operation that failed.
+		operation: NsmOperation,
+		/// The code the driver returned.
+		code: ErrorCode,
+	},
+	/// The NSM returned no random bytes.
+	#[error("NsmError::InsufficientEntropy")]
+	InsufficientEntropy,
+	/// More random bytes were requested at once than `SecureModule::get_random` hands out.
+	#[error("NsmError::RandomTooLarge: requested {requested} bytes, limit is {limit}")]
+	RandomTooLarge {
+		/// The number of bytes requested.
+		requested: usize,
+		/// The largest number of bytes that can be requested at once.
+		limit: usize,
+	},
+}
+
+impl NsmError {
+	/// The error code the NSM driver returned, if the error came from it.
+	#[must_use]
+	pub const fn code(&self) -> Option<&ErrorCode> {
+		match self {
+			Self::Driver { code, .. } => Some(code),
+			Self::InsufficientEntropy | Self::RandomTooLarge { .. } => None,
+		}
+	}
 }
 
 /// Errors that can occur when requesting an attestation document from the NSM.
 #[derive(Debug, thiserror::Error)]
 pub enum AttestationError {
-	/// Failed to get attestation from NSM.
-	#[error("AttestationError::Nsm: {0:?}")]
-	Nsm(ErrorCode),
+	/// The NSM failed to create an attestation document.
+	#[error("AttestationError::Nsm")]
+	Nsm(#[source] NsmError),
 	/// Failed to decode attestation document.
-	#[error("AttestationError::Encoding: {0}")]
-	Encoding(serde_cbor::error::Error),
+	#[error("AttestationError::Encoding")]
+	Encoding(#[source] serde_cbor::error::Error),
 	/// Failed to decode attestation document.
-	#[error("AttestationError::Cose: {0}")]
-	Cose(aws_nitro_enclaves_cose::error::CoseError),
+	#[error("AttestationError::Cose")]
+	Cose(#[source] aws_nitro_enclaves_cose::error::CoseError),
+	/// The `user_data` of an attestation document isn't a MessagePack encoding of the expected type.
+	#[error("AttestationError::UserData")]
+	UserData(#[source] rmp_serde::decode::Error),
+	/// The attestation document doesn't include the certificate it was signed with.
+	#[error("AttestationError::MissingCertificate")]
+	MissingCertificate,
+	/// The attestation document isn't a well-formed `COSE_Sign1` structure.
+	#[error("AttestationError::MalformedCose: {0}")]
+	MalformedCose(&'static str),
+	/// A PCR value isn't valid hexadecimal.
+	#[error("AttestationError::InvalidHex")]
+	InvalidHex,
+	/// The attestation document doesn't include a PCR that was expected.
+	#[error("AttestationError::MissingPcr: PCR{0} is missing")]
+	MissingPcr(usize),
+	/// A PCR of the attestation document doesn't hold the expected value.
+	#[error("AttestationError::PcrMismatch: PCR{index} is {actual}, expected {expected}")]
+	PcrMismatch {
+		/// The index of the PCR.
+		index: usize,
+		/// The expected value, in hexadecimal.
+		expected: String,
+		/// The value found in the document, in hexadecimal.
+		actual: String,
+	},
+	/// The attestation document's signature doesn't match, or uses an unexpected algorithm.
+	#[cfg(feature = "verify")]
+	#[error("AttestationError::InvalidSignature")]
+	InvalidSignature,
+	/// The attestation document's certificate doesn't chain up to a trusted root.
+	#[cfg(feature = "verify")]
+	#[error("AttestationError::InvalidCertChain: {0}")]
+	InvalidCertChain(&'static str),
+	/// The attestation document, or a certificate of its chain, is too old or not yet valid.
+	#[cfg(feature = "verify")]
+	#[error("AttestationError::Expired")]
+	Expired,
+	/// The attestation document doesn't bind a public key.
+	#[cfg(feature = "verify")]
+	#[error("AttestationError::MissingPublicKey")]
+	MissingPublicKey,
 }
 
-#[cfg(feature = "nsm")]
-struct Sha2Hasher;
+/// Verify an attestation document produced by a genuine Nitro enclave, and return its contents.
+///
+/// This checks the document's signature against its embedded certificate, walks that
+/// certificate's chain up to one of `roots` (normally the AWS Nitro Enclaves root
+/// certificate), and checks that the document is no older than
+/// [`DEFAULT_MAX_AGE`](crate::attestation::DEFAULT_MAX_AGE) at `now`. Use
+/// [`attestation::verify_at`](crate::attestation::verify_at) for more control, and more
+/// detailed errors.
+///
+/// # Errors
+///
+/// - `AttestationError::InvalidSignature`: The document's signature doesn't match
+/// - `AttestationError::InvalidCertChain`: The chain doesn't lead to one of `roots`
+/// - `AttestationError::Expired`: The document or one of its certificates isn't valid at `now`
+/// - Any error returned while parsing the document
+#[cfg(feature = "verify")]
+pub fn verify_attestation(
+	document: &[u8],
+	roots: &[crate::attestation::Certificate],
+	now: std::time::SystemTime,
+) -> Result<AttestationDoc, AttestationError> {
+	let options = crate::attestation::VerifyOptions::default().with_roots(roots);
+
+	crate::attestation::verify_at(document, &options, now).map_err(AttestationError::from)
+}
+
+/// Verify an attestation document and its PCRs, and return the public key it binds.
+///
+/// This is how a client learns the key of an enclave that generated a keypair and attested
+/// to it, typically an ephemeral one used to encrypt a secret back to the enclave. The key
+/// is only returned once the document is verified like [`verify_attestation`] does, and its
+/// PCRs hold the `expected` values like [`verify_pcrs`] checks: a genuine document only
+/// proves that some enclave holds the key, the PCRs prove which one.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let expected = PcrSet::new().with_hex(PcrSet::IMAGE, IMAGE_HASH)?;
+/// let key = nsm::attested_public_key(&document, &[aws_root], &expected, SystemTime::now())?;
+/// ```
+///
+/// # Errors
+///
+/// - `AttestationError::MissingPublicKey`: The document doesn't bind a public key
+/// - Any error returned by [`verify_attestation`] or [`verify_pcrs`]
+#[cfg(feature = "verify")]
+pub fn attested_public_key(
+	document: &[u8],
+	roots: &[crate::attestation::Certificate],
+	expected: &PcrSet,
+	now: std::time::SystemTime,
+) -> Result<Vec<u8>, AttestationError> {
+	let document = verify_attestation(document, roots, now)?;
+	verify_pcrs(&document, expected)?;
+
+	document
+		.public_key
+		.map(serde_bytes::ByteBuf::into_vec)
+		.ok_or(AttestationError::MissingPublicKey)
+}
+
+/// Typed access to the fields an enclave binds into its attestation documents.
+pub trait AttestationDocExt {
+	/// The public key bound into the document, if any.
+	fn public_key_bytes(&self) -> Option<&[u8]>;
+
+	/// The user data bound into the document, if any.
+	fn user_data_bytes(&self) -> Option<&[u8]>;
+
+	/// Deserialize the user data bound into the document, if any.
+	///
+	/// The user data is expected to be MessagePack, the same codec requests are encoded
+	/// with, as produced by `rmp_serde::to_vec`. Both struct layouts are accepted.
+	///
+	/// # Errors
+	///
+	/// Returns `AttestationError::UserData` if the user data isn't a valid encoding of `T`.
+	fn user_data_as<T: DeserializeOwned>(&self) -> Result<Option<T>, AttestationError>;
+}
+
+impl AttestationDocExt for AttestationDoc {
+	fn public_key_bytes(&self) -> Option<&[u8]> {
+		self.public_key.as_deref().map(Vec::as_slice)
+	}
+
+	fn user_data_bytes(&self) -> Option<&[u8]> {
+		self.user_data.as_deref().map(Vec::as_slice)
+	}
+
+	fn user_data_as<T: DeserializeOwned>(&self) -> Result<Option<T>, AttestationError> {
+		self.user_data_bytes()
+			.map(|data| rmp_serde::from_slice(data).map_err(AttestationError::UserData))
+			.transpose()
+	}
+}
+
+/// Expected values for some of the PCRs of an attestation document, see [`verify_pcrs`].
+///
+/// PCRs (platform configuration registers) are measurements of the enclave taken by the
+/// NSM, so matching them proves which enclave image produced a document. Only the indices
+/// added to the set are checked.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let expected = PcrSet::new()
+///     .with(PcrSet::IMAGE, image_hash)
+///     .with_hex(PcrSet::SIGNING_CERTIFICATE, SIGNER_HASH)?;
+///
+/// nsm::verify_pcrs(&document, &expected)?;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PcrSet {
+	pcrs: BTreeMap<usize, Vec<u8>>,
+}
+
+impl PcrSet {
+	/// PCR0, the hash of the enclave image file.
+	pub const IMAGE: usize = 0;
+	/// PCR1, the hash of the Linux kernel and bootstrap.
+	pub const KERNEL: usize = 1;
+	/// PCR2, the hash of the application.
+	pub const APPLICATION: usize = 2;
+	/// PCR3, the hash of the IAM role assigned to the parent instance.
+	pub const PARENT_ROLE: usize = 3;
+	/// PCR4, the hash of the parent instance's ID.
+	pub const PARENT_INSTANCE: usize = 4;
+	/// PCR8, the hash of the certificate the enclave image file was signed with.
+	pub const SIGNING_CERTIFICATE: usize = 8;
+
+	/// Create an empty set, which every document matches.
+	#[must_use]
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Expect the PCR at `index` to hold `value`.
+	#[must_use]
+	pub fn with(mut self, index: usize, value: impl Into<Vec<u8>>) -> Self {
+		self.pcrs.insert(index, value.into());
+		self
+	}
+
+	/// Expect the PCR at `index` to hold the value written in hexadecimal, as printed by
+	/// `nitro-cli build-enclave`.
+	///
+	/// # Errors
+	///
+	/// Returns `AttestationError::InvalidHex` if `hex` isn't an even number of hex digits.
+	pub fn with_hex(self, index: usize, hex: &str) -> Result<Self, AttestationError> {
+		let value = from_hex(hex).ok_or(AttestationError::InvalidHex)?;
+
+		Ok(self.with(index, value))
+	}
+
+	/// Iterate over the expected values, ordered by PCR index.
+	pub fn pcrs(&self) -> impl Iterator<Item = (usize, &[u8])> {
+		self.pcrs
+			.iter()
+			.map(|(index, value)| (*index, value.as_slice()))
+	}
+}
+
+/// The certificate chain embedded in an attestation document, from the signing certificate
+/// up to the root.
+///
+/// The NSM stores the signing certificate on its own and the CA bundle from the root down,
+/// so the bundle is reversed to follow the signing certificate. Certificates are returned as
+/// is, without checking that they are well-formed or chain up to each other: use
+/// `attestation::verify` for that.
+///
+/// # Errors
+///
+/// Returns `AttestationError::MissingCertificate` if the document has no signing certificate.
+pub fn certificate_chain(
+	document: &AttestationDoc,
+) -> Result<Vec<CertificateDer<'static>>, AttestationError> {
+	if document.certificate.is_empty() {
+		return Err(AttestationError::MissingCertificate);
+	}
+
+	let bundle = document.cabundle.iter().rev();
+
+	Ok(std::iter::once(&document.certificate)
+		.chain(bundle)
+		.map(|der| CertificateDer::from(der.to_vec()))
+		.collect())
+}
+
+/// Check that the PCRs of an attestation document hold the expected values.
+///
+/// PCRs missing from `expected` aren't checked. Note that this only looks at the contents of
+/// the document: verify its signature first, or a forged document could claim any PCRs.
+///
+/// # Errors
+///
+/// - `AttestationError::MissingPcr`: The document doesn't include an expected PCR
+/// - `AttestationError::PcrMismatch`: A PCR doesn't hold the expected value
+pub fn verify_pcrs(document: &AttestationDoc, expected: &PcrSet) -> Result<(), AttestationError> {
+	for (index, expected) in expected.pcrs() {
+		let actual = document
+			.pcrs
+			.get(&index)
+			.ok_or(AttestationError::MissingPcr(index))?;
+
+		if actual.as_slice() != expected {
+			return Err(AttestationError::PcrMismatch {
+				index,
+				expected: to_hex(expected),
+				actual: to_hex(actual),
+			});
+		}
+	}
+
+	Ok(())
+}
 
+/// Whether sending a request twice has the same effect as sending it once.
 #[cfg(feature = "nsm")]
+const fn is_replayable(request: &Request) -> bool {
+	matches!(
+		request,
+		Request::Attestation { .. }
+			| Request::GetRandom
+			| Request::DescribePCR { .. }
+			| Request::DescribeNSM
+	)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	use std::fmt::Write;
+
+	bytes.iter().fold(String::new(), |mut hex, byte| {
+		_ = write!(hex, "{byte:02x}");
+		hex
+	})
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+	// `from_str_radix` would also accept signs.
+	if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+		return None;
+	}
+
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+		.collect()
+}
+
+/// The CBOR tag optionally wrapping a `COSE_Sign1` structure (RFC 9052).
+const COSE_SIGN1_TAG: u64 = 18;
+
+/// The label of the algorithm header parameter (RFC 9052).
+const COSE_HEADER_ALG: i128 = 1;
+
+/// A COSE signature algorithm, as identified by the `alg` header parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoseAlgorithm {
+	/// ECDSA over P-256 with SHA-256.
+	Es256,
+	/// ECDSA over P-384 with SHA-384. This is what the Nitro Secure Module signs with.
+	Es384,
+	/// ECDSA over P-521 with SHA-512.
+	Es512,
+	/// Any other algorithm, identified by its registered COSE value.
+	Other(i128),
+}
+
+impl CoseAlgorithm {
+	/// Map a registered COSE algorithm value to a `CoseAlgorithm`.
+	#[must_use]
+	pub const fn from_id(id: i128) -> Self {
+		match id {
+			-7 => Self::Es256,
+			-35 => Self::Es384,
+			-36 => Self::Es512,
+			id => Self::Other(id),
+		}
+	}
+
+	/// The registered COSE value of this algorithm.
+	#[must_use]
+	pub const fn id(self) -> i128 {
+		match self {
+			Self::Es256 => -7,
+			Self::Es384 => -35,
+			Self::Es512 => -36,
+			Self::Other(id) => id,
+		}
+	}
+}
+
+impl Display for CoseAlgorithm {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Es256 => write!(f, "ES256"),
+			Self::Es384 => write!(f, "ES384"),
+			Self::Es512 => write!(f, "ES512"),
+			Self::Other(id) => write!(f, "COSE algorithm {id}"),
+		}
+	}
+}
+
+/// The headers of the `COSE_Sign1` structure wrapping an attestation document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoseHeaders {
+	/// The signing algorithm, taken from the protected headers.
+	///
+	/// An `alg` in the unprotected headers is deliberately ignored: it isn't covered by the
+	/// signature, so anyone could rewrite it. `None` means no protected `alg` was present.
+	pub algorithm: Option<CoseAlgorithm>,
+	/// The protected headers, which are covered by the signature.
+	pub protected: BTreeMap<Value, Value>,
+	/// The unprotected headers, which are not covered by the signature.
+	pub unprotected: BTreeMap<Value, Value>,
+}
+
+/// Decode the CBOR payload of a `COSE_Sign1` structure into an `AttestationDoc`.
+#[cfg(any(feature = "nsm", feature = "verify"))]
+pub(crate) fn decode_attestation_doc(payload: &[u8]) -> Result<AttestationDoc, AttestationError> {
+	AttestationDoc::from_binary(payload).map_err(|e| match e {
+		Error::Cbor(e) => AttestationError::Encoding(e),
+		Error::Io(_) => {
+			unreachable!("AttestationDoc::from_binary should not return an IO error")
+		},
+	})
+}
+
+/// Parse the headers of a raw attestation document, without verifying its signature.
+///
+/// Use this to check which algorithm a document claims to be signed with before trusting it;
+/// documents produced by the Nitro Secure Module are always signed with ES384.
+///
+/// # Errors
+///
+/// Returns an error if the document isn't valid CBOR or isn't a `COSE_Sign1` structure.
+pub fn parse_cose_headers(document: &[u8]) -> Result<CoseHeaders, AttestationError> {
+	let malformed = AttestationError::MalformedCose;
+
+	let value: Value = serde_cbor::from_slice(document).map_err(AttestationError::Encoding)?;
+	let value = match value {
+		Value::Tag(COSE_SIGN1_TAG, inner) => *inner,
+		value => value,
+	};
+
+	let Value::Array(items) = value else {
+		return Err(malformed("expected an array"));
+	};
+	let [protected, unprotected, _payload, _signature]: [Value; 4] = items
+		.try_into()
+		.map_err(|_| malformed("expected exactly four elements"))?;
+
+	let Value::Bytes(protected) = protected else {
+		return Err(malformed("protected headers must be a byte string"));
+	};
+	// An empty byte string stands for an empty map (RFC 9052, section 3).
+	let protected = if protected.is_empty() {
+		BTreeMap::new()
+	} else {
+		match serde_cbor::from_slice(&protected).map_err(AttestationError::Encoding)? {
+			Value::Map(headers) => headers,
+			_ => return Err(malformed("protected headers must encode a map")),
+		}
+	};
+
+	let Value::Map(unprotected) = unprotected else {
+		return Err(malformed("unprotected headers must be a map"));
+	};
+
+	let algorithm = match protected.get(&Value::Integer(COSE_HEADER_ALG)) {
+		None => None,
+		Some(Value::Integer(id)) => Some(CoseAlgorithm::from_id(*id)),
+		Some(_) => return Err(malformed("the algorithm must be an integer")),
+	};
+
+	Ok(CoseHeaders {
+		algorithm,
+		protected,
+		unprotected,
+	})
+}
+
+#[cfg(any(feature = "nsm", feature = "verify"))]
+pub(crate) struct Sha2Hasher;
+
+#[cfg(any(feature = "nsm", feature = "verify"))]
 impl Hash for Sha2Hasher {
 	fn hash(digest: MessageDigest, data: &[u8]) -> Result<Vec<u8>, CoseError> {
 		Ok(match digest {
@@ -53,6 +658,89 @@ impl Hash for Sha2Hasher {
 	}
 }
 
+/// The common operations of the Nitro Secure Module: attesting, and drawing entropy.
+///
+/// [`SecureModule`] implements it by calling the NSM, and `MockSecureModule` (behind the
+/// `nsm-mock` feature) with deterministic fixtures. Holding an `Arc<dyn Attester>` in
+/// handlers or the router state, rather than calling [`SecureModule::global`], lets the
+/// NSM be injected, and replaced by the mock to test them off an enclave.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let attester: Arc<dyn Attester> = Arc::new(SecureModule::connect()?);
+/// let router = Router::with_state(attester)
+///     .route::<Attest, _, _>(|attester, req| async move {
+///         attester.raw_attest(None, Some(&req.nonce), None)
+///     });
+/// ```
+#[cfg(feature = "nsm")]
+pub trait Attester: Send + Sync {
+	/// Create an attestation document, and return it as a binary blob.
+	///
+	/// The inputs are borrowed, and only copied into the request sent to the NSM.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the NSM returns an error.
+	fn raw_attest(
+		&self,
+		user_data: Option<&[u8]>,
+		nonce: Option<&[u8]>,
+		public_key: Option<&[u8]>,
+	) -> Result<Vec<u8>, AttestationError>;
+
+	/// Create an attestation document, and parse it into an `AttestationDoc`.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the NSM returns an error or if the document cannot be decoded.
+	fn attest(
+		&self,
+		user_data: Option<&[u8]>,
+		nonce: Option<&[u8]>,
+		public_key: Option<&[u8]>,
+	) -> Result<AttestationDoc, AttestationError> {
+		let document = self.raw_attest(user_data, nonce, public_key)?;
+
+		SecureModule::parse_raw_attestation_doc(&document)
+	}
+
+	/// Get exactly `len` random bytes.
+	///
+	/// # Errors
+	///
+	/// Returns an error if `len` is too large, or if the NSM fails to provide entropy.
+	fn get_random(&self, len: usize) -> Result<Vec<u8>, NsmError>;
+
+	/// Describe the PCR at `index`: whether it is locked, and its current value.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the NSM returns an error, such as for an invalid index.
+	fn describe_pcr(&self, index: u16) -> Result<PcrDescription, NsmError>;
+}
+
+#[cfg(feature = "nsm")]
+impl Attester for SecureModule {
+	fn raw_attest(
+		&self,
+		user_data: Option<&[u8]>,
+		nonce: Option<&[u8]>,
+		public_key: Option<&[u8]>,
+	) -> Result<Vec<u8>, AttestationError> {
+		Self::raw_attest(self, user_data, nonce, public_key)
+	}
+
+	fn get_random(&self, len: usize) -> Result<Vec<u8>, NsmError> {
+		Self::get_random(self, len)
+	}
+
+	fn describe_pcr(&self, index: u16) -> Result<PcrDescription, NsmError> {
+		Self::describe_pcr(self, index)
+	}
+}
+
 #[cfg(feature = "nsm")]
 impl SecureModule {
 	/// Connect to the NSM driver.
@@ -61,7 +749,11 @@ impl SecureModule {
 	///
 	/// Returns an error if a connection to the NSM driver cannot be established.
 	pub fn connect() -> io::Result<Self> {
-		let fd = nsm_init();
+		Self::connect_with(Box::new(NitroDriver))
+	}
+
+	fn connect_with(driver: Box<dyn Driver>) -> io::Result<Self> {
+		let fd = driver.init();
 
 		if fd == -1 {
 			return Err(io::Error::new(
@@ -70,25 +762,262 @@ impl SecureModule {
 			));
 		}
 
-		Ok(Self { fd })
+		Ok(Self {
+			driver,
+			fd: RwLock::new(fd),
+			reconnect_grace_period: Self::DEFAULT_RECONNECT_GRACE_PERIOD,
+			last_reconnect: Mutex::new(None),
+			last_attestation: Mutex::new(None),
+		})
+	}
+
+	/// How long `send` waits after reconnecting before it reconnects again, by default.
+	pub const DEFAULT_RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+	/// Set how long `send` waits after reconnecting to the NSM before it reconnects again.
+	///
+	/// A driver that keeps failing right after a reconnect isn't going to be fixed by
+	/// another one, so within this period failed requests are returned as is.
+	#[must_use]
+	pub const fn with_reconnect_grace_period(mut self, grace_period: Duration) -> Self {
+		self.reconnect_grace_period = grace_period;
+		self
 	}
 
 	/// Send a request to the NSM driver.
+	///
+	/// If the driver fails with `ErrorCode::InternalError`, which is what a broken file
+	/// descriptor results in, the connection is re-established once. Requests that only read
+	/// from the NSM (attestations, random bytes and descriptions) are then sent again, while
+	/// those changing PCRs fail with the original error: the driver may have applied them
+	/// before failing, and extending or locking a PCR twice isn't the same as doing it once.
+	/// No reconnect is attempted within the grace period of the previous one, see
+	/// [`SecureModule::with_reconnect_grace_period`].
 	#[must_use]
 	pub fn send(&self, request: Request) -> Response {
-		nsm_process_request(self.fd, request)
+		// `Request` isn't `Clone`, so keep an encoded copy to replay it after reconnecting.
+		let replay = is_replayable(&request)
+			.then(|| serde_cbor::to_vec(&request).ok())
+			.flatten();
+
+		let (fd, response) = self.process(request);
+		if !matches!(response, Response::Error(ErrorCode::InternalError)) {
+			return response;
+		}
+
+		// Requests that can't be replayed still reconnect, for the next one to succeed.
+		if !self.recover(fd) {
+			return response;
+		}
+
+		replay
+			.and_then(|bytes| serde_cbor::from_slice(&bytes).ok())
+			.map_or(response, |replay| self.process(replay).1)
+	}
+
+	/// Send a request on the current connection, returning the descriptor it was sent on.
+	fn process(&self, request: Request) -> (RawFd, Response) {
+		// Holding the read lock keeps the descriptor from being closed by a reconnect mid-request.
+		let fd = self.fd.read().unwrap_or_else(PoisonError::into_inner);
+
+		// A closed connection fails like a broken one, so that `send` reconnects.
+		if *fd == -1 {
+			return (*fd, Response::Error(ErrorCode::InternalError));
+		}
+
+		(*fd, self.driver.process_request(*fd, request))
+	}
+
+	/// Try to get a working connection after a request on `failed_fd` broke, returning
+	/// whether the request is worth sending again.
+	#[allow(
+		clippy::significant_drop_tightening,
+		reason = "holding the lock while reconnecting keeps concurrent failures from reconnecting twice"
+	)]
+	fn recover(&self, failed_fd: RawFd) -> bool {
+		let mut last_reconnect = self
+			.last_reconnect
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+
+		// Another request broke at the same time, and already reconnected.
+		if *self.fd.read().unwrap_or_else(PoisonError::into_inner) != failed_fd {
+			return true;
+		}
+
+		if last_reconnect.is_some_and(|at| at.elapsed() < self.reconnect_grace_period) {
+			tracing::warn!("NSM request failed, but reconnected too recently to try again");
+			return false;
+		}
+
+		*last_reconnect = Some(Instant::now());
+		tracing::warn!("NSM request failed, reconnecting");
+
+		match self.reconnect() {
+			Ok(()) => true,
+			Err(e) => {
+				tracing::error!(
+					error = &e as &dyn std::error::Error,
+					"Failed to reconnect to NSM"
+				);
+				false
+			},
+		}
+	}
+
+	/// Close the connection to the NSM driver and open a new one.
+	///
+	/// `send` does this on its own when the driver breaks, this is for manual recovery.
+	///
+	/// # Errors
+	///
+	/// Returns an error if a new connection to the NSM driver cannot be established.
+	pub fn reconnect(&self) -> io::Result<()> {
+		let mut fd = self.fd.write().unwrap_or_else(PoisonError::into_inner);
+
+		if *fd != -1 {
+			self.driver.exit(*fd);
+		}
+		*fd = self.driver.init();
+		let failed = *fd == -1;
+		drop(fd);
+
+		if failed {
+			return Err(io::Error::new(
+				io::ErrorKind::ConnectionRefused,
+				"Failed to initialize NSM",
+			));
+		}
+
+		Ok(())
+	}
+
+	/// Close the connection to the NSM driver, releasing its file descriptor.
+	///
+	/// The module stays usable: the next request reconnects, like after the driver broke.
+	pub fn close(&self) {
+		let mut fd = self.fd.write().unwrap_or_else(PoisonError::into_inner);
+
+		if *fd != -1 {
+			self.driver.exit(*fd);
+			*fd = -1;
+		}
+	}
+
+	/// The largest number of bytes [`SecureModule::get_random`] hands out in one call.
+	pub const MAX_RANDOM_BYTES: usize = 64 * 1024;
+
+	/// How many calls in a row may return no random bytes before `get_random` gives up.
+	const RANDOM_ATTEMPTS: usize = 3;
+
+	/// Get exactly `len` random bytes from the NSM, the enclave's trusted entropy source.
+	///
+	/// The NSM caps how many bytes it returns per call, so this calls it as many times as
+	/// needed. Calls returning no bytes are retried a few times before giving up. This is a
+	/// blocking call: in async code, run it in `spawn_blocking`, or use [`NsmRng`] to draw
+	/// from the `rand` ecosystem.
+	///
+	/// # Errors
+	///
+	/// Returns `NsmError::RandomTooLarge` if `len` exceeds [`Self::MAX_RANDOM_BYTES`],
+	/// `NsmError::Driver` if the NSM driver returns an error, and
+	/// `NsmError::InsufficientEntropy` if it keeps returning no bytes.
+	pub fn get_random(&self, len: usize) -> Result<Vec<u8>, NsmError> {
+		if len > Self::MAX_RANDOM_BYTES {
+			return Err(NsmError::RandomTooLarge {
+				requested: len,
+				limit: Self::MAX_RANDOM_BYTES,
+			});
+		}
+
+		let mut random = Vec::with_capacity(len);
+		let mut empty_calls = 0;
+
+		while random.len() < len {
+			match self.random_chunk() {
+				Ok(chunk) => {
+					empty_calls = 0;
+					let missing = len - random.len();
+					random.extend_from_slice(&chunk[..chunk.len().min(missing)]);
+				},
+				Err(NsmError::InsufficientEntropy) if empty_calls + 1 < Self::RANDOM_ATTEMPTS => {
+					empty_calls += 1;
+				},
+				Err(error) => return Err(error),
+			}
+		}
+
+		Ok(random)
+	}
+
+	/// Get a single batch of random bytes from the NSM, as many as it returns in one call.
+	pub(crate) fn random_chunk(&self) -> Result<Vec<u8>, NsmError> {
+		match self.send(Request::GetRandom) {
+			Response::Error(code) => Err(NsmError::Driver {
+				operation: NsmOperation::GetRandom,
+				code,
+			}),
+			Response::GetRandom { random } if random.is_empty() => {
+				Err(NsmError::InsufficientEntropy)
+			},
+			Response::GetRandom { random } => Ok(random),
+			_ => unreachable!("Unexpected response type"),
+		}
+	}
+
+	/// Describe the PCR at `index`: whether it is locked, and its current value.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the NSM driver returns an error, such as for an invalid index.
+	pub fn describe_pcr(&self, index: u16) -> Result<PcrDescription, NsmError> {
+		match self.send(Request::DescribePCR { index }) {
+			Response::Error(code) => Err(NsmError::Driver {
+				operation: NsmOperation::DescribePcr,
+				code,
+			}),
+			Response::DescribePCR { lock, data } => Ok(PcrDescription {
+				locked: lock,
+				value: data,
+			}),
+			_ => unreachable!("Unexpected response type"),
+		}
+	}
+
+	/// Extend the PCR at `index` with `data`, and return its new value.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the NSM driver returns an error, such as for a locked PCR.
+	pub fn extend_pcr(&self, index: u16, data: &[u8]) -> Result<Vec<u8>, NsmError> {
+		let request = Request::ExtendPCR {
+			index,
+			data: data.to_vec(),
+		};
+
+		match self.send(request) {
+			Response::Error(code) => Err(NsmError::Driver {
+				operation: NsmOperation::ExtendPcr,
+				code,
+			}),
+			Response::ExtendPCR { data } => Ok(data),
+			_ => unreachable!("Unexpected response type"),
+		}
 	}
 
 	/// Create an attestation document, and return it as a binary blob.
 	///
+	/// The inputs are borrowed, and only copied into the request sent to the NSM. Unused
+	/// ones are plain `None`s, as in `raw_attest(None, Some(&nonce), None)`.
+	///
 	/// # Errors
 	///
 	/// Returns an error if the NSM driver returns an error.
 	pub fn raw_attest(
 		&self,
-		user_data: Option<impl Into<Vec<u8>>>,
-		nonce: Option<impl Into<Vec<u8>>>,
-		public_key: Option<impl Into<Vec<u8>>>,
+		user_data: Option<&[u8]>,
+		nonce: Option<&[u8]>,
+		public_key: Option<&[u8]>,
 	) -> Result<Vec<u8>, AttestationError> {
 		let response = self.send(Request::Attestation {
 			nonce: nonce.map(ByteBuf::from),
@@ -97,12 +1026,61 @@ impl SecureModule {
 		});
 
 		match response {
-			Response::Error(code) => Err(AttestationError::Nsm(code)),
+			Response::Error(code) => Err(AttestationError::Nsm(NsmError::Driver {
+				operation: NsmOperation::Attest,
+				code,
+			})),
 			Response::Attestation { document } => Ok(document),
 			_ => unreachable!("Unexpected response type"),
 		}
 	}
 
+	/// Create an attestation document as a binary blob, reusing the previous one if it is fresh enough.
+	///
+	/// Only the last generated document is remembered. It is reused when it was produced for
+	/// the same `user_data` and `public_key` and its age is within the given `freshness`
+	/// policy; otherwise a new document is requested from the NSM and replaces it. The
+	/// documents never carry a nonce, since a cached one couldn't prove freshness: use
+	/// [`SecureModule::raw_attest`] when the receiver sends one.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the NSM driver returns an error.
+	#[allow(
+		clippy::significant_drop_tightening,
+		reason = "holding the lock while attesting lets concurrent callers share one document"
+	)]
+	pub fn attest_cached(
+		&self,
+		user_data: Option<&[u8]>,
+		public_key: Option<&[u8]>,
+		freshness: Freshness,
+	) -> Result<Vec<u8>, AttestationError> {
+		let mut last_attestation = self
+			.last_attestation
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+
+		if let (Freshness::MaxAge(max_age), Some(cached)) = (freshness, last_attestation.as_ref())
+			&& cached.user_data.as_deref() == user_data
+			&& cached.public_key.as_deref() == public_key
+			&& cached.created_at.elapsed() <= max_age
+		{
+			return Ok(cached.document.clone());
+		}
+
+		let document = self.raw_attest(user_data, None, public_key)?;
+
+		*last_attestation = Some(CachedAttestation {
+			user_data: user_data.map(<[u8]>::to_vec),
+			public_key: public_key.map(<[u8]>::to_vec),
+			document: document.clone(),
+			created_at: Instant::now(),
+		});
+
+		Ok(document)
+	}
+
 	/// Create an `AttestationDoc` and sign it with it's private key to ensure authenticity.
 	///
 	/// # Errors
@@ -110,9 +1088,9 @@ impl SecureModule {
 	/// Returns an error if the NSM driver returns an error or if the response cannot be decoded.
 	pub fn attest(
 		&self,
-		user_data: Option<impl Into<Vec<u8>>>,
-		nonce: Option<impl Into<Vec<u8>>>,
-		public_key: Option<impl Into<Vec<u8>>>,
+		user_data: Option<&[u8]>,
+		nonce: Option<&[u8]>,
+		public_key: Option<&[u8]>,
 	) -> Result<AttestationDoc, AttestationError> {
 		let document = self.raw_attest(user_data, nonce, public_key)?;
 		Self::parse_raw_attestation_doc(&document)
@@ -129,21 +1107,88 @@ impl SecureModule {
 			.get_payload::<Sha2Hasher>(None)
 			.map_err(AttestationError::Cose)?;
 
-		AttestationDoc::from_binary(&cbor_attestation_doc).map_err(|e| match e {
-			Error::Cbor(e) => AttestationError::Encoding(e),
-			Error::Io(_) => {
-				unreachable!("AttestationDoc::from_binary should not return an IO error")
-			},
-		})
+		decode_attestation_doc(&cbor_attestation_doc)
+	}
+
+	/// Send a request to the NSM driver without blocking the async runtime.
+	///
+	/// NSM requests are blocking ioctls, so calling [`SecureModule::send`] from async code
+	/// stalls a runtime worker for the duration of the call. This runs it on tokio's blocking
+	/// thread pool instead, which requires a `'static` connection such as the one returned
+	/// by [`SecureModule::global`].
+	///
+	/// # Panics
+	///
+	/// Panics if the runtime is shutting down.
+	pub async fn send_async(&'static self, request: Request) -> Response {
+		blocking(move || self.send(request)).await
+	}
+
+	/// Create an attestation document as a binary blob, without blocking the async runtime.
+	///
+	/// See [`SecureModule::send_async`].
+	///
+	/// # Errors
+	///
+	/// Returns an error if the NSM driver returns an error.
+	///
+	/// # Panics
+	///
+	/// Panics if the runtime is shutting down.
+	pub async fn raw_attest_async(
+		&'static self,
+		user_data: Option<&[u8]>,
+		nonce: Option<&[u8]>,
+		public_key: Option<&[u8]>,
+	) -> Result<Vec<u8>, AttestationError> {
+		let request = Request::Attestation {
+			nonce: nonce.map(ByteBuf::from),
+			user_data: user_data.map(ByteBuf::from),
+			public_key: public_key.map(ByteBuf::from),
+		};
+
+		match self.send_async(request).await {
+			Response::Error(code) => Err(AttestationError::Nsm(NsmError::Driver {
+				operation: NsmOperation::Attest,
+				code,
+			})),
+			Response::Attestation { document } => Ok(document),
+			_ => unreachable!("Unexpected response type"),
+		}
+	}
+
+	/// Create an `AttestationDoc`, without blocking the async runtime.
+	///
+	/// See [`SecureModule::send_async`].
+	///
+	/// # Errors
+	///
+	/// Returns an error if the NSM driver returns an error or if the response cannot be decoded.
+	///
+	/// # Panics
+	///
+	/// Panics if the runtime is shutting down.
+	pub async fn attest_async(
+		&'static self,
+		user_data: Option<&[u8]>,
+		nonce: Option<&[u8]>,
+		public_key: Option<&[u8]>,
+	) -> Result<AttestationDoc, AttestationError> {
+		let document = self.raw_attest_async(user_data, nonce, public_key).await?;
+
+		Self::parse_raw_attestation_doc(&document)
 	}
 
-	/// Attempt to get the global NSM instance.
+	/// Attempt to get the global NSM instance, without initializing it.
 	pub fn try_global() -> Option<&'static Self> {
 		SECURE_MODULE_GLOBAL.get()
 	}
 
 	/// Get the global NSM instance.
 	///
+	/// Prefer [`SecureModule::global_or_connect`] outside of handlers run by a router, which
+	/// initializes the global before serving.
+	///
 	/// # Panics
 	///
 	/// Panics if the global NSM instance has not been initialized.
@@ -152,17 +1197,51 @@ impl SecureModule {
 		Self::try_global().expect("NSM global not initialized")
 	}
 
-	/// Attempts to get global NSM instance, initializing it if necessary.
+	/// Get the global NSM instance, connecting to the NSM on first use.
+	///
+	/// Unlike [`SecureModule::global`], this works before a router starts serving, or without
+	/// one at all. If several threads initialize the global at once, only one connection is
+	/// kept and the others are closed.
 	///
 	/// # Errors
 	///
 	/// Propagates `io::Error` if the connection to the NSM fails.
-	pub async fn try_init_global() -> io::Result<&'static Self> {
+	pub fn global_or_connect() -> io::Result<&'static Self> {
+		if let Some(secure_module) = Self::try_global() {
+			return Ok(secure_module);
+		}
+
 		let nsm = Self::connect()?;
 
-		let secure_module = SECURE_MODULE_GLOBAL.get_or_init(|| async { nsm }).await;
+		Ok(SECURE_MODULE_GLOBAL.get_or_init(|| nsm))
+	}
+
+	/// Attempts to get global NSM instance, initializing it if necessary.
+	///
+	/// This is [`SecureModule::global_or_connect`], run on tokio's blocking thread pool.
+	///
+	/// # Errors
+	///
+	/// Propagates `io::Error` if the connection to the NSM fails.
+	///
+	/// # Panics
+	///
+	/// Panics if the runtime is shutting down.
+	pub async fn try_init_global() -> io::Result<&'static Self> {
+		blocking(Self::global_or_connect).await
+	}
 
-		Ok(secure_module)
+	/// Close the connection of the global NSM instance, if it was initialized.
+	///
+	/// The global lives in a static, so it is never dropped, and its file descriptor would
+	/// otherwise stay open until the process exits. This is process-wide: every user of the
+	/// global loses its connection, including servers still running, and reconnects on its
+	/// next request, see [`SecureModule::close`]. Servers only call this once the last of
+	/// them stopped.
+	pub fn shutdown_global() {
+		if let Some(secure_module) = Self::try_global() {
+			secure_module.close();
+		}
 	}
 
 	/// Disconnect from the NSM driver.
@@ -171,14 +1250,27 @@ impl SecureModule {
 	}
 }
 
+/// Run a blocking NSM call on tokio's blocking thread pool.
+#[cfg(feature = "nsm")]
+async fn blocking<T: Send + 'static>(call: impl FnOnce() -> T + Send + 'static) -> T {
+	tokio::task::spawn_blocking(call)
+		.await
+		// If the call panicked, carry on panicking in the caller.
+		.unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+}
+
 #[cfg(feature = "nsm")]
 impl Drop for SecureModule {
 	fn drop(&mut self) {
-		nsm_exit(self.fd);
+		let fd = *self.fd.get_mut().unwrap_or_else(PoisonError::into_inner);
+
+		if fd != -1 {
+			self.driver.exit(fd);
+		}
 	}
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "nsm"))]
 mod tests {
 	use super::*;
 
@@ -196,4 +1288,227 @@ mod tests {
 		assert_eq!(document.nonce, Some(ByteBuf::from(b"some nonce")));
 		assert_eq!(document.user_data, Some(ByteBuf::from(b"hello, world!")));
 	}
+
+	#[test]
+	fn test_certificate_chain_goes_from_leaf_to_root() {
+		let document = include_bytes!("../tests/mock-attestation-doc.cose");
+		let mut document = SecureModule::parse_raw_attestation_doc(document).unwrap();
+
+		let chain = certificate_chain(&document).unwrap();
+		assert_eq!(chain.len(), document.cabundle.len() + 1);
+		assert_eq!(chain[0].as_ref(), &[3, 4]);
+		for (der, bundled) in chain[1..].iter().zip(document.cabundle.iter().rev()) {
+			assert_eq!(der.as_ref(), bundled.as_slice());
+		}
+
+		document.certificate = ByteBuf::new();
+		assert!(matches!(
+			certificate_chain(&document),
+			Err(AttestationError::MissingCertificate)
+		));
+	}
+
+	#[test]
+	fn test_typed_user_data() {
+		let document = include_bytes!("../tests/mock-attestation-doc.cose");
+		let mut document = SecureModule::parse_raw_attestation_doc(document).unwrap();
+
+		assert_eq!(
+			document.user_data_bytes(),
+			Some(b"hello, world!".as_slice())
+		);
+		assert!(document.user_data_as::<Vec<String>>().is_err());
+
+		document.user_data = Some(ByteBuf::from(rmp_serde::to_vec(&("session", 7)).unwrap()));
+		assert_eq!(
+			document.user_data_as::<(String, u32)>().unwrap(),
+			Some(("session".to_string(), 7))
+		);
+
+		document.public_key = Some(ByteBuf::from(vec![1, 2, 3]));
+		assert_eq!(document.public_key_bytes(), Some([1, 2, 3].as_slice()));
+
+		document.user_data = None;
+		assert_eq!(document.user_data_as::<String>().unwrap(), None);
+	}
+
+	#[test]
+	fn test_verify_pcrs() {
+		let document = include_bytes!("../tests/mock-attestation-doc.cose");
+		let mut document = SecureModule::parse_raw_attestation_doc(document).unwrap();
+		document.pcrs = BTreeMap::from([
+			(PcrSet::IMAGE, ByteBuf::from(vec![0xAB; 4])),
+			(PcrSet::KERNEL, ByteBuf::from(vec![0x01; 4])),
+		]);
+
+		assert!(verify_pcrs(&document, &PcrSet::new()).is_ok());
+
+		let expected = PcrSet::new().with_hex(PcrSet::IMAGE, "abababab").unwrap();
+		assert!(verify_pcrs(&document, &expected).is_ok());
+
+		let expected = expected.with(PcrSet::KERNEL, vec![0x02; 4]);
+		assert!(matches!(
+			verify_pcrs(&document, &expected),
+			Err(AttestationError::PcrMismatch { index: 1, ref expected, ref actual })
+				if expected == "02020202" && actual == "01010101"
+		));
+
+		let expected = PcrSet::new().with(PcrSet::SIGNING_CERTIFICATE, vec![0; 4]);
+		assert!(matches!(
+			verify_pcrs(&document, &expected),
+			Err(AttestationError::MissingPcr(8))
+		));
+
+		assert!(PcrSet::new().with_hex(0, "abc").is_err());
+		assert!(PcrSet::new().with_hex(0, "zz").is_err());
+	}
+
+	/// Simulates an NSM whose descriptors break, up to a given one.
+	struct FlakyDriver {
+		next_fd: std::sync::atomic::AtomicI32,
+		first_working_fd: RawFd,
+	}
+
+	impl FlakyDriver {
+		const fn new(first_working_fd: RawFd) -> Self {
+			Self {
+				next_fd: std::sync::atomic::AtomicI32::new(3),
+				first_working_fd,
+			}
+		}
+	}
+
+	impl Driver for FlakyDriver {
+		fn init(&self) -> RawFd {
+			self.next_fd
+				.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+		}
+
+		fn process_request(&self, fd: RawFd, _request: Request) -> Response {
+			if fd < self.first_working_fd {
+				return Response::Error(ErrorCode::InternalError);
+			}
+
+			Response::GetRandom { random: vec![4; 8] }
+		}
+
+		fn exit(&self, _fd: RawFd) {}
+	}
+
+	#[test]
+	fn test_send_recovers_from_broken_descriptor() {
+		let secure_module = SecureModule::connect_with(Box::new(FlakyDriver::new(4))).unwrap();
+
+		assert!(matches!(
+			secure_module.send(Request::GetRandom),
+			Response::GetRandom { .. }
+		));
+		assert_eq!(*secure_module.fd.read().unwrap(), 4);
+	}
+
+	#[test]
+	fn test_send_does_not_replay_pcr_changes() {
+		let requests = [
+			Request::ExtendPCR {
+				index: 16,
+				data: vec![1; 48],
+			},
+			Request::LockPCR { index: 16 },
+			Request::LockPCRs { range: 17 },
+		];
+
+		for request in requests {
+			let secure_module = SecureModule::connect_with(Box::new(FlakyDriver::new(4))).unwrap();
+
+			assert!(matches!(
+				secure_module.send(request),
+				Response::Error(ErrorCode::InternalError)
+			));
+			// The connection was still recovered for the requests that follow.
+			assert_eq!(*secure_module.fd.read().unwrap(), 4);
+		}
+	}
+
+	#[test]
+	fn test_send_does_not_reconnect_within_grace_period() {
+		let secure_module = SecureModule::connect_with(Box::new(FlakyDriver::new(RawFd::MAX)))
+			.unwrap()
+			.with_reconnect_grace_period(Duration::from_mins(1));
+
+		// The first failure reconnects once, the second one is within the grace period.
+		for _ in 0..2 {
+			assert!(matches!(
+				secure_module.send(Request::GetRandom),
+				Response::Error(ErrorCode::InternalError)
+			));
+		}
+		assert_eq!(*secure_module.fd.read().unwrap(), 4);
+
+		// Manual reconnects aren't subject to the grace period.
+		secure_module.reconnect().unwrap();
+		assert_eq!(*secure_module.fd.read().unwrap(), 5);
+	}
+
+	#[test]
+	fn test_close_releases_the_descriptor_until_the_next_request() {
+		let secure_module = SecureModule::connect_with(Box::new(FlakyDriver::new(3))).unwrap();
+
+		secure_module.close();
+		assert_eq!(*secure_module.fd.read().unwrap(), -1);
+		// Closing twice doesn't exit the driver twice.
+		secure_module.close();
+
+		assert!(matches!(
+			secure_module.send(Request::GetRandom),
+			Response::GetRandom { .. }
+		));
+		assert_eq!(*secure_module.fd.read().unwrap(), 4);
+	}
+
+	#[test]
+	fn test_get_random_returns_exactly_len_bytes() {
+		let secure_module = SecureModule::connect_with(Box::new(FlakyDriver::new(3))).unwrap();
+
+		// The driver returns 8 bytes per call, so this takes several calls and a partial one.
+		assert_eq!(secure_module.get_random(20).unwrap(), vec![4; 20]);
+		assert!(secure_module.get_random(0).unwrap().is_empty());
+		assert!(matches!(
+			secure_module.get_random(SecureModule::MAX_RANDOM_BYTES + 1),
+			Err(NsmError::RandomTooLarge { .. })
+		));
+	}
+
+	#[test]
+	fn test_driver_errors_name_the_operation() {
+		let secure_module = SecureModule::connect_with(Box::new(FlakyDriver::new(RawFd::MAX)))
+			.unwrap()
+			.with_reconnect_grace_period(Duration::from_mins(1));
+
+		let error = secure_module.get_random(8).unwrap_err();
+		assert!(matches!(
+			error,
+			NsmError::Driver {
+				operation: NsmOperation::GetRandom,
+				code: ErrorCode::InternalError,
+			}
+		));
+		assert!(matches!(error.code(), Some(ErrorCode::InternalError)));
+		assert!(error.to_string().contains("get_random"));
+	}
+
+	#[test]
+	fn test_parse_cose_headers() {
+		let document = include_bytes!("../tests/mock-attestation-doc.cose");
+		let headers = parse_cose_headers(document).unwrap();
+
+		// The mock document is signed with ES256, unlike documents produced by a real NSM.
+		assert_eq!(headers.algorithm, Some(CoseAlgorithm::Es256));
+		assert_eq!(headers.protected.len(), 1);
+		assert!(headers.unprotected.is_empty());
+
+		assert!(matches!(
+			parse_cose_headers(&[0x80]),
+			Err(AttestationError::MalformedCose(_))
+		));
+	}
 }
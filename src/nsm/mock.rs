@@ -0,0 +1,262 @@
+use aws_nitro_enclaves_cose::{
+	CoseSign1,
+	crypto::{MessageDigest, SignatureAlgorithm, SigningPrivateKey, SigningPublicKey},
+	error::CoseError,
+	header_map::HeaderMap,
+};
+use p384::ecdsa::{
+	Signature, SigningKey, VerifyingKey,
+	signature::hazmat::{PrehashSigner, PrehashVerifier},
+};
+use sha2::{Digest as _, Sha384};
+use std::{
+	collections::BTreeMap,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::{
+	AttestationDoc, AttestationError, Attester, Digest, ErrorCode, NsmError, NsmOperation,
+	PcrDescription, SecureModule, Sha2Hasher,
+};
+
+/// The secret scalar of the key mock attestation documents are signed with.
+///
+/// This key is public knowledge: documents signed with it prove nothing.
+const MOCK_SIGNING_KEY: [u8; 48] = [0x42; 48];
+
+/// How many PCRs the mock exposes, as many as a real NSM.
+const PCR_COUNT: u16 = 32;
+
+/// The length of PCR values, which the NSM computes with SHA-384.
+const PCR_LENGTH: usize = 48;
+
+/// A stand-in for the Nitro Secure Module, for testing code that needs one off an enclave.
+///
+/// It implements [`Attester`] with deterministic fixtures, so handlers generic over
+/// that trait can be tested on any host:
+/// - attestation documents are signed with a fixed ES384 test key, see
+///   [`MockSecureModule::public_key`], and embed the PCRs configured with
+///   [`MockSecureModule::with_pcr`]. They carry no certificate, so they never pass
+///   `attestation::verify`.
+/// - random bytes are derived from a counter, so every mock returns the same sequence.
+/// - configured PCRs are reported as locked, and the others as zeroed and unlocked.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let nsm = MockSecureModule::new().with_pcr(0, [1; 48]);
+/// let document = nsm.attest(Some(b"hello"), None, None)?;
+/// assert_eq!(document.pcrs[&0].as_slice(), &[1; 48]);
+/// ```
+#[derive(Debug)]
+pub struct MockSecureModule {
+	signing_key: SigningKey,
+	module_id: String,
+	timestamp: u64,
+	pcrs: BTreeMap<u16, Vec<u8>>,
+	random_counter: AtomicU64,
+}
+
+impl MockSecureModule {
+	/// Create a mock with no PCRs configured.
+	///
+	/// # Panics
+	///
+	/// Never, as the mock signing key is a valid P-384 scalar.
+	#[must_use]
+	pub fn new() -> Self {
+		Self {
+			signing_key: SigningKey::from_slice(&MOCK_SIGNING_KEY)
+				.expect("the mock signing key is a valid P-384 scalar"),
+			module_id: "i-mock-enc0000000000000000".to_string(),
+			timestamp: 0,
+			pcrs: BTreeMap::new(),
+			random_counter: AtomicU64::new(0),
+		}
+	}
+
+	/// Set the value of the PCR at `index`, which is then reported as locked.
+	///
+	/// Values are padded or truncated to 48 bytes, the length of real PCR values.
+	#[must_use]
+	pub fn with_pcr(mut self, index: u16, value: impl Into<Vec<u8>>) -> Self {
+		let mut value = value.into();
+		value.resize(PCR_LENGTH, 0);
+
+		self.pcrs.insert(index, value);
+		self
+	}
+
+	/// Set the timestamp of attestation documents, in milliseconds since the Unix epoch.
+	///
+	/// Defaults to 0, so that documents are identical from one run to the next.
+	#[must_use]
+	pub const fn with_timestamp(mut self, timestamp: u64) -> Self {
+		self.timestamp = timestamp;
+		self
+	}
+
+	/// The SEC1-encoded public key attestation documents are signed with.
+	#[must_use]
+	pub fn public_key(&self) -> Vec<u8> {
+		self.signing_key
+			.verifying_key()
+			.to_encoded_point(false)
+			.as_bytes()
+			.to_vec()
+	}
+
+	/// The value reported for the PCR at `index`, zeroed unless it was configured.
+	fn pcr(&self, index: u16) -> Vec<u8> {
+		self.pcrs
+			.get(&index)
+			.cloned()
+			.unwrap_or_else(|| vec![0; PCR_LENGTH])
+	}
+}
+
+impl Default for MockSecureModule {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Attester for MockSecureModule {
+	fn raw_attest(
+		&self,
+		user_data: Option<&[u8]>,
+		nonce: Option<&[u8]>,
+		public_key: Option<&[u8]>,
+	) -> Result<Vec<u8>, AttestationError> {
+		let pcrs = (0..PCR_COUNT)
+			.map(|index| (usize::from(index), self.pcr(index)))
+			.collect();
+
+		let document = AttestationDoc::new(
+			self.module_id.clone(),
+			Digest::SHA384,
+			self.timestamp,
+			pcrs,
+			Vec::new(),
+			Vec::new(),
+			user_data.map(<[u8]>::to_vec),
+			nonce.map(<[u8]>::to_vec),
+			public_key.map(<[u8]>::to_vec),
+		);
+
+		let key = MockKey(self.signing_key.clone());
+		CoseSign1::new::<Sha2Hasher>(&document.to_binary(), &HeaderMap::new(), &key)
+			.and_then(|document| document.as_bytes(true))
+			.map_err(AttestationError::Cose)
+	}
+
+	fn get_random(&self, len: usize) -> Result<Vec<u8>, NsmError> {
+		if len > SecureModule::MAX_RANDOM_BYTES {
+			return Err(NsmError::RandomTooLarge {
+				requested: len,
+				limit: SecureModule::MAX_RANDOM_BYTES,
+			});
+		}
+
+		let mut random = Vec::with_capacity(len);
+		while random.len() < len {
+			let counter = self.random_counter.fetch_add(1, Ordering::Relaxed);
+			let block = Sha384::digest(counter.to_be_bytes());
+
+			let missing = len - random.len();
+			random.extend_from_slice(&block[..block.len().min(missing)]);
+		}
+
+		Ok(random)
+	}
+
+	fn describe_pcr(&self, index: u16) -> Result<PcrDescription, NsmError> {
+		if index >= PCR_COUNT {
+			return Err(NsmError::Driver {
+				operation: NsmOperation::DescribePcr,
+				code: ErrorCode::InvalidIndex,
+			});
+		}
+
+		Ok(PcrDescription {
+			locked: self.pcrs.contains_key(&index),
+			value: self.pcr(index),
+		})
+	}
+}
+
+/// The test key mock attestation documents are signed with.
+struct MockKey(SigningKey);
+
+impl SigningPublicKey for MockKey {
+	fn get_parameters(&self) -> Result<(SignatureAlgorithm, MessageDigest), CoseError> {
+		Ok((SignatureAlgorithm::ES384, MessageDigest::Sha384))
+	}
+
+	fn verify(&self, digest: &[u8], signature: &[u8]) -> Result<bool, CoseError> {
+		let Ok(signature) = Signature::from_slice(signature) else {
+			return Ok(false);
+		};
+
+		Ok(VerifyingKey::from(&self.0)
+			.verify_prehash(digest, &signature)
+			.is_ok())
+	}
+}
+
+impl SigningPrivateKey for MockKey {
+	fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, CoseError> {
+		let signature: Signature = self
+			.0
+			.sign_prehash(digest)
+			.map_err(|e| CoseError::SignatureError(Box::new(e)))?;
+
+		Ok(signature.to_vec())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+
+	#[test]
+	fn test_mock_attestation_roundtrip() {
+		let nsm = MockSecureModule::new().with_pcr(0, [1; 48]);
+
+		let document = nsm
+			.attest(Some(b"hello"), None, Some(&nsm.public_key()))
+			.unwrap();
+
+		assert_eq!(document.pcrs[&0].as_slice(), &[1; 48]);
+		assert_eq!(document.pcrs[&1].as_slice(), &[0; 48]);
+		assert_eq!(document.user_data.unwrap().as_slice(), b"hello");
+
+		let raw = nsm.raw_attest(None, None, None).unwrap();
+		let signed = CoseSign1::from_bytes(&raw).unwrap();
+		assert!(
+			signed
+				.verify_signature::<Sha2Hasher>(&MockKey(nsm.signing_key))
+				.unwrap()
+		);
+	}
+
+	#[test]
+	fn test_mock_fixtures_are_deterministic() {
+		let first = MockSecureModule::new();
+		let second: Arc<dyn Attester> = Arc::new(MockSecureModule::new());
+
+		let random = first.get_random(100).unwrap();
+		assert_eq!(random.len(), 100);
+		assert_eq!(random, second.get_random(100).unwrap());
+
+		assert!(first.describe_pcr(0).is_ok_and(|pcr| !pcr.locked));
+		assert!(matches!(
+			first.describe_pcr(PCR_COUNT),
+			Err(NsmError::Driver {
+				operation: NsmOperation::DescribePcr,
+				code: ErrorCode::InvalidIndex,
+			})
+		));
+	}
+}
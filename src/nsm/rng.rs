@@ -0,0 +1,105 @@
+use rand_core::{CryptoRng, RngCore, impls};
+
+use super::SecureModule;
+
+/// A random number generator drawing its entropy from the Nitro Secure Module.
+///
+/// This plugs the NSM into the `rand` ecosystem, so that key generation, nonces and
+/// anything else built on `RngCore` can use the enclave's trusted entropy source.
+///
+/// Each call to the NSM returns a batch of random bytes, which is buffered and handed out
+/// until it runs out, so most calls don't reach the NSM at all. When they do, the call is a
+/// blocking ioctl: in async code, prefer generating randomness in `spawn_blocking`.
+///
+/// # Panics
+///
+/// `fill_bytes`, `next_u32` and `next_u64` panic if the NSM fails to provide entropy, as
+/// required by `RngCore`. Use `try_fill_bytes` to handle that case instead.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut rng = NsmRng::new(SecureModule::global());
+/// let key = SigningKey::random(&mut rng);
+/// ```
+pub struct NsmRng<'a> {
+	secure_module: &'a SecureModule,
+	buffer: Vec<u8>,
+}
+
+impl<'a> NsmRng<'a> {
+	/// Create a generator drawing from the given NSM connection.
+	#[must_use]
+	pub const fn new(secure_module: &'a SecureModule) -> Self {
+		Self {
+			secure_module,
+			buffer: Vec::new(),
+		}
+	}
+}
+
+impl RngCore for NsmRng<'_> {
+	fn next_u32(&mut self) -> u32 {
+		impls::next_u32_via_fill(self)
+	}
+
+	fn next_u64(&mut self) -> u64 {
+		impls::next_u64_via_fill(self)
+	}
+
+	fn fill_bytes(&mut self, dest: &mut [u8]) {
+		self.try_fill_bytes(dest)
+			.expect("the NSM failed to provide entropy");
+	}
+
+	fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+		let mut filled = 0;
+
+		while filled < dest.len() {
+			if self.buffer.is_empty() {
+				self.buffer = self
+					.secure_module
+					.random_chunk()
+					.map_err(rand_core::Error::new)?;
+			}
+
+			filled += drain_into(&mut self.buffer, &mut dest[filled..]);
+		}
+
+		Ok(())
+	}
+}
+
+impl CryptoRng for NsmRng<'_> {}
+
+/// Move as many bytes as fit from the end of `buffer` into `dest`, returning how many were moved.
+///
+/// Bytes are taken from the end so that handing them out never shifts the rest of the buffer.
+fn drain_into(buffer: &mut Vec<u8>, dest: &mut [u8]) -> usize {
+	let count = buffer.len().min(dest.len());
+	let start = buffer.len() - count;
+
+	dest[..count].copy_from_slice(&buffer[start..]);
+	buffer.truncate(start);
+
+	count
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_drain_into_hands_out_each_byte_once() {
+		let mut buffer = vec![1, 2, 3, 4, 5];
+
+		let mut dest = [0; 3];
+		assert_eq!(drain_into(&mut buffer, &mut dest), 3);
+		assert_eq!(dest, [3, 4, 5]);
+
+		let mut dest = [0; 4];
+		assert_eq!(drain_into(&mut buffer, &mut dest), 2);
+		assert_eq!(dest, [1, 2, 0, 0]);
+		assert!(buffer.is_empty());
+	}
+}
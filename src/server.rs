@@ -1,42 +1,545 @@
-use std::{collections::HashMap, future::Future, io, marker::PhantomData, pin::Pin, sync::Arc};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio_vsock::{VsockAddr, VsockListener};
+use futures_util::FutureExt;
+use std::{
+	collections::{BTreeMap, HashMap},
+	fmt::Display,
+	future::Future,
+	hash::{BuildHasherDefault, Hasher},
+	io,
+	marker::PhantomData,
+	panic::{self, AssertUnwindSafe},
+	pin::Pin,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	sync::{OwnedSemaphorePermit, Semaphore, mpsc},
+};
+use tokio_vsock::{VsockAddr, VsockListener, VsockStream};
 
-pub use crate::utils::CodingKey;
-use crate::{Request, utils::Stream};
+#[cfg(feature = "nsm")]
+pub use self::extract::Nsm;
+use self::{
+	cache::{CacheKey, CachedHandler},
+	handle::{ConnectionRegistry, Registration},
+	observe::CapacityWarnings,
+	stats::ActiveConnection,
+	streaming::{StreamHandler, TypedStreamHandler},
+};
+pub use self::{
+	extract::{Context, FromContext, Peer, RequestId, State},
+	handle::{ConnectionInfo, ServerHandle},
+	layer::{Layer, Next, RequestContext, TracingLayer, trace_id},
+	observe::{NoopObserver, Observer},
+	stats::{ServerStats, StatsHandle},
+};
+#[cfg(feature = "tls")]
+use crate::tls::ServerTls;
+pub use crate::utils::{CodingKey, StreamConfig};
+#[cfg(feature = "compression")]
+use crate::wire::CompressionConfig;
+use crate::{
+	Request, StreamingRequest, TypeId,
+	addr::{self, AddrError, Role, VMADDR_CID_ANY},
+	builtin::{HealthCheck, HealthStatus, ListRoutes, RESERVED_ROUTE_PREFIX, RouteList},
+	utils::{ByteStream, FrameTooLarge, ReadFramed, Stream, timed},
+	wire::{
+		self, Checksum, ChecksumMismatch, CodecMismatch, Compression, ErrorFrame, Format,
+		HandlerError, Metadata, TraceId,
+	},
+};
 
-const VMADDR_CID_ANY: u32 = 0xFFFF_FFFF;
+mod cache;
+mod extract;
+mod handle;
+mod layer;
+mod multiplex;
+mod observe;
+mod stats;
+mod streaming;
 
-type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+/// A predicate deciding whether requests to a route are served, see [`Router::gate`].
+type Gate = Arc<dyn Fn(TypeId, &str) -> bool + Send + Sync>;
+
+/// A boxed future, as returned by [`Layer::around`].
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
 /// Errors that can occur when running the server.
+///
+/// Variants are added as features are, so matches on this enum need a wildcard arm.
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error {
+	/// The registered routes conflict with each other.
+	#[error(transparent)]
+	Build(BuildError),
+	/// A [strict](Router::strict) router was served without any routes registered.
+	#[error("no routes were registered, every request would be refused")]
+	NoRoutes,
+	/// The address to listen on can't be bound to.
+	#[error("Invalid address to listen on")]
+	InvalidAddress(#[source] AddrError),
 	/// Failed to bind to vsock address.
-	#[error("Failed to bind to vsock address: {0}")]
-	Bind(io::Error),
+	#[error("Failed to bind to vsock address {cid}:{port}")]
+	Bind {
+		/// The CID that was bound to.
+		cid: u32,
+		/// The port that was bound to.
+		port: u32,
+		/// Why binding failed.
+		#[source]
+		source: io::Error,
+	},
+	/// Failed to bind to a Unix socket, see [`Router::serve_unix`].
+	#[cfg(feature = "test-transport")]
+	#[error("Failed to bind to Unix socket {}", path.display())]
+	BindUnix {
+		/// The path of the socket.
+		path: std::path::PathBuf,
+		/// Why binding failed.
+		#[source]
+		source: io::Error,
+	},
 	/// Failed to accept connection.
-	#[error("Failed to accept connection: {0}")]
-	Accept(io::Error),
+	#[error("Failed to accept connection")]
+	Accept(#[source] io::Error),
+	/// Failed to establish a TLS session with the client.
+	#[cfg(feature = "tls")]
+	#[error("TLS handshake failed")]
+	Tls(#[source] io::Error),
 	/// Failed to connect to NSM.
 	#[cfg(feature = "nsm")]
-	#[error("Failed to connect to NSM: {0}")]
-	NsmConnect(io::Error),
-	/// Failed to encode the request payload.
-	#[error("encoding failed: {0}")]
-	Encoding(rmp_serde::encode::Error),
+	#[error("Failed to connect to NSM")]
+	NsmConnect(#[source] io::Error),
+	/// Failed to encode the response payload.
+	#[error("encoding failed for {route_id}")]
+	Encoding {
+		/// The route ID of the request.
+		route_id: &'static str,
+		/// Why encoding failed.
+		#[source]
+		source: wire::EncodeError,
+	},
 	/// Failed to decode the request payload.
-	#[error("decoding failed: {0}")]
-	Decoding(rmp_serde::decode::Error),
+	#[error("decoding failed for {route_id} ({bytes} bytes)")]
+	Decoding {
+		/// The route ID of the request.
+		route_id: &'static str,
+		/// The length of the payload.
+		bytes: usize,
+		/// Why decoding failed.
+		#[source]
+		source: wire::DecodeError,
+	},
 	/// Failed to write a payload to the stream.
-	#[error("failed to write {0}: {1}")]
-	Writing(CodingKey, io::Error),
+	#[error("failed to write {0}")]
+	Writing(CodingKey, #[source] io::Error),
 	/// Failed to read a payload from the stream.
-	#[error("failed to read {0}: {1}")]
-	Reading(CodingKey, io::Error),
+	#[error("failed to read {0}")]
+	Reading(CodingKey, #[source] io::Error),
 	/// Unknown request type.
 	#[error("Unknown request type: 0x{0:08x}")]
-	UnknownRequest(u32),
+	UnknownRequest(TypeId),
+	/// The client speaks another version of the wire protocol.
+	#[error("unsupported protocol version {got}, expected {expected}")]
+	ProtocolVersion {
+		/// The version announced by the client.
+		got: u8,
+		/// The version this server speaks, [`wire::PROTOCOL_VERSION`].
+		expected: u8,
+	},
+	/// The client uses an incompatible payload format.
+	#[error(transparent)]
+	CodecMismatch(CodecMismatch),
+	/// The client tagged a payload with a format this server doesn't support.
+	#[error("unsupported codec: 0x{0:02x}")]
+	UnsupportedCodec(u8),
+	/// The client compressed its payload in a way this server doesn't support.
+	#[error("unsupported compression: {0}")]
+	UnsupportedCompression(String),
+	/// The client checksummed its payload in a way this server doesn't support.
+	#[error("unsupported checksum: {0}")]
+	UnsupportedChecksum(String),
+	/// The request payload doesn't match its checksum.
+	#[error(transparent)]
+	ChecksumMismatch(ChecksumMismatch),
+	/// The compressed request payload couldn't be decompressed.
+	#[error("decompression failed")]
+	Decompression(#[source] io::Error),
+	/// The client declared a payload larger than the router accepts.
+	#[error("payload too large: {declared} bytes declared, limit is {limit}")]
+	PayloadTooLarge {
+		/// The length announced by the client.
+		declared: u64,
+		/// The largest payload the router accepts.
+		limit: u64,
+	},
+	/// A fallible handler returned an error, which is sent to the client as is.
+	#[error(transparent)]
+	Handler(HandlerError),
+	/// A streaming request was sent over a multiplexed connection, which can't carry streams.
+	#[error("the streaming route {route_id:?} can't be served over a multiplexed connection")]
+	MultiplexedStream {
+		/// The route ID of the request.
+		route_id: &'static str,
+	},
+	/// The handler panicked while handling the request.
+	#[error("the handler for {route_id:?} panicked")]
+	HandlerPanic {
+		/// The route ID of the request.
+		route_id: &'static str,
+	},
+	/// A phase of the connection took longer than the router's timeout.
+	#[error("timed out while {0}")]
+	Timeout(TimeoutPhase),
+	/// The router's gate refused to serve the request's route.
+	#[error("the route {route_id:?} is unavailable")]
+	Gated {
+		/// The route ID of the request.
+		route_id: &'static str,
+	},
+	/// The connection queue of a [`DispatchModel::BoundedPool`] server is full.
+	#[error("service unavailable: the connection queue is full")]
+	ServiceUnavailable,
+	/// The request reuses an idempotency key that came with another payload, see
+	/// [`Router::route_idempotent`].
+	#[error("the idempotency key was already used for another {route_id:?} request")]
+	IdempotencyKeyReused {
+		/// The route ID of the request.
+		route_id: &'static str,
+	},
+}
+
+impl Error {
+	/// The code identifying this kind of error in the error frames sent to clients.
+	///
+	/// See [`ErrorFrame`] for the list of codes.
+	#[must_use]
+	pub const fn code(&self) -> u16 {
+		match self {
+			Self::UnknownRequest(_) => ErrorFrame::UNKNOWN_REQUEST,
+			Self::Decoding { .. } | Self::Decompression(_) => ErrorFrame::DECODING,
+			Self::Encoding { .. } => ErrorFrame::ENCODING,
+			Self::Reading(..) => ErrorFrame::READING,
+			Self::ProtocolVersion { .. }
+			| Self::CodecMismatch(_)
+			| Self::UnsupportedCodec(_)
+			| Self::UnsupportedCompression(_)
+			| Self::UnsupportedChecksum(_) => ErrorFrame::UNSUPPORTED_CODEC,
+			Self::ChecksumMismatch(_) => ErrorFrame::CHECKSUM_MISMATCH,
+			Self::ServiceUnavailable => ErrorFrame::SERVICE_UNAVAILABLE,
+			Self::PayloadTooLarge { .. } => ErrorFrame::PAYLOAD_TOO_LARGE,
+			Self::Timeout(_) => ErrorFrame::TIMEOUT,
+			Self::Handler(error) => error.code,
+			Self::HandlerPanic { .. } => ErrorFrame::HANDLER_PANIC,
+			Self::MultiplexedStream { .. } => ErrorFrame::UNSUPPORTED_MODE,
+			Self::Gated { .. } => ErrorFrame::ROUTE_UNAVAILABLE,
+			Self::IdempotencyKeyReused { .. } => ErrorFrame::IDEMPOTENCY_KEY_REUSED,
+			Self::Build(_)
+			| Self::NoRoutes
+			| Self::InvalidAddress(_)
+			| Self::Bind { .. }
+			| Self::Accept(_)
+			| Self::Writing(..) => ErrorFrame::INTERNAL,
+			#[cfg(feature = "nsm")]
+			Self::NsmConnect(_) => ErrorFrame::INTERNAL,
+			#[cfg(feature = "tls")]
+			Self::Tls(_) => ErrorFrame::INTERNAL,
+			#[cfg(feature = "test-transport")]
+			Self::BindUnix { .. } => ErrorFrame::INTERNAL,
+		}
+	}
+
+	/// Whether this error left the connection at the start of the next request, so that it
+	/// can still be read. Errors that interrupt a request halfway through don't.
+	const fn is_on_frame_boundary(&self, reject_policy: RejectPolicy) -> bool {
+		match self {
+			Self::Decoding { .. }
+			| Self::Encoding { .. }
+			| Self::UnsupportedCompression(_)
+			| Self::ChecksumMismatch(_)
+			| Self::Decompression(_)
+			| Self::Handler(_)
+			| Self::HandlerPanic { .. }
+			| Self::MultiplexedStream { .. }
+			| Self::IdempotencyKeyReused { .. }
+			| Self::Timeout(TimeoutPhase::Handling) => true,
+			// The payload of an unknown request is only read when draining it succeeded.
+			Self::UnknownRequest(_) => matches!(reject_policy, RejectPolicy::Drain),
+			_ => false,
+		}
+	}
+}
+
+/// The routes registered on a router conflict with each other, see [`Router::build`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid routes: {}", display_problems(.problems))]
+pub struct BuildError {
+	/// Every conflict found, in registration order.
+	pub problems: Vec<RouteProblem>,
+}
+
+fn display_problems(problems: &[RouteProblem]) -> String {
+	problems
+		.iter()
+		.map(ToString::to_string)
+		.collect::<Vec<_>>()
+		.join("; ")
+}
+
+/// A single conflict between the routes registered on a router.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RouteProblem {
+	/// Two request types use the same route ID, so one would shadow the other.
+	#[error("{} and {} both use route ID {route_id:?}", type_names[0], type_names[1])]
+	DuplicateRouteId {
+		/// The shared route ID.
+		route_id: &'static str,
+		/// The request types using it.
+		type_names: [&'static str; 2],
+	},
+	/// A request type uses an empty route ID.
+	#[error("{type_name} uses an empty route ID")]
+	EmptyRouteId {
+		/// The request type.
+		type_name: &'static str,
+	},
+	/// A request type uses a route ID reserved for the routes built into pontifex.
+	#[error("{type_name} uses route ID {route_id:?}, reserved for built-in routes")]
+	ReservedRouteId {
+		/// The reserved route ID.
+		route_id: &'static str,
+		/// The request type.
+		type_name: &'static str,
+	},
+	/// Two route IDs hash to the same type ID, so they can't be told apart on the wire.
+	#[error(
+		"route IDs {:?} and {:?} both hash to type ID 0x{type_id:08x}, rename one of them",
+		route_ids[0],
+		route_ids[1]
+	)]
+	HashCollision {
+		/// The shared type ID.
+		type_id: TypeId,
+		/// The route IDs hashing to it.
+		route_ids: [&'static str; 2],
+	},
+	/// More routes were registered than a router accepts, see [`MAX_ROUTES`].
+	#[error("{count} routes were registered, the limit is {limit}")]
+	TooManyRoutes {
+		/// The number of routes registered.
+		count: usize,
+		/// The largest number of routes a router accepts.
+		limit: usize,
+	},
+}
+
+/// A route as it was registered, kept to check for conflicts.
+#[derive(Clone, Copy)]
+struct RouteRegistration {
+	type_id: TypeId,
+	route_id: &'static str,
+	type_name: &'static str,
+}
+
+/// The part of a connection that timed out, see [`Router::with_timeout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPhase {
+	/// Exchanging the handshake, or reading the request.
+	Reading,
+	/// Running the handler.
+	Handling,
+	/// Writing the response.
+	Writing,
+}
+
+impl Display for TimeoutPhase {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Reading => write!(f, "reading the request"),
+			Self::Handling => write!(f, "handling the request"),
+			Self::Writing => write!(f, "writing the response"),
+		}
+	}
+}
+
+/// How accepted connections are handed to handlers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DispatchModel {
+	/// Handle every connection in its own task, as soon as it is accepted.
+	///
+	/// Nothing is ever turned away, but under sustained overload every request slows down.
+	#[default]
+	SpawnPerConnection,
+	/// Queue connections for a fixed number of worker tasks.
+	///
+	/// When `queue_capacity` connections are already waiting, new ones aren't handled: they
+	/// are answered with `Error::ServiceUnavailable` right after the handshake, without
+	/// reading their request, then closed, so the requests that do get in keep a predictable
+	/// latency. Past [`MAX_CONCURRENT_REFUSALS`] refusals at once, connections are closed
+	/// without an answer, and so are all of them on routers [terminating TLS](Router::with_tls),
+	/// since answering would take a whole TLS handshake first. The current queue depth is
+	/// reported by [`ServerStats::queued_connections`].
+	BoundedPool {
+		/// How many connections are handled at the same time. At least one.
+		workers: usize,
+		/// How many accepted connections may wait for a worker. At least one.
+		queue_capacity: usize,
+	},
+}
+
+/// What to do with the payload of a request for an unknown route.
+///
+/// The client sends its whole request before reading anything back, so when the server
+/// refuses a request because its route is unknown, the payload is still in flight.
+/// Requests refused by a [gate](Router::gate) always close the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RejectPolicy {
+	/// Close the connection right away, without reading the payload.
+	Close,
+	/// Read and discard the payload first, along with its checksum if it has one, so the
+	/// connection stays aligned on frame boundaries and the client isn't reset mid-send.
+	/// Payloads larger than the router's [`max_payload`](Router::max_payload), or than
+	/// [`MAX_DRAIN_BYTES`], are never drained, and neither are those with a checksum the
+	/// server doesn't support: the connection is closed instead.
+	///
+	/// This is the default, so that a client sending a request the server doesn't know gets
+	/// an error frame for it rather than a reset halfway through sending it, and can keep
+	/// using the connection for its next request.
+	#[default]
+	Drain,
+}
+
+/// The largest payload that is read and discarded under [`RejectPolicy::Drain`].
+pub const MAX_DRAIN_BYTES: u64 = 16 * 1024 * 1024;
+
+/// How many connections a [`DispatchModel::BoundedPool`] server refuses at once, see there.
+pub const MAX_CONCURRENT_REFUSALS: usize = 64;
+
+/// How long refusing a connection may take, from its handshake to the error frame.
+///
+/// This applies whatever the router's [timeout](Router::with_timeout), as refusals happen
+/// when the server is already overloaded.
+pub const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a client may take to complete its TLS handshake, see [`Router::with_tls`].
+///
+/// This applies even to routers without a [timeout](Router::with_timeout), as the handshake
+/// comes before anything tells a pontifex client apart from a peer holding a connection
+/// open.
+#[cfg(feature = "tls")]
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most routes a router accepts, including built-in ones, see [`Router::build`].
+///
+/// Routes are registered by code rather than configuration, so going past this is a bug,
+/// such as registering routes in a loop. Well before it, 32-bit type IDs are likely to
+/// collide, see the `wide-ids` feature.
+pub const MAX_ROUTES: usize = 65_536;
+
+/// How many idempotency keys each route registered with [`Router::route_idempotent`] remembers.
+pub const MAX_IDEMPOTENCY_KEYS: usize = 1024;
+
+/// How many bytes of keys and responses each route registered with [`Router::route_cached`]
+/// or [`Router::route_idempotent`] keeps at most, however many entries it may hold.
+///
+/// Payloads may be as large as the router's [`max_payload`](Router::max_payload), so bounding
+/// caches by their number of entries alone could hold on to gigabytes of enclave memory.
+pub const MAX_CACHED_BYTES: usize = 64 * 1024 * 1024;
+
+/// How long the server waits for capacity before logging it by default, see
+/// [`Router::capacity_warning`].
+pub const DEFAULT_CAPACITY_WARNING: Duration = Duration::from_secs(1);
+
+/// How many requests of a multiplexed connection are handled at once by default, see
+/// [`Router::max_streams`].
+pub const DEFAULT_MAX_STREAMS: usize = 32;
+
+/// The formats a single request is encoded with, and that its response should be encoded with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PayloadFormats {
+	request: Format,
+	response: Format,
+	presize: bool,
+}
+
+impl PayloadFormats {
+	/// Decode the payload of a request for `route_id`.
+	fn decode_request<T: serde::de::DeserializeOwned>(
+		self,
+		route_id: &'static str,
+		payload: &[u8],
+	) -> Result<T, Error> {
+		self.request
+			.decode(payload)
+			.map_err(|source| Error::Decoding {
+				route_id,
+				bytes: payload.len(),
+				source,
+			})
+	}
+
+	/// Encode a response to a request for `route_id`, or an item of a streamed one, see
+	/// [`Router::presize_responses`].
+	fn encode_response<T: serde::Serialize>(
+		self,
+		route_id: &'static str,
+		response: &T,
+	) -> Result<Vec<u8>, Error> {
+		if self.presize {
+			self.response.encode_exact(response)
+		} else {
+			self.response.encode(response)
+		}
+		.map_err(|source| Error::Encoding { route_id, source })
+	}
+}
+
+/// A request as read off the wire, before its payload is decoded.
+struct RawRequest {
+	formats: PayloadFormats,
+	metadata: Metadata,
+	payload: Vec<u8>,
+	ctx: RequestContext,
+}
+
+/// The route map, keyed by type IDs.
+type RouteMap<S> = TypeIdMap<Route<S>>;
+
+/// A map keyed by type IDs, as routers keep their routes in.
+///
+/// Type IDs are already FNV-1a hashes, so they are used as their own hash rather than
+/// hashed again on every request. Hashing them with a keyed hash would guard against
+/// flooding a bucket, but type IDs hash route IDs fixed at compile time, which clients
+/// can't choose.
+///
+/// Only public so that the benchmarks measure the map routers actually use.
+#[doc(hidden)]
+pub type TypeIdMap<V> = HashMap<TypeId, V, BuildHasherDefault<TypeIdHasher>>;
+
+/// Hashes a type ID to itself, see [`TypeIdMap`].
+#[doc(hidden)]
+#[derive(Debug, Default)]
+pub struct TypeIdHasher(u64);
+
+impl Hasher for TypeIdHasher {
+	fn finish(&self) -> u64 {
+		self.0
+	}
+
+	fn write(&mut self, _: &[u8]) {
+		unreachable!("type IDs are hashed as integers");
+	}
+
+	fn write_u32(&mut self, type_id: u32) {
+		// The map picks buckets with the low bits of the hash and tells entries of a bucket
+		// apart with the high ones, so a 32-bit ID fills both halves.
+		self.0 = (u64::from(type_id) << 32) | u64::from(type_id);
+	}
+
+	fn write_u64(&mut self, type_id: u64) {
+		self.0 = type_id;
+	}
 }
 
 /// A common interface that all request handlers must implement.
@@ -52,8 +555,29 @@ pub enum Error {
 /// regardless of their specific request/response types. Think of it like an electrical
 /// outlet standard - different appliances (handlers) work differently internally,
 /// but they all plug into the same socket (implement this trait).
+///
+/// Handlers only ever see bytes: the connection loop reads the request payload off the
+/// wire and writes the returned response payload back, so framing lives in one place.
 trait Handler<S>: Send + Sync {
-	fn handle<'a>(&'a self, stream: &'a mut Stream, state: S) -> BoxFuture<'a, Result<(), Error>>;
+	fn call(&self, state: S, request: RawRequest) -> BoxFuture<'_, Result<Vec<u8>, Error>>;
+}
+
+/// What a type ID is routed to: a handler answering with a single response, or one
+/// answering with a stream of items.
+///
+/// Routes are shared between clones of a router, rather than copied.
+enum Route<S> {
+	Unary(Arc<dyn Handler<S>>),
+	Streaming(Arc<dyn StreamHandler<S>>),
+}
+
+impl<S> Clone for Route<S> {
+	fn clone(&self) -> Self {
+		match self {
+			Self::Unary(handler) => Self::Unary(Arc::clone(handler)),
+			Self::Streaming(handler) => Self::Streaming(Arc::clone(handler)),
+		}
+	}
 }
 
 /// A wrapper that allows strongly-typed handlers to work with the type-erased system.
@@ -100,26 +624,17 @@ where
 	H: Fn(S, R) -> Fut + Send + Sync,
 	Fut: Future<Output = R::Response> + Send,
 {
-	fn handle<'a>(&'a self, stream: &'a mut Stream, state: S) -> BoxFuture<'a, Result<(), Error>> {
+	fn call(&self, state: S, raw: RawRequest) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
 		Box::pin(async move {
-			// At this point, we know the concrete type R (e.g., HealthCheck),
-			// so we can correctly deserialize the incoming bytes
-			// Read request length
-			let len = stream
-				.read_u64()
-				.await
-				.map_err(|e| Error::Reading(CodingKey::Length, e))?;
+			let RawRequest {
+				formats, payload, ..
+			} = raw;
 
-			// Read request payload
-			let payload = stream
-				.read_exact(len)
-				.await
-				.map_err(|e| Error::Reading(CodingKey::Payload, e))?;
-
-			// Convert bytes -> the specific request type this handler expects.
+			// At this point, we know the concrete type R (e.g., HealthCheck),
+			// so we can correctly deserialize the incoming bytes.
 			// For example, if R = HealthCheck, this deserializes to HealthCheck.
 			// This is safe because the router already verified the type ID matches.
-			let request: R = rmp_serde::from_slice(&payload).map_err(Error::Decoding)?;
+			let request: R = formats.decode_request(R::ROUTE_ID, &payload)?;
 
 			// Call the user's actual handler function with properly typed parameters.
 			// The handler doesn't know about bytes or type erasure - it just gets
@@ -127,24 +642,255 @@ where
 			let response = (self.handler)(state, request).await;
 
 			// Convert the typed response back to bytes for transmission
-			let response_bytes = rmp_serde::to_vec(&response).map_err(Error::Encoding)?;
+			// (in the format the client asked for, which may differ from the request's)
+			formats.encode_response(R::ROUTE_ID, &response)
+		})
+	}
+}
 
-			// Send response
-			stream
-				.write_u64(response_bytes.len() as u64)
-				.await
-				.map_err(|e| Error::Writing(CodingKey::Length, e))?;
+/// The fallible counterpart of [`TypedHandler`], for handlers returning a `Result`.
+///
+/// Decoding and encoding work the same way, but an error returned by the handler is
+/// converted into a [`HandlerError`] and reported to the client instead of a response.
+struct FallibleHandler<R, S, H, Fut, E>
+where
+	R: Request,
+	H: Fn(S, R) -> Fut + Send + Sync,
+	Fut: Future<Output = Result<R::Response, E>> + Send,
+	E: Into<HandlerError>,
+{
+	handler: H,
+	_phantom: PhantomData<(R, S)>,
+}
+
+impl<R, S, H, Fut, E> Handler<S> for FallibleHandler<R, S, H, Fut, E>
+where
+	R: Request,
+	S: Clone + Send + Sync + 'static,
+	H: Fn(S, R) -> Fut + Send + Sync,
+	Fut: Future<Output = Result<R::Response, E>> + Send,
+	E: Into<HandlerError>,
+{
+	fn call(&self, state: S, raw: RawRequest) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+		Box::pin(async move {
+			let RawRequest {
+				formats, payload, ..
+			} = raw;
 
-			stream
-				.write_all(&response_bytes)
+			let request: R = formats.decode_request(R::ROUTE_ID, &payload)?;
+
+			let response = (self.handler)(state, request)
 				.await
-				.map_err(|e| Error::Writing(CodingKey::Payload, e))?;
+				.map_err(|e| Error::Handler(e.into()))?;
+
+			formats.encode_response(R::ROUTE_ID, &response)
+		})
+	}
+}
+
+/// The counterpart of [`TypedHandler`] for handlers borrowing the state rather than owning it.
+///
+/// The state is cloned for every request as usual, but it lives in the adapter's future, so
+/// the handler's future can borrow it across awaits.
+struct RefHandler<R, S, H>
+where
+	R: Request,
+	H: for<'a> Fn(&'a S, R) -> BoxFuture<'a, R::Response> + Send + Sync,
+{
+	handler: H,
+	_phantom: PhantomData<(R, S)>,
+}
+
+impl<R, S, H> Handler<S> for RefHandler<R, S, H>
+where
+	R: Request,
+	S: Clone + Send + Sync + 'static,
+	H: for<'a> Fn(&'a S, R) -> BoxFuture<'a, R::Response> + Send + Sync,
+{
+	fn call(&self, state: S, raw: RawRequest) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+		Box::pin(async move {
+			let RawRequest {
+				formats, payload, ..
+			} = raw;
+
+			let request: R = formats.decode_request(R::ROUTE_ID, &payload)?;
+
+			let response = (self.handler)(&state, request).await;
+
+			formats.encode_response(R::ROUTE_ID, &response)
+		})
+	}
+}
+
+/// The counterpart of [`TypedHandler`] for handlers that are also told who sent the request.
+struct InfoHandler<R, S, H, Fut>
+where
+	R: Request,
+	H: Fn(S, ConnectionInfo, R) -> Fut + Send + Sync,
+	Fut: Future<Output = R::Response> + Send,
+{
+	handler: H,
+	_phantom: PhantomData<(R, S)>,
+}
+
+impl<R, S, H, Fut> Handler<S> for InfoHandler<R, S, H, Fut>
+where
+	R: Request,
+	S: Clone + Send + Sync + 'static,
+	H: Fn(S, ConnectionInfo, R) -> Fut + Send + Sync,
+	Fut: Future<Output = R::Response> + Send,
+{
+	fn call(&self, state: S, raw: RawRequest) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+		Box::pin(async move {
+			let RawRequest {
+				formats,
+				payload,
+				ctx,
+				..
+			} = raw;
+
+			let request: R = formats.decode_request(R::ROUTE_ID, &payload)?;
+
+			let response = (self.handler)(state, ctx.peer(), request).await;
+
+			formats.encode_response(R::ROUTE_ID, &response)
+		})
+	}
+}
+
+/// The counterpart of [`TypedHandler`] for handlers taking extractors, see
+/// [`Router::route_extract`].
+struct ExtractHandler<R, S, E, H, Fut>
+where
+	R: Request,
+	E: FromContext<S>,
+	H: Fn(E, R) -> Fut + Send + Sync,
+	Fut: Future<Output = R::Response> + Send,
+{
+	handler: H,
+	_phantom: PhantomData<(R, S)>,
+	_extracted: PhantomData<fn() -> E>, // Extractors are produced, not held
+}
+
+impl<R, S, E, H, Fut> Handler<S> for ExtractHandler<R, S, E, H, Fut>
+where
+	R: Request,
+	S: Clone + Send + Sync + 'static,
+	E: FromContext<S>,
+	H: Fn(E, R) -> Fut + Send + Sync,
+	Fut: Future<Output = R::Response> + Send,
+{
+	fn call(&self, state: S, raw: RawRequest) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+		Box::pin(async move {
+			let RawRequest {
+				formats,
+				payload,
+				ctx,
+				..
+			} = raw;
+
+			// Extractors run before decoding, so a refused request isn't decoded for nothing.
+			let extracted = E::from_context(&Context {
+				state: &state,
+				request: &ctx,
+			})?;
 
-			Ok(())
+			let request: R = formats.decode_request(R::ROUTE_ID, &payload)?;
+
+			let response = (self.handler)(extracted, request).await;
+
+			formats.encode_response(R::ROUTE_ID, &response)
 		})
 	}
 }
 
+/// A request for a type ID no route was registered for, as given to [`Router::on_unknown`].
+///
+/// Its payload is read in full, checked against its checksum and decompressed, like any
+/// other, but it can't be decoded without knowing its type.
+#[derive(Debug)]
+pub struct Unrouted {
+	/// The type ID the request was sent with.
+	pub type_id: TypeId,
+	/// The client that sent the request.
+	pub peer: ConnectionInfo,
+	/// The metadata headers sent along with the request.
+	pub metadata: Metadata,
+	/// The request payload, still encoded.
+	pub payload: Vec<u8>,
+	/// The format the client expects the response in.
+	pub response_format: Format,
+}
+
+/// The handler of requests for unknown type IDs, see [`Router::on_unknown`].
+struct FallbackHandler<S, H, Fut>
+where
+	H: Fn(S, Unrouted) -> Fut + Send + Sync,
+	Fut: Future<Output = Result<Vec<u8>, Error>> + Send,
+{
+	handler: H,
+	_phantom: PhantomData<S>,
+}
+
+impl<S, H, Fut> Handler<S> for FallbackHandler<S, H, Fut>
+where
+	S: Clone + Send + Sync + 'static,
+	H: Fn(S, Unrouted) -> Fut + Send + Sync,
+	Fut: Future<Output = Result<Vec<u8>, Error>> + Send,
+{
+	fn call(&self, state: S, raw: RawRequest) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+		let RawRequest {
+			formats,
+			metadata,
+			payload,
+			ctx,
+		} = raw;
+
+		Box::pin((self.handler)(
+			state,
+			Unrouted {
+				type_id: ctx.type_id(),
+				peer: ctx.peer(),
+				metadata,
+				payload,
+				response_format: formats.response,
+			},
+		))
+	}
+}
+
+/// A handler for requests of type `R`, registered with [`Router::route_fn`].
+///
+/// This is implemented for every `async fn(S, R) -> R::Response`, and every closure of the
+/// same shape, so it rarely needs implementing by hand. Taking handlers through this trait
+/// lets the compiler infer the handler and future types, and blame the handler itself when
+/// its signature is off.
+#[diagnostic::on_unimplemented(
+	message = "`{Self}` is not a handler for `{R}` requests",
+	label = "expected an `async fn(state, {R}) -> <{R} as Request>::Response`",
+	note = "handlers take the router's state and the request, and resolve to the request's `Response` type"
+)]
+pub trait RouteHandler<S, R: Request>: Send + Sync + 'static {
+	/// The future resolving to the response.
+	type Future: Future<Output = R::Response> + Send + 'static;
+
+	/// Handle a request.
+	fn call(&self, state: S, request: R) -> Self::Future;
+}
+
+impl<S, R, F, Fut> RouteHandler<S, R> for F
+where
+	R: Request,
+	F: Fn(S, R) -> Fut + Send + Sync + 'static,
+	Fut: Future<Output = R::Response> + Send + 'static,
+{
+	type Future = Fut;
+
+	fn call(&self, state: S, request: R) -> Fut {
+		self(state, request)
+	}
+}
+
 /// The main routing system that directs incoming requests to the appropriate handlers.
 ///
 /// # How It Works
@@ -160,7 +906,7 @@ where
 ///
 /// # Type Erasure Explained
 ///
-/// The `Box<dyn Handler<S>>` type means "a box containing any type that implements `Handler`".
+/// The `Arc<dyn Handler<S>>` type means "a shared pointer to any type that implements `Handler`".
 /// This is how we store handlers for different request types in the same `HashMap`.
 /// It's like having a filing cabinet where each drawer (handler) processes different
 /// paperwork (request types), but they all fit in the same cabinet (`HashMap`).
@@ -172,9 +918,37 @@ where
 /// - `Router::with_state(state)` creates a stateful router (`Router<S>`)
 ///
 /// **Warning**: Use `Arc<S>` for expensive states.
+#[allow(
+	clippy::struct_excessive_bools,
+	reason = "independent settings, each toggled by its own builder method"
+)]
 pub struct Router<S = ()> {
-	routes: HashMap<u32, Box<dyn Handler<S>>>, // Maps type IDs to their handlers
+	routes: RouteMap<S>,                       // Maps type IDs to their handlers
+	route_ids: BTreeMap<TypeId, &'static str>, // Maps type IDs to the route IDs they hash
+	registrations: Vec<RouteRegistration>,     // Every route registered, checked by `build`
+	layers: Vec<Arc<dyn Layer>>,               // Wrapped around every handler, outermost first
+	observer: Arc<dyn Observer>,               // Told about every routed request
+	gate: Option<Gate>,                        // Decides which routes are served at all
 	state: S,                                  // Shared application state
+	format: Format,                            // Payload format clients must agree with
+	reject_policy: RejectPolicy,               // What to do with payloads of rejected requests
+	stats: StatsHandle,                        // Counters shared with stats handles
+	dispatch: DispatchModel,                   // How accepted connections reach handlers
+	max_payload_bytes: u64,                    // Largest request payload that gets read
+	presize_responses: bool,                   // Whether responses are sized before being encoded
+	timeout: Option<Duration>,                 // How long each phase of a connection may take
+	max_concurrent: Option<usize>,             // How many connections can be open at once
+	capacity_warning: Option<Duration>,        // How long waiting for capacity goes unlogged
+	max_streams: usize, // How many requests of a multiplexed connection run at once
+	health: bool,       // Whether `build` registers the health route
+	reflection: bool,   // Whether `build` registers the route listing routes
+	strict: bool,       // Whether serving without routes is an error
+	fallback: Option<Route<S>>, // Handles requests for unknown type IDs
+	stream_config: StreamConfig, // Socket options set on accepted vsock connections
+	#[cfg(feature = "compression")]
+	compression: Option<CompressionConfig>, // When responses get compressed
+	#[cfg(feature = "tls")]
+	tls: Option<ServerTls>, // The TLS session connections are wrapped in
 }
 
 impl Router<()> {
@@ -194,8 +968,32 @@ impl Router<()> {
 	#[must_use]
 	pub fn new() -> Self {
 		Self {
-			routes: HashMap::new(),
+			routes: RouteMap::default(),
+			route_ids: BTreeMap::new(),
+			registrations: Vec::new(),
+			layers: Vec::new(),
+			observer: Arc::new(NoopObserver),
+			gate: None,
 			state: (),
+			format: Format::default(),
+			reject_policy: RejectPolicy::default(),
+			stats: StatsHandle::default(),
+			dispatch: DispatchModel::default(),
+			max_payload_bytes: wire::DEFAULT_MAX_PAYLOAD_BYTES,
+			presize_responses: false,
+			timeout: None,
+			max_concurrent: None,
+			capacity_warning: Some(DEFAULT_CAPACITY_WARNING),
+			max_streams: DEFAULT_MAX_STREAMS,
+			health: false,
+			reflection: false,
+			strict: false,
+			fallback: None,
+			stream_config: StreamConfig::default(),
+			#[cfg(feature = "compression")]
+			compression: None,
+			#[cfg(feature = "tls")]
+			tls: None,
 		}
 	}
 }
@@ -206,6 +1004,42 @@ impl Default for Router<()> {
 	}
 }
 
+/// Clones share their handlers, layers and observer, including the responses cached by
+/// [`Router::route_cached`], so that one configuration can be served on several ports.
+/// Each clone counts its own connections and requests, see [`Router::stats_handle`].
+impl<S: Clone> Clone for Router<S> {
+	fn clone(&self) -> Self {
+		Self {
+			routes: self.routes.clone(),
+			route_ids: self.route_ids.clone(),
+			registrations: self.registrations.clone(),
+			layers: self.layers.clone(),
+			observer: Arc::clone(&self.observer),
+			gate: self.gate.clone(),
+			state: self.state.clone(),
+			format: self.format,
+			reject_policy: self.reject_policy,
+			stats: StatsHandle::default(),
+			dispatch: self.dispatch,
+			max_payload_bytes: self.max_payload_bytes,
+			presize_responses: self.presize_responses,
+			timeout: self.timeout,
+			max_concurrent: self.max_concurrent,
+			capacity_warning: self.capacity_warning,
+			max_streams: self.max_streams,
+			health: self.health,
+			reflection: self.reflection,
+			strict: self.strict,
+			fallback: self.fallback.clone(),
+			stream_config: self.stream_config,
+			#[cfg(feature = "compression")]
+			compression: self.compression,
+			#[cfg(feature = "tls")]
+			tls: self.tls.clone(),
+		}
+	}
+}
+
 impl<S> Router<S>
 where
 	S: Clone + Send + Sync + 'static,
@@ -225,118 +1059,2999 @@ where
 	#[must_use]
 	pub fn with_state(state: S) -> Self {
 		Self {
-			routes: HashMap::new(),
+			routes: RouteMap::default(),
+			route_ids: BTreeMap::new(),
+			registrations: Vec::new(),
+			layers: Vec::new(),
+			observer: Arc::new(NoopObserver),
+			gate: None,
 			state,
+			format: Format::default(),
+			reject_policy: RejectPolicy::default(),
+			stats: StatsHandle::default(),
+			dispatch: DispatchModel::default(),
+			max_payload_bytes: wire::DEFAULT_MAX_PAYLOAD_BYTES,
+			presize_responses: false,
+			timeout: None,
+			max_concurrent: None,
+			capacity_warning: Some(DEFAULT_CAPACITY_WARNING),
+			max_streams: DEFAULT_MAX_STREAMS,
+			health: false,
+			reflection: false,
+			strict: false,
+			fallback: None,
+			stream_config: StreamConfig::default(),
+			#[cfg(feature = "compression")]
+			compression: None,
+			#[cfg(feature = "tls")]
+			tls: None,
 		}
 	}
 
-	/// Register a handler for a specific request type.
-	///
-	/// This method is type-safe: the compiler ensures that:
-	/// - The handler accepts the correct request type
-	/// - The handler returns the correct response type
-	/// - The types match what the Request trait specifies
+	/// Make room for `routes` more routes, so that registering them doesn't grow the route
+	/// map as it goes.
 	///
-	/// # Example
+	/// This only saves reallocating while the router is built, as lookups cost the same
+	/// however the map was sized. It is worth it for routers registering hundreds of routes.
+	#[must_use]
+	pub fn with_capacity(mut self, routes: usize) -> Self {
+		self.routes.reserve(routes);
+		self.registrations.reserve(routes);
+		self
+	}
+
+	/// Set the payload format used to decode requests and encode responses.
 	///
-	/// ```rust,ignore
-	/// router.route::<HealthCheck>(|state, req| async move {
-	///     // state is AppState - cheap to clone!
-	///     // Access fields with state.field_name
-	///     HealthStatus { ok: true }
-	/// })
-	/// ```
+	/// Clients announce their own format when connecting, and are refused with
+	/// `Error::CodecMismatch` if it isn't compatible with this one. Individual requests
+	/// may still ask for a different response format, see `ConnectionDetails::with_response_format`.
 	#[must_use]
-	pub fn route<R, H, Fut>(mut self, handler: H) -> Self
-	where
-		R: Request,
-		H: Fn(S, R) -> Fut + Send + Sync + 'static,
-		Fut: Future<Output = R::Response> + Send + 'static,
-	{
-		let type_id = R::type_id();
-		tracing::debug!(
-			route_id = R::ROUTE_ID,
-			type_id = format!("0x{:08x}", type_id),
-			"Registering route"
-		);
+	pub const fn format(mut self, format: Format) -> Self {
+		self.format = format;
+		self
+	}
 
-		// Step 1: Wrap the user's typed handler in our adapter.
-		// This preserves type information while providing a common interface.
-		let typed_adapter = TypedHandler {
-			handler,
-			_phantom: PhantomData::<(R, S)>,
-		};
+	/// Set what happens to the payload of requests for unknown routes.
+	///
+	/// Defaults to [`RejectPolicy::Drain`].
+	#[must_use]
+	pub const fn reject_policy(mut self, policy: RejectPolicy) -> Self {
+		self.reject_policy = policy;
+		self
+	}
 
-		// Step 2: Box the adapter as a trait object.
-		// This "erases" the specific type, allowing storage in the HashMap.
-		// The adapter still knows the real types internally.
-		let boxed: Box<dyn Handler<S>> = Box::new(typed_adapter);
+	/// Set the largest request payload the router accepts, in bytes.
+	///
+	/// Requests declaring a longer payload are refused with `Error::PayloadTooLarge` before
+	/// anything is allocated for them. Defaults to [`wire::DEFAULT_MAX_PAYLOAD_BYTES`].
+	#[must_use]
+	pub const fn max_payload(mut self, bytes: u64) -> Self {
+		self.max_payload_bytes = bytes;
+		self
+	}
 
-		// Step 3: Store the handler, indexed by its type ID for fast lookup
-		self.routes.insert(type_id, boxed);
+	/// Size responses before encoding them, so that they are encoded into a buffer of exactly
+	/// their size.
+	///
+	/// Responses are otherwise encoded into a buffer that grows as needed, which copies what
+	/// was encoded so far every time it grows, and can end up with up to twice the memory the
+	/// response takes. Sizing them first means serializing them twice, so this is worth it for
+	/// routers sending large responses out of a memory-constrained enclave, rather than by
+	/// default. It also applies to the items of streamed responses. Defaults to `false`.
+	#[must_use]
+	pub const fn presize_responses(mut self, presize: bool) -> Self {
+		self.presize_responses = presize;
 		self
 	}
 
-	/// Start serving requests on the specified port.
+	/// Refuse to serve without any routes registered, with `Error::NoRoutes`.
 	///
-	/// # Errors
+	/// A router without routes answers every request with `Error::UnknownRequest`, which
+	/// clients can't tell apart from sending the wrong route IDs, so serving one is most
+	/// likely a mistake. It is only logged as a warning by default. Built-in routes, such
+	/// as the [health route](Self::with_health), don't count.
+	#[must_use]
+	pub const fn strict(mut self) -> Self {
+		self.strict = true;
+		self
+	}
+
+	/// Give up on connections whose reading, handling or writing phase takes longer than `timeout`.
 	///
-	/// - `Error::Bind`: Failed to bind to the vsock address
-	/// - `Error::Accept`: Failed to accept incoming connection
-	/// - `Error::Nsm`: Failed to connect to NSM (if feature enabled)
-	pub async fn serve(self, port: u32) -> Result<(), Error> {
-		let listener =
-			VsockListener::bind(VsockAddr::new(VMADDR_CID_ANY, port)).map_err(Error::Bind)?;
+	/// Each phase gets the full `timeout`: a client stalling while sending its request fails
+	/// with `Error::Timeout(TimeoutPhase::Reading)`, while a slow handler fails with
+	/// `Error::Timeout(TimeoutPhase::Handling)`. Either way the connection is closed, and
+	/// the client is told about it when possible. On a connection carrying several requests,
+	/// the reading phase also bounds how long it may sit idle between two of them. Every read
+	/// and write of the handshake gets the full `timeout` too, failing like the reading phase.
+	/// By default, connections never time out.
+	#[must_use]
+	pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
 
-		tracing::info!("Router listening on port {port}");
+	/// Keep at most `connections` connections open at once.
+	///
+	/// Once the limit is reached, the server stops accepting until a connection closes, so
+	/// further clients wait in the listener's backlog instead of each holding a task and a
+	/// file descriptor in the enclave. This applies on top of the dispatch model, with queued
+	/// connections counting towards the limit. A limit always allows at least one connection.
+	/// By default, the number of connections is unbounded.
+	#[must_use]
+	pub const fn max_concurrent(mut self, connections: usize) -> Self {
+		self.max_concurrent = Some(connections);
+		self
+	}
 
-		// Initialize the secure module global if the feature is enabled.
-		#[cfg(feature = "nsm")]
-		{
-			crate::nsm::SecureModule::try_init_global()
+	/// Log a warning when the server waits longer than `threshold` for a connection to close,
+	/// having reached [`Router::max_concurrent`], or never with `None`.
+	///
+	/// Warnings go out at most every 10 seconds, each counting the waits left out since the
+	/// previous one. Every wait is reported to the observer regardless, see
+	/// [`Observer::on_capacity_wait`]. Defaults to [`DEFAULT_CAPACITY_WARNING`].
+	#[must_use]
+	pub const fn capacity_warning(mut self, threshold: Option<Duration>) -> Self {
+		self.capacity_warning = threshold;
+		self
+	}
+
+	/// Handle at most `streams` requests of a multiplexed connection at once.
+	///
+	/// Clients opening a `MultiplexedConnection` may send many requests without waiting for
+	/// their responses. Once `streams` of them are being handled, the server stops reading
+	/// the connection until one is answered, so a single client can't queue unbounded work.
+	/// A limit always allows at least one request. Defaults to [`DEFAULT_MAX_STREAMS`].
+	#[must_use]
+	pub const fn max_streams(mut self, streams: usize) -> Self {
+		self.max_streams = streams;
+		self
+	}
+
+	/// Compress responses according to `config`, for clients that accept it.
+	///
+	/// Clients announce the compressions they accept with every request, so responses to
+	/// clients unaware of compression are always sent as is. Compressed requests are
+	/// accepted regardless of this setting, and are refused with `Error::Decompression` if
+	/// they inflate beyond the [maximum payload size](Self::max_payload).
+	/// By default, responses are never compressed.
+	#[cfg(feature = "compression")]
+	#[must_use]
+	pub const fn compression(mut self, config: CompressionConfig) -> Self {
+		self.compression = Some(config);
+		self
+	}
+
+	/// Set `config`'s socket options on every vsock connection accepted, before reading
+	/// from it.
+	///
+	/// Connections whose options can't be set are dropped. Clients set their own options,
+	/// see `ConnectionDetails::with_stream_config`.
+	#[must_use]
+	pub const fn with_stream_config(mut self, config: StreamConfig) -> Self {
+		self.stream_config = config;
+		self
+	}
+
+	/// Terminate a TLS session on every accepted connection, according to `tls`.
+	///
+	/// Requests and responses are then exchanged through the session, so clients must
+	/// connect with a matching `ConnectionDetails::with_tls`. Clients that don't are dropped
+	/// once the TLS handshake fails, without an error frame, as there is no session to send
+	/// it through. The handshake is bounded by the router's timeout, like reading a request,
+	/// and by [`TLS_HANDSHAKE_TIMEOUT`] even without one.
+	///
+	/// Connection limits are still enforced before the handshake, but everything checked on
+	/// requests themselves, such as [`wire::MAX_METADATA_BYTES`], only once the session is
+	/// established.
+	#[cfg(feature = "tls")]
+	#[must_use]
+	pub fn with_tls(mut self, tls: ServerTls) -> Self {
+		self.tls = Some(tls);
+		self
+	}
+
+	/// Answer [`HealthCheck`] requests, so that tooling can probe any pontifex server uniformly.
+	///
+	/// The route reports the crate version, how long the server has been running and how
+	/// many application routes it handles. It is registered by [`Router::build`] under a
+	/// reserved route ID, and left out of [`Router::debug_routes`]. By default, routers
+	/// don't answer health checks.
+	#[must_use]
+	pub const fn with_health(mut self) -> Self {
+		self.health = true;
+		self
+	}
+
+	/// Answer [`ListRoutes`] requests with the route IDs of the router's application routes.
+	///
+	/// This lets clients discover the routes a server handles, for example to check at
+	/// deploy time that both sides agree on them. Like the [health route](Self::with_health),
+	/// it is registered by [`Router::build`] under a reserved route ID. By default, routers
+	/// don't list their routes.
+	#[must_use]
+	pub const fn with_reflection(mut self) -> Self {
+		self.reflection = true;
+		self
+	}
+
+	/// Wrap every request handled by the router in `layer`.
+	///
+	/// Layers run in registration order, each wrapping the ones registered after it, with
+	/// the handler innermost. They apply to every route, and see the route ID of a request
+	/// but not its decoded payload, see [`Layer`]. For streaming routes, layers run before the
+	/// stream starts, with an empty response, so they can still refuse the request.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// let router = Router::new()
+	///     .layer(TracingLayer)
+	///     .layer(ParentOnly)
+	///     .route::<HealthCheck, _, _>(|_state, _req| async { HealthStatus { ok: true } });
+	/// ```
+	#[must_use]
+	pub fn layer(mut self, layer: impl Layer) -> Self {
+		self.layers.push(Arc::new(layer));
+		self
+	}
+
+	/// Report every request handled by the router to `observer`, for per-route metrics.
+	///
+	/// The observer is told when a request is routed, and how handling it went: the size
+	/// of the response and how long the handler took, or the error it failed with. Only one
+	/// observer is kept, so this replaces any previous one. By default, nothing is observed.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// let router = Router::new()
+	///     .observe(Latency)
+	///     .route::<HealthCheck, _, _>(|_state, _req| async { HealthStatus { ok: true } });
+	/// ```
+	#[must_use]
+	pub fn observe(mut self, observer: impl Observer) -> Self {
+		self.observer = Arc::new(observer);
+		self
+	}
+
+	/// Only serve the requests for which `gate` returns `true`, given their type ID and route
+	/// ID.
+	///
+	/// The gate is consulted as soon as a request is routed, before its payload is read, so
+	/// refusing requests costs next to nothing: use it for rate limiting, feature flags or a
+	/// maintenance mode without touching individual handlers. Refused requests are answered
+	/// with `Error::Gated`, then the connection is closed without reading their payload,
+	/// whatever the router's [`RejectPolicy`]. Built-in routes, such as the
+	/// [health route](Self::with_health), are gated too. Only one gate is kept, so this
+	/// replaces any previous one. By default, every route is served.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// let maintenance = Arc::new(AtomicBool::new(false));
+	///
+	/// let router = Router::new()
+	///     .gate({
+	///         let maintenance = maintenance.clone();
+	///         move |_type_id, route_id| {
+	///             !maintenance.load(Ordering::Relaxed) || route_id.starts_with(RESERVED_ROUTE_PREFIX)
+	///         }
+	///     })
+	///     .route::<Transfer, _, _>(transfer);
+	/// ```
+	#[must_use]
+	pub fn gate(mut self, gate: impl Fn(TypeId, &str) -> bool + Send + Sync + 'static) -> Self {
+		self.gate = Some(Arc::new(gate));
+		self
+	}
+
+	/// Set how accepted connections are handed to handlers.
+	///
+	/// Defaults to [`DispatchModel::SpawnPerConnection`].
+	#[must_use]
+	pub const fn dispatch(mut self, model: DispatchModel) -> Self {
+		self.dispatch = model;
+		self
+	}
+
+	/// Get a handle to the server's counters, which keeps reporting them once the router is serving.
+	#[must_use]
+	pub fn stats_handle(&self) -> StatsHandle {
+		self.stats.clone()
+	}
+
+	/// Record counters into the given handle, rather than a fresh one.
+	///
+	/// This lets handlers report the counters of the server they run in, by creating the
+	/// handle before the router and storing a clone of it in the router's state.
+	#[must_use]
+	pub fn with_stats_handle(mut self, stats: StatsHandle) -> Self {
+		self.stats = stats;
+		self
+	}
+
+	/// Register a handler for a specific request type.
+	///
+	/// This method is type-safe: the compiler ensures that:
+	/// - The handler accepts the correct request type
+	/// - The handler returns the correct response type
+	/// - The types match what the Request trait specifies
+	///
+	/// Registering a request type again replaces its previous handler.
+	///
+	/// Conflicting registrations, such as two request types sharing a route ID, are reported
+	/// by [`Router::build`].
+	///
+	/// See [`Router::route_fn`] to leave out the handler and future types.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// router.route::<HealthCheck, _, _>(|state, req| async move {
+	///     // state is AppState - cheap to clone!
+	///     // Access fields with state.field_name
+	///     HealthStatus { ok: true }
+	/// })
+	/// ```
+	#[must_use]
+	pub fn route<R, H, Fut>(mut self, handler: H) -> Self
+	where
+		R: Request,
+		H: Fn(S, R) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = R::Response> + Send + 'static,
+	{
+		let type_id = R::type_id();
+		tracing::debug!(
+			route_id = R::ROUTE_ID,
+			type_id = format!("0x{:08x}", type_id),
+			"Registering route"
+		);
+
+		// Step 1: Wrap the user's typed handler in our adapter.
+		// This preserves type information while providing a common interface.
+		let typed_adapter = TypedHandler {
+			handler,
+			_phantom: PhantomData::<(R, S)>,
+		};
+
+		// Step 2: Share the adapter as a trait object.
+		// This "erases" the specific type, allowing storage in the HashMap.
+		// The adapter still knows the real types internally.
+		let shared: Arc<dyn Handler<S>> = Arc::new(typed_adapter);
+
+		// Step 3: Store the handler, indexed by its type ID for fast lookup
+		self.insert_route(
+			type_id,
+			R::ROUTE_ID,
+			std::any::type_name::<R>(),
+			Route::Unary(shared),
+		);
+		self
+	}
+
+	/// Register a handler for a specific request type, naming only the request type.
+	///
+	/// Works like [`Router::route`], but takes any [`RouteHandler`], so that the handler and
+	/// future types are inferred rather than spelled out as `_`. A handler with the wrong
+	/// signature is reported as not being a [`RouteHandler`] for the request type, rather
+	/// than as a mismatch between `Fn` bounds.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// async fn handle_echo(state: Arc<AppState>, req: Echo) -> EchoResponse {
+	///     EchoResponse { message: req.message }
+	/// }
+	///
+	/// router.route_fn::<Echo>(handle_echo)
+	/// ```
+	#[must_use]
+	pub fn route_fn<R: Request>(self, handler: impl RouteHandler<S, R>) -> Self {
+		self.route::<R, _, _>(move |state, request| handler.call(state, request))
+	}
+
+	/// Register a blocking handler, for requests answered by CPU-heavy work.
+	///
+	/// Works like [`Router::route_fn`], but the handler is a plain function rather than an
+	/// `async` one, and runs on tokio's blocking thread pool with `spawn_blocking`. Signing,
+	/// or hashing large buffers, can then take as long as it needs without stalling the
+	/// other connections served by the same worker thread. Decoding the request and encoding
+	/// the response still happen on the async side.
+	///
+	/// The handler is moved to the blocking thread pool, so it must be `Send` and `'static`,
+	/// as must the router's state. The router's timeout still applies, but a handler that
+	/// times out keeps running until it returns, as blocking work can't be cancelled.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// router.route_blocking::<Sign>(|state: Arc<AppState>, req| SignResponse {
+	///     signature: state.key.sign(&req.message),
+	/// })
+	/// ```
+	#[must_use]
+	pub fn route_blocking<R>(
+		self,
+		handler: impl Fn(S, R) -> R::Response + Send + Sync + 'static,
+	) -> Self
+	where
+		R: Request,
+		R::Response: 'static,
+	{
+		let handler = Arc::new(handler);
+
+		self.route::<R, _, _>(move |state, request| {
+			let handler = Arc::clone(&handler);
+			let work = layer::propagate(move || handler(state, request));
+
+			async move {
+				// Panics are raised again here, to be reported like those of async handlers.
+				tokio::task::spawn_blocking(work)
+					.await
+					.unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+			}
+		})
+	}
+
+	/// Register a handler answering a request with a stream of items.
+	///
+	/// The handler returns a [`Stream`](futures_util::Stream) rather than a future, and each
+	/// item it yields is sent to the client as soon as the previous one was written, so a
+	/// slow client slows the stream down rather than letting items pile up in memory. Clients
+	/// receive the items with `client::send_stream`.
+	///
+	/// If the stream fails halfway through, for example because an item can't be encoded,
+	/// the connection is closed and the client sees the stream end with an error.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// router.route_stream::<TailLogs, _, _>(|state, req| {
+	///     futures_util::stream::iter(state.logs.since(req.since))
+	/// })
+	/// ```
+	#[must_use]
+	pub fn route_stream<R, H, St>(mut self, handler: H) -> Self
+	where
+		R: StreamingRequest,
+		H: Fn(S, R) -> St + Send + Sync + 'static,
+		St: futures_util::Stream<Item = R::Item> + Send + 'static,
+	{
+		let type_id = R::type_id();
+		tracing::debug!(
+			route_id = R::ROUTE_ID,
+			type_id = format!("0x{:08x}", type_id),
+			"Registering streaming route"
+		);
+
+		let shared: Arc<dyn StreamHandler<S>> = Arc::new(TypedStreamHandler {
+			handler,
+			_phantom: PhantomData::<(R, S)>,
+			_streamed: PhantomData::<fn() -> St>,
+		});
+
+		self.insert_route(
+			type_id,
+			R::ROUTE_ID,
+			std::any::type_name::<R>(),
+			Route::Streaming(shared),
+		);
+		self
+	}
+
+	/// Register a handler that is also told which client sent each request.
+	///
+	/// Works like [`Router::route`], except that the handler gets the [`ConnectionInfo`] of
+	/// the connection the request arrived on. Since the vsock CID of a peer is assigned by
+	/// the hypervisor rather than claimed by the peer, this lets sensitive routes only answer
+	/// a known caller, such as the parent instance.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// router.route_with_info::<RotateKeys, _, _>(|state, peer, req| async move {
+	///     if peer.cid != PARENT_CID {
+	///         return RotateKeysResponse::Forbidden;
+	///     }
+	///
+	///     state.rotate_keys(req).await
+	/// })
+	/// ```
+	#[must_use]
+	pub fn route_with_info<R, H, Fut>(mut self, handler: H) -> Self
+	where
+		R: Request,
+		H: Fn(S, ConnectionInfo, R) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = R::Response> + Send + 'static,
+	{
+		tracing::debug!(
+			route_id = R::ROUTE_ID,
+			type_id = format!("0x{:08x}", R::type_id()),
+			"Registering route with connection info"
+		);
+
+		let shared: Arc<dyn Handler<S>> = Arc::new(InfoHandler {
+			handler,
+			_phantom: PhantomData::<(R, S)>,
+		});
+
+		self.insert_route(
+			R::type_id(),
+			R::ROUTE_ID,
+			std::any::type_name::<R>(),
+			Route::Unary(shared),
+		);
+		self
+	}
+
+	/// Register a handler taking extractors, such as the state and the client, as a tuple.
+	///
+	/// Works like [`Router::route`], except that the handler's first argument is anything
+	/// implementing [`FromContext`], usually a tuple of [`State`], [`Peer`], [`RequestId`]
+	/// and the like, which is extracted for every request before the handler is called. A
+	/// handler then only declares what it needs, however many things that is, and an
+	/// application's own extractors can do checks shared by many routes.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// async fn rotate_keys(
+	///     (State(state), Peer(peer), RequestId(id)): (State<AppState>, Peer, RequestId),
+	///     req: RotateKeys,
+	/// ) -> RotateKeysResponse {
+	///     tracing::info!(%id, cid = peer.cid, "rotating keys");
+	///     state.rotate_keys(req).await
+	/// }
+	///
+	/// router.route_extract::<RotateKeys, _, _, _>(rotate_keys)
+	/// ```
+	#[must_use]
+	pub fn route_extract<R, E, H, Fut>(mut self, handler: H) -> Self
+	where
+		R: Request,
+		E: FromContext<S> + 'static,
+		H: Fn(E, R) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = R::Response> + Send + 'static,
+	{
+		tracing::debug!(
+			route_id = R::ROUTE_ID,
+			type_id = format!("0x{:08x}", R::type_id()),
+			"Registering route with extractors"
+		);
+
+		let shared: Arc<dyn Handler<S>> = Arc::new(ExtractHandler {
+			handler,
+			_phantom: PhantomData::<(R, S)>,
+			_extracted: PhantomData,
+		});
+
+		self.insert_route(
+			R::type_id(),
+			R::ROUTE_ID,
+			std::any::type_name::<R>(),
+			Route::Unary(shared),
+		);
+		self
+	}
+
+	/// Handle requests for type IDs no route was registered for with `handler`, rather than
+	/// refusing them with `Error::UnknownRequest`.
+	///
+	/// The handler gets the request's type ID and its payload, still encoded, and returns
+	/// either the encoded response or the error to answer with. Rolling out a new route to
+	/// clients before every server has it, this lets older servers answer it with a
+	/// structured "unsupported" error, or a response that clients understand, instead of
+	/// closing the connection. Layers, the gate and the router's timeout apply as for any
+	/// route, with an empty route ID.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// router.on_unknown(|_state, unrouted| async move {
+	///     let error = HandlerError::new(format!("0x{:08x} isn't supported", unrouted.type_id));
+	///     Err(Error::Handler(error.with_code(UNSUPPORTED)))
+	/// })
+	/// ```
+	#[must_use]
+	pub fn on_unknown<H, Fut>(mut self, handler: H) -> Self
+	where
+		H: Fn(S, Unrouted) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = Result<Vec<u8>, Error>> + Send + 'static,
+	{
+		self.fallback = Some(Route::Unary(Arc::new(FallbackHandler {
+			handler,
+			_phantom: PhantomData,
+		})));
+		self
+	}
+
+	/// Register a handler that borrows the state instead of taking its own copy.
+	///
+	/// Works like [`Router::route`], except that the handler gets a `&S` that it may hold
+	/// across awaits, which saves cloning out of the state in handlers that only read it.
+	/// Since the returned future borrows the state, it has to be boxed, for example with
+	/// `Box::pin`. Use [`Router::route`] for handlers that move the state into spawned tasks.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// router.route_ref::<GetConfig, _>(|state, req| {
+	///     Box::pin(async move { state.config.get(&req.key).await })
+	/// })
+	/// ```
+	#[must_use]
+	pub fn route_ref<R, H>(mut self, handler: H) -> Self
+	where
+		R: Request,
+		H: for<'a> Fn(&'a S, R) -> BoxFuture<'a, R::Response> + Send + Sync + 'static,
+	{
+		tracing::debug!(
+			route_id = R::ROUTE_ID,
+			type_id = format!("0x{:08x}", R::type_id()),
+			"Registering route borrowing state"
+		);
+
+		let shared: Arc<dyn Handler<S>> = Arc::new(RefHandler {
+			handler,
+			_phantom: PhantomData::<(R, S)>,
+		});
+
+		self.insert_route(
+			R::type_id(),
+			R::ROUTE_ID,
+			std::any::type_name::<R>(),
+			Route::Unary(shared),
+		);
+		self
+	}
+
+	/// Register a fallible handler for a specific request type.
+	///
+	/// Works like [`Router::route`], except that the handler returns a `Result`. When it
+	/// fails, its error is converted into a [`HandlerError`] and sent to the client in an
+	/// error frame, where it surfaces as a remote error carrying the same code and message.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// router.try_route::<GetBalance, HandlerError, _, _>(|state, req| async move {
+	///     let account = state.accounts.get(&req.id).ok_or("unknown account")?;
+	///     Ok(Balance { amount: account.balance })
+	/// });
+	/// ```
+	#[must_use]
+	pub fn try_route<R, E, H, Fut>(mut self, handler: H) -> Self
+	where
+		R: Request,
+		E: Into<HandlerError> + 'static,
+		H: Fn(S, R) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = Result<R::Response, E>> + Send + 'static,
+	{
+		let type_id = R::type_id();
+		tracing::debug!(
+			route_id = R::ROUTE_ID,
+			type_id = format!("0x{:08x}", type_id),
+			"Registering fallible route"
+		);
+
+		let shared: Arc<dyn Handler<S>> = Arc::new(FallibleHandler {
+			handler,
+			_phantom: PhantomData::<(R, S)>,
+		});
+
+		self.insert_route(
+			type_id,
+			R::ROUTE_ID,
+			std::any::type_name::<R>(),
+			Route::Unary(shared),
+		);
+		self
+	}
+
+	/// Register a handler whose responses are cached, keyed on the serialized request bytes.
+	///
+	/// Two requests that serialize to the same bytes are considered identical, so a cached
+	/// response is served without running the handler again as long as it is younger than
+	/// `ttl`. At most `max_entries` responses are kept, taking up at most [`MAX_CACHED_BYTES`]
+	/// along with their keys; when the cache is full the least recently used entries are
+	/// evicted. Responses too large to ever fit aren't cached.
+	///
+	/// Only use this for handlers that are deterministic and free of side effects: a cached
+	/// response is returned even if the handler would have answered differently. Clients can
+	/// still bypass the cache for a single request with the [`Metadata::NO_CACHE`] header.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// router.route_cached::<GetConfig, _, _>(
+	///     |state, req| async move { state.config_for(req.key) },
+	///     Duration::from_secs(60),
+	///     1024,
+	/// )
+	/// ```
+	#[must_use]
+	pub fn route_cached<R, H, Fut>(self, handler: H, ttl: Duration, max_entries: usize) -> Self
+	where
+		R: Request,
+		H: Fn(S, R) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = R::Response> + Send + 'static,
+	{
+		let type_id = R::type_id();
+		tracing::debug!(
+			route_id = R::ROUTE_ID,
+			type_id = format!("0x{:08x}", type_id),
+			ttl = ?ttl,
+			max_entries,
+			"Registering cached route"
+		);
+
+		// Same as `route`, but the adapter sits behind a cache that short-circuits repeated payloads.
+		self.route_behind_cache::<R, H, Fut>(handler, ttl, max_entries, CacheKey::Payload)
+	}
+
+	/// Register a handler that runs at most once per idempotency key.
+	///
+	/// Clients that retry requests can attach an idempotency key with
+	/// [`Metadata::with_idempotency_key`]. The first request carrying a key runs the handler,
+	/// and any request with the same key arriving within `ttl` gets that same response back
+	/// instead of running it again, which makes retrying safe even for handlers with side
+	/// effects. Requests without a key are always handled.
+	///
+	/// Keys are scoped to this route and to the CID of the client sending them, and only the
+	/// last [`MAX_IDEMPOTENCY_KEYS`] are remembered, within [`MAX_CACHED_BYTES`]. A request
+	/// reusing a key with a different payload is refused with [`Error::IdempotencyKeyReused`]
+	/// rather than answered with the response to another request. A duplicate that arrives
+	/// while the first request is still being handled isn't recognized, so clients should
+	/// only retry once the previous attempt has failed.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// router.route_idempotent::<ChargeCard, _, _>(
+	///     |state, req| async move { state.charge(req.card, req.amount).await },
+	///     Duration::from_secs(300),
+	/// )
+	/// ```
+	#[must_use]
+	pub fn route_idempotent<R, H, Fut>(self, handler: H, ttl: Duration) -> Self
+	where
+		R: Request,
+		H: Fn(S, R) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = R::Response> + Send + 'static,
+	{
+		tracing::debug!(
+			route_id = R::ROUTE_ID,
+			type_id = format!("0x{:08x}", R::type_id()),
+			ttl = ?ttl,
+			"Registering idempotent route"
+		);
+
+		self.route_behind_cache::<R, H, Fut>(
+			handler,
+			ttl,
+			MAX_IDEMPOTENCY_KEYS,
+			CacheKey::IdempotencyKey,
+		)
+	}
+
+	fn route_behind_cache<R, H, Fut>(
+		mut self,
+		handler: H,
+		ttl: Duration,
+		max_entries: usize,
+		key: CacheKey,
+	) -> Self
+	where
+		R: Request,
+		H: Fn(S, R) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = R::Response> + Send + 'static,
+	{
+		let typed_adapter = TypedHandler {
+			handler,
+			_phantom: PhantomData::<(R, S)>,
+		};
+
+		let cached = CachedHandler::new(Box::new(typed_adapter), ttl, max_entries, key);
+
+		self.insert_route(
+			R::type_id(),
+			R::ROUTE_ID,
+			std::any::type_name::<R>(),
+			Route::Unary(Arc::new(cached)),
+		);
+		self
+	}
+
+	/// Add the routes of `other` to this router, for services split into route groups.
+	///
+	/// Routes are merged as if they were registered on this router after its own, so a
+	/// request type registered on both ends up with the handler of `other`. Conflicts
+	/// between the two routers, such as different request types sharing a route ID, are
+	/// reported by [`Router::build`] like any other, and leave the route to this router's
+	/// handler until then. Only the routes of `other` are taken: its state, layers and
+	/// settings are ignored.
+	///
+	/// # Example
+	///
+	/// ```rust,ignore
+	/// let router = Router::with_state(state.clone())
+	///     .merge(auth::routes(state.clone()))
+	///     .merge(admin::routes(state))
+	///     .layer(TracingLayer);
+	/// ```
+	#[must_use]
+	pub fn merge(mut self, mut other: Self) -> Self {
+		for registration in other.registrations {
+			// Only the first registration of a type ID holds its route, see `insert_route`.
+			let Some(route) = other.routes.remove(&registration.type_id) else {
+				self.registrations.push(registration);
+				continue;
+			};
+
+			self.insert_route(
+				registration.type_id,
+				registration.route_id,
+				registration.type_name,
+				route,
+			);
+		}
+		self
+	}
+
+	/// Store a route under its type ID, replacing any handler of the same request type.
+	///
+	/// Conflicts with the routes registered so far aren't checked here, but by
+	/// [`Router::build`], so that they can all be reported at once. Until then, a type ID
+	/// keeps the handler and route ID of the first request type registered under it.
+	fn insert_route(
+		&mut self,
+		type_id: TypeId,
+		route_id: &'static str,
+		type_name: &'static str,
+		route: Route<S>,
+	) {
+		let owner = self.route_owner(type_id);
+		self.registrations.push(RouteRegistration {
+			type_id,
+			route_id,
+			type_name,
+		});
+
+		if owner.is_none_or(|owner| owner == type_name) {
+			self.route_ids.insert(type_id, route_id);
+			self.routes.insert(type_id, route);
+		}
+	}
+
+	/// The request type holding the route of a type ID, if any.
+	fn route_owner(&self, type_id: TypeId) -> Option<&'static str> {
+		if !self.routes.contains_key(&type_id) {
+			return None;
+		}
+
+		self.registrations
+			.iter()
+			.find(|registration| registration.type_id == type_id)
+			.map(|registration| registration.type_name)
+	}
+
+	/// Wrap the router's layers around a handler's response.
+	fn layered<'a>(
+		&'a self,
+		ctx: &'a RequestContext,
+		handler: BoxFuture<'a, Result<Vec<u8>, Error>>,
+	) -> BoxFuture<'a, Result<Vec<u8>, Error>> {
+		Next {
+			layers: &self.layers,
+			ctx,
+			handler,
+		}
+		.run()
+	}
+
+	/// Check that the registered routes can all be told apart, before serving them.
+	///
+	/// Every problem found is reported at once: request types sharing a route ID, where one
+	/// would silently shadow the other, empty route IDs, different route IDs hashing to the
+	/// same type ID, and more than [`MAX_ROUTES`] routes. [`Router::serve`] and
+	/// [`Router::spawn`] build the router themselves, so calling this is only needed to catch
+	/// these problems before then, such as in tests.
+	///
+	/// # Errors
+	///
+	/// Returns a [`BuildError`] listing every conflict between the registered routes.
+	pub fn build(mut self) -> Result<Self, BuildError> {
+		if self.health && !self.routes.contains_key(&HealthCheck::type_id()) {
+			self = self.with_health_route();
+		}
+		if self.reflection && !self.routes.contains_key(&ListRoutes::type_id()) {
+			self = self.with_reflection_route();
+		}
+
+		let builtin = [
+			std::any::type_name::<HealthCheck>(),
+			std::any::type_name::<ListRoutes>(),
+		];
+
+		let mut problems = Vec::new();
+		let mut by_route_id: BTreeMap<&str, &RouteRegistration> = BTreeMap::new();
+		let mut by_type_id: BTreeMap<TypeId, &RouteRegistration> = BTreeMap::new();
+
+		for registration in &self.registrations {
+			if registration.route_id.is_empty() {
+				problems.push(RouteProblem::EmptyRouteId {
+					type_name: registration.type_name,
+				});
+			}
+
+			if registration.route_id.starts_with(RESERVED_ROUTE_PREFIX)
+				&& !builtin.contains(&registration.type_name)
+			{
+				problems.push(RouteProblem::ReservedRouteId {
+					route_id: registration.route_id,
+					type_name: registration.type_name,
+				});
+			}
+
+			// Registering the same request type again only replaces its handler.
+			let first = *by_route_id
+				.entry(registration.route_id)
+				.or_insert(registration);
+			if first.type_name != registration.type_name {
+				problems.push(RouteProblem::DuplicateRouteId {
+					route_id: registration.route_id,
+					type_names: [first.type_name, registration.type_name],
+				});
+			}
+
+			let first = *by_type_id
+				.entry(registration.type_id)
+				.or_insert(registration);
+			if first.route_id != registration.route_id {
+				problems.push(RouteProblem::HashCollision {
+					type_id: registration.type_id,
+					route_ids: [first.route_id, registration.route_id],
+				});
+			}
+		}
+
+		if self.routes.len() > MAX_ROUTES {
+			problems.push(RouteProblem::TooManyRoutes {
+				count: self.routes.len(),
+				limit: MAX_ROUTES,
+			});
+		}
+
+		if !problems.is_empty() {
+			return Err(BuildError { problems });
+		}
+
+		Ok(self)
+	}
+
+	/// Register the health route, counting the application routes registered so far.
+	fn with_health_route(self) -> Self {
+		let routes = self.debug_routes().len();
+		let started = Instant::now();
+
+		self.route::<HealthCheck, _, _>(move |_, _| async move {
+			HealthStatus {
+				version: env!("CARGO_PKG_VERSION").to_string(),
+				uptime: started.elapsed(),
+				routes,
+			}
+		})
+	}
+
+	/// Register the route listing the application routes registered so far.
+	fn with_reflection_route(self) -> Self {
+		let route_ids: Vec<String> = self.route_ids().into_iter().map(String::from).collect();
+
+		self.route::<ListRoutes, _, _>(move |_, _| {
+			let route_ids = route_ids.clone();
+			async move { RouteList { route_ids } }
+		})
+	}
+
+	/// The compression to apply to the response of a request carrying `metadata`.
+	#[cfg_attr(
+		not(feature = "compression"),
+		allow(unused_variables, clippy::unused_self, clippy::missing_const_for_fn)
+	)]
+	fn response_compression(&self, metadata: &Metadata) -> Compression {
+		#[cfg(feature = "compression")]
+		if self.compression.is_some() && metadata.accepts(Compression::Zstd) {
+			return Compression::Zstd;
+		}
+
+		Compression::None
+	}
+
+	/// Tell the observer how handling a request routed to `route_id` went.
+	fn report(&self, route_id: &'static str, result: &Result<Vec<u8>, Error>, started: Instant) {
+		match result {
+			Ok(response) => {
+				self.observer
+					.on_response(route_id, response.len(), started.elapsed());
+			},
+			Err(error) => self.observer.on_error(route_id, error),
+		}
+	}
+
+	/// Compress a response as requested, returning the status byte announcing it.
+	#[cfg_attr(
+		not(feature = "compression"),
+		allow(clippy::unused_self, clippy::missing_const_for_fn)
+	)]
+	fn compress_response(&self, compression: Compression, response: Vec<u8>) -> (u8, Vec<u8>) {
+		match compression {
+			Compression::None => (wire::STATUS_OK, response),
+			#[cfg(feature = "compression")]
+			Compression::Zstd => match self.compression.unwrap_or_default().compress(response) {
+				(Compression::Zstd, compressed) => (wire::STATUS_OK_ZSTD, compressed),
+				(Compression::None, response) => (wire::STATUS_OK, response),
+			},
+		}
+	}
+
+	/// The route ID each registered type ID was derived from, ordered by type ID.
+	///
+	/// Useful to audit which requests a router handles, or to match the type IDs found in
+	/// logs back to their requests. Built-in routes, such as the [health route](Self::with_health),
+	/// are left out: see [`Router::debug_all_routes`] to include them.
+	#[must_use]
+	pub fn debug_routes(&self) -> BTreeMap<TypeId, &'static str> {
+		self.route_ids
+			.iter()
+			.filter(|(_, route_id)| !route_id.starts_with(RESERVED_ROUTE_PREFIX))
+			.map(|(&type_id, &route_id)| (type_id, route_id))
+			.collect()
+	}
+
+	/// The route IDs of the router's application routes, in alphabetical order.
+	///
+	/// Built-in routes are left out, as in [`Router::debug_routes`]. This is also what the
+	/// [reflection route](Self::with_reflection) answers with.
+	#[must_use]
+	pub fn route_ids(&self) -> Vec<&'static str> {
+		let mut route_ids: Vec<_> = self.debug_routes().into_values().collect();
+		route_ids.sort_unstable();
+		route_ids
+	}
+
+	/// Like [`Router::debug_routes`], including the routes built into pontifex.
+	#[must_use]
+	pub const fn debug_all_routes(&self) -> &BTreeMap<TypeId, &'static str> {
+		&self.route_ids
+	}
+
+	/// Build the router for serving, and check that it has something to serve.
+	fn prepare(self) -> Result<Self, Error> {
+		let router = self.build().map_err(Error::Build)?;
+
+		if router.debug_routes().is_empty() && router.fallback.is_none() {
+			if router.strict {
+				return Err(Error::NoRoutes);
+			}
+			tracing::warn!("Serving without any routes, every request will be refused");
+		}
+
+		Ok(router)
+	}
+
+	/// Start serving requests on the specified port, accepting connections to any local CID.
+	///
+	/// # Errors
+	///
+	/// Same as [`Router::serve_on`].
+	pub async fn serve(self, port: u32) -> Result<(), Error> {
+		self.serve_on(VMADDR_CID_ANY, port).await
+	}
+
+	/// Start serving requests on the specified local CID and port.
+	///
+	/// Binding a specific CID rather than [`VMADDR_CID_ANY`] only accepts connections made
+	/// to that CID, which lets several services share a port on different interfaces.
+	///
+	/// With the `nsm` feature, the connection to the secure module opened before serving is
+	/// closed once serving stops, unless other servers of the process still run, see
+	/// `SecureModule::shutdown_global`.
+	///
+	/// # Errors
+	///
+	/// - `Error::Build`: The registered routes conflict, see [`Router::build`]
+	/// - `Error::NoRoutes`: The router is [strict](Router::strict) and has no routes
+	/// - `Error::InvalidAddress`: The CID or port can't be bound to
+	/// - `Error::Bind`: Failed to bind to the vsock address
+	/// - `Error::Accept`: Failed to accept incoming connection
+	/// - `Error::Nsm`: Failed to connect to NSM (if feature enabled)
+	pub async fn serve_on(self, cid: u32, port: u32) -> Result<(), Error> {
+		let router = self.prepare()?;
+		let (listener, lease) = listen(cid, port).await?;
+
+		accept_loop(Listener::Vsock(listener), Arc::new(router), None, lease).await
+	}
+
+	/// Start serving requests on a Unix socket bound at `path`, rather than over vsock.
+	///
+	/// This runs the router, with the same framing and dispatch as over vsock, on machines
+	/// without vsock, so that clients and handlers can be tested end to end in CI: clients
+	/// connect with `ConnectionDetails::with_unix_socket`. Clients are reported with the
+	/// loopback CID and port `0`, TLS isn't supported, and the secure module global isn't
+	/// initialized, so connections fail with `Error::Tls` if TLS is configured.
+	///
+	/// # Errors
+	///
+	/// - `Error::Build`: The registered routes conflict, see [`Router::build`]
+	/// - `Error::NoRoutes`: The router is [strict](Router::strict) and has no routes
+	/// - `Error::BindUnix`: Failed to bind to the socket, for example if `path` exists
+	/// - `Error::Accept`: Failed to accept incoming connection
+	#[cfg(feature = "test-transport")]
+	pub async fn serve_unix(self, path: impl AsRef<std::path::Path>) -> Result<(), Error> {
+		let router = self.prepare()?;
+		let listener = listen_unix(path.as_ref())?;
+
+		accept_loop(listener, Arc::new(router), None, None).await
+	}
+
+	/// Start serving requests on the specified port in a background task.
+	///
+	/// Unlike [`Router::serve`], this returns as soon as the listener is bound, with a
+	/// [`ServerHandle`] that can be used to inspect and stop the running server.
+	///
+	/// # Errors
+	///
+	/// Same as [`Router::spawn_on`].
+	pub async fn spawn(self, port: u32) -> Result<ServerHandle, Error> {
+		self.spawn_on(VMADDR_CID_ANY, port).await
+	}
+
+	/// Start serving requests on the specified local CID and port in a background task.
+	///
+	/// See [`Router::serve_on`] and [`Router::spawn`].
+	///
+	/// # Errors
+	///
+	/// - `Error::Build`: The registered routes conflict, see [`Router::build`]
+	/// - `Error::NoRoutes`: The router is [strict](Router::strict) and has no routes
+	/// - `Error::InvalidAddress`: The CID or port can't be bound to
+	/// - `Error::Bind`: Failed to bind to the vsock address
+	/// - `Error::Nsm`: Failed to connect to NSM (if feature enabled)
+	pub async fn spawn_on(self, cid: u32, port: u32) -> Result<ServerHandle, Error> {
+		let router = self.prepare()?;
+		let (listener, lease) = listen(cid, port).await?;
+
+		Ok(spawn_accept_loop(Listener::Vsock(listener), router, lease))
+	}
+
+	/// Start serving requests on a Unix socket bound at `path` in a background task.
+	///
+	/// See [`Router::serve_unix`] and [`Router::spawn`].
+	///
+	/// # Errors
+	///
+	/// - `Error::Build`: The registered routes conflict, see [`Router::build`]
+	/// - `Error::NoRoutes`: The router is [strict](Router::strict) and has no routes
+	/// - `Error::BindUnix`: Failed to bind to the socket, for example if `path` exists
+	#[cfg(feature = "test-transport")]
+	#[allow(
+		clippy::unused_async,
+		reason = "async like `Router::spawn`, so that tests switch transports in one line"
+	)]
+	pub async fn spawn_unix(
+		self,
+		path: impl AsRef<std::path::Path>,
+	) -> Result<ServerHandle, Error> {
+		let router = self.prepare()?;
+		let listener = listen_unix(path.as_ref())?;
+
+		Ok(spawn_accept_loop(listener, router, None))
+	}
+}
+
+/// Run the accept loop of a built router in a background task.
+fn spawn_accept_loop<S>(
+	listener: Listener,
+	router: Router<S>,
+	lease: Option<NsmLease>,
+) -> ServerHandle
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let connections = Arc::new(ConnectionRegistry::default());
+	let stats = router.stats_handle();
+
+	let task = tokio::spawn(accept_loop(
+		listener,
+		Arc::new(router),
+		Some(connections.clone()),
+		lease,
+	));
+
+	ServerHandle::new(task, connections, stats)
+}
+
+/// Keeps the secure module global connected while a server runs, see `nsm::GlobalLease`.
+#[cfg(feature = "nsm")]
+type NsmLease = crate::nsm::GlobalLease;
+
+/// Without the `nsm` feature, there is no secure module to keep connected.
+#[cfg(not(feature = "nsm"))]
+type NsmLease = std::convert::Infallible;
+
+/// Bind a listener on the given address, and get everything handlers rely on ready.
+///
+/// The returned lease must be held for as long as the server runs.
+#[cfg_attr(not(feature = "nsm"), allow(clippy::unused_async))]
+async fn listen(cid: u32, port: u32) -> Result<(VsockListener, Option<NsmLease>), Error> {
+	addr::validate_cid(cid, Role::Bind).map_err(Error::InvalidAddress)?;
+	addr::validate_port(port, Role::Bind).map_err(Error::InvalidAddress)?;
+
+	let listener = VsockListener::bind(VsockAddr::new(cid, port))
+		.map_err(|source| Error::Bind { cid, port, source })?;
+
+	tracing::info!("Router listening on CID {cid}, port {port}");
+
+	// Initialize the secure module global if the feature is enabled.
+	#[cfg(feature = "nsm")]
+	let lease = Some(
+		crate::nsm::GlobalLease::acquire()
+			.await
+			.map_err(Error::NsmConnect)?,
+	);
+	#[cfg(not(feature = "nsm"))]
+	let lease = None;
+
+	Ok((listener, lease))
+}
+
+/// Bind a Unix socket listener standing in for vsock, see [`Router::serve_unix`].
+#[cfg(feature = "test-transport")]
+fn listen_unix(path: &std::path::Path) -> Result<Listener, Error> {
+	let listener = tokio::net::UnixListener::bind(path).map_err(|source| Error::BindUnix {
+		path: path.to_owned(),
+		source,
+	})?;
+
+	tracing::info!("Router listening on Unix socket {}", path.display());
+
+	Ok(Listener::Unix(listener))
+}
+
+/// Where connections are accepted from: vsock, or a Unix socket standing in for it.
+enum Listener {
+	Vsock(VsockListener),
+	#[cfg(feature = "test-transport")]
+	Unix(tokio::net::UnixListener),
+}
+
+/// A connection as accepted, before any TLS session is established over it.
+enum Socket {
+	Vsock(VsockStream),
+	#[cfg_attr(
+		not(feature = "test-transport"),
+		allow(
+			dead_code,
+			reason = "only the test transport accepts anything but vsock for now"
+		)
+	)]
+	Other(Box<dyn ByteStream>),
+}
+
+impl Listener {
+	async fn accept(&self) -> io::Result<(Socket, ConnectionInfo)> {
+		match self {
+			Self::Vsock(listener) => {
+				let (stream, addr) = listener.accept().await?;
+				Ok((
+					Socket::Vsock(stream),
+					ConnectionInfo::new(addr.cid(), addr.port()),
+				))
+			},
+			// Unix peers have no CID, so they are reported as connecting over loopback.
+			#[cfg(feature = "test-transport")]
+			Self::Unix(listener) => {
+				let (stream, _) = listener.accept().await?;
+				Ok((
+					Socket::Other(Box::new(stream)),
+					ConnectionInfo::new(addr::VMADDR_CID_LOCAL, 0),
+				))
+			},
+		}
+	}
+}
+
+/// An accepted connection, along with the guards that keep it accounted for until it is dropped.
+struct Accepted {
+	stream: Socket,
+	peer: ConnectionInfo,
+	_registration: Option<Registration>,
+	_active: ActiveConnection,
+	_permit: Option<OwnedSemaphorePermit>,
+}
+
+#[allow(
+	clippy::significant_drop_tightening,
+	reason = "the permit moves into the connection, which owns it until it is closed"
+)]
+async fn accept_loop<S>(
+	listener: Listener,
+	router: Arc<Router<S>>,
+	connections: Option<Arc<ConnectionRegistry>>,
+	_lease: Option<NsmLease>,
+) -> Result<(), Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let queue = match router.dispatch {
+		DispatchModel::SpawnPerConnection => None,
+		DispatchModel::BoundedPool {
+			workers,
+			queue_capacity,
+		} => Some(spawn_workers(&router, workers, queue_capacity)),
+	};
+
+	let limit = Arc::new(Semaphore::new(
+		router.max_concurrent.map_or(Semaphore::MAX_PERMITS, |max| {
+			max.clamp(1, Semaphore::MAX_PERMITS)
+		}),
+	));
+
+	let mut warnings = router.capacity_warning.map(CapacityWarnings::new);
+	let refusals = Arc::new(Semaphore::new(MAX_CONCURRENT_REFUSALS));
+
+	// An error frame can only be sent through a TLS session, and an overloaded server is the
+	// last one that should run a handshake for a connection it won't serve.
+	#[cfg(feature = "tls")]
+	let answers_refusals = router.tls.is_none();
+	#[cfg(not(feature = "tls"))]
+	let answers_refusals = true;
+
+	loop {
+		// Wait for a connection to close before accepting one over the limit. The semaphore
+		// is never closed, so acquiring a permit can't fail.
+		let permit = if let Ok(permit) = limit.clone().try_acquire_owned() {
+			Some(permit)
+		} else {
+			let started = Instant::now();
+			let permit = limit.clone().acquire_owned().await.ok();
+
+			let now = Instant::now();
+			let waited = now.duration_since(started);
+			router.observer.on_capacity_wait(waited);
+			if let Some(warnings) = &mut warnings {
+				warnings.record(waited, now);
+			}
+
+			permit
+		};
+
+		let (stream, peer) = listener.accept().await.map_err(Error::Accept)?;
+
+		let connection = Accepted {
+			stream,
+			peer,
+			// Only spawned servers track their connections, so `serve` pays nothing for it.
+			_registration: connections.as_ref().map(|registry| registry.register(peer)),
+			_active: router.stats.connection_opened(),
+			_permit: permit,
+		};
+
+		let Some(queue) = &queue else {
+			tokio::spawn(serve_connection(connection, router.clone()));
+			continue;
+		};
+
+		router.stats.connection_queued();
+		if let Err(full) = queue.try_send(connection) {
+			router.stats.connection_dequeued();
+			router.stats.connection_failed();
+			tracing::warn!("Rejecting connection: {}", Error::ServiceUnavailable);
+
+			// Refusing takes a task of its own, so past a few at once connections are just closed.
+			if answers_refusals && let Ok(refusal) = refusals.clone().try_acquire_owned() {
+				tokio::spawn(refuse_connection(
+					full.into_inner(),
+					router.clone(),
+					refusal,
+				));
+			}
+		}
+	}
+}
+
+/// Start the workers of a bounded pool, returning the queue that feeds them.
+///
+/// Workers stop once the queue is dropped and every connection left in it was handled.
+#[allow(
+	clippy::significant_drop_tightening,
+	reason = "the queue lock is released before the connection is served"
+)]
+fn spawn_workers<S>(
+	router: &Arc<Router<S>>,
+	workers: usize,
+	queue_capacity: usize,
+) -> mpsc::Sender<Accepted>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let (sender, receiver) = mpsc::channel(queue_capacity.max(1));
+	let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+
+	for _ in 0..workers.max(1) {
+		let router = router.clone();
+		let receiver = receiver.clone();
+
+		tokio::spawn(async move {
+			loop {
+				// Idle workers take turns waiting on the queue.
+				let next = receiver.lock().await.recv().await;
+				let Some(connection) = next else {
+					break;
+				};
+
+				router.stats.connection_dequeued();
+				serve_connection(connection, router.clone()).await;
+			}
+		});
+	}
+
+	sender
+}
+
+async fn serve_connection<S>(connection: Accepted, router: Arc<Router<S>>)
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let result = async {
+		let mut stream = open_stream(connection.stream, &router).await?;
+		handle_connection(&mut stream, connection.peer, router.clone()).await
+	};
+
+	if let Err(e) = result.await {
+		router.stats.connection_failed();
+		tracing::error!(
+			error = &e as &dyn std::error::Error,
+			"Failed to handle request"
+		);
+	}
+}
+
+/// Answer a connection the bounded pool has no room for with `Error::ServiceUnavailable`,
+/// so that its client can tell why it is closed.
+///
+/// The error is written as soon as the handshake is exchanged, without reading the request
+/// it answers, and the connection is given up on after [`REFUSAL_TIMEOUT`]. It keeps its
+/// permit until then, so refusals count against [`Router::max_concurrent`] rather than
+/// piling up, and `_refusal` against [`MAX_CONCURRENT_REFUSALS`].
+async fn refuse_connection<S>(
+	connection: Accepted,
+	router: Arc<Router<S>>,
+	_refusal: OwnedSemaphorePermit,
+) where
+	S: Clone + Send + Sync + 'static,
+{
+	let refusal = async {
+		let mut stream = open_stream(connection.stream, &router).await?;
+		// The refusal as a whole is bounded below, handshake included.
+		if handshake(&mut stream, &router, None).await? == wire::MODE_MULTIPLEXED {
+			return multiplex::refuse(&mut stream).await;
+		}
+
+		write_error(&mut stream, &Error::ServiceUnavailable).await
+	};
+
+	let refusal = within(Some(REFUSAL_TIMEOUT), TimeoutPhase::Writing, refusal);
+	if let Err(e) = refusal.await {
+		tracing::debug!(
+			error = &e as &dyn std::error::Error,
+			"Failed to refuse connection"
+		);
+	}
+}
+
+/// Serve a single connection over any byte stream in a background task, such as an in-memory
+/// pipe to a client running in the same process.
+#[cfg(all(feature = "client", feature = "test-transport"))]
+pub(crate) fn serve_io<S>(router: &Arc<Router<S>>, io: Box<dyn ByteStream>)
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let connection = Accepted {
+		stream: Socket::Other(io),
+		peer: ConnectionInfo::new(addr::VMADDR_CID_LOCAL, 0),
+		_registration: None,
+		_active: router.stats.connection_opened(),
+		_permit: None,
+	};
+
+	tokio::spawn(serve_connection(connection, router.clone()));
+}
+
+/// Set the router's socket options on an accepted connection, and wrap it in a TLS session
+/// if the router terminates TLS.
+#[cfg_attr(
+	not(feature = "tls"),
+	allow(
+		clippy::unused_async,
+		reason = "only establishing TLS sessions awaits anything"
+	)
+)]
+async fn open_stream<S>(socket: Socket, router: &Router<S>) -> Result<Stream, Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let stream = match socket {
+		Socket::Vsock(stream) => stream,
+		Socket::Other(stream) => {
+			#[cfg(feature = "tls")]
+			if router.tls.is_some() {
+				return Err(Error::Tls(io::Error::new(
+					io::ErrorKind::Unsupported,
+					"TLS is only supported over vsock",
+				)));
+			}
+
+			return Ok(Stream::from_io(stream));
+		},
+	};
+	router.stream_config.apply(&stream).map_err(Error::Accept)?;
+
+	#[cfg(feature = "tls")]
+	if let Some(tls) = &router.tls {
+		let timeout = router.timeout.map_or(TLS_HANDSHAKE_TIMEOUT, |timeout| {
+			timeout.min(TLS_HANDSHAKE_TIMEOUT)
+		});
+		let session = async { Stream::accept_tls(stream, tls).await.map_err(Error::Tls) };
+		return within(Some(timeout), TimeoutPhase::Reading, session).await;
+	}
+
+	Ok(Stream::new(stream))
+}
+
+async fn handle_connection<S>(
+	stream: &mut Stream,
+	peer: ConnectionInfo,
+	router: Arc<Router<S>>,
+) -> Result<(), Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let timeout = router.timeout;
+
+	let mode = handshake(stream, &router, timeout).await?;
+	if mode == wire::MODE_MULTIPLEXED {
+		return multiplex::serve(stream, peer, &router).await;
+	}
+
+	// Clients may send any number of requests over a connection, one after the other, until
+	// they close it.
+	loop {
+		let (route, ctx, request) = match within(
+			timeout,
+			TimeoutPhase::Reading,
+			read_request(stream, peer, &router),
+		)
+		.await
+		{
+			Ok(None) => return Ok(()),
+			Ok(Some(request)) => request,
+			Err(error) => {
+				respond(
+					stream,
+					&router,
+					Err(error),
+					Compression::None,
+					Checksum::None,
+				)
+				.await?;
+				continue;
+			},
+		};
+
+		ctx.scope(serve_request(stream, &router, route, &ctx, request))
+			.await?;
+	}
+}
+
+/// Handle a request read off a sequential connection, and write its response.
+///
+/// Returns an error if the connection can't be used for the next request afterwards.
+async fn serve_request<S>(
+	stream: &mut Stream,
+	router: &Router<S>,
+	route: &Route<S>,
+	ctx: &RequestContext,
+	request: RawRequest,
+) -> Result<(), Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let timeout = router.timeout;
+	let route_id = ctx.route_id();
+	router
+		.observer
+		.on_request(route_id, ctx.type_id(), request.payload.len());
+	let started = Instant::now();
+
+	let mut compression = Compression::None;
+	let mut checksum = Checksum::None;
+	let result = match route {
+		// Call the handler's type-erased method.
+		// The handler internally knows its concrete types and will:
+		// 1. Deserialize the payload to the correct request type
+		// 2. Call the user's handler function with typed parameters
+		// 3. Serialize the typed response back to bytes
+		Route::Unary(handler) => {
+			compression = router.response_compression(&request.metadata);
+			checksum = request.metadata.checksum().unwrap_or_default();
+			call_unary(router, &**handler, ctx, request).await
+		},
+		// Streamed responses are written as they are produced, unless the request
+		// fails before the stream even starts. Layers only get to run before that.
+		Route::Streaming(handler) => {
+			let admitted = router.layered(ctx, Box::pin(async { Ok(Vec::new()) }));
+			let admitted = catch_panic(route_id, admitted);
+
+			match within(timeout, TimeoutPhase::Handling, admitted)
+				.await
+				.and_then(|_| {
+					let call = AssertUnwindSafe(|| handler.call(router.state.clone(), request));
+					panic::catch_unwind(call)
+						.unwrap_or_else(|panic| Err(handler_panicked(route_id, &*panic)))
+				}) {
+				Ok(items) => {
+					match streaming::write_stream(stream, timeout, route_id, items).await {
+						Ok(bytes) => {
+							router
+								.observer
+								.on_response(route_id, bytes, started.elapsed());
+							return Ok(());
+						},
+						Err(error) => {
+							router.observer.on_error(route_id, &error);
+							return Err(error);
+						},
+					}
+				},
+				Err(error) => Err(error),
+			}
+		},
+	};
+
+	router.report(route_id, &result, started);
+
+	respond(stream, router, result, compression, checksum).await
+}
+
+/// Run the handler of a unary request, wrapped in the router's layers, timeout and panic
+/// handling.
+async fn call_unary<S>(
+	router: &Router<S>,
+	handler: &dyn Handler<S>,
+	ctx: &RequestContext,
+	request: RawRequest,
+) -> Result<Vec<u8>, Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let response = handler.call(router.state.clone(), request);
+	let response = catch_panic(ctx.route_id(), router.layered(ctx, response));
+
+	within(router.timeout, TimeoutPhase::Handling, response).await
+}
+
+/// Send the response to a request, or report why it failed.
+///
+/// Failures are reported back to the client in an error frame rather than by just closing
+/// the connection, so that it can tell what went wrong. The connection is only kept open
+/// afterwards if the request was read in full, as the next one couldn't be found otherwise.
+/// Responses are checksummed like the request was, while error frames never are.
+async fn respond<S>(
+	stream: &mut (impl AsyncWrite + Unpin + Send),
+	router: &Router<S>,
+	result: Result<Vec<u8>, Error>,
+	compression: Compression,
+	checksum: Checksum,
+) -> Result<(), Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let error = match result {
+		Ok(response) => {
+			let (status, response) = router.compress_response(compression, response);
+
+			return within(
+				router.timeout,
+				TimeoutPhase::Writing,
+				write_response(stream, status, &response, checksum),
+			)
+			.await;
+		},
+		// There's no point trying to write anything else to a stream that can't be written to.
+		Err(error @ (Error::Writing(..) | Error::Timeout(TimeoutPhase::Writing))) => {
+			return Err(error);
+		},
+		Err(error) => error,
+	};
+
+	let report = write_error(stream, &error);
+	if let Err(e) = within(router.timeout, TimeoutPhase::Writing, report).await {
+		tracing::debug!(
+			error = &e as &dyn std::error::Error,
+			"Failed to report error to client"
+		);
+		return Err(error);
+	}
+
+	if !error.is_on_frame_boundary(router.reject_policy) {
+		return Err(error);
+	}
+
+	tracing::warn!(
+		error = &error as &dyn std::error::Error,
+		"Failed to handle request"
+	);
+	Ok(())
+}
+
+/// Run a handler, turning a panic into `Error::HandlerPanic` rather than letting it tear down
+/// the connection's task without telling the client.
+async fn catch_panic<T>(
+	route_id: &'static str,
+	future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+	AssertUnwindSafe(future)
+		.catch_unwind()
+		.await
+		.unwrap_or_else(|panic| Err(handler_panicked(route_id, &*panic)))
+}
+
+/// Log a handler's panic, returning the error reported to the client in its place.
+///
+/// The panic message stays in the logs, as it may describe internal state the client
+/// shouldn't see.
+fn handler_panicked(route_id: &'static str, panic: &(dyn std::any::Any + Send)) -> Error {
+	let message = panic
+		.downcast_ref::<&str>()
+		.copied()
+		.or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+		.unwrap_or("non-string panic payload");
+
+	tracing::error!(route_id, panic = message, "handler panicked");
+	Error::HandlerPanic { route_id }
+}
+
+/// Run one phase of a connection, giving up after `timeout` if there is one.
+async fn within<T>(
+	timeout: Option<Duration>,
+	phase: TimeoutPhase,
+	future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+	timed(timeout, future)
+		.await
+		.unwrap_or(Err(Error::Timeout(phase)))
+}
+
+/// Exchange protocol versions and payload formats: the client announces its own, and we
+/// answer with ours so that both sides can refuse to talk rather than silently misread each
+/// other. Returns the mode the client opened the connection in.
+///
+/// Every read and write of the handshake is given up on after `timeout`, if there is one.
+async fn handshake<S: Sync>(
+	stream: &mut Stream,
+	router: &Router<S>,
+	timeout: Option<Duration>,
+) -> Result<u8, Error> {
+	let client_version = stream
+		.read_exact_timeout(1, timeout)
+		.await
+		.map_err(|e| handshake_error(e, Error::Reading))?[0];
+
+	stream
+		.write_all_timeout(
+			&[wire::PROTOCOL_VERSION, router.format.descriptor()],
+			timeout,
+		)
+		.await
+		.map_err(|e| handshake_error(e, Error::Writing))?;
+
+	// Whatever follows is laid out differently in other versions, so it isn't even read.
+	if client_version != wire::PROTOCOL_VERSION {
+		return Err(Error::ProtocolVersion {
+			got: client_version,
+			expected: wire::PROTOCOL_VERSION,
+		});
+	}
+
+	let client_format = stream
+		.read_exact_timeout(1, timeout)
+		.await
+		.map_err(|e| handshake_error(e, Error::Reading))?[0];
+
+	router
+		.format
+		.negotiate(client_format)
+		.map_err(Error::CodecMismatch)?;
+
+	let mode = stream
+		.read_exact_timeout(1, timeout)
+		.await
+		.map_err(|e| handshake_error(e, Error::Reading))?[0];
+
+	if !matches!(mode, wire::MODE_SEQUENTIAL | wire::MODE_MULTIPLEXED) {
+		return Err(Error::Reading(
+			CodingKey::Handshake,
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("unknown connection mode 0x{mode:02x}"),
+			),
+		));
+	}
+
+	Ok(mode)
+}
+
+/// The error exchanging the handshake failed with: `Error::Timeout` if it took too long, or
+/// `error` wrapped by `otherwise`.
+fn handshake_error(error: io::Error, otherwise: fn(CodingKey, io::Error) -> Error) -> Error {
+	if error.kind() == io::ErrorKind::TimedOut {
+		return Error::Timeout(TimeoutPhase::Reading);
+	}
+
+	otherwise(CodingKey::Handshake, error)
+}
+
+/// Read a request after the handshake, along with the handler it is routed to.
+///
+/// Returns `None` if the client closed the connection instead of sending another request.
+async fn read_request<'r, S>(
+	stream: &mut (impl AsyncRead + Unpin + Send),
+	peer: ConnectionInfo,
+	router: &'r Router<S>,
+) -> Result<Option<(&'r Route<S>, RequestContext, RawRequest)>, Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	// Read type ID from the wire (first 4 bytes of every request, or 8 with `wide-ids`)
+	let Some(type_id) = read_leading(stream, CodingKey::Length).await? else {
+		return Ok(None);
+	};
+	let type_id = TypeId::from_be_bytes(type_id);
+
+	// Read the formats of this request's payload and of the response the client expects.
+	// Clients tag every request explicitly, defaulting both to the format agreed on above.
+	let formats = PayloadFormats {
+		request: read_format_tag(stream).await?,
+		response: read_format_tag(stream).await?,
+		presize: router.presize_responses,
+	};
+
+	let metadata = wire::read_metadata(stream)
+		.await
+		.map_err(|(key, e)| Error::Reading(key, e))?;
+
+	// Look up the type-erased handler for this type ID, falling back to `on_unknown`'s
+	let Some(route) = router.routes.get(&type_id).or(router.fallback.as_ref()) else {
+		tracing::warn!(
+			type_id = format!("0x{:08x}", type_id),
+			"Unknown request type"
+		);
+		return Err(reject_unknown(stream, router, &metadata, type_id).await);
+	};
+
+	let route_id = router.route_ids.get(&type_id).copied().unwrap_or_default();
+	if router
+		.gate
+		.as_ref()
+		.is_some_and(|gate| !gate(type_id, route_id))
+	{
+		tracing::debug!(route_id, "Refusing gated request");
+		return Err(Error::Gated { route_id });
+	}
+
+	router.stats.request_routed();
+
+	let payload = stream
+		.read_framed(router.max_payload_bytes)
+		.await
+		.map_err(|e| {
+			FrameTooLarge::find(&e).copied().map_or_else(
+				|| Error::Reading(CodingKey::Payload, e),
+				|frame| {
+					tracing::warn!(
+						length = frame.declared,
+						limit = frame.limit,
+						"Refusing oversized payload"
+					);
+					Error::PayloadTooLarge {
+						declared: frame.declared,
+						limit: frame.limit,
+					}
+				},
+			)
+		})?;
+
+	// The checksum covers the payload as sent, so it is checked before decompressing it.
+	// Without knowing the checksum, its length is unknown too, so the connection is lost.
+	let checksum = metadata.checksum().map_err(Error::UnsupportedChecksum)?;
+	if let Some(actual) = checksum.compute(&payload) {
+		let expected = stream
+			.read_u32()
+			.await
+			.map_err(|e| Error::Reading(CodingKey::Checksum, e))?;
+
+		if expected != actual {
+			tracing::warn!(route_id = ?router.route_ids.get(&type_id), "Corrupted request payload");
+			return Err(Error::ChecksumMismatch(ChecksumMismatch {
+				expected,
+				actual,
+			}));
+		}
+	}
+
+	// The payload is read in full before this can fail, so that the connection stays usable.
+	let compression = metadata
+		.content_encoding()
+		.map_err(Error::UnsupportedCompression)?;
+	let payload = wire::decompress(compression, payload, router.max_payload_bytes)
+		.map_err(Error::Decompression)?;
+
+	let request_id = metadata
+		.request_id()
+		.map_or_else(wire::generate_request_id, ToOwned::to_owned);
+	let ctx = RequestContext {
+		type_id,
+		route_id,
+		peer,
+		metadata: metadata.clone(),
+		request_id,
+		trace_id: metadata.trace_id().unwrap_or_else(TraceId::generate),
+	};
+
+	let request = RawRequest {
+		formats,
+		metadata,
+		payload,
+		ctx: ctx.clone(),
+	};
+
+	Ok(Some((route, ctx, request)))
+}
+
+/// Read the integer opening a request, such as its type ID, as big-endian bytes, or `None`
+/// if the connection was closed before it.
+async fn read_leading<const N: usize>(
+	stream: &mut (impl AsyncRead + Unpin + Send),
+	key: CodingKey,
+) -> Result<Option<[u8; N]>, Error> {
+	let mut value = [0; N];
+
+	let read = stream
+		.read(&mut value)
+		.await
+		.map_err(|e| Error::Reading(key, e))?;
+
+	if read == 0 {
+		return Ok(None);
+	}
+
+	stream
+		.read_exact(&mut value[read..])
+		.await
+		.map_err(|e| Error::Reading(key, e))?;
+
+	Ok(Some(value))
+}
+
+/// Write an error frame, which is never checksummed.
+///
+/// The client only gets the message of the frame, so it carries the sources of the error
+/// along with it.
+async fn write_error(
+	stream: &mut (impl AsyncWrite + Unpin + Send),
+	error: &Error,
+) -> Result<(), Error> {
+	let frame = ErrorFrame::new(error.code(), error_chain(error)).encode();
+	write_response(stream, wire::STATUS_ERROR, &frame, Checksum::None).await
+}
+
+/// Write a status byte, followed by the response or error frame it announces, and its
+/// checksum if it has one.
+async fn write_response(
+	stream: &mut (impl AsyncWrite + Unpin + Send),
+	status: u8,
+	payload: &[u8],
+	checksum: Checksum,
+) -> Result<(), Error> {
+	let trailer = checksum.compute(payload);
+	let status = match trailer {
+		Some(_) => status | wire::STATUS_CHECKSUM_FLAG,
+		None => status,
+	};
+
+	stream
+		.write_u8(status)
+		.await
+		.map_err(|e| Error::Writing(CodingKey::Status, e))?;
+
+	wire::write_frame(stream, payload, Error::Writing).await?;
+
+	if let Some(trailer) = trailer {
+		stream
+			.write_u32(trailer)
+			.await
+			.map_err(|e| Error::Writing(CodingKey::Checksum, e))?;
+	}
+
+	// Nothing is buffered over plain vsock, but TLS sessions hold writes back until flushed.
+	stream
+		.flush()
+		.await
+		.map_err(|e| Error::Writing(CodingKey::Payload, e))
+}
+
+/// The message of an error followed by those of its sources, as `error: source: ...`.
+fn error_chain(error: &dyn std::error::Error) -> String {
+	let mut message = error.to_string();
+
+	let mut source = error.source();
+	while let Some(cause) = source {
+		message.push_str(": ");
+		message.push_str(&cause.to_string());
+		source = cause.source();
+	}
+
+	message
+}
+
+async fn read_format_tag(stream: &mut (impl AsyncRead + Unpin + Send)) -> Result<Format, Error> {
+	let tag = stream
+		.read_u8()
+		.await
+		.map_err(|e| Error::Reading(CodingKey::Format, e))?;
+
+	Format::from_descriptor(tag).ok_or_else(|| {
+		tracing::warn!(tag = format!("0x{tag:02x}"), "Unsupported codec");
+		Error::UnsupportedCodec(tag)
+	})
+}
+
+/// Apply the reject policy to a request for an unknown route, before its payload was read,
+/// returning the error it is refused with.
+///
+/// Payloads are never drained past the router's payload limit, as they wouldn't have been
+/// read either had the request been accepted. Without knowing the checksum of the request,
+/// the length of its trailer is unknown too, so the connection is lost.
+async fn reject_unknown<S>(
+	stream: &mut (impl AsyncRead + Unpin + Send),
+	router: &Router<S>,
+	metadata: &Metadata,
+	type_id: TypeId,
+) -> Error {
+	if router.reject_policy == RejectPolicy::Drain {
+		let checksum = match metadata.checksum() {
+			Ok(checksum) => checksum,
+			Err(name) => return Error::UnsupportedChecksum(name),
+		};
+
+		let limit = router.max_payload_bytes.min(MAX_DRAIN_BYTES);
+		match wire::drain_frame(stream, limit).await {
+			Ok(length) => tracing::debug!(length, "drained payload of unknown request"),
+			Err((key, e)) => return Error::Reading(key, e),
+		}
+
+		if checksum != Checksum::None
+			&& let Err(e) = stream.read_u32().await
+		{
+			return Error::Reading(CodingKey::Checksum, e);
+		}
+	}
+
+	Error::UnknownRequest(type_id)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde::{Deserialize, Serialize};
+
+	#[derive(Serialize, Deserialize)]
+	struct Ping;
+
+	#[derive(Serialize, Deserialize)]
+	struct Pong;
+
+	#[derive(Serialize, Deserialize)]
+	struct Unnamed;
+
+	impl Request for Ping {
+		const ROUTE_ID: &'static str = "ping_v1";
+		type Response = ();
+	}
+
+	impl Request for Pong {
+		const ROUTE_ID: &'static str = "ping_v1";
+		type Response = ();
+	}
+
+	impl Request for Unnamed {
+		const ROUTE_ID: &'static str = "";
+		type Response = ();
+	}
+
+	#[derive(Serialize, Deserialize)]
+	struct Impostor;
+
+	impl Request for Impostor {
+		const ROUTE_ID: &'static str = "__pontifex_impostor_v1";
+		type Response = ();
+	}
+
+	#[test]
+	fn test_build_reports_every_conflict() {
+		// Registering the same request type twice only replaces its handler.
+		let router = Router::new()
+			.route::<Ping, _, _>(|(), _| async {})
+			.route::<Ping, _, _>(|(), _| async {});
+		assert!(router.build().is_ok());
+
+		let router = Router::new()
+			.route::<Ping, _, _>(|(), _| async {})
+			.route::<Pong, _, _>(|(), _| async {})
+			.route::<Unnamed, _, _>(|(), _| async {});
+		let Err(error) = router.build() else {
+			panic!("conflicting routes were accepted");
+		};
+
+		assert_eq!(
+			error.problems,
+			[
+				RouteProblem::DuplicateRouteId {
+					route_id: "ping_v1",
+					type_names: [std::any::type_name::<Ping>(), std::any::type_name::<Pong>()],
+				},
+				RouteProblem::EmptyRouteId {
+					type_name: std::any::type_name::<Unnamed>(),
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn test_merge_reports_conflicts_between_routers() {
+		let router = Router::new()
+			.route::<Ping, _, _>(|(), _| async {})
+			.merge(Router::new().route::<Ping, _, _>(|(), _| async {}));
+		assert_eq!(
+			router.build().map(|router| router.route_ids()).ok(),
+			Some(vec!["ping_v1"])
+		);
+
+		let router = Router::new()
+			.route::<Ping, _, _>(|(), _| async {})
+			.merge(Router::new().route::<Pong, _, _>(|(), _| async {}));
+		let Err(error) = router.build() else {
+			panic!("conflicting routes were merged");
+		};
+		assert!(matches!(
+			error.problems.as_slice(),
+			[RouteProblem::DuplicateRouteId { .. }]
+		));
+	}
+
+	/// Merging a route whose type ID is taken leaves the first one in place, until `build`
+	/// reports the conflict.
+	#[test]
+	fn test_merge_keeps_the_first_of_colliding_routes() {
+		fn handler() -> Arc<dyn Handler<()>> {
+			Arc::new(FallbackHandler {
+				handler: |(), _| async { Ok(Vec::new()) },
+				_phantom: PhantomData,
+			})
+		}
+
+		let first = handler();
+		let mut router = Router::new();
+		router.insert_route(1, "first_v1", "First", Route::Unary(first.clone()));
+		let mut other = Router::new();
+		other.insert_route(1, "second_v1", "Second", Route::Unary(handler()));
+
+		let router = router.merge(other);
+		assert_eq!(router.route_ids.get(&1), Some(&"first_v1"));
+		let Some(Route::Unary(kept)) = router.routes.get(&1) else {
+			panic!("the first route was replaced");
+		};
+		assert!(Arc::ptr_eq(kept, &first));
+
+		let Err(error) = router.build() else {
+			panic!("colliding routes were merged");
+		};
+		assert!(matches!(
+			error.problems.as_slice(),
+			[RouteProblem::HashCollision { .. }]
+		));
+	}
+
+	/// Requests lead with type IDs as wide as the `wide-ids` feature makes them, which the
+	/// protocol version tells peers about.
+	#[test]
+	fn test_type_id_width() {
+		let request = request_bytes(&Ping, &Metadata::new());
+		let (type_id, _) = request.split_at(size_of::<TypeId>());
+		assert_eq!(
+			TypeId::from_be_bytes(type_id.try_into().unwrap()),
+			Ping::type_id()
+		);
+
+		assert_eq!(wire::PROTOCOL_VERSION & 0x80 != 0, size_of::<TypeId>() == 8);
+	}
+
+	/// Routers refuse to build with more routes than `MAX_ROUTES`.
+	#[test]
+	fn test_route_count_is_limited() {
+		let mut router = Router::new().with_capacity(MAX_ROUTES + 1);
+		let capacity = router.routes.capacity();
+		assert!(capacity > MAX_ROUTES);
+
+		for type_id in 0..=MAX_ROUTES {
+			let type_id = TypeId::try_from(type_id).unwrap();
+			let handler = Arc::new(FallbackHandler {
+				handler: |(), _| async { Ok(Vec::new()) },
+				_phantom: PhantomData,
+			});
+			router.insert_route(type_id, "", "Unrouted", Route::Unary(handler));
+		}
+		// The map was sized upfront, so registering didn't grow it.
+		assert_eq!(router.routes.capacity(), capacity);
+
+		let Err(error) = router.build() else {
+			panic!("a router with too many routes was built");
+		};
+		assert!(error.problems.contains(&RouteProblem::TooManyRoutes {
+			count: MAX_ROUTES + 1,
+			limit: MAX_ROUTES,
+		}));
+	}
+
+	/// Type IDs are hashes already, so the route map doesn't hash them again.
+	#[test]
+	fn test_type_ids_are_their_own_hash() {
+		use std::hash::BuildHasher;
+
+		let hasher = BuildHasherDefault::<TypeIdHasher>::default();
+		let hash = hasher.hash_one(Ping::TYPE_ID);
+		#[cfg(not(feature = "wide-ids"))]
+		assert_eq!(
+			hash,
+			(u64::from(Ping::TYPE_ID) << 32) | u64::from(Ping::TYPE_ID)
+		);
+		#[cfg(feature = "wide-ids")]
+		assert_eq!(hash, Ping::TYPE_ID);
+
+		let router = Router::new().route_fn::<Ping>(|(), _| async {});
+		assert!(router.routes.contains_key(&Ping::TYPE_ID));
+	}
+
+	/// Type IDs are constants, so that hand-written dispatchers can match on them.
+	#[test]
+	fn test_type_ids_are_constants() {
+		const PING: TypeId = crate::route_type_id("ping_v1");
+		assert_eq!(Ping::TYPE_ID, PING);
+		assert_eq!(Ping::type_id(), PING);
+
+		let routed = match Double::type_id() {
+			Ping::TYPE_ID => "ping",
+			Double::TYPE_ID => "double",
+			_ => "unknown",
+		};
+		assert_eq!(routed, "double");
+	}
+
+	#[test]
+	fn test_builtin_routes_are_left_out_of_debug_routes() {
+		let router = Router::new()
+			.with_health()
+			.route::<Ping, _, _>(|(), _| async {})
+			.build()
+			.unwrap();
+
+		assert_eq!(
+			router.debug_routes().values().collect::<Vec<_>>(),
+			[&"ping_v1"]
+		);
+		assert_eq!(
+			router.debug_all_routes().get(&HealthCheck::type_id()),
+			Some(&HealthCheck::ROUTE_ID)
+		);
+		// Building again doesn't register the health route twice.
+		assert!(router.build().is_ok());
+
+		let router = Router::new()
+			.with_health()
+			.with_reflection()
+			.route::<Ping, _, _>(|(), _| async {})
+			.build()
+			.unwrap();
+		assert_eq!(router.route_ids(), ["ping_v1"]);
+		assert_eq!(router.debug_all_routes().len(), 3);
+
+		let router = Router::new().route::<Impostor, _, _>(|(), _| async {});
+		let Err(error) = router.build() else {
+			panic!("a reserved route ID was accepted");
+		};
+		assert!(matches!(
+			error.problems.as_slice(),
+			[RouteProblem::ReservedRouteId { .. }]
+		));
+	}
+
+	/// Encode a request to `R`'s route, as clients write it after the handshake.
+	fn request_bytes<R: Request>(request: &R, metadata: &Metadata) -> Vec<u8> {
+		let format = Format::default();
+		let payload = format.encode(request).unwrap();
+
+		let mut bytes = R::type_id().to_be_bytes().to_vec();
+		bytes.extend([format.descriptor(), format.descriptor()]);
+		tokio_test::block_on(async {
+			wire::write_metadata(&mut bytes, metadata, |_, e| e).await?;
+			wire::write_frame(&mut bytes, &payload, |_, e| e).await
+		})
+		.unwrap();
+
+		bytes
+	}
+
+	#[derive(Serialize, Deserialize)]
+	struct Double(u32);
+
+	impl Request for Double {
+		const ROUTE_ID: &'static str = "double_v1";
+		type Response = u32;
+	}
+
+	async fn double(offset: u32, Double(n): Double) -> u32 {
+		n * 2 + offset
+	}
+
+	/// Decoding errors name the route and the length of the payload that failed to decode.
+	#[test]
+	fn test_decoding_errors_name_the_route() {
+		#[derive(Serialize, Deserialize)]
+		struct MalformedDouble(String);
+
+		impl Request for MalformedDouble {
+			const ROUTE_ID: &'static str = "double_v1";
+			type Response = u32;
+		}
+
+		let router = Router::with_state(1).route_fn::<Double>(double);
+		let malformed = MalformedDouble("twenty-one".to_string());
+		let bytes = request_bytes(&malformed, &Metadata::new());
+
+		let error = tokio_test::block_on(async {
+			let (route, _, request) = read_request(
+				&mut bytes.as_slice(),
+				ConnectionInfo::new(16, 1000),
+				&router,
+			)
+			.await
+			.unwrap()
+			.unwrap();
+			let Route::Unary(handler) = route else {
+				panic!("a unary route was registered as streaming");
+			};
+
+			handler.call(router.state, request).await.unwrap_err()
+		});
+
+		let len = Format::default().encode(&malformed).unwrap().len();
+		assert!(
+			matches!(
+				error,
+				Error::Decoding {
+					route_id: "double_v1",
+					bytes,
+					..
+				} if bytes == len
+			),
+			"{error:?}"
+		);
+		assert_eq!(error.code(), ErrorFrame::DECODING);
+		assert_eq!(
+			error.to_string(),
+			format!("decoding failed for double_v1 ({len} bytes)")
+		);
+		// Clients only see the error frame, whose message carries the reason too.
+		assert!(
+			error_chain(&error).starts_with(&format!(
+				"decoding failed for double_v1 ({len} bytes): MessagePack: "
+			)),
+			"{}",
+			error_chain(&error)
+		);
+	}
+
+	/// `route_fn` takes plain `async fn`s and closures, naming only the request type.
+	#[test]
+	fn test_route_fn_infers_the_handler_types() {
+		let router = Router::with_state(1)
+			.route_fn::<Double>(double)
+			.route_fn::<Ping>(|_, _| async {});
+		assert_eq!(router.route_ids(), ["double_v1", "ping_v1"]);
+
+		let bytes = request_bytes(&Double(21), &Metadata::new());
+		let peer = ConnectionInfo::new(16, 1000);
+		let response = tokio_test::block_on(async {
+			let (route, _, request) = read_request(&mut bytes.as_slice(), peer, &router)
+				.await
+				.unwrap()
+				.unwrap();
+			let Route::Unary(handler) = route else {
+				panic!("a unary route was registered as streaming");
+			};
+
+			handler.call(router.state, request).await.unwrap()
+		});
+		assert_eq!(Format::default().decode::<u32>(&response).unwrap(), 43);
+	}
+
+	#[test]
+	fn test_strict_routers_refuse_to_serve_without_routes() {
+		assert!(Router::new().prepare().is_ok());
+		assert!(matches!(
+			Router::new().with_health().strict().prepare(),
+			Err(Error::NoRoutes)
+		));
+		assert!(
+			Router::new()
+				.strict()
+				.route::<Ping, _, _>(|(), _| async {})
+				.prepare()
+				.is_ok()
+		);
+	}
+
+	#[test]
+	fn test_unknown_requests_reach_the_fallback() {
+		let router = Router::with_state(1).on_unknown(|offset: u32, unrouted| async move {
+			assert_eq!(unrouted.type_id, Double::type_id());
+			let Double(n) = Format::default().decode(&unrouted.payload).unwrap();
+
+			Ok(unrouted.response_format.encode(&(n * 2 + offset)).unwrap())
+		});
+		let peer = ConnectionInfo::new(16, 1000);
+
+		let bytes = request_bytes(&Double(21), &Metadata::new());
+		let response = tokio_test::block_on(async {
+			let (route, ctx, request) = read_request(&mut bytes.as_slice(), peer, &router)
 				.await
-				.map_err(Error::NsmConnect)?;
+				.unwrap()
+				.unwrap();
+			assert_eq!(ctx.route_id(), "");
+			let Route::Unary(handler) = route else {
+				panic!("the fallback was registered as streaming");
+			};
+
+			handler.call(router.state, request).await.unwrap()
+		});
+		assert_eq!(Format::default().decode::<u32>(&response).unwrap(), 43);
+	}
+
+	/// Refuses every request, to check that handlers aren't called when extraction fails.
+	struct Refused;
+
+	impl<S> FromContext<S> for Refused {
+		fn from_context(_: &Context<'_, S>) -> Result<Self, Error> {
+			Err(Error::Handler(HandlerError::new("refused")))
+		}
+	}
+
+	#[test]
+	fn test_route_extract_pulls_from_the_context() {
+		async fn double(
+			(State(offset), Peer(peer), RequestId(id)): (State<u32>, Peer, RequestId),
+			Double(n): Double,
+		) -> u32 {
+			assert_eq!(id, "from-client");
+			n * 2 + offset + peer.cid
 		}
 
-		let router = Arc::new(self);
+		let router = Router::with_state(1)
+			.route_extract::<Double, _, _, _>(double)
+			.route_extract::<Ping, _, _, _>(|Refused, _| async { unreachable!() });
+		let peer = ConnectionInfo::new(16, 1000);
+
+		let bytes = request_bytes(&Double(21), &Metadata::new().with_request_id("from-client"));
+		let response = tokio_test::block_on(async {
+			let (route, _, request) = read_request(&mut bytes.as_slice(), peer, &router)
+				.await
+				.unwrap()
+				.unwrap();
+			let Route::Unary(handler) = route else {
+				panic!("a unary route was registered as streaming");
+			};
+
+			handler.call(router.state, request).await.unwrap()
+		});
+		assert_eq!(Format::default().decode::<u32>(&response).unwrap(), 59);
+
+		let bytes = request_bytes(&Ping, &Metadata::new());
+		let error = tokio_test::block_on(async {
+			let (route, _, request) = read_request(&mut bytes.as_slice(), peer, &router)
+				.await
+				.unwrap()
+				.unwrap();
+			let Route::Unary(handler) = route else {
+				panic!("a unary route was registered as streaming");
+			};
+
+			handler.call(router.state, request).await.unwrap_err()
+		});
+		assert!(matches!(error, Error::Handler(_)), "{error:?}");
+	}
+
+	/// Blocking handlers see the request's trace ID, and their panics are reported as such.
+	#[test]
+	fn test_route_blocking_runs_off_the_async_side() {
+		let router = Router::with_state(1)
+			.route_blocking::<Double>(|offset, Double(n)| {
+				assert!(trace_id().is_some());
+				n * 2 + offset
+			})
+			.route_blocking::<Ping>(|_, _| panic!("out of entropy"));
+		let peer = ConnectionInfo::new(16, 1000);
 
-		loop {
-			let (stream, _) = listener.accept().await.map_err(Error::Accept)?;
-			let mut stream = Stream::new(stream);
-			let router = router.clone();
+		let call = |bytes: Vec<u8>| {
+			tokio_test::block_on(async {
+				let (route, ctx, request) = read_request(&mut bytes.as_slice(), peer, &router)
+					.await
+					.unwrap()
+					.unwrap();
+				let Route::Unary(handler) = route else {
+					panic!("a unary route was registered as streaming");
+				};
 
-			tokio::spawn(async move {
-				if let Err(e) = handle_connection(&mut stream, router).await {
-					tracing::error!("Failed to handle request: {e}");
+				ctx.scope(call_unary(&router, &**handler, &ctx, request))
+					.await
+			})
+		};
+
+		let response = call(request_bytes(&Double(21), &Metadata::new())).unwrap();
+		assert_eq!(Format::default().decode::<u32>(&response).unwrap(), 43);
+
+		let error = call(request_bytes(&Ping, &Metadata::new())).unwrap_err();
+		assert!(matches!(
+			error,
+			Error::HandlerPanic {
+				route_id: "ping_v1"
+			}
+		));
+	}
+
+	/// Routers serve clients over Unix sockets like over vsock.
+	#[cfg(all(feature = "client", feature = "test-transport"))]
+	#[test]
+	fn test_unix_socket_round_trip() {
+		let path = std::env::temp_dir().join(format!("pontifex-{}.sock", std::process::id()));
+		_ = std::fs::remove_file(&path);
+		let path: &'static std::path::Path = Box::leak(path.into_boxed_path());
+
+		tokio_test::block_on(async {
+			let server = Router::with_state(1)
+				.route_fn::<Double>(double)
+				.spawn_unix(path)
+				.await
+				.unwrap();
+
+			let connection = crate::client::ConnectionDetails::to_host(1000).with_unix_socket(path);
+			let response = crate::client::send(connection, &Double(21)).await.unwrap();
+			assert_eq!(response, 43);
+
+			let (response, stats) = crate::client::send_with_stats(connection, &Double(21))
+				.await
+				.unwrap();
+			assert_eq!(response, 43);
+			// Both ends exchange their handshake, then a type ID, formats, headers and a frame.
+			assert!(stats.request_bytes > 3 + 4 + 2 + 1 + 8);
+			assert!(stats.response_bytes > 2 + 1 + 8);
+
+			server.shutdown().await;
+		});
+
+		std::fs::remove_file(path).unwrap();
+	}
+
+	/// Connections over the limit wait in the backlog until another one closes.
+	#[cfg(all(feature = "client", feature = "test-transport"))]
+	#[test]
+	fn test_connections_over_the_limit_wait() {
+		let path = std::env::temp_dir().join(format!("pontifex-limit-{}.sock", std::process::id()));
+		_ = std::fs::remove_file(&path);
+		let path: &'static std::path::Path = Box::leak(path.into_boxed_path());
+
+		tokio_test::block_on(async {
+			let server = Router::with_state(1)
+				.route_fn::<Double>(double)
+				.max_concurrent(1)
+				.spawn_unix(path)
+				.await
+				.unwrap();
+
+			let connection = crate::client::ConnectionDetails::to_host(1000).with_unix_socket(path);
+			let mut open = crate::client::Connection::open(connection).await.unwrap();
+			assert_eq!(open.send(&Double(1)).await.unwrap(), 3);
+
+			let waiting = tokio::spawn(crate::client::send(connection, &Double(2)));
+			tokio::time::sleep(Duration::from_millis(100)).await;
+			assert!(!waiting.is_finished());
+			assert_eq!(server.stats().total_connections, 1);
+
+			drop(open);
+			assert_eq!(waiting.await.unwrap().unwrap(), 5);
+			assert_eq!(server.stats().total_connections, 2);
+
+			server.shutdown().await;
+		});
+
+		std::fs::remove_file(path).unwrap();
+	}
+
+	/// Connections the bounded pool has no room for are told so before being closed.
+	#[cfg(all(feature = "client", feature = "test-transport"))]
+	#[test]
+	fn test_full_queue_refuses_connections() {
+		let path = std::env::temp_dir().join(format!("pontifex-full-{}.sock", std::process::id()));
+		_ = std::fs::remove_file(&path);
+		let path: &'static std::path::Path = Box::leak(path.into_boxed_path());
+
+		tokio_test::block_on(async {
+			let permits = Arc::new(tokio::sync::Semaphore::new(0));
+			let server = Router::with_state(permits.clone())
+				.route::<Ping, _, _>(|permits: Arc<tokio::sync::Semaphore>, _| async move {
+					permits.acquire().await.unwrap().forget();
+				})
+				.dispatch(DispatchModel::BoundedPool {
+					workers: 1,
+					queue_capacity: 1,
+				})
+				.spawn_unix(path)
+				.await
+				.unwrap();
+
+			let connection = crate::client::ConnectionDetails::to_host(1000).with_unix_socket(path);
+			// One connection keeps the worker busy, the other waits in the queue.
+			let handled = tokio::spawn(crate::client::send(connection, &Ping));
+			while server.stats().total_requests == 0 {
+				tokio::task::yield_now().await;
+			}
+			let queued = tokio::spawn(crate::client::send(connection, &Ping));
+			while server.stats().queued_connections == 0 {
+				tokio::task::yield_now().await;
+			}
+
+			let error = crate::client::send(connection, &Ping).await.unwrap_err();
+			assert!(matches!(
+				error,
+				crate::client::Error::Remote {
+					code: ErrorFrame::SERVICE_UNAVAILABLE,
+					..
 				}
-			});
+			));
+
+			// Refusals don't wait for the request they answer.
+			let mut raw = tokio::net::UnixStream::connect(path).await.unwrap();
+			let handshake = [
+				wire::PROTOCOL_VERSION,
+				Format::default().descriptor(),
+				wire::MODE_SEQUENTIAL,
+			];
+			raw.write_all(&handshake).await.unwrap();
+			let mut reply = [0; 3];
+			raw.read_exact(&mut reply).await.unwrap();
+			assert_eq!(reply[2], wire::STATUS_ERROR);
+
+			let multiplexed = crate::client::MultiplexedConnection::open(connection)
+				.await
+				.unwrap();
+			let error = multiplexed.send(&Ping).await.unwrap_err();
+			assert!(matches!(
+				error,
+				crate::client::Error::Remote {
+					code: ErrorFrame::SERVICE_UNAVAILABLE,
+					..
+				}
+			));
+
+			permits.add_permits(2);
+			handled.await.unwrap().unwrap();
+			queued.await.unwrap().unwrap();
+
+			server.shutdown().await;
+		});
+
+		std::fs::remove_file(path).unwrap();
+	}
+
+	/// Requests are handled under the ID the client sent, or a generated one.
+	#[test]
+	fn test_request_id_is_taken_from_metadata() {
+		let router = Router::new().route::<Ping, _, _>(|(), _| async {});
+		let peer = ConnectionInfo::new(16, 1000);
+
+		let bytes = request_bytes(&Ping, &Metadata::new().with_request_id("from-client"));
+		let (_, ctx, _) = tokio_test::block_on(read_request(&mut bytes.as_slice(), peer, &router))
+			.unwrap()
+			.unwrap();
+		assert_eq!(ctx.request_id(), "from-client");
+
+		let bytes = request_bytes(&Ping, &Metadata::new());
+		let (_, ctx, _) = tokio_test::block_on(read_request(&mut bytes.as_slice(), peer, &router))
+			.unwrap()
+			.unwrap();
+		assert_eq!(ctx.request_id().len(), 16);
+	}
+
+	/// Handlers see the trace ID the client sent, or a generated one, and only while handling.
+	#[test]
+	fn test_trace_id_is_scoped_to_the_request() {
+		let router = Router::new().route::<Ping, _, _>(|(), _| async {});
+		let peer = ConnectionInfo::new(16, 1000);
+		let sent = TraceId::new([7; 16]);
+
+		let bytes = request_bytes(&Ping, &Metadata::new().with_trace_id(sent));
+		let (_, ctx, _) = tokio_test::block_on(read_request(&mut bytes.as_slice(), peer, &router))
+			.unwrap()
+			.unwrap();
+		assert_eq!(ctx.trace_id(), sent);
+		assert_eq!(
+			tokio_test::block_on(ctx.scope(async { trace_id() })),
+			Some(sent)
+		);
+		assert_eq!(trace_id(), None);
+
+		let bytes = request_bytes(&Ping, &Metadata::new());
+		let (_, ctx, _) = tokio_test::block_on(read_request(&mut bytes.as_slice(), peer, &router))
+			.unwrap()
+			.unwrap();
+		assert_ne!(ctx.trace_id(), sent);
+	}
+
+	#[test]
+	fn test_gate_refuses_requests_before_their_payload() {
+		let router = Router::new()
+			.gate(|type_id, route_id| type_id != Ping::type_id() && route_id != "ping_v1")
+			.route::<Ping, _, _>(|(), _| async {})
+			.reject_policy(RejectPolicy::Drain);
+		let peer = ConnectionInfo::new(16, 1000);
+
+		let bytes = request_bytes(&Ping, &Metadata::new());
+		let mut reader = bytes.as_slice();
+		let Err(error) = tokio_test::block_on(read_request(&mut reader, peer, &router)) else {
+			panic!("a gated request was routed");
+		};
+
+		assert!(matches!(
+			error,
+			Error::Gated {
+				route_id: "ping_v1"
+			}
+		));
+		assert_eq!(error.code(), ErrorFrame::ROUTE_UNAVAILABLE);
+		// Even under `RejectPolicy::Drain`, the whole payload frame is left unread.
+		let payload = Format::default().encode(&Ping).unwrap();
+		assert_eq!(reader.len(), 8 + payload.len());
+		assert!(!error.is_on_frame_boundary(router.reject_policy));
+
+		let router = Router::new()
+			.gate(|_, _| true)
+			.route::<Ping, _, _>(|(), _| async {});
+		let request = tokio_test::block_on(read_request(&mut bytes.as_slice(), peer, &router));
+		assert!(matches!(request, Ok(Some(_))));
+	}
+
+	/// Rejected payloads are only drained up to the router's payload limit, past which the
+	/// connection is closed instead.
+	#[test]
+	fn test_drain_is_bounded_by_the_payload_limit() {
+		#[derive(Serialize, Deserialize)]
+		struct Blob(String);
+
+		impl Request for Blob {
+			const ROUTE_ID: &'static str = "blob_v1";
+			type Response = ();
 		}
+
+		let router = Router::new()
+			.route::<Ping, _, _>(|(), _| async {})
+			.reject_policy(RejectPolicy::Drain)
+			.max_payload(16);
+		let peer = ConnectionInfo::new(16, 1000);
+
+		let bytes = request_bytes(&Blob("small".to_string()), &Metadata::new());
+		let mut reader = bytes.as_slice();
+		let Err(error) = tokio_test::block_on(read_request(&mut reader, peer, &router)) else {
+			panic!("an unknown request was routed");
+		};
+		assert!(matches!(error, Error::UnknownRequest(_)), "{error:?}");
+		assert!(error.is_on_frame_boundary(router.reject_policy));
+		assert!(reader.is_empty());
+
+		let bytes = request_bytes(&Blob("large".repeat(16)), &Metadata::new());
+		let Err(error) = tokio_test::block_on(read_request(&mut bytes.as_slice(), peer, &router))
+		else {
+			panic!("an unknown request was routed");
+		};
+		assert!(
+			matches!(error, Error::Reading(CodingKey::Length, _)),
+			"{error:?}"
+		);
+		assert!(!error.is_on_frame_boundary(router.reject_policy));
 	}
-}
 
-async fn handle_connection<S>(stream: &mut Stream, router: Arc<Router<S>>) -> Result<(), Error>
-where
-	S: Clone + Send + Sync + 'static,
-{
-	// Read type ID from the wire (first 4 bytes of the message)
-	let type_id = stream
-		.read_u32()
-		.await
-		.map_err(|e| Error::Reading(CodingKey::Length, e))?;
+	/// Payload lengths are declared by the peer, so longer ones than the router accepts are
+	/// refused before anything is allocated for them, whatever the peer claims.
+	#[test]
+	fn test_declared_payload_lengths_are_bounded() {
+		let router = Router::new()
+			.route::<Ping, _, _>(|(), _| async {})
+			.max_payload(16);
+		let peer = ConnectionInfo::new(16, 1000);
 
-	// Look up the type-erased handler for this type ID
-	let handler = router.routes.get(&type_id).ok_or_else(|| {
-		tracing::warn!(
-			type_id = format!("0x{:08x}", type_id),
-			"Unknown request type"
+		let mut bytes = Ping::type_id().to_be_bytes().to_vec();
+		bytes.extend([Format::default().descriptor(); 2]);
+		tokio_test::block_on(wire::write_metadata(&mut bytes, &Metadata::new(), |_, e| e)).unwrap();
+		// Only the length prefix is sent: the server must not wait for, or make room for,
+		// the payload it announces.
+		bytes.extend(u64::MAX.to_be_bytes());
+
+		let Err(error) = tokio_test::block_on(read_request(&mut bytes.as_slice(), peer, &router))
+		else {
+			panic!("an oversized request was read");
+		};
+		assert!(
+			matches!(
+				error,
+				Error::PayloadTooLarge {
+					declared: u64::MAX,
+					limit: 16
+				}
+			),
+			"{error:?}"
 		);
-		Error::UnknownRequest(type_id)
-	})?;
+		assert_eq!(error.code(), ErrorFrame::PAYLOAD_TOO_LARGE);
+	}
+
+	/// A request cut off before its payload format tags reports the tags as what failed.
+	#[test]
+	fn test_truncated_format_tags_are_reported() {
+		let router = Router::new().route::<Ping, _, _>(|(), _| async {});
+		let bytes = Ping::type_id().to_be_bytes();
+
+		let Err(error) = tokio_test::block_on(read_request(
+			&mut bytes.as_slice(),
+			ConnectionInfo::new(16, 1000),
+			&router,
+		)) else {
+			panic!("a truncated request was read");
+		};
+		assert!(
+			matches!(error, Error::Reading(CodingKey::Format, _)),
+			"{error:?}"
+		);
+	}
+
+	/// A client stalling halfway through its request is told it timed out before the
+	/// connection is closed.
+	#[test]
+	fn test_stalled_requests_time_out() {
+		let router = Router::new()
+			.route::<Ping, _, _>(|(), _| async {})
+			.with_timeout(Duration::from_secs(1));
+
+		tokio_test::block_on(async {
+			// The clock only moves once every task is idle, so the timeout elapses right away.
+			tokio::time::pause();
+			let (mut client, server) = tokio::io::duplex(1024);
+
+			// The handshake, then only half of a type ID.
+			let handshake = [
+				wire::PROTOCOL_VERSION,
+				Format::default().descriptor(),
+				wire::MODE_SEQUENTIAL,
+			];
+			client.write_all(&handshake).await.unwrap();
+			client.write_all(&[0, 0]).await.unwrap();
 
-	// Call the handler's type-erased handle method.
-	// The handler internally knows its concrete types and will:
-	// 1. Deserialize the stream to the correct request type
-	// 2. Call the user's handler function with typed parameters
-	// 3. Serialize and send the typed response
-	handler.handle(stream, router.state.clone()).await
+			let mut stream = Stream::from_io(Box::new(server));
+			let peer = ConnectionInfo::new(16, 1000);
+			let result = handle_connection(&mut stream, peer, Arc::new(router)).await;
+			assert!(
+				matches!(result, Err(Error::Timeout(TimeoutPhase::Reading))),
+				"{result:?}"
+			);
+
+			// The server's handshake, then an error frame.
+			let mut reply = [0; 3];
+			client.read_exact(&mut reply).await.unwrap();
+			assert_eq!(reply[2], wire::STATUS_ERROR);
+		});
+	}
+
+	/// A client stalling during the handshake times out like one stalling while sending its
+	/// request.
+	#[test]
+	fn test_stalled_handshakes_time_out() {
+		let router = Router::new()
+			.route::<Ping, _, _>(|(), _| async {})
+			.with_timeout(Duration::from_secs(1));
+
+		tokio_test::block_on(async {
+			tokio::time::pause();
+			let (mut client, server) = tokio::io::duplex(1024);
+
+			// Only the protocol version, without the format descriptor and mode.
+			client.write_all(&[wire::PROTOCOL_VERSION]).await.unwrap();
+
+			let mut stream = Stream::from_io(Box::new(server));
+			let peer = ConnectionInfo::new(16, 1000);
+			let result = handle_connection(&mut stream, peer, Arc::new(router)).await;
+			assert!(
+				matches!(result, Err(Error::Timeout(TimeoutPhase::Reading))),
+				"{result:?}"
+			);
+		});
+	}
+
+	/// Draining an unknown request reads its checksum too, so the next request on the
+	/// connection is read from its start.
+	#[cfg(feature = "checksum")]
+	#[test]
+	fn test_drain_reads_the_checksum_of_unknown_requests() {
+		let router = Router::with_state(0)
+			.route_fn::<Double>(double)
+			.reject_policy(RejectPolicy::Drain);
+
+		let mut metadata = Metadata::new();
+		metadata.insert(Metadata::CHECKSUM, Checksum::Crc32c.name());
+		let mut unknown = request_bytes(&Ping, &metadata);
+		let payload = Format::default().encode(&Ping).unwrap();
+		unknown.extend(crc32c::crc32c(&payload).to_be_bytes());
+
+		tokio_test::block_on(async {
+			let (mut client, server) = tokio::io::duplex(1024);
+
+			let handshake = [
+				wire::PROTOCOL_VERSION,
+				Format::default().descriptor(),
+				wire::MODE_SEQUENTIAL,
+			];
+			client.write_all(&handshake).await.unwrap();
+			client.write_all(&unknown).await.unwrap();
+			client
+				.write_all(&request_bytes(&Double(21), &Metadata::new()))
+				.await
+				.unwrap();
+			client.shutdown().await.unwrap();
+
+			let mut stream = Stream::from_io(Box::new(server));
+			let peer = ConnectionInfo::new(16, 1000);
+			let result = handle_connection(&mut stream, peer, Arc::new(router)).await;
+			assert!(result.is_ok(), "{result:?}");
+
+			// The server's handshake, then an error frame for the unknown request.
+			let mut reply = [0; 3];
+			client.read_exact(&mut reply).await.unwrap();
+			assert_eq!(reply[2], wire::STATUS_ERROR);
+			wire::drain_frame(&mut client, u64::MAX).await.unwrap();
+
+			// Then the response to the next request.
+			assert_eq!(client.read_u8().await.unwrap(), wire::STATUS_OK);
+			let length = client.read_u64().await.unwrap();
+			let mut response = vec![0; usize::try_from(length).unwrap()];
+			client.read_exact(&mut response).await.unwrap();
+			assert_eq!(Format::default().decode::<u32>(&response).unwrap(), 42);
+		});
+	}
 }
@@ -0,0 +1,412 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	hash::{BuildHasher, RandomState},
+	sync::{Arc, Mutex, PoisonError},
+	time::{Duration, Instant},
+};
+
+use super::{BoxFuture, Error, Handler, MAX_CACHED_BYTES, RawRequest};
+use crate::wire::Metadata;
+
+/// What makes two requests share a cached response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CacheKey {
+	/// The exact serialized payload, unless the client sent a `no-cache` header.
+	Payload,
+	/// The idempotency key supplied by the client, if any, scoped to the client's CID.
+	IdempotencyKey,
+}
+
+impl CacheKey {
+	/// The key to cache the response to a request under, or `None` if it must not be cached.
+	fn derive(self, request: &RawRequest) -> Option<Vec<u8>> {
+		let id = match self {
+			Self::Payload if request.metadata.get(Metadata::NO_CACHE).is_some() => return None,
+			Self::Payload => request.payload.as_slice(),
+			Self::IdempotencyKey => request.metadata.idempotency_key()?,
+		};
+
+		// The same request answered in another format is a different response.
+		let mut key = Vec::with_capacity(id.len() + 6);
+		key.push(request.formats.request.descriptor());
+		key.push(request.formats.response.descriptor());
+
+		// Clients choose their keys, so one client must not be able to replay another's.
+		if self == Self::IdempotencyKey {
+			key.extend_from_slice(&request.ctx.peer.cid.to_be_bytes());
+		}
+		key.extend_from_slice(id);
+
+		Some(key)
+	}
+}
+
+/// A handler wrapper that serves repeated requests from a bounded, time-limited cache.
+///
+/// With `CacheKey::Payload` the cache is content-addressed: the key is the exact serialized
+/// request payload (along with the formats it was tagged with), so only byte-for-byte
+/// identical requests share a response. With `CacheKey::IdempotencyKey` the client decides
+/// which requests are the same by tagging them with a key. Either way, responses are stored
+/// already serialized, which means a cache hit skips both the handler and the response encoding.
+pub(super) struct CachedHandler<S> {
+	inner: Box<dyn Handler<S>>,
+	key: CacheKey,
+	cache: Mutex<ResponseCache>,
+	/// Hashes the payloads sent with an idempotency key, keyed randomly so that clients
+	/// can't craft two payloads with the same hash.
+	hasher: RandomState,
+}
+
+impl<S> CachedHandler<S> {
+	pub(super) fn new(
+		inner: Box<dyn Handler<S>>,
+		ttl: Duration,
+		max_entries: usize,
+		key: CacheKey,
+	) -> Self {
+		Self {
+			inner,
+			key,
+			cache: Mutex::new(ResponseCache::new(ttl, max_entries, MAX_CACHED_BYTES)),
+			hasher: RandomState::new(),
+		}
+	}
+
+	fn cache(&self) -> std::sync::MutexGuard<'_, ResponseCache> {
+		// The cache holds no invariants a panic could break halfway, so a poisoned lock is safe to reuse.
+		self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+}
+
+impl<S> Handler<S> for CachedHandler<S>
+where
+	S: Send + Sync + 'static,
+{
+	fn call(&self, state: S, request: RawRequest) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+		Box::pin(async move {
+			let Some(key) = self.key.derive(&request) else {
+				return self.inner.call(state, request).await;
+			};
+
+			// The payload is part of content-addressed keys already.
+			let payload_hash = (self.key == CacheKey::IdempotencyKey)
+				.then(|| self.hasher.hash_one(&request.payload));
+
+			let cached = self
+				.cache()
+				.get(&key)
+				.map(|entry| (entry.payload_hash, entry.response.clone()));
+			if let Some((cached_hash, response)) = cached {
+				if cached_hash != payload_hash {
+					return Err(Error::IdempotencyKeyReused {
+						route_id: request.ctx.route_id,
+					});
+				}
+
+				tracing::debug!(
+					key = ?self.key,
+					length = request.payload.len(),
+					"serving response from cache"
+				);
+				return Ok(response);
+			}
+
+			// Errors are never cached, so a failing request is retried on the next call.
+			let response = self.inner.call(state, request).await?;
+			self.cache().insert(key, payload_hash, response.clone());
+
+			Ok(response)
+		})
+	}
+}
+
+struct CacheEntry {
+	response: Vec<u8>,
+	/// The hash of the payload the response answered, for keys that don't include it.
+	payload_hash: Option<u64>,
+	inserted_at: Instant,
+	/// When the entry was last used, which it is found under in `ResponseCache::recency`.
+	last_used: u64,
+}
+
+/// A least-recently-used map from request bytes to response bytes with a fixed time-to-live,
+/// bounded both in entries and in bytes.
+///
+/// Keys are also ordered by when their entry was last used, so that the least recently used
+/// one is found without scanning the cache: every operation takes logarithmic time at most,
+/// and the lock around the cache is never held for long. Keys are shared between both maps
+/// rather than copied, as they can be as large as request payloads.
+struct ResponseCache {
+	ttl: Duration,
+	max_entries: usize,
+	max_bytes: usize,
+	/// How many bytes of keys and responses the cache holds.
+	bytes: usize,
+	clock: u64,
+	entries: HashMap<Arc<[u8]>, CacheEntry>,
+	/// The keys of every entry, least recently used first.
+	recency: BTreeMap<u64, Arc<[u8]>>,
+}
+
+/// The most entries a cache makes room for up front, however many it may hold, so that a
+/// generous bound doesn't reserve memory for responses that may never be cached.
+const MAX_INITIAL_ENTRIES: usize = 1024;
+
+impl ResponseCache {
+	fn new(ttl: Duration, max_entries: usize, max_bytes: usize) -> Self {
+		Self {
+			ttl,
+			max_entries,
+			max_bytes,
+			bytes: 0,
+			clock: 0,
+			entries: HashMap::with_capacity(max_entries.min(MAX_INITIAL_ENTRIES)),
+			recency: BTreeMap::new(),
+		}
+	}
+
+	const fn tick(&mut self) -> u64 {
+		self.clock += 1;
+		self.clock
+	}
+
+	fn get(&mut self, key: &[u8]) -> Option<&CacheEntry> {
+		if self.entries.get(key)?.inserted_at.elapsed() >= self.ttl {
+			self.remove(key);
+			return None;
+		}
+
+		let now = self.tick();
+		let entry = self.entries.get_mut(key)?;
+		if let Some(key) = self.recency.remove(&entry.last_used) {
+			self.recency.insert(now, key);
+		}
+		entry.last_used = now;
+
+		Some(entry)
+	}
+
+	fn insert(&mut self, key: Vec<u8>, payload_hash: Option<u64>, response: Vec<u8>) {
+		let size = key.len().saturating_add(response.len());
+		if self.max_entries == 0 || size > self.max_bytes {
+			return;
+		}
+
+		// Replacing an entry makes room for its replacement first.
+		self.remove(&key);
+		while self.entries.len() >= self.max_entries || self.bytes + size > self.max_bytes {
+			let Some((_, oldest)) = self.recency.pop_first() else {
+				break;
+			};
+			self.remove(&oldest);
+		}
+
+		let key: Arc<[u8]> = key.into();
+		let last_used = self.tick();
+		self.bytes += size;
+		self.recency.insert(last_used, key.clone());
+		self.entries.insert(
+			key,
+			CacheEntry {
+				response,
+				payload_hash,
+				last_used,
+				inserted_at: Instant::now(),
+			},
+		);
+	}
+
+	/// Drop the entry cached under `key`, if there is one.
+	fn remove(&mut self, key: &[u8]) {
+		if let Some((key, entry)) = self.entries.remove_entry(key) {
+			self.recency.remove(&entry.last_used);
+			self.bytes -= key.len() + entry.response.len();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		server::{ConnectionInfo, PayloadFormats, RequestContext},
+		wire::{ErrorFrame, Format, TraceId},
+	};
+	use std::sync::{
+		Arc,
+		atomic::{AtomicUsize, Ordering},
+	};
+
+	fn cached(cache: &mut ResponseCache, key: &[u8]) -> Option<Vec<u8>> {
+		cache.get(key).map(|entry| entry.response.clone())
+	}
+
+	/// Answers with the payload it was sent, counting how many times it ran.
+	struct Echo(Arc<AtomicUsize>);
+
+	impl Handler<()> for Echo {
+		fn call(&self, (): (), request: RawRequest) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+			self.0.fetch_add(1, Ordering::Relaxed);
+			Box::pin(async move { Ok(request.payload) })
+		}
+	}
+
+	fn request(cid: u32, metadata: Metadata, payload: &[u8]) -> RawRequest {
+		let format = Format::default();
+		RawRequest {
+			formats: PayloadFormats {
+				request: format,
+				response: format,
+				presize: false,
+			},
+			ctx: RequestContext {
+				type_id: 0,
+				route_id: "cached_v1",
+				peer: ConnectionInfo::new(cid, 1234),
+				metadata: metadata.clone(),
+				request_id: String::new(),
+				trace_id: TraceId::new([0; 16]),
+			},
+			metadata,
+			payload: payload.to_vec(),
+		}
+	}
+
+	#[test]
+	fn test_evicts_least_recently_used() {
+		let mut cache = ResponseCache::new(Duration::from_mins(1), 2, usize::MAX);
+
+		cache.insert(b"a".to_vec(), None, b"1".to_vec());
+		cache.insert(b"b".to_vec(), None, b"2".to_vec());
+
+		// Touch `a` so that `b` becomes the least recently used entry.
+		assert_eq!(cached(&mut cache, b"a"), Some(b"1".to_vec()));
+		cache.insert(b"c".to_vec(), None, b"3".to_vec());
+
+		assert_eq!(cached(&mut cache, b"a"), Some(b"1".to_vec()));
+		assert_eq!(cached(&mut cache, b"b"), None);
+		assert_eq!(cached(&mut cache, b"c"), Some(b"3".to_vec()));
+	}
+
+	#[test]
+	fn test_cache_keys() {
+		let plain = request(3, Metadata::new(), b"payload");
+		assert!(
+			CacheKey::Payload
+				.derive(&plain)
+				.unwrap()
+				.ends_with(b"payload")
+		);
+		assert_eq!(CacheKey::IdempotencyKey.derive(&plain), None);
+
+		let keyed = |cid| {
+			request(
+				cid,
+				Metadata::new().with_idempotency_key(b"retry-1".to_vec()),
+				b"payload",
+			)
+		};
+		assert!(
+			CacheKey::IdempotencyKey
+				.derive(&keyed(3))
+				.unwrap()
+				.ends_with(b"retry-1")
+		);
+		assert_ne!(
+			CacheKey::IdempotencyKey.derive(&keyed(3)),
+			CacheKey::IdempotencyKey.derive(&keyed(4))
+		);
+
+		let uncached = request(
+			3,
+			Metadata::new().with(Metadata::NO_CACHE, Vec::new()),
+			b"payload",
+		);
+		assert_eq!(CacheKey::Payload.derive(&uncached), None);
+	}
+
+	fn idempotent(calls: &Arc<AtomicUsize>) -> CachedHandler<()> {
+		CachedHandler::new(
+			Box::new(Echo(calls.clone())),
+			Duration::from_mins(1),
+			2,
+			CacheKey::IdempotencyKey,
+		)
+	}
+
+	fn keyed(cid: u32, payload: &[u8]) -> RawRequest {
+		let metadata = Metadata::new().with_idempotency_key(b"order-42".to_vec());
+		request(cid, metadata, payload)
+	}
+
+	#[test]
+	fn test_idempotency_keys_are_scoped_to_the_client() {
+		let calls = Arc::new(AtomicUsize::new(0));
+		let handler = idempotent(&calls);
+
+		for cid in [3, 3, 4] {
+			tokio_test::block_on(handler.call((), keyed(cid, b"payload"))).unwrap();
+		}
+
+		// The retry from CID 3 is answered from the cache, CID 4 reusing its key isn't.
+		assert_eq!(calls.load(Ordering::Relaxed), 2);
+	}
+
+	#[test]
+	fn test_idempotency_keys_reused_with_another_payload_are_refused() {
+		let calls = Arc::new(AtomicUsize::new(0));
+		let handler = idempotent(&calls);
+
+		tokio_test::block_on(handler.call((), keyed(3, b"payload"))).unwrap();
+		let error = tokio_test::block_on(handler.call((), keyed(3, b"other payload"))).unwrap_err();
+
+		assert!(
+			matches!(
+				error,
+				Error::IdempotencyKeyReused {
+					route_id: "cached_v1"
+				}
+			),
+			"{error:?}"
+		);
+		assert_eq!(error.code(), ErrorFrame::IDEMPOTENCY_KEY_REUSED);
+		assert_eq!(calls.load(Ordering::Relaxed), 1);
+	}
+
+	#[test]
+	fn test_expired_entries_are_not_served() {
+		let mut cache = ResponseCache::new(Duration::ZERO, 2, usize::MAX);
+
+		cache.insert(b"a".to_vec(), None, b"1".to_vec());
+
+		assert_eq!(cached(&mut cache, b"a"), None);
+		assert!(cache.entries.is_empty());
+		assert!(cache.recency.is_empty());
+		assert_eq!(cache.bytes, 0);
+	}
+
+	/// Keys count towards the byte limit along with responses, and entries that could never
+	/// fit aren't cached at all.
+	#[test]
+	fn test_evicts_to_stay_within_the_byte_limit() {
+		let mut cache = ResponseCache::new(Duration::from_mins(1), 16, 8);
+
+		cache.insert(b"a".to_vec(), None, b"123".to_vec());
+		cache.insert(b"b".to_vec(), None, b"456".to_vec());
+		cache.insert(b"c".to_vec(), None, b"7".to_vec());
+
+		assert_eq!(cached(&mut cache, b"a"), None);
+		assert_eq!(cached(&mut cache, b"b"), Some(b"456".to_vec()));
+		assert_eq!(cached(&mut cache, b"c"), Some(b"7".to_vec()));
+		assert_eq!(cache.bytes, 6);
+
+		cache.insert(b"d".to_vec(), None, b"12345678".to_vec());
+		assert_eq!(cached(&mut cache, b"d"), None);
+		assert_eq!(cache.entries.len(), 2);
+		assert_eq!(cache.bytes, 6);
+
+		// Replacing an entry releases the bytes of the one it replaces.
+		cache.insert(b"b".to_vec(), None, b"4".to_vec());
+		assert_eq!(cache.bytes, 4);
+	}
+}
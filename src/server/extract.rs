@@ -0,0 +1,126 @@
+use super::{ConnectionInfo, Error, RequestContext};
+
+/// Everything an extractor can pull from, see [`FromContext`].
+pub struct Context<'a, S> {
+	pub(super) state: &'a S,
+	pub(super) request: &'a RequestContext,
+}
+
+impl<S> Context<'_, S> {
+	/// The router's state.
+	#[must_use]
+	pub const fn state(&self) -> &S {
+		self.state
+	}
+
+	/// What is known about the request besides its payload: who sent it, its metadata
+	/// headers, and its request and trace IDs.
+	#[must_use]
+	pub const fn request(&self) -> &RequestContext {
+		self.request
+	}
+}
+
+/// A value a handler registered with `Router::route_extract` takes along with its request.
+///
+/// Extractors are pulled from the request's [`Context`] before the handler is called, so a
+/// handler only declares the ones it needs, in a tuple, rather than taking them all as
+/// positional arguments. Extraction failing answers the request with the returned error,
+/// without calling the handler.
+///
+/// This is implemented for [`State`], [`Peer`], [`RequestId`], [`Nsm`] and tuples of up to
+/// six extractors. Implement it to extract an application's own types.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// struct Caller(Role);
+///
+/// impl FromContext<AppState> for Caller {
+///     fn from_context(cx: &Context<'_, AppState>) -> Result<Self, Error> {
+///         cx.state().roles.get(cx.request().peer().cid).map(Caller).ok_or_else(|| {
+///             Error::Handler(HandlerError::new("unknown caller").with_code(FORBIDDEN))
+///         })
+///     }
+/// }
+/// ```
+pub trait FromContext<S>: Sized + Send {
+	/// Extract the value for the request being handled.
+	///
+	/// # Errors
+	///
+	/// Returns the error to answer the request with instead of calling the handler.
+	fn from_context(cx: &Context<'_, S>) -> Result<Self, Error>;
+}
+
+/// Extracts a clone of the router's state.
+#[derive(Debug, Clone)]
+pub struct State<S>(pub S);
+
+impl<S: Clone + Send> FromContext<S> for State<S> {
+	fn from_context(cx: &Context<'_, S>) -> Result<Self, Error> {
+		Ok(Self(cx.state.clone()))
+	}
+}
+
+/// Extracts the client that sent the request, see `Router::route_with_info`.
+#[derive(Debug, Clone, Copy)]
+pub struct Peer(pub ConnectionInfo);
+
+impl<S> FromContext<S> for Peer {
+	fn from_context(cx: &Context<'_, S>) -> Result<Self, Error> {
+		Ok(Self(cx.request.peer()))
+	}
+}
+
+/// Extracts the ID the client sent along with the request, or the one generated for it.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl<S> FromContext<S> for RequestId {
+	fn from_context(cx: &Context<'_, S>) -> Result<Self, Error> {
+		Ok(Self(cx.request.request_id().to_owned()))
+	}
+}
+
+/// Extracts the global secure module, connected to when the router started serving.
+///
+/// Extraction fails with an internal error if the router was served without connecting to
+/// it, such as over in-memory pipes.
+#[cfg(feature = "nsm")]
+#[derive(Clone, Copy)]
+pub struct Nsm(pub &'static crate::nsm::SecureModule);
+
+#[cfg(feature = "nsm")]
+impl<S> FromContext<S> for Nsm {
+	fn from_context(_: &Context<'_, S>) -> Result<Self, Error> {
+		crate::nsm::SecureModule::try_global()
+			.map(Self)
+			.ok_or_else(|| {
+				Error::NsmConnect(std::io::Error::new(
+					std::io::ErrorKind::NotConnected,
+					"the secure module was not connected to",
+				))
+			})
+	}
+}
+
+macro_rules! impl_from_context {
+	($($extractor:ident),+) => {
+		impl<S, $($extractor),+> FromContext<S> for ($($extractor,)+)
+		where
+			$($extractor: FromContext<S>,)+
+		{
+			fn from_context(cx: &Context<'_, S>) -> Result<Self, Error> {
+				Ok(($($extractor::from_context(cx)?,)+))
+			}
+		}
+	};
+}
+
+impl_from_context!(A);
+impl_from_context!(A, B);
+impl_from_context!(A, B, C);
+impl_from_context!(A, B, C, D);
+impl_from_context!(A, B, C, D, E);
+impl_from_context!(A, B, C, D, E, F);
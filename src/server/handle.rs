@@ -0,0 +1,136 @@
+use std::{
+	collections::HashMap,
+	sync::{
+		Arc, Mutex, MutexGuard, PoisonError,
+		atomic::{AtomicU64, Ordering},
+	},
+	time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+use super::{Error, ServerStats, StatsHandle};
+
+/// Details about a client connected to the server.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo {
+	/// The CID of the client.
+	pub cid: u32,
+	/// The port the client connected from.
+	pub port: u32,
+	/// When the connection was accepted.
+	pub connected_at: Instant,
+}
+
+impl ConnectionInfo {
+	pub(super) fn new(cid: u32, port: u32) -> Self {
+		Self {
+			cid,
+			port,
+			connected_at: Instant::now(),
+		}
+	}
+
+	/// How long the connection has been open.
+	#[must_use]
+	pub fn age(&self) -> Duration {
+		self.connected_at.elapsed()
+	}
+}
+
+/// A handle to a server started with [`Router::spawn`](super::Router::spawn).
+pub struct ServerHandle {
+	task: JoinHandle<Result<(), Error>>,
+	connections: Arc<ConnectionRegistry>,
+	stats: StatsHandle,
+}
+
+impl ServerHandle {
+	pub(super) const fn new(
+		task: JoinHandle<Result<(), Error>>,
+		connections: Arc<ConnectionRegistry>,
+		stats: StatsHandle,
+	) -> Self {
+		Self {
+			task,
+			connections,
+			stats,
+		}
+	}
+
+	/// Take a snapshot of the server's counters.
+	#[must_use]
+	pub fn stats(&self) -> ServerStats {
+		self.stats.stats()
+	}
+
+	/// List the connections currently being handled by the server.
+	#[must_use]
+	pub fn active_connections(&self) -> Vec<ConnectionInfo> {
+		self.connections.connections().values().copied().collect()
+	}
+
+	/// Whether the server has stopped accepting connections, either because it failed or was aborted.
+	#[must_use]
+	pub fn is_finished(&self) -> bool {
+		self.task.is_finished()
+	}
+
+	/// Stop accepting new connections.
+	///
+	/// Connections that were already accepted keep being handled until they complete.
+	pub fn abort(&self) {
+		self.task.abort();
+	}
+
+	/// Stop accepting new connections, and release what the server held once it stopped.
+	///
+	/// Like [`ServerHandle::abort`], connections that were already accepted keep being
+	/// handled. With the `nsm` feature, the connection to the secure module the server
+	/// initialized is closed, see `SecureModule::shutdown_global`, rather than left open
+	/// until the process exits. It stays open while other servers of the process still run.
+	pub async fn shutdown(self) {
+		self.task.abort();
+		// The task was aborted, so it either failed earlier or was cancelled: neither is
+		// worth reporting to a caller stopping it anyway. Either way, it released the
+		// secure module by the time it is awaited.
+		let _ = self.task.await;
+	}
+}
+
+/// The set of open connections of a spawned server.
+#[derive(Default)]
+pub(super) struct ConnectionRegistry {
+	next_id: AtomicU64,
+	connections: Mutex<HashMap<u64, ConnectionInfo>>,
+}
+
+impl ConnectionRegistry {
+	/// Record a new connection, which stays listed until the returned guard is dropped.
+	pub(super) fn register(self: &Arc<Self>, info: ConnectionInfo) -> Registration {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		self.connections().insert(id, info);
+
+		Registration {
+			id,
+			registry: self.clone(),
+		}
+	}
+
+	fn connections(&self) -> MutexGuard<'_, HashMap<u64, ConnectionInfo>> {
+		self.connections
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+	}
+}
+
+/// Removes its connection from the registry when dropped.
+pub(super) struct Registration {
+	id: u64,
+	registry: Arc<ConnectionRegistry>,
+}
+
+impl Drop for Registration {
+	fn drop(&mut self) {
+		self.registry.connections().remove(&self.id);
+	}
+}
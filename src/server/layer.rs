@@ -0,0 +1,204 @@
+use std::{future::Future, sync::Arc, time::Instant};
+
+use tracing::Instrument;
+
+use super::{BoxFuture, ConnectionInfo, Error};
+use crate::{
+	TypeId,
+	wire::{Metadata, TraceId},
+};
+
+tokio::task_local! {
+	static TRACE_ID: TraceId;
+}
+
+/// The trace ID of the request being handled, to pass on to the calls made while handling it.
+///
+/// This is the ID the client sent along with the request, or a generated one if it didn't.
+/// It is set for handlers and layers, and for the streams returned by streaming handlers;
+/// outside of them, this returns `None`.
+#[must_use]
+pub fn trace_id() -> Option<TraceId> {
+	TRACE_ID.try_with(|id| *id).ok()
+}
+
+/// Wrap work moved off the calling task to run in its span, and under its trace ID.
+pub(super) fn propagate<T>(work: impl FnOnce() -> T) -> impl FnOnce() -> T {
+	let span = tracing::Span::current();
+	let trace_id = trace_id();
+
+	move || {
+		let _entered = span.enter();
+		match trace_id {
+			Some(id) => TRACE_ID.sync_scope(id, work),
+			None => work(),
+		}
+	}
+}
+
+/// What a [`Layer`] knows about the request it wraps.
+///
+/// Layers sit in front of every route, so they only see what is known before a request is
+/// decoded: which route it is for, who sent it and its metadata headers.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+	pub(super) type_id: TypeId,
+	pub(super) route_id: &'static str,
+	pub(super) peer: ConnectionInfo,
+	pub(super) metadata: Metadata,
+	pub(super) request_id: String,
+	pub(super) trace_id: TraceId,
+}
+
+impl RequestContext {
+	/// The type ID the request was routed by.
+	#[must_use]
+	pub const fn type_id(&self) -> TypeId {
+		self.type_id
+	}
+
+	/// The route ID of the request type, as registered on the router.
+	#[must_use]
+	pub const fn route_id(&self) -> &'static str {
+		self.route_id
+	}
+
+	/// The client that sent the request.
+	#[must_use]
+	pub const fn peer(&self) -> ConnectionInfo {
+		self.peer
+	}
+
+	/// The metadata headers sent along with the request.
+	#[must_use]
+	pub const fn metadata(&self) -> &Metadata {
+		&self.metadata
+	}
+
+	/// The ID the client sent along with the request, or a generated one if it didn't.
+	#[must_use]
+	pub fn request_id(&self) -> &str {
+		&self.request_id
+	}
+
+	/// The trace ID the client sent along with the request, or a generated one if it didn't.
+	#[must_use]
+	pub const fn trace_id(&self) -> TraceId {
+		self.trace_id
+	}
+
+	/// Run the handling of the request in its span, with its trace ID set for [`trace_id`].
+	pub(super) fn scope<F: Future>(&self, future: F) -> impl Future<Output = F::Output> + use<F> {
+		let span = tracing::info_span!(
+			"pontifex.request",
+			route_id = self.route_id,
+			type_id = format!("0x{:08x}", self.type_id),
+			request_id = self.request_id,
+			trace_id = %self.trace_id,
+		);
+
+		TRACE_ID.scope(self.trace_id, future.instrument(span))
+	}
+}
+
+/// Behavior wrapped around every request handled by a router, see `Router::layer`.
+///
+/// A layer runs the rest of the chain by calling [`Next::run`], and can act before and
+/// after it, or return early without calling it at all to refuse the request. Responses
+/// are seen as the encoded bytes sent to the client, and errors as the [`Error`] reported
+/// to it: refuse a request with `Error::Handler` to give the client a specific code.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// struct ParentOnly;
+///
+/// impl Layer for ParentOnly {
+///     fn around<'a>(&'a self, ctx: &'a RequestContext, next: Next<'a>) -> BoxFuture<'a, Result<Vec<u8>, Error>> {
+///         if ctx.peer().cid != PARENT_CID {
+///             let error = HandlerError::with_code(FORBIDDEN, "only the parent may call this");
+///             return Box::pin(async { Err(Error::Handler(error)) });
+///         }
+///
+///         next.run()
+///     }
+/// }
+/// ```
+pub trait Layer: Send + Sync + 'static {
+	/// Handle a request, running the rest of the chain with `next`.
+	fn around<'a>(
+		&'a self,
+		ctx: &'a RequestContext,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Result<Vec<u8>, Error>>;
+}
+
+/// The rest of the chain a [`Layer`] wraps: the layers registered after it, then the handler.
+pub struct Next<'a> {
+	pub(super) layers: &'a [Arc<dyn Layer>],
+	pub(super) ctx: &'a RequestContext,
+	pub(super) handler: BoxFuture<'a, Result<Vec<u8>, Error>>,
+}
+
+impl<'a> Next<'a> {
+	/// Run the rest of the chain, returning the encoded response.
+	#[must_use]
+	pub fn run(self) -> BoxFuture<'a, Result<Vec<u8>, Error>> {
+		match self.layers.split_first() {
+			Some((layer, layers)) => layer.around(
+				self.ctx,
+				Next {
+					layers,
+					ctx: self.ctx,
+					handler: self.handler,
+				},
+			),
+			None => self.handler,
+		}
+	}
+}
+
+/// A layer opening a tracing span for every request, and logging how it went.
+///
+/// The span records the route ID, the type ID and the CID of the client, so that anything
+/// logged by the handler can be traced back to its request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingLayer;
+
+impl Layer for TracingLayer {
+	fn around<'a>(
+		&'a self,
+		ctx: &'a RequestContext,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Result<Vec<u8>, Error>> {
+		let span = tracing::info_span!(
+			"request",
+			route_id = ctx.route_id(),
+			type_id = format!("0x{:08x}", ctx.type_id()),
+			peer_cid = ctx.peer().cid,
+		);
+
+		Box::pin(
+			async move {
+				let started = Instant::now();
+				let result = next.run().await;
+
+				match &result {
+					Ok(response) => tracing::info!(
+						elapsed = ?started.elapsed(),
+						response_bytes = response.len(),
+						"handled request"
+					),
+					Err(error) => tracing::warn!(
+						elapsed = ?started.elapsed(),
+						%error,
+						"failed to handle request"
+					),
+				}
+
+				result
+			}
+			.instrument(span),
+		)
+	}
+}
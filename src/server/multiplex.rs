@@ -0,0 +1,232 @@
+use futures_util::{
+	StreamExt,
+	future::{self, Either},
+	stream::FuturesUnordered,
+};
+use std::{io, time::Instant};
+use tokio::{
+	io::{AsyncWriteExt, ReadHalf, WriteHalf},
+	sync::Mutex,
+};
+
+use super::{
+	ConnectionInfo, Error, RawRequest, RequestContext, Route, Router, TimeoutPhase, call_unary,
+	read_leading, read_request, respond, within, write_error,
+};
+use crate::{
+	utils::{CodingKey, Stream},
+	wire::{Checksum, Compression},
+};
+
+/// The reading half of a multiplexed connection.
+type Reader<'s> = ReadHalf<&'s mut Stream>;
+
+/// The writing half of a multiplexed connection, shared by the requests being handled.
+type Writer<'s> = Mutex<WriteHalf<&'s mut Stream>>;
+
+/// A request read off a multiplexed connection, along with its stream ID.
+type Incoming<'r, S> = (
+	u32,
+	Result<(&'r Route<S>, RequestContext, RawRequest), Error>,
+);
+
+/// Serve a connection the client opened in multiplexed mode.
+///
+/// Requests are read as they come, and handled concurrently on the connection's task, up to
+/// [`Router::max_streams`] at once. Each response is written as soon as it is ready, preceded
+/// by the stream ID of its request. Once the client closes the connection, or a request can't
+/// be read, the requests still being handled are answered before the connection is closed.
+pub(super) async fn serve<S>(
+	stream: &mut Stream,
+	peer: ConnectionInfo,
+	router: &Router<S>,
+) -> Result<(), Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let (reader, writer) = tokio::io::split(stream);
+	let writer = Mutex::new(writer);
+	let max_streams = router.max_streams.max(1);
+
+	let mut in_flight = FuturesUnordered::new();
+	let mut next = Box::pin(read_next(reader, peer, router));
+
+	let closed = loop {
+		// The next request is only read once there's room to handle it.
+		let event = if in_flight.is_empty() {
+			Either::Left((&mut next).await)
+		} else if in_flight.len() >= max_streams {
+			Either::Right(in_flight.next().await)
+		} else {
+			match future::select(&mut next, in_flight.next()).await {
+				Either::Left((read, _)) => Either::Left(read),
+				Either::Right((handled, _)) => Either::Right(handled),
+			}
+		};
+
+		match event {
+			Either::Left((reader, read)) => {
+				match read {
+					Ok(Some((id, Ok((route, ctx, request))))) => {
+						let scoped = ctx.clone();
+						let handled = handle(router, &writer, id, route, ctx, request);
+						in_flight.push(scoped.scope(handled));
+					},
+					// Like on sequential connections, reading goes on if the request was read in full.
+					Ok(Some((id, Err(error)))) => {
+						let report = respond_tagged(
+							router,
+							&writer,
+							id,
+							Err(error),
+							Compression::None,
+							Checksum::None,
+						);
+
+						if let Err(error) = report.await {
+							break Err(error);
+						}
+					},
+					Ok(None) => break Ok(()),
+					Err(error) => break Err(error),
+				}
+
+				next.set(read_next(reader, peer, router));
+			},
+			// Nothing more can be written once writing a response failed.
+			Either::Right(Some(Err(error))) => return Err(error),
+			Either::Right(_) => {},
+		}
+	};
+
+	// Answer the requests still being handled before closing the connection.
+	while let Some(handled) = in_flight.next().await {
+		handled?;
+	}
+
+	closed
+}
+
+/// Answer the first request of a multiplexed connection that won't be served with
+/// `Error::ServiceUnavailable`.
+///
+/// Only the stream ID of the request is read, so that the client can tell which of its
+/// requests was refused.
+pub(super) async fn refuse(stream: &mut Stream) -> Result<(), Error> {
+	let Some(id) = read_leading(stream, CodingKey::StreamId).await? else {
+		return Ok(());
+	};
+
+	stream
+		.write_u32(u32::from_be_bytes(id))
+		.await
+		.map_err(|e| Error::Writing(CodingKey::StreamId, e))?;
+	write_error(stream, &Error::ServiceUnavailable).await
+}
+
+/// Read the next request off the connection, handing the reader back along with it so that
+/// the following one can be read next.
+async fn read_next<'s, 'r, S>(
+	mut reader: Reader<'s>,
+	peer: ConnectionInfo,
+	router: &'r Router<S>,
+) -> (Reader<'s>, Result<Option<Incoming<'r, S>>, Error>)
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let read = read_tagged(&mut reader, peer, router);
+	let read = within(router.timeout, TimeoutPhase::Reading, read).await;
+
+	(reader, read)
+}
+
+/// Read a request preceded by its stream ID, or `None` if the client closed the connection.
+///
+/// Once the stream ID is known, failing to read the request is returned along with it, so
+/// that the client can be told which of its requests failed.
+async fn read_tagged<'r, S>(
+	reader: &mut Reader<'_>,
+	peer: ConnectionInfo,
+	router: &'r Router<S>,
+) -> Result<Option<Incoming<'r, S>>, Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let Some(id) = read_leading(reader, CodingKey::StreamId).await? else {
+		return Ok(None);
+	};
+	let id = u32::from_be_bytes(id);
+
+	let request = read_request(reader, peer, router)
+		.await
+		.and_then(|request| {
+			request.ok_or_else(|| {
+				Error::Reading(CodingKey::Length, io::ErrorKind::UnexpectedEof.into())
+			})
+		});
+
+	Ok(Some((id, request)))
+}
+
+/// Handle a request, then write its response preceded by its stream ID.
+async fn handle<S>(
+	router: &Router<S>,
+	writer: &Writer<'_>,
+	id: u32,
+	route: &Route<S>,
+	ctx: RequestContext,
+	request: RawRequest,
+) -> Result<(), Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let route_id = ctx.route_id();
+	router
+		.observer
+		.on_request(route_id, ctx.type_id(), request.payload.len());
+	let started = Instant::now();
+
+	// Each item of a stream would need its own stream ID, which the wire format doesn't have.
+	let Route::Unary(handler) = route else {
+		let error = Error::MultiplexedStream { route_id };
+		router.observer.on_error(route_id, &error);
+
+		let error = Err(error);
+		return respond_tagged(router, writer, id, error, Compression::None, Checksum::None).await;
+	};
+
+	let compression = router.response_compression(&request.metadata);
+	let checksum = request.metadata.checksum().unwrap_or_default();
+	let result = call_unary(router, &**handler, &ctx, request).await;
+	router.report(route_id, &result, started);
+
+	respond_tagged(router, writer, id, result, compression, checksum).await
+}
+
+/// Write the response to a request, or the error it failed with, preceded by its stream ID.
+///
+/// The writer stays locked until the whole response is written, so that responses never
+/// interleave.
+async fn respond_tagged<S>(
+	router: &Router<S>,
+	writer: &Writer<'_>,
+	id: u32,
+	result: Result<Vec<u8>, Error>,
+	compression: Compression,
+	checksum: Checksum,
+) -> Result<(), Error>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	let mut writer = writer.lock().await;
+
+	let tag = async {
+		writer
+			.write_u32(id)
+			.await
+			.map_err(|e| Error::Writing(CodingKey::StreamId, e))
+	};
+	within(router.timeout, TimeoutPhase::Writing, tag).await?;
+
+	respond(&mut *writer, router, result, compression, checksum).await
+}
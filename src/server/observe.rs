@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use super::Error;
+use crate::TypeId;
+
+/// Callbacks reporting every request a router handles, see `Router::observe`.
+///
+/// This is meant to feed per-route metrics into whichever metrics crate the application
+/// uses. Every method does nothing by default, so an observer only implements the ones it
+/// needs. Callbacks run inline on the connection's task, so they should return quickly.
+///
+/// Sizes are those of the encoded payloads, after decompressing requests and before
+/// compressing responses, so they don't depend on what each client negotiated.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// struct Latency;
+///
+/// impl Observer for Latency {
+///     fn on_response(&self, route_id: &'static str, _bytes: usize, latency: Duration) {
+///         metrics::histogram!("handler_latency", "route" => route_id).record(latency);
+///     }
+/// }
+/// ```
+pub trait Observer: Send + Sync + 'static {
+	/// A request of `bytes` bytes was read and routed to the handler for `route_id`.
+	///
+	/// Requests that can't be routed, such as those with an unknown type ID, aren't reported.
+	fn on_request(&self, route_id: &'static str, type_id: TypeId, bytes: usize) {
+		let _ = (route_id, type_id, bytes);
+	}
+
+	/// The handler for `route_id` responded with `bytes` bytes, `latency` after it was called.
+	///
+	/// For streaming routes, this is reported once the stream ended, with the size of all
+	/// its items together.
+	fn on_response(&self, route_id: &'static str, bytes: usize, latency: Duration) {
+		let _ = (route_id, bytes, latency);
+	}
+
+	/// Handling a request routed to the handler for `route_id` failed with `error`.
+	fn on_error(&self, route_id: &'static str, error: &Error) {
+		let _ = (route_id, error);
+	}
+
+	/// The server had as many connections open as `Router::max_concurrent` allows, and waited
+	/// `waited` for one to close before accepting the next.
+	///
+	/// This is only reported when the server had to wait, so a steady stream of reports means
+	/// clients queue up in the listener's backlog: latency then comes from the connection
+	/// limit rather than from the handlers.
+	fn on_capacity_wait(&self, waited: Duration) {
+		let _ = waited;
+	}
+}
+
+/// An observer ignoring everything, used by routers that aren't observed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {}
+
+/// How often waits for capacity are logged at most, see `Router::capacity_warning`.
+pub(super) const CAPACITY_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Warnings about the accept loop waiting for capacity, rate limited so that a saturated
+/// server doesn't log for every connection it accepts.
+#[derive(Debug)]
+pub(super) struct CapacityWarnings {
+	threshold: Duration,
+	last_warned: Option<Instant>,
+	suppressed: u64,
+	longest_suppressed: Duration,
+}
+
+impl CapacityWarnings {
+	pub(super) const fn new(threshold: Duration) -> Self {
+		Self {
+			threshold,
+			last_warned: None,
+			suppressed: 0,
+			longest_suppressed: Duration::ZERO,
+		}
+	}
+
+	/// Record a wait for capacity that ended `now`, warning about it unless it was short or
+	/// another warning went out recently. Returns whether it warned.
+	pub(super) fn record(&mut self, waited: Duration, now: Instant) -> bool {
+		if waited < self.threshold {
+			return false;
+		}
+
+		if self
+			.last_warned
+			.is_some_and(|last| now.duration_since(last) < CAPACITY_WARNING_INTERVAL)
+		{
+			self.suppressed += 1;
+			self.longest_suppressed = self.longest_suppressed.max(waited);
+			return false;
+		}
+
+		tracing::warn!(
+			waited = ?waited,
+			suppressed = self.suppressed,
+			longest_suppressed = ?self.longest_suppressed,
+			"At capacity: waited for a connection to close before accepting another"
+		);
+		self.last_warned = Some(now);
+		self.suppressed = 0;
+		self.longest_suppressed = Duration::ZERO;
+
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_capacity_warnings_are_rate_limited() {
+		let mut warnings = CapacityWarnings::new(Duration::from_millis(100));
+		let start = Instant::now();
+		let long = Duration::from_millis(250);
+
+		assert!(!warnings.record(Duration::from_millis(50), start));
+		assert!(warnings.record(long, start));
+		assert!(!warnings.record(long, start + Duration::from_secs(1)));
+		assert_eq!(warnings.suppressed, 1);
+
+		assert!(warnings.record(long, start + CAPACITY_WARNING_INTERVAL));
+		assert_eq!(warnings.suppressed, 0);
+	}
+}
@@ -0,0 +1,131 @@
+use std::sync::{
+	Arc,
+	atomic::{AtomicU64, Ordering},
+};
+
+/// A snapshot of a server's counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerStats {
+	/// Connections accepted since the server started.
+	pub total_connections: u64,
+	/// Requests that were routed to a handler.
+	pub total_requests: u64,
+	/// Connections that ended with an error, whether before or after reaching a handler.
+	pub total_errors: u64,
+	/// Connections currently open, including those waiting in the queue.
+	pub active_connections: u64,
+	/// Connections waiting for a worker, with [`DispatchModel::BoundedPool`](super::DispatchModel::BoundedPool).
+	pub queued_connections: u64,
+}
+
+/// A shared, cheaply cloneable view of a server's counters.
+///
+/// Get one from [`Router::stats_handle`](super::Router::stats_handle) to read the counters
+/// while the router is serving, for example from a status handler. Counters are plain
+/// relaxed atomics, so a snapshot is not guaranteed to be consistent across fields.
+#[derive(Debug, Clone, Default)]
+pub struct StatsHandle {
+	counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+	total_connections: AtomicU64,
+	total_requests: AtomicU64,
+	total_errors: AtomicU64,
+	active_connections: AtomicU64,
+	queued_connections: AtomicU64,
+}
+
+impl StatsHandle {
+	/// Take a snapshot of the counters.
+	#[must_use]
+	pub fn stats(&self) -> ServerStats {
+		let counters = &self.counters;
+
+		ServerStats {
+			total_connections: counters.total_connections.load(Ordering::Relaxed),
+			total_requests: counters.total_requests.load(Ordering::Relaxed),
+			total_errors: counters.total_errors.load(Ordering::Relaxed),
+			active_connections: counters.active_connections.load(Ordering::Relaxed),
+			queued_connections: counters.queued_connections.load(Ordering::Relaxed),
+		}
+	}
+
+	/// Count a newly accepted connection, which stays active until the returned guard is dropped.
+	pub(super) fn connection_opened(&self) -> ActiveConnection {
+		self.counters
+			.total_connections
+			.fetch_add(1, Ordering::Relaxed);
+		self.counters
+			.active_connections
+			.fetch_add(1, Ordering::Relaxed);
+
+		ActiveConnection {
+			counters: self.counters.clone(),
+		}
+	}
+
+	pub(super) fn connection_queued(&self) {
+		self.counters
+			.queued_connections
+			.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(super) fn connection_dequeued(&self) {
+		self.counters
+			.queued_connections
+			.fetch_sub(1, Ordering::Relaxed);
+	}
+
+	pub(super) fn request_routed(&self) {
+		self.counters.total_requests.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(super) fn connection_failed(&self) {
+		self.counters.total_errors.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+/// Counts its connection as active until dropped.
+pub(super) struct ActiveConnection {
+	counters: Arc<Counters>,
+}
+
+impl Drop for ActiveConnection {
+	fn drop(&mut self) {
+		self.counters
+			.active_connections
+			.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_active_connections_follow_guards() {
+		let handle = StatsHandle::default();
+
+		let first = handle.connection_opened();
+		let second = handle.connection_opened();
+		handle.request_routed();
+		assert_eq!(handle.stats().active_connections, 2);
+
+		drop(first);
+		handle.connection_failed();
+		drop(second);
+
+		assert_eq!(
+			handle.stats(),
+			ServerStats {
+				total_connections: 2,
+				total_requests: 1,
+				total_errors: 1,
+				active_connections: 0,
+				queued_connections: 0,
+			}
+		);
+	}
+}
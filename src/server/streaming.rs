@@ -0,0 +1,100 @@
+use std::{marker::PhantomData, panic::AssertUnwindSafe, pin::Pin, time::Duration};
+
+use futures_util::{Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+
+use super::{Error, RawRequest, TimeoutPhase, handler_panicked, within};
+use crate::{
+	StreamingRequest,
+	utils::{CodingKey, Stream as VsockStream},
+	wire,
+};
+
+/// The encoded items of a streamed response, in the format the client asked for.
+pub(super) type ItemStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>, Error>> + Send>>;
+
+/// The streaming counterpart of [`Handler`](super::Handler): instead of a single response,
+/// it produces a stream of encoded items.
+///
+/// The request is decoded before the stream is returned, so a request that fails to decode
+/// is still answered with an error frame rather than a stream.
+pub(super) trait StreamHandler<S>: Send + Sync {
+	fn call(&self, state: S, request: RawRequest) -> Result<ItemStream, Error>;
+}
+
+/// Adapts a typed streaming handler to the type-erased [`StreamHandler`] interface.
+pub(super) struct TypedStreamHandler<R, S, H, St> {
+	pub(super) handler: H,
+	pub(super) _phantom: PhantomData<(R, S)>,
+	pub(super) _streamed: PhantomData<fn() -> St>, // Streams are produced, not held
+}
+
+impl<R, S, H, St> StreamHandler<S> for TypedStreamHandler<R, S, H, St>
+where
+	R: StreamingRequest,
+	S: Clone + Send + Sync + 'static,
+	H: Fn(S, R) -> St + Send + Sync,
+	St: Stream<Item = R::Item> + Send + 'static,
+{
+	fn call(&self, state: S, raw: RawRequest) -> Result<ItemStream, Error> {
+		let RawRequest {
+			formats, payload, ..
+		} = raw;
+
+		let request: R = formats.decode_request(R::ROUTE_ID, &payload)?;
+
+		let items = (self.handler)(state, request)
+			.map(move |item| formats.encode_response(R::ROUTE_ID, &item));
+
+		Ok(Box::pin(items))
+	}
+}
+
+/// Write a streamed response: a status byte, then every item in its own length-prefixed
+/// frame, then an empty frame marking the end of the stream.
+///
+/// Items are only pulled from the handler once the previous one was written, so a client
+/// reading slowly holds the handler back instead of letting items pile up in memory. The
+/// timeout bounds each write rather than the whole stream, which may legitimately last for
+/// as long as the client keeps reading.
+///
+/// Once the first item was written, an error can no longer be reported in an error frame:
+/// it is returned instead, closing the connection halfway through the stream. Otherwise,
+/// returns the size of all the items written.
+pub(super) async fn write_stream(
+	stream: &mut VsockStream,
+	timeout: Option<Duration>,
+	route_id: &'static str,
+	items: ItemStream,
+) -> Result<usize, Error> {
+	within(timeout, TimeoutPhase::Writing, async {
+		stream
+			.write_u8(wire::STATUS_OK)
+			.await
+			.map_err(|e| Error::Writing(CodingKey::Status, e))
+	})
+	.await?;
+
+	// A panicking stream is ended like a failing one, since it can't be resumed either.
+	let mut items = AssertUnwindSafe(items).catch_unwind();
+	let mut bytes = 0;
+	while let Some(item) = items.next().await {
+		let item = item.unwrap_or_else(|panic| Err(handler_panicked(route_id, &*panic)))?;
+		within(timeout, TimeoutPhase::Writing, write_item(stream, &item)).await?;
+		bytes += item.len();
+	}
+
+	within(timeout, TimeoutPhase::Writing, write_item(stream, &[])).await?;
+
+	Ok(bytes)
+}
+
+/// Write an item, flushing it so that it reaches the client before the next one is produced.
+async fn write_item(stream: &mut VsockStream, item: &[u8]) -> Result<(), Error> {
+	wire::write_frame(stream, item, Error::Writing).await?;
+
+	stream
+		.flush()
+		.await
+		.map_err(|e| Error::Writing(CodingKey::Payload, e))
+}
@@ -0,0 +1,213 @@
+use std::sync::Arc;
+
+use crate::{
+	Request,
+	addr::VMADDR_CID_LOCAL,
+	client::{self, Connection, ConnectionDetails},
+	server::{self, BuildError, Router},
+	utils::Stream,
+	wire::Metadata,
+};
+
+/// How many bytes each direction of an in-memory connection buffers before writes wait.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Serve `router` to clients in the same process, over in-memory pipes rather than vsock.
+///
+/// The router is built as by [`Router::serve`], then every connection made through the
+/// returned client is served by it, with the same framing, codecs and dispatch as over
+/// vsock. Requests then go through their real handlers and codecs in plain unit tests, so
+/// that a request type whose encoding doesn't round-trip fails there rather than in an
+/// enclave. Clients are reported to handlers with the loopback CID and port `0`.
+///
+/// Connections are served on the tokio runtime they are opened from.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// #[tokio::test]
+/// async fn echo_round_trips() {
+///     let client = pontifex::testing::local(Router::new().route_fn::<Echo>(handle_echo)).unwrap();
+///
+///     let response = client.send(&Echo { message: "hello".into() }).await.unwrap();
+///     assert_eq!(response.message, "hello");
+/// }
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if the registered routes conflict, see [`Router::build`].
+pub fn local<S>(router: Router<S>) -> Result<LocalClient<S>, BuildError>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	Ok(LocalClient {
+		router: Arc::new(router.build()?),
+		details: ConnectionDetails::new(VMADDR_CID_LOCAL, 0),
+	})
+}
+
+/// A client of a router running in the same process, see [`local`].
+pub struct LocalClient<S> {
+	router: Arc<Router<S>>,
+	details: ConnectionDetails,
+}
+
+impl<S> LocalClient<S>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	/// Connect with the payload formats, limits and timeout of `details`, rather than the
+	/// defaults. Its CID, port and transport are ignored.
+	#[must_use]
+	pub const fn with_details(mut self, details: ConnectionDetails) -> Self {
+		self.details = details;
+		self
+	}
+
+	/// Open a connection to the router, carrying any number of requests.
+	///
+	/// # Errors
+	///
+	/// Same as [`Connection::open`], except that connecting itself can't fail.
+	pub async fn connect(&self) -> Result<Connection, client::Error> {
+		let (client, server) = tokio::io::duplex(PIPE_CAPACITY);
+		server::serve_io(&self.router, Box::new(server));
+
+		Connection::over(Stream::from_io(Box::new(client)), self.details).await
+	}
+
+	/// Send a request to the router over a new connection, and receive its response.
+	///
+	/// # Errors
+	///
+	/// Same as [`client::send`].
+	pub async fn send<R: Request>(&self, request: &R) -> Result<R::Response, client::Error> {
+		self.send_with_metadata(request, &Metadata::new()).await
+	}
+
+	/// Send a request along with metadata headers to the router, and receive its response.
+	///
+	/// # Errors
+	///
+	/// Same as [`client::send`].
+	pub async fn send_with_metadata<R: Request>(
+		&self,
+		request: &R,
+		metadata: &Metadata,
+	) -> Result<R::Response, client::Error> {
+		self.connect()
+			.await?
+			.send_with_metadata(request, metadata)
+			.await
+	}
+
+	/// Send a batch of requests to the router over a new connection, and receive their
+	/// responses in order.
+	///
+	/// # Errors
+	///
+	/// Same as [`client::send_batch`].
+	pub async fn send_batch<R: Request>(
+		&self,
+		requests: &[R],
+	) -> Result<Vec<R::Response>, client::Error> {
+		self.connect().await?.send_batch(requests).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde::{Deserialize, Serialize};
+
+	#[derive(Debug, Serialize, Deserialize)]
+	struct Echo {
+		message: String,
+	}
+
+	#[derive(Debug, PartialEq, Serialize, Deserialize)]
+	struct EchoResponse {
+		message: String,
+	}
+
+	impl Request for Echo {
+		const ROUTE_ID: &'static str = "echo_v1";
+		type Response = EchoResponse;
+	}
+
+	async fn handle_echo(prefix: &'static str, request: Echo) -> EchoResponse {
+		EchoResponse {
+			message: format!("{prefix}{}", request.message),
+		}
+	}
+
+	#[test]
+	fn test_echo_round_trips() {
+		let router = Router::with_state("echo: ").route_fn::<Echo>(handle_echo);
+
+		tokio_test::block_on(async {
+			let client = local(router).unwrap();
+
+			let response = client
+				.send(&Echo {
+					message: "hello".to_string(),
+				})
+				.await
+				.unwrap();
+			assert_eq!(response.message, "echo: hello");
+
+			// A connection carries requests one after the other, like over vsock.
+			let mut connection = client.connect().await.unwrap();
+			for message in ["a", "b"] {
+				let request = Echo {
+					message: message.to_string(),
+				};
+				let response = connection.send(&request).await.unwrap();
+				assert_eq!(response.message, format!("echo: {message}"));
+			}
+		});
+	}
+
+	/// Clones of a router serve the same routes, and count their requests apart.
+	#[test]
+	fn test_cloned_routers_serve_alike() {
+		let router = Router::with_state("echo: ").route_fn::<Echo>(handle_echo);
+		let stats = router.stats_handle();
+
+		tokio_test::block_on(async {
+			let clients = [local(router.clone()).unwrap(), local(router).unwrap()];
+			for client in clients {
+				let response = client
+					.send(&Echo {
+						message: "hello".to_string(),
+					})
+					.await
+					.unwrap();
+				assert_eq!(response.message, "echo: hello");
+			}
+		});
+
+		assert_eq!(stats.stats().total_requests, 1);
+	}
+
+	/// Requests for routes the router doesn't have fail like they would over vsock, and
+	/// leave the connection usable.
+	#[test]
+	fn test_unknown_routes_fail() {
+		tokio_test::block_on(async {
+			let client = local(Router::new()).unwrap();
+
+			let mut connection = client.connect().await.unwrap();
+			for _ in 0..2 {
+				let error = connection
+					.send(&Echo {
+						message: "hello".to_string(),
+					})
+					.await
+					.unwrap_err();
+				assert!(matches!(error, client::Error::Remote { .. }), "{error:?}");
+			}
+		});
+	}
+}
@@ -0,0 +1,332 @@
+//! TLS sessions between clients and enclaves, on top of the vsock connection.
+//!
+//! The host relays every byte exchanged with an enclave, so in multi-tenant setups, or as a
+//! defense in depth, requests can be sent through a TLS session instead of in the clear.
+//! Rather than a certificate authority, the enclave's attestation document vouches for the
+//! server: the enclave binds the public key of its TLS certificate into the document, and
+//! clients only accept a server presenting that very key.
+//!
+//! The enclave serves with a [`ServerTls`], see `Router::with_tls`, and clients connect with
+//! a [`ClientTls`], see `ConnectionDetails::with_tls`. Requests and responses are then
+//! framed exactly as they are over a plain connection.
+
+use std::sync::Arc;
+
+use rustls::{
+	CertificateError, ClientConfig, DigitallySignedStruct, OtherError, ServerConfig,
+	SignatureScheme,
+	client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+	crypto::{WebPkiSupportedAlgorithms, ring, verify_tls12_signature, verify_tls13_signature},
+	pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use x509_cert::{
+	Certificate,
+	der::{Decode, Encode},
+};
+
+/// The name clients expect the enclave's certificate to be issued for, unless configured
+/// otherwise with [`ClientTls::from_config`].
+///
+/// Attested sessions don't check it, as the attested key identifies the server instead.
+pub const ENCLAVE_SERVER_NAME: &str = "enclave";
+
+/// How an enclave terminates the TLS sessions of its clients.
+#[derive(Clone)]
+pub struct ServerTls {
+	acceptor: TlsAcceptor,
+	public_key: Option<Vec<u8>>,
+}
+
+impl ServerTls {
+	/// Serve the certificate chain `chain`, whose first certificate holds the public key of
+	/// `key`.
+	///
+	/// The certificate is usually self-signed, by a key generated inside the enclave: clients
+	/// trust it because its public key is bound into the enclave's attestation document, see
+	/// [`ServerTls::public_key`].
+	///
+	/// # Errors
+	///
+	/// Returns an error if `key` isn't a supported private key, or doesn't match the chain.
+	pub fn new(
+		chain: Vec<CertificateDer<'static>>,
+		key: PrivateKeyDer<'static>,
+	) -> Result<Self, rustls::Error> {
+		let public_key = chain.first().and_then(|certificate| spki(certificate));
+		let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+			.with_safe_default_protocol_versions()?
+			.with_no_client_auth()
+			.with_single_cert(chain, key)?;
+
+		Ok(Self {
+			acceptor: TlsAcceptor::from(Arc::new(config)),
+			public_key,
+		})
+	}
+
+	/// Serve with a custom rustls configuration, such as one requiring client certificates.
+	#[must_use]
+	pub fn from_config(config: Arc<ServerConfig>) -> Self {
+		Self {
+			acceptor: TlsAcceptor::from(config),
+			public_key: None,
+		}
+	}
+
+	/// The DER-encoded `SubjectPublicKeyInfo` of the served certificate, to bind into the
+	/// `public_key` field of the enclave's attestation documents.
+	///
+	/// Returns `None` for configurations created with [`ServerTls::from_config`], or if the
+	/// certificate can't be parsed.
+	#[must_use]
+	pub fn public_key(&self) -> Option<&[u8]> {
+		self.public_key.as_deref()
+	}
+
+	/// The acceptor terminating sessions, to serve them over other streams than vsock.
+	#[must_use]
+	pub const fn acceptor(&self) -> &TlsAcceptor {
+		&self.acceptor
+	}
+}
+
+impl std::fmt::Debug for ServerTls {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ServerTls").finish_non_exhaustive()
+	}
+}
+
+/// How clients establish TLS sessions with an enclave.
+#[derive(Clone)]
+pub struct ClientTls {
+	connector: TlsConnector,
+	server_name: ServerName<'static>,
+}
+
+impl ClientTls {
+	/// Only trust a server presenting a certificate for the given public key.
+	///
+	/// `public_key` is the DER-encoded `SubjectPublicKeyInfo` the enclave bound into its
+	/// attestation document, as returned by [`ServerTls::public_key`]. Handshakes with a
+	/// server presenting any other key fail with a [`KeyMismatch`] error. Neither the
+	/// certificate's issuer, name nor validity period are checked: the attestation document
+	/// is what vouches for the key, so make sure it was verified before trusting it.
+	#[must_use]
+	pub fn attested(public_key: impl Into<Vec<u8>>) -> Self {
+		let verifier = AttestedVerifier {
+			public_key: public_key.into(),
+			algorithms: ring::default_provider().signature_verification_algorithms,
+		};
+
+		let config = attested_config(verifier);
+
+		Self {
+			connector: TlsConnector::from(Arc::new(config)),
+			server_name: enclave_server_name(),
+		}
+	}
+
+	/// Only trust the server identified by an attestation document, like
+	/// [`ClientTls::attested`].
+	///
+	/// The document must have been verified first, see `attestation::verify`. Returns `None`
+	/// if it doesn't bind a public key.
+	#[cfg(feature = "nsm-types")]
+	#[must_use]
+	pub fn from_attestation(document: &crate::AttestationDoc) -> Option<Self> {
+		use crate::AttestationDocExt;
+
+		document.public_key_bytes().map(Self::attested)
+	}
+
+	/// Connect with a custom rustls configuration, verifying the server's certificate for
+	/// `server_name`.
+	#[must_use]
+	pub fn from_config(config: Arc<ClientConfig>, server_name: ServerName<'static>) -> Self {
+		Self {
+			connector: TlsConnector::from(config),
+			server_name,
+		}
+	}
+
+	/// The connector establishing sessions, to connect over other streams than vsock.
+	#[must_use]
+	pub const fn connector(&self) -> &TlsConnector {
+		&self.connector
+	}
+
+	/// The name the server's certificate is verified for.
+	#[must_use]
+	pub fn server_name(&self) -> ServerName<'static> {
+		self.server_name.clone()
+	}
+}
+
+impl std::fmt::Debug for ClientTls {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ClientTls")
+			.field("server_name", &self.server_name)
+			.finish_non_exhaustive()
+	}
+}
+
+/// The server presented a certificate for another key than the attested one.
+#[derive(Debug, thiserror::Error)]
+#[error("the server's certificate doesn't hold the attested public key")]
+pub struct KeyMismatch;
+
+/// Accepts the server's certificate if it holds the attested public key, whoever issued it.
+#[derive(Debug)]
+struct AttestedVerifier {
+	public_key: Vec<u8>,
+	algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for AttestedVerifier {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		_intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		_ocsp_response: &[u8],
+		_now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		if spki(end_entity).as_deref() != Some(self.public_key.as_slice()) {
+			tracing::warn!("refusing server certificate holding another key than the attested one");
+			return Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+				OtherError(Arc::new(KeyMismatch)),
+			)));
+		}
+
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls12_signature(message, cert, dss, &self.algorithms)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls13_signature(message, cert, dss, &self.algorithms)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.algorithms.supported_schemes()
+	}
+}
+
+fn attested_config(verifier: AttestedVerifier) -> ClientConfig {
+	ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+		.with_safe_default_protocol_versions()
+		.expect("the ring provider supports the default protocol versions")
+		.dangerous()
+		.with_custom_certificate_verifier(Arc::new(verifier))
+		.with_no_client_auth()
+}
+
+fn enclave_server_name() -> ServerName<'static> {
+	ServerName::try_from(ENCLAVE_SERVER_NAME).expect("the enclave server name is a valid DNS name")
+}
+
+/// The DER-encoded `SubjectPublicKeyInfo` of a certificate, or `None` if it can't be parsed.
+fn spki(certificate: &CertificateDer<'_>) -> Option<Vec<u8>> {
+	Certificate::from_der(certificate)
+		.ok()?
+		.tbs_certificate
+		.subject_public_key_info
+		.to_der()
+		.ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rustls::pki_types::PrivatePkcs8KeyDer;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+	const ENCLAVE_CERT: &[u8] = include_bytes!("../tests/tls/enclave.crt.der");
+	const ENCLAVE_KEY: &[u8] = include_bytes!("../tests/tls/enclave.key.der");
+	const ENCLAVE_SPKI: &[u8] = include_bytes!("../tests/tls/enclave.spki.der");
+	const IMPOSTOR_CERT: &[u8] = include_bytes!("../tests/tls/impostor.crt.der");
+	const IMPOSTOR_KEY: &[u8] = include_bytes!("../tests/tls/impostor.key.der");
+
+	fn server(cert: &'static [u8], key: &'static [u8]) -> ServerTls {
+		ServerTls::new(
+			vec![CertificateDer::from(cert)],
+			PrivatePkcs8KeyDer::from(key).into(),
+		)
+		.unwrap()
+	}
+
+	/// Run a handshake over an in-memory pipe, echoing a byte back if it succeeds.
+	fn exchange(server: &ServerTls, client: &ClientTls) -> std::io::Result<u8> {
+		tokio_test::block_on(async {
+			let (client_io, server_io) = duplex(4096);
+
+			let acceptor = server.acceptor().clone();
+			let echo = tokio::spawn(async move {
+				let mut stream = acceptor.accept(server_io).await?;
+				let byte = stream.read_u8().await?;
+				stream.write_u8(byte).await?;
+				stream.flush().await
+			});
+
+			let mut stream = client
+				.connector()
+				.connect(client.server_name(), client_io)
+				.await?;
+			stream.write_u8(42).await?;
+			stream.flush().await?;
+			let byte = stream.read_u8().await?;
+
+			echo.await.unwrap()?;
+			Ok(byte)
+		})
+	}
+
+	/// The served key is the one enclaves bind into their attestation documents.
+	#[test]
+	fn test_server_exposes_its_public_key() {
+		let tls = server(ENCLAVE_CERT, ENCLAVE_KEY);
+
+		assert_eq!(tls.public_key(), Some(ENCLAVE_SPKI));
+	}
+
+	/// Clients establish a session with a server presenting the attested key.
+	#[test]
+	fn test_attested_key_is_trusted() {
+		let tls = server(ENCLAVE_CERT, ENCLAVE_KEY);
+		let client = ClientTls::attested(ENCLAVE_SPKI);
+
+		assert_eq!(exchange(&tls, &client).unwrap(), 42);
+	}
+
+	/// A server presenting any other key is refused, however valid its certificate.
+	#[test]
+	fn test_other_key_is_refused() {
+		let tls = server(IMPOSTOR_CERT, IMPOSTOR_KEY);
+		let client = ClientTls::attested(ENCLAVE_SPKI);
+
+		let error = exchange(&tls, &client).unwrap_err();
+		let error = error
+			.get_ref()
+			.and_then(|error| error.downcast_ref::<rustls::Error>())
+			.expect("the handshake fails with a rustls error");
+
+		assert!(matches!(
+			error,
+			rustls::Error::InvalidCertificate(CertificateError::Other(other))
+				if other.0.downcast_ref::<KeyMismatch>().is_some()
+		));
+	}
+}
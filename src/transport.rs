@@ -0,0 +1,268 @@
+use hyper::{
+	Uri,
+	rt::{Read, ReadBufCursor, Write},
+};
+use hyper_util::{
+	client::legacy::{
+		Builder,
+		connect::{Connected, Connection},
+	},
+	rt::{TokioIo, TokioTimer},
+};
+use std::{
+	io,
+	net::Shutdown,
+	pin::Pin,
+	task::{Context, Poll},
+	time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_vsock::{VsockAddr, VsockStream};
+use tower_service::Service;
+
+/// A hyper connector that opens every connection to a fixed vsock address.
+///
+/// This type implements the `Service<Uri>` trait, so it can be used wherever hyper-util
+/// expects a connector, typically to reach the host's vsock proxy from within a Nitro
+/// Enclave. The URI of each request is ignored when dialing: it is only used by the layers
+/// above, such as TLS for SNI and hostname verification. Wrap it in an
+/// `hyper_rustls::HttpsConnector` to speak HTTPS over it, as `http::client` and
+/// `kms::client` do.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let connector = VsockConnector::new(VsockAddr::new(PARENT_CID, 8000))
+///     .with_connect_timeout(Duration::from_secs(5));
+/// let client: Client<_, Full<Bytes>> = Client::builder(TokioExecutor::new()).build(connector);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct VsockConnector {
+	address: VsockAddr,
+	connect_timeout: Option<Duration>,
+}
+
+impl VsockConnector {
+	/// Create a connector that connects to `address` for every request.
+	#[must_use]
+	pub const fn new(address: VsockAddr) -> Self {
+		Self {
+			address,
+			connect_timeout: None,
+		}
+	}
+
+	/// Give up on opening a connection after `timeout`, failing it with an
+	/// [`io::ErrorKind::TimedOut`] error.
+	///
+	/// A vsock proxy that isn't running, or that silently drops connections, otherwise leaves
+	/// requests waiting forever for their connection.
+	#[must_use]
+	pub const fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+		self.connect_timeout = Some(timeout);
+		self
+	}
+
+	/// The address connections are opened to.
+	#[must_use]
+	pub const fn address(&self) -> VsockAddr {
+		self.address
+	}
+
+	/// How long opening a connection may take, if bounded.
+	#[must_use]
+	pub const fn connect_timeout(&self) -> Option<Duration> {
+		self.connect_timeout
+	}
+}
+
+impl Service<Uri> for VsockConnector {
+	type Response = VsockConnection;
+	type Error = io::Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+	fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, _: Uri) -> Self::Future {
+		let (address, connect_timeout) = (self.address, self.connect_timeout);
+
+		Box::pin(async move {
+			let Some(timeout) = connect_timeout else {
+				return VsockConnection::connect(address).await;
+			};
+
+			tokio::time::timeout(timeout, VsockConnection::connect(address))
+				.await
+				.unwrap_or_else(|_| {
+					Err(io::Error::new(
+						io::ErrorKind::TimedOut,
+						format!("connecting to the vsock proxy timed out after {timeout:?}"),
+					))
+				})
+		})
+	}
+}
+
+/// How a hyper client keeps the connections opened by a [`VsockConnector`] for reuse.
+///
+/// hyper pools connections by target, so requests to the same host reuse an idle
+/// connection, along with its TLS session, instead of dialing the vsock proxy and
+/// handshaking again. Idle connections are closed after `idle_timeout`, before the proxy or
+/// the upstream service is likely to drop them on their own.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let pool = PoolConfig::default().with_idle_timeout(Duration::from_secs(10));
+/// let client = http::client_with_pool(8000, pool);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+	/// How long a connection may stay idle before it is closed, or `None` to keep it until
+	/// the peer closes it.
+	pub idle_timeout: Option<Duration>,
+	/// How many idle connections are kept per target. Zero disables reuse.
+	pub max_idle_per_target: usize,
+}
+
+impl PoolConfig {
+	/// How long connections stay idle by default.
+	pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+	/// How many idle connections are kept per target by default.
+	pub const DEFAULT_MAX_IDLE_PER_TARGET: usize = 8;
+
+	/// Never reuse connections, opening a new one for every request.
+	#[must_use]
+	pub const fn disabled() -> Self {
+		Self {
+			idle_timeout: None,
+			max_idle_per_target: 0,
+		}
+	}
+
+	/// Close connections once they stayed idle for `timeout`.
+	#[must_use]
+	pub const fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+		self.idle_timeout = Some(timeout);
+		self
+	}
+
+	/// Keep at most `connections` idle connections per target.
+	#[must_use]
+	pub const fn with_max_idle_per_target(mut self, connections: usize) -> Self {
+		self.max_idle_per_target = connections;
+		self
+	}
+
+	/// Configure the pool of a hyper client accordingly.
+	///
+	/// This also gives the pool a Tokio timer, without which idle connections are never
+	/// closed.
+	pub fn apply(self, builder: &mut Builder) -> &mut Builder {
+		builder
+			.pool_timer(TokioTimer::new())
+			.pool_idle_timeout(self.idle_timeout)
+			.pool_max_idle_per_host(self.max_idle_per_target)
+	}
+}
+
+impl Default for PoolConfig {
+	fn default() -> Self {
+		Self {
+			idle_timeout: Some(Self::DEFAULT_IDLE_TIMEOUT),
+			max_idle_per_target: Self::DEFAULT_MAX_IDLE_PER_TARGET,
+		}
+	}
+}
+
+/// A vsock stream opened by a [`VsockConnector`], shut down when dropped.
+#[derive(Debug)]
+pub struct VsockConnection {
+	stream: TokioIo<VsockStream>,
+}
+
+impl VsockConnection {
+	/// Connect to the given vsock address.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the connection can't be established.
+	pub async fn connect(address: VsockAddr) -> io::Result<Self> {
+		let stream = VsockStream::connect(address).await?;
+
+		Ok(Self {
+			stream: TokioIo::new(stream),
+		})
+	}
+}
+
+impl AsyncRead for VsockConnection {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		Pin::new(self.get_mut().stream.inner_mut()).poll_read(cx, buf)
+	}
+}
+
+impl AsyncWrite for VsockConnection {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<Result<usize, io::Error>> {
+		Pin::new(self.get_mut().stream.inner_mut()).poll_write(cx, buf)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		Pin::new(self.get_mut().stream.inner_mut()).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		Pin::new(self.get_mut().stream.inner_mut()).poll_shutdown(cx)
+	}
+}
+
+impl Read for VsockConnection {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: ReadBufCursor<'_>,
+	) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+	}
+}
+
+impl Write for VsockConnection {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<Result<usize, io::Error>> {
+		Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+	}
+}
+
+impl Drop for VsockConnection {
+	fn drop(&mut self) {
+		// The peer learns about the closed connection either way, so a failure is harmless.
+		let _ = self.stream.inner().shutdown(Shutdown::Both);
+	}
+}
+
+impl Connection for VsockConnection {
+	fn connected(&self) -> Connected {
+		Connected::new()
+	}
+}
@@ -1,125 +1,67 @@
-use hyper::{
-	Uri,
-	client::connect::{Connected, Connection},
-	service::Service,
-};
-use hyper_rustls::{ConfigBuilderExt, HttpsConnector};
-use std::{
-	io,
-	net::Shutdown,
-	pin::Pin,
-	task::{Context, Poll},
-};
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_vsock::{VsockAddr, VsockStream};
+use std::sync::Arc;
 
-pub fn vsock_proxy(address: VsockAddr) -> HttpsConnector<VSockClientBuilder> {
-	let cc = rustls::ClientConfig::builder()
-		.with_webpki_roots()
-		.with_no_client_auth();
+use hyper_rustls::HttpsConnector;
+use rustls::{ClientConfig, ConfigBuilder, RootCertStore, WantsVerifier, crypto::ring};
 
-	HttpsConnector::from((VSockClientBuilder { address }, cc))
-}
+use crate::transport::VsockConnector;
 
-pub fn vsock_proxy_http2_only(address: VsockAddr) -> HttpsConnector<VSockClientBuilder> {
-	let mut cc = rustls::ClientConfig::builder()
-		.with_webpki_roots()
-		.with_no_client_auth();
-
-	cc.alpn_protocols = vec![b"h2".to_vec()];
+/// The public certificate authorities trusted by default, from the webpki root store.
+pub fn webpki_roots() -> RootCertStore {
+	let mut roots = RootCertStore::empty();
+	roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
 
-	HttpsConnector::from((VSockClientBuilder { address }, cc))
+	roots
 }
 
-/// A connector builder for creating vsock-based HTTP(S) connections.
-///
-/// This type implements hyper's `Service` trait to create connections through
-/// a vsock address, typically used for communication with the host from within
-/// a Nitro Enclave.
-#[derive(Debug, Clone, Copy)]
-pub struct VSockClientBuilder {
-	address: VsockAddr,
-}
+pub fn vsock_proxy_with_roots(
+	connector: VsockConnector,
+	roots: RootCertStore,
+) -> HttpsConnector<VsockConnector> {
+	let cc = client_config()
+		.with_root_certificates(roots)
+		.with_no_client_auth();
 
-pub struct VSockClient {
-	stream: Option<VsockStream>,
+	https_only(connector, cc)
 }
 
-impl VSockClient {
-	pub async fn connect(address: VsockAddr) -> io::Result<Self> {
-		let stream = VsockStream::connect(address).await?;
-
-		Ok(Self {
-			stream: Some(stream),
-		})
-	}
-
-	fn with_pinned_stream<T>(&mut self, closure: impl FnOnce(Pin<&mut VsockStream>) -> T) -> T {
-		let mut stream = self.stream.take().expect("stream is None");
-		let pinned_stream = Pin::new(&mut stream);
+#[cfg(feature = "http")]
+pub fn vsock_proxy_http2_only(connector: VsockConnector) -> HttpsConnector<VsockConnector> {
+	let mut cc = client_config()
+		.with_root_certificates(webpki_roots())
+		.with_no_client_auth();
 
-		let result = closure(pinned_stream);
+	cc.alpn_protocols = vec![b"h2".to_vec()];
 
-		self.stream = Some(stream);
-		result
-	}
+	https_only(connector, cc)
 }
 
-impl Service<Uri> for VSockClientBuilder {
-	type Response = VSockClient;
-	type Error = io::Error;
-	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
-
-	fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-		Poll::Ready(Ok(()))
-	}
-
-	fn call(&mut self, _: Uri) -> Self::Future {
-		Box::pin(VSockClient::connect(self.address))
-	}
-}
+#[cfg(feature = "http")]
+pub fn vsock_proxy_with_verifier(
+	connector: VsockConnector,
+	verifier: Arc<dyn rustls::client::danger::ServerCertVerifier>,
+) -> HttpsConnector<VsockConnector> {
+	let cc = client_config()
+		.dangerous()
+		.with_custom_certificate_verifier(verifier)
+		.with_no_client_auth();
 
-impl AsyncRead for VSockClient {
-	fn poll_read(
-		mut self: Pin<&mut Self>,
-		cx: &mut Context<'_>,
-		buf: &mut tokio::io::ReadBuf<'_>,
-	) -> Poll<io::Result<()>> {
-		self.with_pinned_stream(|stream| stream.poll_read(cx, buf))
-	}
+	https_only(connector, cc)
 }
 
-impl AsyncWrite for VSockClient {
-	fn poll_write(
-		mut self: Pin<&mut Self>,
-		cx: &mut Context<'_>,
-		buf: &[u8],
-	) -> Poll<Result<usize, io::Error>> {
-		self.with_pinned_stream(|stream| stream.poll_write(cx, buf))
-	}
-
-	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-		self.with_pinned_stream(|stream| stream.poll_flush(cx))
-	}
-
-	fn poll_shutdown(
-		mut self: Pin<&mut Self>,
-		cx: &mut Context<'_>,
-	) -> Poll<Result<(), io::Error>> {
-		self.with_pinned_stream(|stream| stream.poll_shutdown(cx))
-	}
+/// A TLS client configuration using the ring crypto provider.
+///
+/// The provider is set explicitly rather than picked from rustls' crate features, as
+/// dependencies enabling another provider would otherwise make rustls refuse to choose.
+fn client_config() -> ConfigBuilder<ClientConfig, WantsVerifier> {
+	ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+		.with_safe_default_protocol_versions()
+		.expect("the ring provider supports the default protocol versions")
 }
 
-impl Drop for VSockClient {
-	fn drop(&mut self) {
-		self.stream
-			.as_ref()
-			.map(|stream| stream.shutdown(Shutdown::Both));
-	}
-}
+/// Wrap `connector` in TLS, refusing `http://` URIs rather than sending them in plaintext.
+fn https_only(connector: VsockConnector, config: ClientConfig) -> HttpsConnector<VsockConnector> {
+	let mut connector = HttpsConnector::from((connector, config));
+	connector.enforce_https();
 
-impl Connection for VSockClient {
-	fn connected(&self) -> Connected {
-		Connected::new()
-	}
+	connector
 }
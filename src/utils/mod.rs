@@ -6,9 +6,11 @@ use {
 	std::{
 		io,
 		net::Shutdown,
-		ops::{Deref, DerefMut},
+		pin::Pin,
+		task::{Context, Poll},
+		time::Duration,
 	},
-	tokio::io::AsyncReadExt,
+	tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
 	tokio_vsock::VsockStream,
 };
 
@@ -16,74 +18,570 @@ use {
 pub mod http;
 
 /// The piece of data that was being read/written when an error occurred.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[allow(
 	dead_code,
 	reason = "CodingKey gets re-exported in client.rs and server.rs, but clippy doesn't know that"
 )]
 pub enum CodingKey {
+	/// The handshake exchanged when a connection is opened.
+	Handshake,
+	/// The metadata headers sent along with a request.
+	Metadata,
+	/// The status of a response, telling a successful one from an error.
+	Status,
 	/// The length of the data.
 	Length,
+	/// The payload format a request or response is tagged with.
+	Format,
 	/// The data itself.
 	Payload,
+	/// The checksum following the data.
+	Checksum,
+	/// The ID tagging a request or response on a multiplexed connection.
+	StreamId,
 }
 
 impl Display for CodingKey {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match self {
+			Self::Handshake => write!(f, "handshake"),
+			Self::Metadata => write!(f, "metadata"),
+			Self::Status => write!(f, "status"),
 			Self::Length => write!(f, "length"),
+			Self::Format => write!(f, "format"),
 			Self::Payload => write!(f, "payload"),
+			Self::Checksum => write!(f, "checksum"),
+			Self::StreamId => write!(f, "stream ID"),
 		}
 	}
 }
 
+/// A connection between a client and a server, over vsock, optionally wrapped in TLS, or
+/// over any other [`ByteStream`].
 #[cfg(any(feature = "server", feature = "client"))]
 pub struct Stream {
-	stream: VsockStream,
+	transport: Transport,
+	read: u64,
+	written: u64,
+}
+
+/// A bidirectional byte stream that requests and responses can be framed over, see
+/// [`Stream::from_io`].
+#[cfg(any(feature = "server", feature = "client"))]
+pub trait ByteStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+#[cfg(any(feature = "server", feature = "client"))]
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ByteStream for T {}
+
+#[cfg(any(feature = "server", feature = "client"))]
+enum Transport {
+	Plain(VsockStream),
+	#[cfg(feature = "tls")]
+	Tls(Box<tokio_rustls::TlsStream<VsockStream>>),
+	Other(Box<dyn ByteStream>),
 }
 
 #[cfg(any(feature = "server", feature = "client"))]
 impl Stream {
+	const fn over(transport: Transport) -> Self {
+		Self {
+			transport,
+			read: 0,
+			written: 0,
+		}
+	}
+
+	/// How many bytes were read off the stream so far, and how many were written to it.
+	///
+	/// Over TLS, these are the bytes before encryption and after decryption.
+	#[cfg_attr(
+		not(feature = "client"),
+		allow(dead_code, reason = "only clients report what they transferred")
+	)]
+	pub const fn transferred(&self) -> (u64, u64) {
+		(self.read, self.written)
+	}
+
+	/// Read exactly `size` bytes, failing with a `TimedOut` error if it takes longer than
+	/// `timeout`, if there is one.
+	///
+	/// Whatever was read before timing out is lost, so the stream shouldn't be used for
+	/// anything else afterwards.
+	#[cfg_attr(
+		not(feature = "server"),
+		allow(dead_code, reason = "only servers time single reads for now")
+	)]
+	pub async fn read_exact_timeout(
+		&mut self,
+		size: usize,
+		timeout: Option<Duration>,
+	) -> io::Result<Vec<u8>> {
+		let mut buf = vec![0; size];
+		timed(timeout, self.read_exact(&mut buf)).await??;
+
+		Ok(buf)
+	}
+
+	/// Write all of `buf` and flush it, failing with a `TimedOut` error if it takes longer
+	/// than `timeout`, if there is one.
+	#[cfg_attr(
+		not(feature = "server"),
+		allow(dead_code, reason = "only servers time single writes for now")
+	)]
+	pub async fn write_all_timeout(
+		&mut self,
+		buf: &[u8],
+		timeout: Option<Duration>,
+	) -> io::Result<()> {
+		let write = async {
+			self.write_all(buf).await?;
+			self.flush().await
+		};
+
+		timed(timeout, write).await?
+	}
+
 	#[cfg(feature = "server")]
 	pub const fn new(stream: VsockStream) -> Self {
-		Self { stream }
+		Self::over(Transport::Plain(stream))
+	}
+
+	/// Terminate a TLS session on an accepted stream, once its handshake completes.
+	#[cfg(all(feature = "server", feature = "tls"))]
+	pub async fn accept_tls(stream: VsockStream, tls: &crate::tls::ServerTls) -> io::Result<Self> {
+		let stream = tls.acceptor().accept(stream).await?;
+
+		Ok(Self::over(Transport::Tls(Box::new(stream.into()))))
+	}
+
+	/// Wrap any byte stream standing in for vsock, such as a Unix socket or an in-memory pipe.
+	///
+	/// Framing works the same over any stream, but TLS sessions are only established over
+	/// vsock.
+	#[cfg_attr(
+		not(feature = "test-transport"),
+		allow(
+			dead_code,
+			reason = "only the test transport connects over anything but vsock for now"
+		)
+	)]
+	pub fn from_io(io: Box<dyn ByteStream>) -> Self {
+		Self::over(Transport::Other(io))
+	}
+
+	/// Connect to a Unix socket standing in for vsock outside of a virtual machine.
+	#[cfg(all(feature = "client", feature = "test-transport"))]
+	pub async fn connect_unix(path: &std::path::Path) -> io::Result<Self> {
+		let stream = tokio::net::UnixStream::connect(path).await?;
+
+		Ok(Self::from_io(Box::new(stream)))
 	}
 
 	#[cfg(feature = "client")]
-	pub async fn connect(cid: u32, port: u32) -> io::Result<Self> {
+	pub async fn connect(cid: u32, port: u32, config: StreamConfig) -> io::Result<Self> {
 		let stream = VsockStream::connect(VsockAddr::new(cid, port)).await?;
+		config.apply(&stream)?;
 
-		Ok(Self { stream })
+		Ok(Self::over(Transport::Plain(stream)))
 	}
 
-	#[cfg(any(feature = "client", feature = "server"))]
-	pub async fn read_exact(&mut self, size: u64) -> io::Result<Vec<u8>> {
-		let mut buf = vec![0; usize::try_from(size).map_err(|_| io::ErrorKind::InvalidInput)?];
-		self.stream.read_exact(&mut buf).await?;
+	/// Connect like [`Stream::connect`], then establish a TLS session over the connection.
+	///
+	/// Failing to connect is reported in the outer result, and failing to establish the
+	/// session in the inner one, so that callers can tell them apart.
+	#[cfg(all(feature = "client", feature = "tls"))]
+	pub async fn connect_tls(
+		cid: u32,
+		port: u32,
+		config: StreamConfig,
+		tls: &crate::tls::ClientTls,
+	) -> io::Result<io::Result<Self>> {
+		let stream = VsockStream::connect(VsockAddr::new(cid, port)).await?;
+		config.apply(&stream)?;
 
-		Ok(buf)
+		Ok(tls
+			.connector()
+			.connect(tls.server_name(), stream)
+			.await
+			.map(|stream| Self::over(Transport::Tls(Box::new(stream.into())))))
 	}
 }
 
+/// Run `future`, failing with a `TimedOut` error if it takes longer than `timeout`, if there
+/// is one.
+///
+/// Every timeout of clients and servers goes through this, from single reads and writes on a
+/// [`Stream`] to whole phases of a connection, so that they all time out the same way.
 #[cfg(any(feature = "server", feature = "client"))]
-impl Deref for Stream {
-	type Target = VsockStream;
+pub async fn timed<F: Future>(timeout: Option<Duration>, future: F) -> io::Result<F::Output> {
+	let Some(timeout) = timeout else {
+		return Ok(future.await);
+	};
 
-	fn deref(&self) -> &Self::Target {
-		&self.stream
+	tokio::time::timeout(timeout, future).await.map_err(|_| {
+		io::Error::new(
+			io::ErrorKind::TimedOut,
+			format!("timed out after {timeout:?}"),
+		)
+	})
+}
+
+/// Socket options set on vsock connections before anything is sent over them.
+///
+/// `tokio_vsock` doesn't expose any socket options, so these are set on the underlying
+/// socket directly. Of the options vsock sockets support, only the buffer sizes can be set
+/// on a connected socket: the connect timeout only applies before connecting, which
+/// `tokio_vsock` does itself, see `ConnectionDetails::with_timeout` instead. vsock has no
+/// Nagle's algorithm to disable either, and ignores `SO_SNDBUF` and `SO_RCVBUF`.
+/// Connections over Unix sockets or in-memory pipes ignore these options.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let config = StreamConfig::default().with_buffer_size(4 * 1024 * 1024);
+/// let details = ConnectionDetails::new(cid, port).with_stream_config(config);
+/// ```
+#[cfg(any(feature = "server", feature = "client"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamConfig {
+	/// How many bytes each side of the connection buffers, or `None` for the kernel's
+	/// default, usually 256 KiB.
+	///
+	/// The peer sends at most this much before waiting for it to be read, so raising it
+	/// helps large payloads through. The kernel clamps the buffer size between a minimum
+	/// and a maximum, 256 KiB by default, so both are set to this size as well.
+	pub buffer_size: Option<u64>,
+}
+
+#[cfg(any(feature = "server", feature = "client"))]
+impl StreamConfig {
+	/// Buffer `bytes` on each side of the connection, see [`StreamConfig::buffer_size`].
+	#[must_use]
+	pub const fn with_buffer_size(mut self, bytes: u64) -> Self {
+		self.buffer_size = Some(bytes);
+		self
+	}
+
+	/// Set the options on a vsock socket.
+	pub(crate) fn apply(self, socket: &impl std::os::fd::AsFd) -> io::Result<()> {
+		if let Some(bytes) = self.buffer_size {
+			// Setting the maximum first clamps the size to it, and the minimum then raises
+			// the size to it, whichever way the size moves.
+			nix::sys::socket::setsockopt(socket, VsockMaxBufferSize, &bytes)?;
+			nix::sys::socket::setsockopt(socket, VsockMinBufferSize, &bytes)?;
+			nix::sys::socket::setsockopt(socket, VsockBufferSize, &bytes)?;
+		}
+
+		Ok(())
 	}
 }
 
+// Options from `linux/vm_sockets.h`, which `libc` doesn't define.
+#[cfg(any(feature = "server", feature = "client"))]
+const SO_VM_SOCKETS_BUFFER_SIZE: libc::c_int = 0;
+#[cfg(any(feature = "server", feature = "client"))]
+const SO_VM_SOCKETS_BUFFER_MIN_SIZE: libc::c_int = 1;
+#[cfg(any(feature = "server", feature = "client"))]
+const SO_VM_SOCKETS_BUFFER_MAX_SIZE: libc::c_int = 2;
+
+// `sockopt_impl` expands to the other macros and to `libc` paths, so they must be in scope.
+#[cfg(any(feature = "server", feature = "client"))]
+use nix::{getsockopt_impl, libc, setsockopt_impl, sockopt_impl};
+
 #[cfg(any(feature = "server", feature = "client"))]
-impl DerefMut for Stream {
-	fn deref_mut(&mut self) -> &mut Self::Target {
-		&mut self.stream
+sockopt_impl!(
+	/// How many bytes a vsock socket buffers, see [`StreamConfig::buffer_size`].
+	VsockBufferSize,
+	Both,
+	libc::AF_VSOCK,
+	SO_VM_SOCKETS_BUFFER_SIZE,
+	u64
+);
+
+#[cfg(any(feature = "server", feature = "client"))]
+sockopt_impl!(
+	/// The smallest buffer size of a vsock socket.
+	VsockMinBufferSize,
+	Both,
+	libc::AF_VSOCK,
+	SO_VM_SOCKETS_BUFFER_MIN_SIZE,
+	u64
+);
+
+#[cfg(any(feature = "server", feature = "client"))]
+sockopt_impl!(
+	/// The largest buffer size of a vsock socket.
+	VsockMaxBufferSize,
+	Both,
+	libc::AF_VSOCK,
+	SO_VM_SOCKETS_BUFFER_MAX_SIZE,
+	u64
+);
+
+/// Reading of length-prefixed frames, off a [`Stream`] or either half of a split one.
+#[cfg(any(feature = "server", feature = "client"))]
+pub trait ReadFramed: AsyncRead + Unpin + Send {
+	/// Read a length-prefixed frame, refusing to allocate more than `max` bytes for it.
+	///
+	/// Frames declaring more than `max` bytes fail with an `InvalidData` error wrapping
+	/// [`FrameTooLarge`], before any of their payload is read. So do frames too large to be
+	/// addressed on this platform, whatever `max` is, and frames that can't be allocated fail
+	/// with an `OutOfMemory` error, rather than wrapping around or aborting.
+	fn read_framed(&mut self, max: u64) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+		async move {
+			let declared = self.read_u64().await?;
+			let too_large = |limit| {
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					FrameTooLarge { declared, limit },
+				)
+			};
+
+			if declared > max {
+				return Err(too_large(max));
+			}
+
+			let len = usize::try_from(declared)
+				.map_err(|_| too_large(u64::try_from(usize::MAX).unwrap_or(u64::MAX)))?;
+
+			let mut buf = Vec::new();
+			buf.try_reserve_exact(len)
+				.map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+
+			self.take(declared).read_to_end(&mut buf).await?;
+			if buf.len() != len {
+				return Err(io::ErrorKind::UnexpectedEof.into());
+			}
+
+			Ok(buf)
+		}
+	}
+}
+
+#[cfg(any(feature = "server", feature = "client"))]
+impl<R: AsyncRead + Unpin + Send + ?Sized> ReadFramed for R {}
+
+/// A peer declared a frame longer than the reader accepts, see [`ReadFramed::read_framed`].
+#[cfg(any(feature = "server", feature = "client"))]
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("frame too large: {declared} bytes declared, limit is {limit}")]
+pub struct FrameTooLarge {
+	/// The length announced by the peer.
+	pub declared: u64,
+	/// The largest frame the reader accepts.
+	pub limit: u64,
+}
+
+#[cfg(any(feature = "server", feature = "client"))]
+impl FrameTooLarge {
+	/// The oversized frame an error returned by [`ReadFramed::read_framed`] reports, if any.
+	#[must_use]
+	pub fn find(error: &io::Error) -> Option<&Self> {
+		error.get_ref()?.downcast_ref()
+	}
+}
+
+#[cfg(any(feature = "server", feature = "client"))]
+impl AsyncRead for Stream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		let before = buf.filled().len();
+
+		let poll = match &mut this.transport {
+			Transport::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+			#[cfg(feature = "tls")]
+			Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+			Transport::Other(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+		};
+
+		let read = u64::try_from(buf.filled().len() - before).unwrap_or(u64::MAX);
+		this.read = this.read.saturating_add(read);
+		poll
+	}
+}
+
+#[cfg(any(feature = "server", feature = "client"))]
+impl AsyncWrite for Stream {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+
+		let poll = match &mut this.transport {
+			Transport::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+			#[cfg(feature = "tls")]
+			Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+			Transport::Other(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+		};
+
+		if let Poll::Ready(Ok(written)) = poll {
+			let written = u64::try_from(written).unwrap_or(u64::MAX);
+			this.written = this.written.saturating_add(written);
+		}
+		poll
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match &mut self.get_mut().transport {
+			Transport::Plain(stream) => Pin::new(stream).poll_flush(cx),
+			#[cfg(feature = "tls")]
+			Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+			Transport::Other(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match &mut self.get_mut().transport {
+			Transport::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+			#[cfg(feature = "tls")]
+			Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+			Transport::Other(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+		}
 	}
 }
 
 #[cfg(any(feature = "server", feature = "client"))]
 impl Drop for Stream {
 	fn drop(&mut self) {
-		_ = self.stream.shutdown(Shutdown::Both);
+		match &self.transport {
+			Transport::Plain(stream) => _ = stream.shutdown(Shutdown::Both),
+			#[cfg(feature = "tls")]
+			Transport::Tls(stream) => _ = stream.get_ref().0.shutdown(Shutdown::Both),
+			// Other streams close however they do when dropped, which is right after this.
+			Transport::Other(_) => {},
+		}
+	}
+}
+
+#[cfg(all(test, any(feature = "server", feature = "client")))]
+mod tests {
+	use super::*;
+	use tokio::io::AsyncWriteExt;
+
+	fn frame(declared: u64, payload: &[u8]) -> Vec<u8> {
+		[&declared.to_be_bytes(), payload].concat()
+	}
+
+	#[test]
+	fn test_read_framed() {
+		let frame = frame(3, b"abc");
+
+		let payload = tokio_test::block_on(frame.as_slice().read_framed(3)).unwrap();
+		assert_eq!(payload, b"abc");
+
+		let error = tokio_test::block_on(frame.as_slice().read_framed(2)).unwrap_err();
+		let too_large = FrameTooLarge::find(&error).unwrap();
+		assert_eq!((too_large.declared, too_large.limit), (3, 2));
+	}
+
+	#[test]
+	fn test_stream_config_sets_the_buffer_size() {
+		use nix::sys::socket::{AddressFamily, SockFlag, SockType, getsockopt, socket};
+
+		// Machines without vsock, such as most CI runners, can't open vsock sockets at all.
+		let Ok(socket) = socket(
+			AddressFamily::Vsock,
+			SockType::Stream,
+			SockFlag::SOCK_CLOEXEC,
+			None,
+		) else {
+			return;
+		};
+
+		StreamConfig::default().apply(&socket).unwrap();
+		let default = getsockopt(&socket, VsockBufferSize).unwrap();
+
+		for bytes in [default * 4, default / 4] {
+			StreamConfig::default()
+				.with_buffer_size(bytes)
+				.apply(&socket)
+				.unwrap();
+			assert_eq!(getsockopt(&socket, VsockBufferSize).unwrap(), bytes);
+		}
+	}
+
+	/// Streams frame requests the same way over any byte stream as over vsock.
+	#[test]
+	fn test_stream_over_any_io() {
+		let (client, server) = tokio::io::duplex(64);
+		let (mut client, mut server) = (
+			Stream::from_io(Box::new(client)),
+			Stream::from_io(Box::new(server)),
+		);
+
+		tokio_test::block_on(async {
+			client.write_all(&frame(3, b"abc")).await.unwrap();
+			assert_eq!(server.read_framed(3).await.unwrap(), b"abc");
+			assert_eq!(client.transferred(), (0, 11));
+			assert_eq!(server.transferred(), (11, 0));
+
+			drop(client);
+			assert_eq!(
+				server.read_u8().await.unwrap_err().kind(),
+				io::ErrorKind::UnexpectedEof
+			);
+		});
+	}
+
+	/// Reads and writes that don't complete in time fail with a `TimedOut` error.
+	#[test]
+	fn test_timed_io() {
+		let timeout = Some(Duration::from_millis(10));
+		let (client, server) = tokio::io::duplex(4);
+		let (mut client, mut server) = (
+			Stream::from_io(Box::new(client)),
+			Stream::from_io(Box::new(server)),
+		);
+
+		tokio_test::block_on(async {
+			client.write_all_timeout(b"abc", timeout).await.unwrap();
+			let read = server.read_exact_timeout(3, timeout).await.unwrap();
+			assert_eq!(read, b"abc");
+
+			let error = server.read_exact_timeout(1, timeout).await.unwrap_err();
+			assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+			// The pipe only buffers 4 bytes, and nobody reads them.
+			let error = client
+				.write_all_timeout(b"abcde", timeout)
+				.await
+				.unwrap_err();
+			assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+		});
+	}
+
+	/// A frame cut short fails to read instead of coming back truncated.
+	#[test]
+	fn test_read_framed_truncated() {
+		let frame = frame(4, b"abc");
+
+		let error = tokio_test::block_on(frame.as_slice().read_framed(4)).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+	}
+
+	/// Lengths close to `u64::MAX` are refused before anything is allocated for them, even
+	/// when the reader accepts frames of any length.
+	#[test]
+	fn test_read_framed_huge_lengths() {
+		for declared in [u64::MAX, u64::MAX - 1, u64::try_from(usize::MAX).unwrap()] {
+			let frame = frame(declared, b"abc");
+
+			let error =
+				tokio_test::block_on(frame.as_slice().read_framed(declared - 1)).unwrap_err();
+			assert!(FrameTooLarge::find(&error).is_some());
+
+			let error = tokio_test::block_on(frame.as_slice().read_framed(u64::MAX)).unwrap_err();
+			assert!(matches!(
+				error.kind(),
+				io::ErrorKind::InvalidData | io::ErrorKind::OutOfMemory
+			));
+		}
 	}
 }
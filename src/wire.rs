@@ -0,0 +1,1386 @@
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{collections::BTreeMap, fmt::Display, io};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+#[cfg(any(feature = "server", test))]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::utils::CodingKey;
+
+/// How MessagePack lays out structs.
+///
+/// Both layouts are valid MessagePack, but a peer expecting one may silently misread the
+/// other (for example, fields get matched by position instead of by name), so both ends of
+/// a connection must agree on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StructEncoding {
+	/// Structs are encoded as arrays of their fields, in declaration order.
+	#[default]
+	Array,
+	/// Structs are encoded as maps keyed by field name.
+	Map,
+}
+
+/// The serialization codec payloads are encoded with.
+///
+/// MessagePack is always available, as error frames use it. Other codecs are enabled with
+/// their feature flag, for payloads shared with services that don't speak MessagePack.
+/// Which variants exist depends on the enabled features, so matches on this enum need a
+/// wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Codec {
+	/// MessagePack, the default.
+	#[default]
+	MessagePack,
+	/// CBOR, enabled by the `codec-cbor` feature.
+	#[cfg(feature = "codec-cbor")]
+	Cbor,
+	/// JSON, enabled by the `codec-json` feature.
+	#[cfg(feature = "codec-json")]
+	Json,
+}
+
+impl Codec {
+	/// The identifier of the codec, sent in the high nibble of format descriptors.
+	#[must_use]
+	pub const fn id(self) -> u8 {
+		match self {
+			Self::MessagePack => 0x0,
+			#[cfg(feature = "codec-cbor")]
+			Self::Cbor => 0x1,
+			#[cfg(feature = "codec-json")]
+			Self::Json => 0x2,
+		}
+	}
+
+	/// The codec with the given identifier, or `None` if it is unknown or not enabled.
+	#[must_use]
+	pub const fn from_id(id: u8) -> Option<Self> {
+		match id {
+			0x0 => Some(Self::MessagePack),
+			#[cfg(feature = "codec-cbor")]
+			0x1 => Some(Self::Cbor),
+			#[cfg(feature = "codec-json")]
+			0x2 => Some(Self::Json),
+			_ => None,
+		}
+	}
+}
+
+impl Display for Codec {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::MessagePack => write!(f, "msgpack"),
+			#[cfg(feature = "codec-cbor")]
+			Self::Cbor => write!(f, "cbor"),
+			#[cfg(feature = "codec-json")]
+			Self::Json => write!(f, "json"),
+		}
+	}
+}
+
+/// A payload couldn't be encoded.
+#[derive(Debug, thiserror::Error)]
+pub enum EncodeError {
+	/// MessagePack encoding failed.
+	#[error("MessagePack")]
+	MessagePack(#[source] rmp_serde::encode::Error),
+	/// CBOR encoding failed.
+	#[cfg(feature = "codec-cbor")]
+	#[error("CBOR")]
+	Cbor(#[source] serde_cbor::Error),
+	/// JSON encoding failed.
+	#[cfg(feature = "codec-json")]
+	#[error("JSON")]
+	Json(#[source] serde_json::Error),
+}
+
+/// A payload couldn't be decoded.
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+	/// The payload isn't valid MessagePack for the expected type.
+	#[error("MessagePack")]
+	MessagePack(#[source] rmp_serde::decode::Error),
+	/// The payload isn't valid CBOR for the expected type.
+	#[cfg(feature = "codec-cbor")]
+	#[error("CBOR")]
+	Cbor(#[source] serde_cbor::Error),
+	/// The payload isn't valid JSON for the expected type.
+	#[cfg(feature = "codec-json")]
+	#[error("JSON")]
+	Json(#[source] serde_json::Error),
+}
+
+/// The serialization format used for request and response payloads.
+///
+/// Each peer announces its format when a connection is opened, and the connection is
+/// refused with a `CodecMismatch` error if the two are incompatible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Format {
+	/// The codec payloads are encoded with.
+	pub codec: Codec,
+	/// How structs are laid out in MessagePack. CBOR and JSON always encode structs as maps.
+	pub structs: StructEncoding,
+}
+
+/// The descriptor sent by a peer doesn't match the local format.
+#[derive(Debug, thiserror::Error)]
+#[error("codec mismatch: local peer uses {local}, remote peer sent descriptor 0x{remote:02x}")]
+pub struct CodecMismatch {
+	/// The format of the local peer.
+	pub local: Format,
+	/// The raw descriptor announced by the remote peer.
+	pub remote: u8,
+}
+
+impl Format {
+	/// Create a new MessagePack `Format` with the given struct encoding.
+	#[must_use]
+	pub const fn new(structs: StructEncoding) -> Self {
+		Self {
+			codec: Codec::MessagePack,
+			structs,
+		}
+	}
+
+	/// Use the given codec instead of MessagePack.
+	///
+	/// CBOR and JSON encode structs as maps, so this also switches the struct encoding to
+	/// maps for them, to describe payloads accurately.
+	#[must_use]
+	pub const fn with_codec(mut self, codec: Codec) -> Self {
+		self.codec = codec;
+		if !matches!(codec, Codec::MessagePack) {
+			self.structs = StructEncoding::Map;
+		}
+		self
+	}
+
+	/// The one-byte descriptor announced during the handshake.
+	///
+	/// The high nibble identifies the codec and the low nibble its struct encoding.
+	#[must_use]
+	pub const fn descriptor(self) -> u8 {
+		let structs = match self.structs {
+			StructEncoding::Array => 0x0,
+			StructEncoding::Map => 0x1,
+		};
+
+		(self.codec.id() << 4) | structs
+	}
+
+	/// Parse a descriptor announced by a peer, returning `None` if it is unknown.
+	#[must_use]
+	pub const fn from_descriptor(descriptor: u8) -> Option<Self> {
+		let Some(codec) = Codec::from_id(descriptor >> 4) else {
+			return None;
+		};
+
+		let structs = match descriptor & 0x0F {
+			0x0 => StructEncoding::Array,
+			0x1 => StructEncoding::Map,
+			_ => return None,
+		};
+
+		Some(Self { codec, structs })
+	}
+
+	/// Check that a descriptor announced by a peer is compatible with this format.
+	///
+	/// # Errors
+	///
+	/// Returns `CodecMismatch` if the descriptor is unknown, or uses a different codec or
+	/// struct encoding.
+	pub fn negotiate(self, remote: u8) -> Result<(), CodecMismatch> {
+		match Self::from_descriptor(remote) {
+			Some(peer) if peer == self => Ok(()),
+			_ => Err(CodecMismatch {
+				local: self,
+				remote,
+			}),
+		}
+	}
+
+	/// Serialize a value with this format.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the value cannot be serialized.
+	pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, EncodeError> {
+		match (self.codec, self.structs) {
+			(Codec::MessagePack, StructEncoding::Array) => {
+				rmp_serde::to_vec(value).map_err(EncodeError::MessagePack)
+			},
+			(Codec::MessagePack, StructEncoding::Map) => {
+				rmp_serde::to_vec_named(value).map_err(EncodeError::MessagePack)
+			},
+			#[cfg(feature = "codec-cbor")]
+			(Codec::Cbor, _) => serde_cbor::to_vec(&value).map_err(EncodeError::Cbor),
+			#[cfg(feature = "codec-json")]
+			(Codec::Json, _) => serde_json::to_vec(value).map_err(EncodeError::Json),
+		}
+	}
+
+	/// Compute how many bytes a value serializes to with this format, without sending it.
+	///
+	/// The value is serialized into a counter rather than a buffer, so this doesn't allocate.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the value cannot be serialized.
+	pub fn encoded_len<T: Serialize + ?Sized>(self, value: &T) -> Result<usize, EncodeError> {
+		let mut counter = ByteCounter(0);
+		self.encode_into(&mut counter, value)?;
+
+		Ok(counter.0)
+	}
+
+	/// Serialize a value with this format into a buffer of exactly its size.
+	///
+	/// [`Format::encode`] grows its buffer as the value is serialized, copying what was written
+	/// so far every time, and can leave as much capacity unused as the value takes. This
+	/// serializes the value twice instead, first to size the buffer like
+	/// [`Format::encoded_len`] does, trading CPU time for a lower peak memory use on large
+	/// values.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the value cannot be serialized.
+	pub fn encode_exact<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, EncodeError> {
+		let mut buf = Vec::with_capacity(self.encoded_len(value)?);
+		self.encode_into(&mut buf, value)?;
+
+		Ok(buf)
+	}
+
+	/// Serialize a value with this format into a writer.
+	fn encode_into<W, T>(self, writer: &mut W, value: &T) -> Result<(), EncodeError>
+	where
+		W: io::Write,
+		T: Serialize + ?Sized,
+	{
+		match (self.codec, self.structs) {
+			(Codec::MessagePack, StructEncoding::Array) => {
+				rmp_serde::encode::write(writer, value).map_err(EncodeError::MessagePack)
+			},
+			(Codec::MessagePack, StructEncoding::Map) => {
+				rmp_serde::encode::write_named(writer, value).map_err(EncodeError::MessagePack)
+			},
+			#[cfg(feature = "codec-cbor")]
+			(Codec::Cbor, _) => serde_cbor::to_writer(writer, &value).map_err(EncodeError::Cbor),
+			#[cfg(feature = "codec-json")]
+			(Codec::Json, _) => serde_json::to_writer(writer, value).map_err(EncodeError::Json),
+		}
+	}
+
+	/// Deserialize a value with this format.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the bytes are not a valid encoding of `T`.
+	pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, DecodeError> {
+		match self.codec {
+			// The decoder reads whichever layout it finds, the handshake is what keeps peers consistent.
+			Codec::MessagePack => rmp_serde::from_slice(bytes).map_err(DecodeError::MessagePack),
+			#[cfg(feature = "codec-cbor")]
+			Codec::Cbor => serde_cbor::from_slice(bytes).map_err(DecodeError::Cbor),
+			#[cfg(feature = "codec-json")]
+			Codec::Json => serde_json::from_slice(bytes).map_err(DecodeError::Json),
+		}
+	}
+}
+
+/// Compute how many bytes a value serializes to with the default format, without sending it.
+///
+/// Use this to check a request or response against a payload size limit before sending it.
+/// For a connection or router configured with another format, use [`Format::encoded_len`].
+///
+/// # Errors
+///
+/// Returns an error if the value cannot be serialized.
+pub fn encoded_len<T: Serialize + ?Sized>(value: &T) -> Result<usize, EncodeError> {
+	Format::default().encoded_len(value)
+}
+
+/// A writer that discards everything, keeping count of the bytes it was given.
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0 += buf.len();
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl Display for Format {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match (self.codec, self.structs) {
+			(Codec::MessagePack, StructEncoding::Array) => write!(f, "msgpack (structs as arrays)"),
+			(Codec::MessagePack, StructEncoding::Map) => write!(f, "msgpack (structs as maps)"),
+			#[allow(
+				unreachable_patterns,
+				reason = "only reachable with another codec enabled"
+			)]
+			(codec, _) => write!(f, "{codec}"),
+		}
+	}
+}
+
+/// The largest payload peers accept by default, see `Router::max_payload` and
+/// `ConnectionDetails::with_max_payload`.
+pub const DEFAULT_MAX_PAYLOAD_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The version of the wire protocol, sent by both peers first thing in the handshake.
+///
+/// Peers refuse to talk to each other when their versions differ, rather than misread each
+/// other's bytes. Bump it with every change to the layout of the handshake or of frames.
+///
+/// With the `wide-ids` feature, its high bit is set, as requests then lead with 8-byte type
+/// IDs rather than 4-byte ones.
+#[cfg(not(feature = "wide-ids"))]
+pub const PROTOCOL_VERSION: u8 = 2;
+/// The version of the wire protocol, sent by both peers first thing in the handshake.
+///
+/// Its high bit marks the 8-byte type IDs of the `wide-ids` feature, so that peers without
+/// it refuse to talk to this one.
+#[cfg(feature = "wide-ids")]
+pub const PROTOCOL_VERSION: u8 = 2 | 0x80;
+
+/// Connection mode announced by clients sending one request at a time, each answered before
+/// the next one is read.
+pub(crate) const MODE_SEQUENTIAL: u8 = 0;
+/// Connection mode announced by clients multiplexing requests over the connection.
+///
+/// Every request and response is then preceded by a `u32` stream ID, chosen by the client,
+/// and responses come back in whatever order the server finishes handling them.
+pub(crate) const MODE_MULTIPLEXED: u8 = 1;
+
+/// Status byte preceding a successful response frame.
+pub(crate) const STATUS_OK: u8 = 0;
+/// Status byte preceding an [`ErrorFrame`].
+pub(crate) const STATUS_ERROR: u8 = 1;
+/// Status byte preceding a successful response frame compressed with zstd.
+///
+/// Servers only send it to clients that accept zstd, so that a peer unaware of compression
+/// never receives a compressed payload.
+#[cfg(any(feature = "client", feature = "compression"))]
+pub(crate) const STATUS_OK_ZSTD: u8 = 2;
+/// Flag set in the status byte of a response whose frame is followed by its checksum.
+///
+/// Servers only set it in answer to requests carrying a checksum themselves, see [`Checksum`].
+pub(crate) const STATUS_CHECKSUM_FLAG: u8 = 0x80;
+
+/// An error reported by the server instead of a response.
+///
+/// Every response starts with a status byte. When a request fails after the handshake, the
+/// server sends an error status followed by a regular frame holding the error code and its
+/// description, encoded as a MessagePack array regardless of the negotiated format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorFrame {
+	/// What kind of error occurred, one of the associated constants.
+	pub code: u16,
+	/// A human-readable description of the error.
+	pub message: String,
+}
+
+impl ErrorFrame {
+	/// An error the client can't do anything about.
+	pub const INTERNAL: u16 = 0;
+	/// No handler is registered for the request's type ID.
+	pub const UNKNOWN_REQUEST: u16 = 1;
+	/// The request payload couldn't be decoded.
+	pub const DECODING: u16 = 2;
+	/// The response couldn't be encoded.
+	pub const ENCODING: u16 = 3;
+	/// The request couldn't be read.
+	pub const READING: u16 = 4;
+	/// The request uses a format the server doesn't support.
+	pub const UNSUPPORTED_CODEC: u16 = 5;
+	/// The server is overloaded.
+	pub const SERVICE_UNAVAILABLE: u16 = 6;
+	/// The request payload is larger than the server accepts.
+	pub const PAYLOAD_TOO_LARGE: u16 = 7;
+	/// The server gave up on the request after its timeout.
+	pub const TIMEOUT: u16 = 8;
+	/// The handler failed, and described the failure in the message.
+	pub const HANDLER: u16 = 9;
+	/// The handler panicked while handling the request.
+	pub const HANDLER_PANIC: u16 = 10;
+	/// The request payload doesn't match its checksum, so it was corrupted on the way.
+	pub const CHECKSUM_MISMATCH: u16 = 11;
+	/// The request can't be served over this kind of connection, such as a streaming
+	/// request over a multiplexed one.
+	pub const UNSUPPORTED_MODE: u16 = 12;
+	/// The server turned the request's route off, for example for maintenance.
+	pub const ROUTE_UNAVAILABLE: u16 = 13;
+	/// The request reuses an idempotency key sent earlier with a different payload.
+	pub const IDEMPOTENCY_KEY_REUSED: u16 = 14;
+	/// The first code free for applications to use in a [`HandlerError`].
+	///
+	/// Codes below it are reserved for errors reported by the library itself.
+	pub const APPLICATION: u16 = 1000;
+
+	/// Create a new `ErrorFrame`.
+	#[must_use]
+	pub const fn new(code: u16, message: String) -> Self {
+		Self { code, message }
+	}
+
+	#[cfg(any(feature = "server", test))]
+	pub(crate) fn encode(&self) -> Vec<u8> {
+		// Encoding a tuple of plain values into memory can't fail.
+		rmp_serde::to_vec(&(self.code, &self.message)).unwrap_or_default()
+	}
+
+	#[cfg(any(feature = "client", test))]
+	pub(crate) fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+		let (code, message) = rmp_serde::from_slice(bytes).map_err(DecodeError::MessagePack)?;
+
+		Ok(Self { code, message })
+	}
+}
+
+impl From<HandlerError> for ErrorFrame {
+	fn from(error: HandlerError) -> Self {
+		Self::new(error.code, error.message)
+	}
+}
+
+/// An error returned by a fallible handler, see `Router::try_route`.
+///
+/// The server sends it to the client in an [`ErrorFrame`] instead of a response, where the
+/// client receives it as a remote error with the same code and message. Handlers can convert
+/// their own errors into it, which lets them use `?` on anything that converts into a
+/// `HandlerError`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[error("{message}")]
+pub struct HandlerError {
+	/// What kind of error occurred, [`ErrorFrame::HANDLER`] unless set by the application.
+	pub code: u16,
+	/// A human-readable description of the error.
+	pub message: String,
+}
+
+impl HandlerError {
+	/// Create a new `HandlerError` with the generic [`ErrorFrame::HANDLER`] code.
+	#[must_use]
+	pub fn new(message: impl Into<String>) -> Self {
+		Self {
+			code: ErrorFrame::HANDLER,
+			message: message.into(),
+		}
+	}
+
+	/// Report the error with an application-defined code, which should be at least
+	/// [`ErrorFrame::APPLICATION`] to stay clear of the library's own codes.
+	#[must_use]
+	pub const fn with_code(mut self, code: u16) -> Self {
+		self.code = code;
+		self
+	}
+}
+
+impl From<String> for HandlerError {
+	fn from(message: String) -> Self {
+		Self::new(message)
+	}
+}
+
+impl From<&str> for HandlerError {
+	fn from(message: &str) -> Self {
+		Self::new(message)
+	}
+}
+
+/// How a payload is compressed.
+///
+/// A request announces the compression of its payload in the [`Metadata::CONTENT_ENCODING`]
+/// header, and the compressions it accepts for its response in [`Metadata::ACCEPT_ENCODING`].
+/// A response announces its compression in its status byte. Which variants exist depends on
+/// the enabled features, so matches on this enum need a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Compression {
+	/// The payload is sent as is.
+	#[default]
+	None,
+	/// The payload is compressed with zstd, enabled by the `compression` feature.
+	#[cfg(feature = "compression")]
+	Zstd,
+}
+
+impl Compression {
+	/// The name of the compression in metadata headers.
+	#[must_use]
+	pub const fn name(self) -> &'static str {
+		match self {
+			Self::None => "identity",
+			#[cfg(feature = "compression")]
+			Self::Zstd => "zstd",
+		}
+	}
+
+	/// The compression with the given name, or `None` if it is unknown or not enabled.
+	#[must_use]
+	pub fn from_name(name: &[u8]) -> Option<Self> {
+		match name {
+			b"identity" => Some(Self::None),
+			#[cfg(feature = "compression")]
+			b"zstd" => Some(Self::Zstd),
+			_ => None,
+		}
+	}
+}
+
+/// How a payload is checked for corruption on the way.
+///
+/// A request carrying a checksum names it in the [`Metadata::CHECKSUM`] header, and its
+/// payload frame is followed by the checksum, as 4 big-endian bytes. The server then
+/// checksums its response the same way, announcing it with a flag in its status byte. The
+/// checksum covers the payload as sent, after compression. Which variants exist depends on
+/// the enabled features, so matches on this enum need a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Checksum {
+	/// The payload isn't checksummed.
+	#[default]
+	None,
+	/// The payload is followed by its CRC32C, enabled by the `checksum` feature.
+	#[cfg(feature = "checksum")]
+	Crc32c,
+}
+
+impl Checksum {
+	/// The name of the checksum in metadata headers.
+	#[must_use]
+	pub const fn name(self) -> &'static str {
+		match self {
+			Self::None => "none",
+			#[cfg(feature = "checksum")]
+			Self::Crc32c => "crc32c",
+		}
+	}
+
+	/// The checksum with the given name, or `None` if it is unknown or not enabled.
+	#[must_use]
+	pub fn from_name(name: &[u8]) -> Option<Self> {
+		match name {
+			b"none" => Some(Self::None),
+			#[cfg(feature = "checksum")]
+			b"crc32c" => Some(Self::Crc32c),
+			_ => None,
+		}
+	}
+
+	/// Compute the checksum of a payload, or `None` if payloads aren't checksummed.
+	#[cfg_attr(
+		not(feature = "checksum"),
+		allow(unused_variables, clippy::missing_const_for_fn)
+	)]
+	pub(crate) fn compute(self, payload: &[u8]) -> Option<u32> {
+		match self {
+			Self::None => None,
+			#[cfg(feature = "checksum")]
+			Self::Crc32c => Some(crc32c::crc32c(payload)),
+		}
+	}
+}
+
+/// A payload doesn't match the checksum sent along with it.
+#[derive(Debug, thiserror::Error)]
+#[error("checksum mismatch: expected 0x{expected:08x}, payload checksums to 0x{actual:08x}")]
+pub struct ChecksumMismatch {
+	/// The checksum sent along with the payload.
+	pub expected: u32,
+	/// The checksum of the payload as it was received.
+	pub actual: u32,
+}
+
+/// When payloads get compressed, see `Router::compression` and
+/// `ConnectionDetails::with_compression`.
+///
+/// Compression costs CPU time on both ends, so it only pays off for large payloads that
+/// compress well, when vsock throughput is the bottleneck.
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+	/// Payloads smaller than this many bytes are sent as is.
+	pub threshold: usize,
+	/// The zstd compression level, from 1 (fastest) to 22 (smallest).
+	pub level: i32,
+}
+
+#[cfg(feature = "compression")]
+impl Default for CompressionConfig {
+	fn default() -> Self {
+		Self::new(1024)
+	}
+}
+
+#[cfg(feature = "compression")]
+impl CompressionConfig {
+	/// Compress payloads of at least `threshold` bytes, at zstd's default level.
+	#[must_use]
+	pub const fn new(threshold: usize) -> Self {
+		Self {
+			threshold,
+			level: zstd::DEFAULT_COMPRESSION_LEVEL,
+		}
+	}
+
+	/// Compress payloads at the given zstd level.
+	#[must_use]
+	pub const fn with_level(mut self, level: i32) -> Self {
+		self.level = level;
+		self
+	}
+
+	/// Compress a payload if it is large enough, and if compressing actually shrinks it.
+	pub(crate) fn compress(self, payload: Vec<u8>) -> (Compression, Vec<u8>) {
+		if payload.len() < self.threshold {
+			return (Compression::None, payload);
+		}
+
+		match zstd::bulk::compress(&payload, self.level) {
+			Ok(compressed) if compressed.len() < payload.len() => (Compression::Zstd, compressed),
+			// Failing to compress is no reason to fail the exchange, the payload is still valid.
+			_ => (Compression::None, payload),
+		}
+	}
+}
+
+/// Undo the compression of a payload, refusing to inflate it beyond `limit` bytes.
+///
+/// The limit is what keeps a small compressed payload from expanding into gigabytes.
+#[cfg(any(feature = "server", feature = "compression"))]
+#[cfg_attr(
+	not(feature = "compression"),
+	allow(
+		unused_variables,
+		clippy::missing_const_for_fn,
+		clippy::unnecessary_wraps
+	)
+)]
+pub(crate) fn decompress(
+	compression: Compression,
+	payload: Vec<u8>,
+	limit: u64,
+) -> io::Result<Vec<u8>> {
+	match compression {
+		Compression::None => Ok(payload),
+		#[cfg(feature = "compression")]
+		Compression::Zstd => {
+			use std::io::Read;
+
+			let mut decompressed = Vec::new();
+			zstd::stream::read::Decoder::new(payload.as_slice())?
+				.take(limit.saturating_add(1))
+				.read_to_end(&mut decompressed)?;
+
+			if u64::try_from(decompressed.len()).map_or(true, |len| len > limit) {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("decompressed payload exceeds the limit of {limit} bytes"),
+				));
+			}
+
+			Ok(decompressed)
+		},
+	}
+}
+
+/// The largest encoded size of the metadata headers of a request.
+///
+/// Everything a server reads before a request's payload is either fixed-size or bounded by
+/// this limit, so a peer sending garbage is refused after a few kilobytes at most and before
+/// anything is allocated for the payload itself:
+///
+/// | Field              | Size                                          |
+/// |--------------------|-----------------------------------------------|
+/// | Protocol version   | 1 byte, once per connection                   |
+/// | Format descriptor  | 1 byte, once per connection                   |
+/// | Connection mode    | 1 byte, once per connection                   |
+/// | Stream ID          | 4 bytes, on multiplexed connections only      |
+/// | Type ID            | 4 bytes, or 8 with the `wide-ids` feature     |
+/// | Format tags        | 2 bytes                                       |
+/// | Metadata headers   | at most `MAX_METADATA_BYTES`                  |
+/// | Payload length     | 8 bytes                                       |
+///
+/// An unsupported protocol version, format descriptor, connection mode or tag, or oversized
+/// headers each end the connection as soon as they are read. So does an unknown type ID when
+/// the server is set not to drain the payload of such requests, see `server::RejectPolicy`.
+///
+/// On servers terminating TLS, these bytes are read through the session, so they are only
+/// checked once the TLS handshake is done. That handshake is bounded by a timeout of its own.
+pub const MAX_METADATA_BYTES: usize = 4 * 1024;
+
+/// Generate a random request ID, as 16 hexadecimal digits.
+///
+/// Clients send one with every request, which the server records in the span it handles the
+/// request in, so that the logs of both ends can be matched. The IDs are only meant to
+/// correlate logs: they are unpredictable enough not to collide, but not cryptographically
+/// secure.
+#[must_use]
+pub fn generate_request_id() -> String {
+	format!("{:016x}", random_u64())
+}
+
+/// A random number, unpredictable enough to tell IDs apart but not cryptographically secure.
+fn random_u64() -> u64 {
+	use std::{
+		hash::{BuildHasher, Hasher, RandomState},
+		sync::atomic::{AtomicU64, Ordering},
+	};
+
+	// Every `RandomState` is seeded differently, the counter sets apart IDs generated at once.
+	static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+	let mut hasher = RandomState::new().build_hasher();
+	hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+
+	hasher.finish()
+}
+
+/// The ID of a distributed trace, carried from clients to the handlers of their requests.
+///
+/// It has the size of a W3C Trace Context trace ID, so that the host's traces can be carried
+/// over the vsock boundary as is: send it with [`Metadata::with_trace_id`], and read it in
+/// handlers with `server::trace_id`. Requests sent without one get a generated one.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceId([u8; 16]);
+
+impl TraceId {
+	/// Create a trace ID from its bytes.
+	#[must_use]
+	pub const fn new(bytes: [u8; 16]) -> Self {
+		Self(bytes)
+	}
+
+	/// Generate a random trace ID, for requests that aren't part of a trace yet.
+	#[must_use]
+	pub fn generate() -> Self {
+		let mut bytes = [0; 16];
+		bytes[..8].copy_from_slice(&random_u64().to_be_bytes());
+		bytes[8..].copy_from_slice(&random_u64().to_be_bytes());
+
+		Self(bytes)
+	}
+
+	/// Parse a trace ID from 32 hexadecimal digits, as found in a W3C `traceparent` header.
+	#[must_use]
+	pub fn from_hex(hex: &str) -> Option<Self> {
+		if hex.len() != 32 || !hex.is_ascii() {
+			return None;
+		}
+
+		let mut bytes = [0; 16];
+		for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+			let digits = std::str::from_utf8(digits).ok()?;
+			*byte = u8::from_str_radix(digits, 16).ok()?;
+		}
+
+		Some(Self(bytes))
+	}
+
+	/// The bytes of the trace ID.
+	#[must_use]
+	pub const fn as_bytes(&self) -> &[u8; 16] {
+		&self.0
+	}
+}
+
+impl std::fmt::Display for TraceId {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+	}
+}
+
+impl std::fmt::Debug for TraceId {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "TraceId({self})")
+	}
+}
+
+/// Headers attached to a request next to its payload, such as an idempotency key.
+///
+/// Headers let the server act on a request without decoding its payload, so they are
+/// encoded independently of the payload format: a count, followed by each key (a byte
+/// length and UTF-8 text) and its value (a big-endian `u16` length and raw bytes). The
+/// whole encoding may not exceed [`MAX_METADATA_BYTES`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+	headers: BTreeMap<String, Vec<u8>>,
+}
+
+impl Metadata {
+	/// Identifies a request across retries, see `Router::route_idempotent`.
+	pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+	/// Asks the server not to serve the request from a response cache, see `Router::route_cached`.
+	pub const NO_CACHE: &str = "no-cache";
+	/// The [`Compression`] of the request payload, by name. Uncompressed when missing.
+	pub const CONTENT_ENCODING: &str = "content-encoding";
+	/// The compressions the client accepts for the response, as comma-separated names.
+	pub const ACCEPT_ENCODING: &str = "accept-encoding";
+	/// The [`Checksum`] following the request payload, by name. Not checksummed when missing.
+	pub const CHECKSUM: &str = "checksum";
+	/// Ties the log lines of a request together on both ends, see [`generate_request_id`].
+	pub const REQUEST_ID: &str = "request-id";
+	/// The [`TraceId`] of the trace the request is part of, as 16 raw bytes.
+	pub const TRACE_ID: &str = "trace-id";
+
+	/// Create an empty set of headers.
+	#[must_use]
+	pub const fn new() -> Self {
+		Self {
+			headers: BTreeMap::new(),
+		}
+	}
+
+	/// Add a header, returning its previous value if it was already set.
+	///
+	/// Keys longer than 255 bytes, more than 255 headers, or headers adding up to more than
+	/// [`MAX_METADATA_BYTES`] can't be sent, and fail the request with a `Writing` error.
+	pub fn insert(&mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Option<Vec<u8>> {
+		self.headers.insert(key.into(), value.into())
+	}
+
+	/// Add a header.
+	#[must_use]
+	pub fn with(mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+		self.insert(key, value);
+		self
+	}
+
+	/// Add an idempotency key, identifying the request across retries.
+	#[must_use]
+	pub fn with_idempotency_key(self, key: impl Into<Vec<u8>>) -> Self {
+		self.with(Self::IDEMPOTENCY_KEY, key)
+	}
+
+	/// Get the value of a header.
+	#[must_use]
+	pub fn get(&self, key: &str) -> Option<&[u8]> {
+		self.headers.get(key).map(Vec::as_slice)
+	}
+
+	/// Get the idempotency key of the request, if it has one.
+	#[must_use]
+	pub fn idempotency_key(&self) -> Option<&[u8]> {
+		self.get(Self::IDEMPOTENCY_KEY)
+	}
+
+	/// Add a request ID, to correlate the logs of the client and the server.
+	#[must_use]
+	pub fn with_request_id(self, id: impl Into<String>) -> Self {
+		self.with(Self::REQUEST_ID, id.into())
+	}
+
+	/// Get the ID of the request, if it has one and it is valid UTF-8.
+	#[must_use]
+	pub fn request_id(&self) -> Option<&str> {
+		self.get(Self::REQUEST_ID)
+			.and_then(|id| std::str::from_utf8(id).ok())
+	}
+
+	/// Add the ID of the trace the request is part of, to carry it over to the server.
+	#[must_use]
+	pub fn with_trace_id(self, id: TraceId) -> Self {
+		self.with(Self::TRACE_ID, id.0)
+	}
+
+	/// Get the ID of the trace the request is part of, if it has one of the right length.
+	#[must_use]
+	pub fn trace_id(&self) -> Option<TraceId> {
+		self.get(Self::TRACE_ID)
+			.and_then(|id| id.try_into().ok())
+			.map(TraceId)
+	}
+
+	/// Get the compression of the request payload, or the unknown name it announces.
+	///
+	/// # Errors
+	///
+	/// Returns the announced name if it isn't a known and enabled compression.
+	pub fn content_encoding(&self) -> Result<Compression, String> {
+		let Some(name) = self.get(Self::CONTENT_ENCODING) else {
+			return Ok(Compression::None);
+		};
+
+		Compression::from_name(name).ok_or_else(|| String::from_utf8_lossy(name).into_owned())
+	}
+
+	/// Get the checksum following the request payload, or the unknown name it announces.
+	///
+	/// # Errors
+	///
+	/// Returns the announced name if it isn't a known and enabled checksum.
+	pub fn checksum(&self) -> Result<Checksum, String> {
+		let Some(name) = self.get(Self::CHECKSUM) else {
+			return Ok(Checksum::None);
+		};
+
+		Checksum::from_name(name).ok_or_else(|| String::from_utf8_lossy(name).into_owned())
+	}
+
+	/// Whether the client accepts responses with the given compression.
+	#[must_use]
+	pub fn accepts(&self, compression: Compression) -> bool {
+		compression == Compression::None
+			|| self.get(Self::ACCEPT_ENCODING).is_some_and(|names| {
+				names
+					.split(|&byte| byte == b',')
+					.any(|name| name.trim_ascii() == compression.name().as_bytes())
+			})
+	}
+
+	/// The number of headers.
+	#[must_use]
+	pub fn len(&self) -> usize {
+		self.headers.len()
+	}
+
+	/// Whether there are no headers.
+	#[must_use]
+	pub fn is_empty(&self) -> bool {
+		self.headers.is_empty()
+	}
+
+	/// Iterate over the headers, ordered by key.
+	pub fn headers(&self) -> impl Iterator<Item = (&str, &[u8])> {
+		self.headers
+			.iter()
+			.map(|(key, value)| (key.as_str(), value.as_slice()))
+	}
+}
+
+/// Write request headers, in the layout described on [`Metadata`].
+///
+/// Errors are reported through `on_error`, like [`write_frame`].
+#[cfg(any(feature = "client", test))]
+pub(crate) async fn write_metadata<W, E>(
+	writer: &mut W,
+	metadata: &Metadata,
+	on_error: impl Fn(CodingKey, io::Error) -> E + Send + Sync,
+) -> Result<(), E>
+where
+	W: AsyncWrite + Unpin + Send + ?Sized,
+{
+	let invalid = |reason: &str| {
+		on_error(
+			CodingKey::Metadata,
+			io::Error::new(io::ErrorKind::InvalidInput, reason),
+		)
+	};
+
+	// Headers are tiny, so they are assembled up front and written in one go.
+	let mut buf = vec![u8::try_from(metadata.len()).map_err(|_| invalid("too many headers"))?];
+	for (key, value) in metadata.headers() {
+		buf.push(u8::try_from(key.len()).map_err(|_| invalid("header key too long"))?);
+		buf.extend_from_slice(key.as_bytes());
+
+		let length = u16::try_from(value.len()).map_err(|_| invalid("header value too long"))?;
+		buf.extend_from_slice(&length.to_be_bytes());
+		buf.extend_from_slice(value);
+	}
+
+	if buf.len() > MAX_METADATA_BYTES {
+		return Err(invalid("headers exceed MAX_METADATA_BYTES"));
+	}
+
+	writer
+		.write_all(&buf)
+		.await
+		.map_err(|e| on_error(CodingKey::Metadata, e))
+}
+
+/// Read request headers, in the layout described on [`Metadata`].
+///
+/// Headers adding up to more than [`MAX_METADATA_BYTES`] are refused as soon as a length
+/// prefix would cross the limit, before the bytes it announces are read.
+#[cfg(any(feature = "server", test))]
+pub(crate) async fn read_metadata<R>(reader: &mut R) -> Result<Metadata, (CodingKey, io::Error)>
+where
+	R: AsyncRead + Unpin + ?Sized,
+{
+	let error = |e| (CodingKey::Metadata, e);
+	let mut budget = Budget(MAX_METADATA_BYTES);
+
+	let count = reader.read_u8().await.map_err(error)?;
+	budget.spend(1)?;
+
+	let mut metadata = Metadata::new();
+	for _ in 0..count {
+		let length = usize::from(reader.read_u8().await.map_err(error)?);
+		budget.spend(1 + length)?;
+		let mut key = vec![0; length];
+		reader.read_exact(&mut key).await.map_err(error)?;
+		let key = String::from_utf8(key)
+			.map_err(|e| error(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+
+		let length = usize::from(reader.read_u16().await.map_err(error)?);
+		budget.spend(2 + length)?;
+		let mut value = vec![0; length];
+		reader.read_exact(&mut value).await.map_err(error)?;
+
+		metadata.insert(key, value);
+	}
+
+	Ok(metadata)
+}
+
+/// How many more metadata bytes a peer may send.
+#[cfg(any(feature = "server", test))]
+struct Budget(usize);
+
+#[cfg(any(feature = "server", test))]
+impl Budget {
+	fn spend(&mut self, bytes: usize) -> Result<(), (CodingKey, io::Error)> {
+		self.0 = self.0.checked_sub(bytes).ok_or_else(|| {
+			(
+				CodingKey::Metadata,
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("headers exceed {MAX_METADATA_BYTES} bytes"),
+				),
+			)
+		})?;
+
+		Ok(())
+	}
+}
+
+/// Write a length-prefixed frame: a big-endian `u64` length followed by the payload.
+///
+/// The payload must already be fully encoded. The length prefix is derived from the very
+/// buffer that is written right after it, so the two can never disagree; a peer reading
+/// exactly `length` bytes always ends up at the start of the next frame. Any future
+/// encoding path that can't produce the whole payload up front must not go through here.
+///
+/// Errors are reported through `on_error`, which is told which part of the frame failed.
+pub(crate) async fn write_frame<W, E>(
+	writer: &mut W,
+	payload: &[u8],
+	on_error: impl Fn(CodingKey, io::Error) -> E,
+) -> Result<(), E>
+where
+	W: AsyncWrite + Unpin + ?Sized,
+{
+	let length = u64::try_from(payload.len())
+		.map_err(|_| on_error(CodingKey::Length, io::ErrorKind::InvalidInput.into()))?;
+
+	writer
+		.write_u64(length)
+		.await
+		.map_err(|e| on_error(CodingKey::Length, e))?;
+
+	writer
+		.write_all(payload)
+		.await
+		.map_err(|e| on_error(CodingKey::Payload, e))
+}
+
+/// Read a length-prefixed frame and throw its payload away, leaving the reader at the next frame.
+///
+/// Frames declaring more than `limit` bytes are refused before any of their payload is read.
+/// Returns the number of discarded payload bytes.
+#[cfg(any(feature = "server", test))]
+pub(crate) async fn drain_frame<R>(
+	reader: &mut R,
+	limit: u64,
+) -> Result<u64, (CodingKey, io::Error)>
+where
+	R: AsyncRead + Unpin + ?Sized,
+{
+	let length = reader
+		.read_u64()
+		.await
+		.map_err(|e| (CodingKey::Length, e))?;
+
+	if length > limit {
+		return Err((
+			CodingKey::Length,
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("refusing to drain {length} bytes, limit is {limit}"),
+			),
+		));
+	}
+
+	let drained = tokio::io::copy(&mut reader.take(length), &mut tokio::io::sink())
+		.await
+		.map_err(|e| (CodingKey::Payload, e))?;
+
+	if drained < length {
+		return Err((CodingKey::Payload, io::ErrorKind::UnexpectedEof.into()));
+	}
+
+	Ok(drained)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use proptest::prelude::*;
+
+	#[test]
+	fn test_encoded_len_matches_encoding() {
+		#[derive(Serialize)]
+		struct Echo {
+			message: String,
+			repeat: u32,
+		}
+
+		let value = Echo {
+			message: "hello, world!".to_string(),
+			repeat: 3,
+		};
+
+		for structs in [StructEncoding::Array, StructEncoding::Map] {
+			let format = Format::new(structs);
+
+			assert_eq!(
+				format.encoded_len(&value).unwrap(),
+				format.encode(&value).unwrap().len()
+			);
+
+			let exact = format.encode_exact(&value).unwrap();
+			assert_eq!(exact, format.encode(&value).unwrap());
+			assert_eq!(exact.capacity(), exact.len());
+		}
+
+		assert_eq!(
+			encoded_len(&value).unwrap(),
+			Format::default().encode(&value).unwrap().len()
+		);
+	}
+
+	fn frame(payload: &[u8]) -> Vec<u8> {
+		let mut frame = Vec::new();
+		tokio_test::block_on(write_frame(&mut frame, payload, |_, e| e)).unwrap();
+		frame
+	}
+
+	/// Draining a rejected frame leaves the connection positioned at the follow-up request.
+	#[test]
+	fn test_drain_keeps_stream_aligned() {
+		let mut wire = frame(b"rejected request");
+		wire.extend(frame(b"follow-up"));
+		let mut reader = wire.as_slice();
+
+		let drained = tokio_test::block_on(drain_frame(&mut reader, 1024)).unwrap();
+		assert_eq!(drained, 16);
+
+		let length = tokio_test::block_on(reader.read_u64()).unwrap();
+		assert_eq!(length, 9);
+		assert_eq!(reader, b"follow-up");
+	}
+
+	/// A frame larger than the drain limit is refused without consuming its payload.
+	#[test]
+	fn test_drain_refuses_oversized_frame() {
+		let wire = frame(&[0; 64]);
+		let mut reader = wire.as_slice();
+
+		let (key, _) = tokio_test::block_on(drain_frame(&mut reader, 32)).unwrap_err();
+		assert!(matches!(key, CodingKey::Length));
+		assert_eq!(reader.len(), 64);
+	}
+
+	proptest! {
+		/// The length prefix of a frame always equals the number of payload bytes following it.
+		#[test]
+		fn test_frame_length_matches_payload(payload in proptest::collection::vec(any::<u8>(), 0..4096)) {
+			let mut frame = Vec::new();
+			tokio_test::block_on(write_frame(&mut frame, &payload, |_, e| e)).unwrap();
+
+			let (prefix, written) = frame.split_at(8);
+			let length = u64::from_be_bytes(prefix.try_into().unwrap());
+
+			prop_assert_eq!(usize::try_from(length).unwrap(), written.len());
+			prop_assert_eq!(written, payload.as_slice());
+		}
+	}
+
+	#[test]
+	fn test_metadata_roundtrip() {
+		let metadata = Metadata::new()
+			.with_idempotency_key(b"order-42".to_vec())
+			.with(Metadata::NO_CACHE, Vec::new());
+
+		let mut wire = Vec::new();
+		tokio_test::block_on(write_metadata(&mut wire, &metadata, |_, e| e)).unwrap();
+		wire.extend(frame(b"payload"));
+		let mut reader = wire.as_slice();
+
+		let decoded = tokio_test::block_on(read_metadata(&mut reader)).unwrap();
+		assert_eq!(decoded, metadata);
+		assert_eq!(decoded.idempotency_key(), Some(b"order-42".as_slice()));
+		assert_eq!(tokio_test::block_on(reader.read_u64()).unwrap(), 7);
+
+		let oversized = Metadata::new().with("key", vec![0; MAX_METADATA_BYTES]);
+		assert!(
+			tokio_test::block_on(write_metadata(&mut Vec::new(), &oversized, |_, e| e)).is_err()
+		);
+	}
+
+	/// Generated request IDs are distinct hexadecimal strings, readable back from metadata.
+	#[test]
+	fn test_request_id() {
+		let ids: std::collections::HashSet<_> = (0..1000).map(|_| generate_request_id()).collect();
+		assert_eq!(ids.len(), 1000);
+		assert!(
+			ids.iter()
+				.all(|id| id.len() == 16 && id.bytes().all(|b| b.is_ascii_hexdigit()))
+		);
+
+		let metadata = Metadata::new().with_request_id("0123456789abcdef");
+		assert_eq!(metadata.request_id(), Some("0123456789abcdef"));
+		assert_eq!(Metadata::new().request_id(), None);
+	}
+
+	/// Trace IDs travel as raw bytes, and print as the hexadecimal digits they parse from.
+	#[test]
+	fn test_trace_id() {
+		let hex = "4bf92f3577b34da6a3ce929d0e0e4736";
+		let id = TraceId::from_hex(hex).unwrap();
+		assert_eq!(id.to_string(), hex);
+		assert_eq!(id.as_bytes()[..2], [0x4b, 0xf9]);
+
+		assert_eq!(TraceId::from_hex(&hex[1..]), None);
+		assert_eq!(TraceId::from_hex(&hex.replace('4', "g")), None);
+
+		let metadata = Metadata::new().with_trace_id(id);
+		assert_eq!(metadata.trace_id(), Some(id));
+		let truncated = Metadata::new().with(Metadata::TRACE_ID, vec![0; 8]);
+		assert_eq!(truncated.trace_id(), None);
+
+		assert_ne!(TraceId::generate(), TraceId::generate());
+	}
+
+	/// Oversized headers are refused from their length prefix, before the value is read.
+	#[test]
+	fn test_read_metadata_enforces_limit() {
+		let mut wire = vec![1, 3];
+		wire.extend_from_slice(b"key");
+		wire.extend_from_slice(&u16::MAX.to_be_bytes());
+		let mut reader = wire.as_slice();
+
+		let (key, e) = tokio_test::block_on(read_metadata(&mut reader)).unwrap_err();
+		assert!(matches!(key, CodingKey::Metadata));
+		assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn test_error_frame_roundtrip() {
+		let frame = ErrorFrame::new(
+			ErrorFrame::UNKNOWN_REQUEST,
+			"Unknown request type: 0x0000002a".to_string(),
+		);
+
+		assert_eq!(ErrorFrame::decode(&frame.encode()).unwrap(), frame);
+		assert!(ErrorFrame::decode(b"garbage").is_err());
+	}
+
+	#[test]
+	fn test_handler_error_into_frame() {
+		let frame = ErrorFrame::from(HandlerError::from("unknown account"));
+		assert_eq!(frame.code, ErrorFrame::HANDLER);
+		assert_eq!(frame.message, "unknown account");
+
+		let error = HandlerError::new("over quota").with_code(ErrorFrame::APPLICATION + 1);
+		assert_eq!(ErrorFrame::from(error).code, 1001);
+	}
+
+	#[test]
+	fn test_descriptor_roundtrip() {
+		for structs in [StructEncoding::Array, StructEncoding::Map] {
+			let format = Format::new(structs);
+
+			assert_eq!(Format::from_descriptor(format.descriptor()), Some(format));
+		}
+
+		assert_eq!(Format::from_descriptor(0xF0), None);
+	}
+
+	#[test]
+	fn test_codec_roundtrip() {
+		let codecs = [
+			Codec::MessagePack,
+			#[cfg(feature = "codec-cbor")]
+			Codec::Cbor,
+			#[cfg(feature = "codec-json")]
+			Codec::Json,
+		];
+		let value = (42_u32, "payload".to_string(), vec![1_u8, 2, 3]);
+
+		for codec in codecs {
+			let format = Format::default().with_codec(codec);
+			let encoded = format.encode(&value).unwrap();
+
+			assert_eq!(Codec::from_id(codec.id()), Some(codec));
+			assert_eq!(Format::from_descriptor(format.descriptor()), Some(format));
+			assert_eq!(format.encoded_len(&value).unwrap(), encoded.len());
+			assert_eq!(
+				format.decode::<(u32, String, Vec<u8>)>(&encoded).unwrap(),
+				value
+			);
+		}
+	}
+
+	/// A peer encoding structs as maps must be refused by one encoding them as arrays, and vice versa.
+	#[test]
+	fn test_mismatched_struct_encoding() {
+		let arrays = Format::new(StructEncoding::Array);
+		let maps = Format::new(StructEncoding::Map);
+
+		assert!(arrays.negotiate(arrays.descriptor()).is_ok());
+		assert!(maps.negotiate(maps.descriptor()).is_ok());
+
+		let err = arrays.negotiate(maps.descriptor()).unwrap_err();
+		assert_eq!(err.local, arrays);
+		assert_eq!(err.remote, maps.descriptor());
+
+		assert!(maps.negotiate(arrays.descriptor()).is_err());
+		assert!(arrays.negotiate(0xFF).is_err());
+	}
+
+	#[cfg(feature = "compression")]
+	#[test]
+	fn test_compression_roundtrip() {
+		let config = CompressionConfig::new(64);
+		let payload = vec![7; 4096];
+
+		let (compression, compressed) = config.compress(payload.clone());
+		assert_eq!(compression, Compression::Zstd);
+		assert!(compressed.len() < payload.len());
+
+		let decompressed = decompress(compression, compressed.clone(), 4096).unwrap();
+		assert_eq!(decompressed, payload);
+
+		// Inflating beyond the limit is refused rather than allocated.
+		assert!(decompress(compression, compressed, 4095).is_err());
+
+		// Small payloads aren't worth compressing.
+		assert_eq!(config.compress(vec![7; 16]).0, Compression::None);
+
+		let metadata = Metadata::new().with(Metadata::ACCEPT_ENCODING, "identity, zstd");
+		assert!(metadata.accepts(Compression::Zstd));
+		assert!(!Metadata::new().accepts(Compression::Zstd));
+	}
+
+	#[cfg(feature = "checksum")]
+	#[test]
+	fn test_crc32c_checksum() {
+		assert_eq!(Checksum::Crc32c.compute(b"123456789"), Some(0xE306_9283));
+		assert_eq!(Checksum::None.compute(b"123456789"), None);
+
+		let metadata = Metadata::new().with(Metadata::CHECKSUM, Checksum::Crc32c.name());
+		assert_eq!(metadata.checksum(), Ok(Checksum::Crc32c));
+		assert_eq!(Metadata::new().checksum(), Ok(Checksum::None));
+	}
+}
@@ -0,0 +1,310 @@
+//! Drives clients against routers served over in-memory pipes, through the same framing,
+//! codecs and dispatch as over vsock.
+
+use pontifex::{
+	Request, Router,
+	client::{self, ConnectionDetails},
+	testing::{self, LocalClient},
+	wire::{ErrorFrame, HandlerError},
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Echo {
+	message: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct EchoResponse {
+	message: String,
+}
+
+impl Request for Echo {
+	const ROUTE_ID: &'static str = "echo_v1";
+	type Response = EchoResponse;
+}
+
+/// Sent to the echo route, but with a payload that doesn't decode as an [`Echo`].
+#[derive(Debug, Serialize, Deserialize)]
+struct MalformedEcho(u64);
+
+impl Request for MalformedEcho {
+	const ROUTE_ID: &'static str = "echo_v1";
+	type Response = EchoResponse;
+}
+
+/// A route the router doesn't have.
+#[derive(Debug, Serialize, Deserialize)]
+struct Shout {
+	message: String,
+}
+
+impl Request for Shout {
+	const ROUTE_ID: &'static str = "shout_v1";
+	type Response = EchoResponse;
+}
+
+/// Echoes `times` copies of the message back.
+#[derive(Debug, Serialize, Deserialize)]
+struct Repeat {
+	message: String,
+	times: usize,
+}
+
+impl Request for Repeat {
+	const ROUTE_ID: &'static str = "repeat_v1";
+	type Response = EchoResponse;
+}
+
+/// Halves even numbers, and refuses odd ones.
+#[derive(Debug, Serialize, Deserialize)]
+struct Half(u32);
+
+impl Request for Half {
+	const ROUTE_ID: &'static str = "half_v1";
+	type Response = u32;
+}
+
+fn client() -> LocalClient<()> {
+	let router = Router::new()
+		.route_fn::<Echo>(|(), request: Echo| async move {
+			EchoResponse {
+				message: request.message,
+			}
+		})
+		.route_fn::<Repeat>(|(), request: Repeat| async move {
+			EchoResponse {
+				message: request.message.repeat(request.times),
+			}
+		})
+		.try_route::<Half, HandlerError, _, _>(|(), Half(n)| async move {
+			if n % 2 == 0 {
+				Ok(n / 2)
+			} else {
+				Err(HandlerError::new(format!("{n} is odd")))
+			}
+		});
+
+	testing::local(router).unwrap()
+}
+
+fn echo(message: &str) -> Echo {
+	Echo {
+		message: message.to_string(),
+	}
+}
+
+#[test]
+fn typed_requests_round_trip() {
+	tokio_test::block_on(async {
+		let client = client();
+
+		let response = client.send(&echo("hello")).await.unwrap();
+		assert_eq!(response.message, "hello");
+	});
+}
+
+#[test]
+fn unknown_routes_are_refused_without_breaking_the_connection() {
+	tokio_test::block_on(async {
+		let mut connection = client().connect().await.unwrap();
+
+		let shout = Shout {
+			message: "hello".to_string(),
+		};
+		let error = connection.send(&shout).await.unwrap_err();
+		assert!(
+			matches!(
+				error,
+				client::Error::Remote {
+					code: ErrorFrame::UNKNOWN_REQUEST,
+					..
+				}
+			),
+			"{error:?}"
+		);
+
+		let response = connection.send(&echo("still there")).await.unwrap();
+		assert_eq!(response.message, "still there");
+	});
+}
+
+#[test]
+fn undecodable_payloads_are_refused_without_breaking_the_connection() {
+	tokio_test::block_on(async {
+		let mut connection = client().connect().await.unwrap();
+
+		let error = connection.send(&MalformedEcho(42)).await.unwrap_err();
+		assert!(
+			matches!(
+				error,
+				client::Error::Remote {
+					code: ErrorFrame::DECODING,
+					..
+				}
+			),
+			"{error:?}"
+		);
+
+		let response = connection.send(&echo("still there")).await.unwrap();
+		assert_eq!(response.message, "still there");
+	});
+}
+
+#[test]
+fn connections_carry_requests_one_after_the_other() {
+	tokio_test::block_on(async {
+		let mut connection = client().connect().await.unwrap();
+
+		for n in [2, 4, 8] {
+			assert_eq!(connection.send(&Half(n)).await.unwrap(), n / 2);
+		}
+
+		// Errors reported by the server leave the connection on a frame boundary.
+		let error = connection.send(&Half(3)).await.unwrap_err();
+		assert!(matches!(error, client::Error::Remote { .. }), "{error:?}");
+
+		let response = connection.send(&echo("still there")).await.unwrap();
+		assert_eq!(response.message, "still there");
+	});
+}
+
+#[test]
+fn connections_are_broken_by_requests_failing_halfway() {
+	tokio_test::block_on(async {
+		tokio::time::pause();
+		let router = Router::new().route_fn::<Echo>(|(), request: Echo| async move {
+			tokio::time::sleep(Duration::from_secs(3600)).await;
+			EchoResponse {
+				message: request.message,
+			}
+		});
+		let details = ConnectionDetails::new(0, 0).with_timeout(Duration::from_secs(1));
+		let mut connection = testing::local(router)
+			.unwrap()
+			.with_details(details)
+			.connect()
+			.await
+			.unwrap();
+
+		// The response may still arrive, and would be read as the next one's.
+		let error = connection.send(&echo("hello")).await.unwrap_err();
+		assert!(matches!(error, client::Error::Timeout(_)), "{error:?}");
+
+		let error = connection.send(&echo("hello")).await.unwrap_err();
+		assert!(matches!(error, client::Error::Broken), "{error:?}");
+	});
+}
+
+#[test]
+fn slow_handlers_time_out() {
+	tokio_test::block_on(async {
+		// The clock only moves once every task is idle, so the timeout elapses right away.
+		tokio::time::pause();
+		let router = Router::new()
+			.route_fn::<Echo>(|(), request: Echo| async move {
+				tokio::time::sleep(Duration::from_secs(3600)).await;
+				EchoResponse {
+					message: request.message,
+				}
+			})
+			.with_timeout(Duration::from_secs(1));
+
+		let error = testing::local(router)
+			.unwrap()
+			.send(&echo("hello"))
+			.await
+			.unwrap_err();
+		assert!(
+			matches!(
+				error,
+				client::Error::Remote {
+					code: ErrorFrame::TIMEOUT,
+					..
+				}
+			),
+			"{error:?}"
+		);
+	});
+}
+
+#[test]
+fn responses_larger_than_the_pipe_round_trip() {
+	tokio_test::block_on(async {
+		let client = client();
+		// The pipe buffers 64 KiB each way, so this can only arrive if it is streamed.
+		let repeat = Repeat {
+			message: "0123456789abcdef".to_string(),
+			times: 64 * 1024,
+		};
+
+		let response = client.send(&repeat).await.unwrap();
+		assert_eq!(response.message.len(), 16 * 64 * 1024);
+	});
+}
+
+#[test]
+fn responses_larger_than_the_client_accepts_are_refused() {
+	tokio_test::block_on(async {
+		let details = ConnectionDetails::new(0, 0).with_max_payload(1024);
+		let client = client().with_details(details);
+		let repeat = Repeat {
+			message: "0123456789abcdef".to_string(),
+			times: 1024,
+		};
+
+		let error = client.send(&repeat).await.unwrap_err();
+		assert!(
+			matches!(error, client::Error::PayloadTooLarge { .. }),
+			"{error:?}"
+		);
+	});
+}
+
+#[test]
+fn batches_round_trip_in_order() {
+	tokio_test::block_on(async {
+		let client = client();
+		// Both the requests and the responses overflow the pipe, so this only completes if
+		// responses are read while requests are still being written.
+		let requests: Vec<_> = (0..100)
+			.map(|i| Repeat {
+				message: format!("{i:04}").repeat(1024),
+				times: 4,
+			})
+			.collect();
+
+		let responses = client.send_batch(&requests).await.unwrap();
+		assert_eq!(responses.len(), requests.len());
+		for (i, response) in responses.iter().enumerate() {
+			assert_eq!(response.message, format!("{i:04}").repeat(4 * 1024));
+		}
+
+		assert!(client.send_batch::<Echo>(&[]).await.unwrap().is_empty());
+	});
+}
+
+#[test]
+fn batches_fail_with_the_first_failed_request() {
+	tokio_test::block_on(async {
+		let mut connection = client().connect().await.unwrap();
+
+		let error = connection
+			.send_batch(&[Half(2), Half(4), Half(5), Half(7)])
+			.await
+			.unwrap_err();
+		let client::Error::Batch { index, source } = &error else {
+			panic!("{error:?}");
+		};
+		assert_eq!(*index, 2);
+		assert!(
+			matches!(**source, client::Error::Remote { ref message, .. } if message == "5 is odd"),
+			"{source:?}"
+		);
+
+		// The responses to the requests after it were left unread.
+		let error = connection.send(&echo("hello")).await.unwrap_err();
+		assert!(matches!(error, client::Error::Broken), "{error:?}");
+	});
+}